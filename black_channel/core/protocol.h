@@ -0,0 +1,68 @@
+#ifndef _BC_PROTOCOL_H_
+#define _BC_PROTOCOL_H_
+
+#include <stdint.h>
+
+/// -------------------------------------------------------------------------
+/// wire protocol, mirrors crimson_heart/src/crimson/black_channel_protocol.rs
+
+/*
+Description
+-----------
+Every frame is an 8 byte header followed by `length` payload bytes.
+All integers are big endian (network order).
+
+	0       2         3      4          8
+	+-------+---------+------+----------+---------------+
+	| magic | version | type |  length  |  payload ...  |
+	+-------+---------+------+----------+---------------+
+
+Payloads
+--------
+HANDSHAKE       u16 len, client_id
+HANDSHAKE_ACK   u16 len, node_id | u32 capacity
+DISPATCH_JOB    u8[16] job_id | u32 required_slots | u32 len, command
+CANCEL          u8[16] job_id
+HEARTBEAT       u64 timestamp | u32 running_jobs
+RESULT          u8[16] job_id | u8 outcome | i32 exit_code | u32 len, message
+
+Note
+----
+Strings are UTF-8 and not NUL terminated. A frame with a bad magic,
+an unknown version or a length above `BC_PROTOCOL_MAX_PAYLOAD` closes
+the connection.
+*/
+
+#define BC_PROTOCOL_MAGIC 0xBC0D
+#define BC_PROTOCOL_VERSION 1
+#define BC_PROTOCOL_HEADER_LEN 8
+#define BC_PROTOCOL_MAX_PAYLOAD (16 * 1024 * 1024)
+
+typedef enum bc_frame_type_t {
+	BC_FRAME_HANDSHAKE = 1,
+	BC_FRAME_HANDSHAKE_ACK = 2,
+	BC_FRAME_DISPATCH_JOB = 3,
+	BC_FRAME_CANCEL = 4,
+	BC_FRAME_HEARTBEAT = 5,
+	BC_FRAME_RESULT = 6,
+} bc_frame_type_t;
+
+typedef enum bc_job_outcome_t {
+	BC_JOB_STARTED = 1,
+	BC_JOB_SUCCEEDED = 2,
+	BC_JOB_FAILED = 3,
+	BC_JOB_CANCELLED = 4,
+	BC_JOB_REJECTED = 5,
+} bc_job_outcome_t;
+
+typedef struct __attribute__((packed)) bc_frame_header_t {
+	uint16_t magic; // BC_PROTOCOL_MAGIC, big endian
+	uint8_t version; // BC_PROTOCOL_VERSION
+	uint8_t type; // bc_frame_type_t
+	uint32_t length; // payload length, big endian
+} bc_frame_header_t;
+
+_Static_assert(sizeof(bc_frame_header_t) == BC_PROTOCOL_HEADER_LEN,
+	       "bc_frame_header_t must match the wire header");
+
+#endif
@@ -0,0 +1,23 @@
+CREATE TABLE IF NOT EXISTS jobs (
+    job_id UUID PRIMARY KEY,
+    user_id UUID NOT NULL REFERENCES users (user_id) ON DELETE CASCADE,
+    name STRING NOT NULL,
+    command STRING NOT NULL,
+    required_slots INT8 NOT NULL DEFAULT 1,
+    labels JSONB NOT NULL DEFAULT '{}',
+    state STRING NOT NULL DEFAULT 'queued',
+    worker_id STRING,
+    exit_code INT8,
+    error STRING,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    updated_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    started_at TIMESTAMPTZ,
+    finished_at TIMESTAMPTZ,
+    CONSTRAINT jobs_state_check CHECK (
+        state IN ('queued', 'scheduled', 'running', 'succeeded', 'failed', 'cancelled')
+    )
+);
+
+CREATE INDEX IF NOT EXISTS jobs_user_id_created_at_idx ON jobs (user_id, created_at DESC);
+
+CREATE INDEX IF NOT EXISTS jobs_state_created_at_idx ON jobs (state, created_at);
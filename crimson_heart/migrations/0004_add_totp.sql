@@ -0,0 +1,15 @@
+-- hex of nonce || AES-256-GCM ciphertext, set on enrollment
+ALTER TABLE users ADD COLUMN IF NOT EXISTS totp_secret STRING;
+
+-- null while enrollment is unconfirmed
+ALTER TABLE users ADD COLUMN IF NOT EXISTS totp_enabled_at TIMESTAMPTZ;
+
+-- last accepted time step, a code is never accepted twice
+ALTER TABLE users ADD COLUMN IF NOT EXISTS totp_last_step INT8;
+
+CREATE TABLE IF NOT EXISTS user_recovery_codes (
+    user_id UUID NOT NULL REFERENCES users (user_id) ON DELETE CASCADE,
+    code_hash STRING NOT NULL,
+    used_at TIMESTAMPTZ,
+    PRIMARY KEY (user_id, code_hash)
+);
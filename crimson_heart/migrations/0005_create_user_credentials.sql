@@ -0,0 +1,14 @@
+-- WebAuthn credentials (passkeys), one user may register several
+CREATE TABLE IF NOT EXISTS user_credentials (
+    -- base64url credential id chosen by the authenticator
+    credential_id STRING PRIMARY KEY,
+    user_id UUID NOT NULL REFERENCES users (user_id) ON DELETE CASCADE,
+    -- hex of the uncompressed P-256 point, ES256 only
+    public_key STRING NOT NULL,
+    sign_count INT8 NOT NULL DEFAULT 0,
+    name STRING NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    last_used_at TIMESTAMPTZ
+);
+
+CREATE INDEX IF NOT EXISTS user_credentials_user_id_idx ON user_credentials (user_id);
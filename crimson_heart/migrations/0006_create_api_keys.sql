@@ -0,0 +1,16 @@
+-- personal API keys, the key itself is only ever shown on creation
+CREATE TABLE IF NOT EXISTS api_keys (
+    key_id UUID PRIMARY KEY,
+    user_id UUID NOT NULL REFERENCES users (user_id) ON DELETE CASCADE,
+    name STRING NOT NULL,
+    -- first characters of the key, to tell keys apart in listings
+    prefix STRING NOT NULL,
+    -- hex SHA-256 of the whole key
+    key_hash STRING UNIQUE NOT NULL,
+    scopes STRING[] NOT NULL,
+    expires_at TIMESTAMPTZ,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    last_used_at TIMESTAMPTZ
+);
+
+CREATE INDEX IF NOT EXISTS api_keys_user_id_idx ON api_keys (user_id);
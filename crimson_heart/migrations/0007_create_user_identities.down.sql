@@ -0,0 +1,7 @@
+-- refuses while users created through a provider exist, there is no password
+-- nor birth date to give them, delete or complete those accounts first
+ALTER TABLE users ALTER COLUMN password SET NOT NULL;
+
+ALTER TABLE users ALTER COLUMN birth_date SET NOT NULL;
+
+DROP TABLE IF EXISTS user_identities;
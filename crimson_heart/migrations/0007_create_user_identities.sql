@@ -0,0 +1,20 @@
+-- accounts at external OpenID Connect providers, linked to local users
+CREATE TABLE IF NOT EXISTS user_identities (
+    -- provider name from `[oidc.providers]`
+    provider STRING NOT NULL,
+    -- `sub` claim, stable per provider
+    subject STRING NOT NULL,
+    user_id UUID NOT NULL REFERENCES users (user_id) ON DELETE CASCADE,
+    -- email claimed when the identity was linked
+    email STRING NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    last_login_at TIMESTAMPTZ,
+    PRIMARY KEY (provider, subject)
+);
+
+CREATE INDEX IF NOT EXISTS user_identities_user_id_idx ON user_identities (user_id);
+
+-- users created through a provider have no password nor birth date
+ALTER TABLE users ALTER COLUMN password DROP NOT NULL;
+
+ALTER TABLE users ALTER COLUMN birth_date DROP NOT NULL;
@@ -0,0 +1,12 @@
+-- services allowed to sign users in through crimson
+CREATE TABLE IF NOT EXISTS oauth_clients (
+    client_id STRING PRIMARY KEY,
+    name STRING NOT NULL,
+    -- hex SHA-256 of the secret, null for public clients which must use PKCE
+    secret_hash STRING,
+    -- exact match, no wildcards
+    redirect_uris STRING[] NOT NULL,
+    -- scopes the client may ask for
+    scopes STRING[] NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
@@ -0,0 +1,24 @@
+-- named sets of permissions, see `server_roles` for the permissions checked
+CREATE TABLE IF NOT EXISTS roles (
+    role STRING PRIMARY KEY,
+    description STRING NOT NULL,
+    permissions STRING[] NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+CREATE TABLE IF NOT EXISTS user_roles (
+    user_id UUID NOT NULL REFERENCES users (user_id) ON DELETE CASCADE,
+    role STRING NOT NULL REFERENCES roles (role) ON DELETE CASCADE,
+    -- null when granted from the command line
+    granted_by UUID REFERENCES users (user_id) ON DELETE SET NULL,
+    granted_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    PRIMARY KEY (user_id, role)
+);
+
+CREATE INDEX IF NOT EXISTS user_roles_role_idx ON user_roles (role);
+
+INSERT INTO roles (role, description, permissions)
+VALUES
+    ('admin', 'everything, including granting roles', ARRAY['jobs:submit', 'workers:manage', 'users:admin']),
+    ('operator', 'runs jobs & looks after the worker fleet', ARRAY['jobs:submit', 'workers:manage'])
+ON CONFLICT (role) DO NOTHING;
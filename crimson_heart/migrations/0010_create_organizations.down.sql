@@ -0,0 +1,11 @@
+ALTER TABLE api_keys DROP COLUMN IF EXISTS org_id;
+
+DROP INDEX IF EXISTS jobs_org_id_created_at_idx;
+
+ALTER TABLE jobs DROP COLUMN IF EXISTS org_id;
+
+DROP TABLE IF EXISTS org_invitations;
+
+DROP TABLE IF EXISTS org_members;
+
+DROP TABLE IF EXISTS organizations;
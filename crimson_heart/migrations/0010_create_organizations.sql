@@ -0,0 +1,42 @@
+-- team workspaces sharing jobs & workers
+CREATE TABLE IF NOT EXISTS organizations (
+    org_id UUID PRIMARY KEY,
+    name STRING NOT NULL,
+    created_by UUID REFERENCES users (user_id) ON DELETE SET NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+CREATE TABLE IF NOT EXISTS org_members (
+    org_id UUID NOT NULL REFERENCES organizations (org_id) ON DELETE CASCADE,
+    user_id UUID NOT NULL REFERENCES users (user_id) ON DELETE CASCADE,
+    role STRING NOT NULL,
+    joined_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    PRIMARY KEY (org_id, user_id),
+    CONSTRAINT org_members_role_check CHECK (role IN ('owner', 'admin', 'member'))
+);
+
+CREATE INDEX IF NOT EXISTS org_members_user_id_idx ON org_members (user_id);
+
+-- the token itself is only ever emailed
+CREATE TABLE IF NOT EXISTS org_invitations (
+    invitation_id UUID PRIMARY KEY,
+    org_id UUID NOT NULL REFERENCES organizations (org_id) ON DELETE CASCADE,
+    email STRING NOT NULL,
+    role STRING NOT NULL,
+    -- hex SHA-256 of the token
+    token_hash STRING UNIQUE NOT NULL,
+    invited_by UUID REFERENCES users (user_id) ON DELETE SET NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    expires_at TIMESTAMPTZ NOT NULL,
+    accepted_at TIMESTAMPTZ,
+    CONSTRAINT org_invitations_role_check CHECK (role IN ('owner', 'admin', 'member'))
+);
+
+CREATE INDEX IF NOT EXISTS org_invitations_org_id_idx ON org_invitations (org_id);
+
+-- null for personal jobs & keys
+ALTER TABLE jobs ADD COLUMN IF NOT EXISTS org_id UUID REFERENCES organizations (org_id) ON DELETE CASCADE;
+
+CREATE INDEX IF NOT EXISTS jobs_org_id_created_at_idx ON jobs (org_id, created_at DESC);
+
+ALTER TABLE api_keys ADD COLUMN IF NOT EXISTS org_id UUID REFERENCES organizations (org_id) ON DELETE CASCADE;
@@ -0,0 +1,2 @@
+-- the original spelling is gone, case-folded emails keep working
+DROP TABLE IF EXISTS user_email_conflicts;
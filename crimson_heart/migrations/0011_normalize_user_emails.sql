@@ -0,0 +1,29 @@
+-- emails are compared case-folded since registration validates them, stored
+-- ones are case-folded too. Of accounts whose addresses differ only in case,
+-- one keeps the address, the others are recorded here & left as they are.
+-- Login can't reach them until an operator resolves each entry.
+CREATE TABLE IF NOT EXISTS user_email_conflicts (
+    user_id UUID PRIMARY KEY REFERENCES users (user_id) ON DELETE CASCADE,
+    email STRING NOT NULL,
+    normalized_email STRING NOT NULL,
+    detected_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+-- the address goes to the account already spelling it case-folded, else to the
+-- verified one, else to the oldest
+INSERT INTO user_email_conflicts (user_id, email, normalized_email)
+SELECT user_id, email, normalized_email
+FROM (
+    SELECT user_id, email, lower(trim(email)) AS normalized_email,
+        ROW_NUMBER() OVER (
+            PARTITION BY lower(trim(email))
+            ORDER BY email = lower(trim(email)) DESC, email_verified_at IS NULL, created_at, user_id
+        ) AS claim
+    FROM users
+) AS ranked
+WHERE claim > 1
+ON CONFLICT (user_id) DO NOTHING;
+
+UPDATE users SET email = lower(trim(email))
+WHERE email != lower(trim(email))
+    AND user_id NOT IN (SELECT user_id FROM user_email_conflicts);
@@ -0,0 +1,153 @@
+use super::api_admin_types;
+use super::server_errors::ApiError;
+use super::server_roles::{self, Permitted, RevokeOutcome, UsersAdmin};
+use super::server_types;
+
+// API keys carry no admin scope, roles are only changed from a cookie session
+fn require_session(caller: &Permitted<UsersAdmin>) -> Result<(), ApiError> {
+    match caller.key_id {
+        Some(_) => Err(ApiError::forbidden(
+            "session_required",
+            "API keys can't administer users",
+        )),
+        None => Ok(()),
+    }
+}
+
+/**
+ * # Brief
+ * HTTP GET request. Lists roles & the permissions they grant.
+ *
+ * # Detail
+ * - Requires `users:admin` & a cookie session.
+ */
+#[actix_web::get("/admin/roles")]
+async fn http_get_admin_roles(
+    __authenticated_caller: Permitted<UsersAdmin>,
+    __server_state: actix_web::web::Data<server_types::ServerState>,
+) -> Result<actix_web::HttpResponse, ApiError> {
+    require_session(&__authenticated_caller)?;
+
+    let roles = server_roles::list_roles(&__server_state.central_db_pool)
+        .await
+        .map_err(|e| ApiError::database(e, "SELECT", "roles"))?;
+    Ok(actix_web::HttpResponse::Ok().json(roles))
+}
+
+/**
+ * # Brief
+ * HTTP GET request. Lists the roles a User holds.
+ *
+ * # Detail
+ * - Requires `users:admin` & a cookie session.
+ */
+#[actix_web::get("/admin/users/{user_id}/roles")]
+async fn http_get_admin_user_roles(
+    __authenticated_caller: Permitted<UsersAdmin>,
+    __request_path: actix_web::web::Path<uuid::Uuid>,
+    __server_state: actix_web::web::Data<server_types::ServerState>,
+) -> Result<actix_web::HttpResponse, ApiError> {
+    require_session(&__authenticated_caller)?;
+
+    let user_roles =
+        server_roles::user_roles(&__server_state.central_db_pool, __request_path.into_inner())
+            .await
+            .map_err(|e| ApiError::database(e, "SELECT", "user_roles"))?;
+    Ok(actix_web::HttpResponse::Ok().json(user_roles))
+}
+
+/**
+ * # Brief
+ * HTTP POST request. Grants a role to a User.
+ *
+ * # Detail
+ * - Requires `users:admin` & a cookie session.
+ * - Responds 201 when granted, 200 when the User already held the role.
+ */
+#[actix_web::post("/admin/users/{user_id}/roles")]
+async fn http_post_admin_user_role(
+    __authenticated_caller: Permitted<UsersAdmin>,
+    __request_path: actix_web::web::Path<uuid::Uuid>,
+    __request_payload: actix_web::web::Json<api_admin_types::HTTPRoleGrant>,
+    __server_state: actix_web::web::Data<server_types::ServerState>,
+) -> Result<actix_web::HttpResponse, ApiError> {
+    require_session(&__authenticated_caller)?;
+    let user_id = __request_path.into_inner();
+    let role = __request_payload.into_inner().role;
+    let pool = &__server_state.central_db_pool;
+
+    if !server_roles::role_exists(pool, &role)
+        .await
+        .map_err(|e| ApiError::database(e, "SELECT", "roles"))?
+    {
+        return Err(ApiError::not_found("role_not_found", "Role not found"));
+    }
+    if !sqlx::query_scalar::<_, bool>("SELECT EXISTS (SELECT 1 FROM users WHERE user_id = $1)")
+        .bind(user_id)
+        .fetch_one(pool)
+        .await
+        .map_err(|e| ApiError::database(e, "SELECT", "users"))?
+    {
+        return Err(ApiError::not_found("user_not_found", "User not found"));
+    }
+
+    let granted =
+        server_roles::grant_role(pool, user_id, &role, Some(__authenticated_caller.user_id))
+            .await
+            .map_err(|e| ApiError::database(e, "INSERT INTO", "user_roles"))?;
+    tracing::info!(
+        component = "rbac",
+        user_id = %user_id,
+        role = %role,
+        granted_by = %__authenticated_caller.user_id,
+        granted = granted,
+        "role granted"
+    );
+    Ok(match granted {
+        true => actix_web::HttpResponse::Created().finish(),
+        false => actix_web::HttpResponse::Ok().finish(),
+    })
+}
+
+/**
+ * # Brief
+ * HTTP DELETE request. Revokes a role from a User.
+ *
+ * # Detail
+ * - Requires `users:admin` & a cookie session.
+ * - The last `admin` can't be revoked, answers 409.
+ * - Takes effect on the User's next request.
+ */
+#[actix_web::delete("/admin/users/{user_id}/roles/{role}")]
+async fn http_delete_admin_user_role(
+    __authenticated_caller: Permitted<UsersAdmin>,
+    __request_path: actix_web::web::Path<(uuid::Uuid, String)>,
+    __server_state: actix_web::web::Data<server_types::ServerState>,
+) -> Result<actix_web::HttpResponse, ApiError> {
+    require_session(&__authenticated_caller)?;
+    let (user_id, role) = __request_path.into_inner();
+
+    match server_roles::revoke_role(&__server_state.central_db_pool, user_id, &role)
+        .await
+        .map_err(|e| ApiError::database(e, "DELETE", "user_roles"))?
+    {
+        RevokeOutcome::Revoked => {
+            tracing::info!(
+                component = "rbac",
+                user_id = %user_id,
+                role = %role,
+                revoked_by = %__authenticated_caller.user_id,
+                "role revoked"
+            );
+            Ok(actix_web::HttpResponse::NoContent().finish())
+        }
+        RevokeOutcome::NotGranted => Err(ApiError::not_found(
+            "role_not_granted",
+            "User doesn't hold this role",
+        )),
+        RevokeOutcome::LastAdmin => Err(ApiError::conflict(
+            "last_admin",
+            "Can't revoke the last admin",
+        )),
+    }
+}
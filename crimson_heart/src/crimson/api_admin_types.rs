@@ -0,0 +1,7 @@
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct HTTPRoleGrant {
+    /// name of an existing role, see `GET /admin/roles`
+    pub role: String,
+}
@@ -1,9 +1,12 @@
 use super::api_auth_types;
+use super::server_errors::{ApiError, CODE_LOGIN_REQUIRED};
+use super::server_mailer::MailMessage;
+use super::server_passwords::Verification;
+use super::server_sessions;
 use super::server_types;
+use super::server_validation::{self, Validate};
 use crate::crimson::server_types::SessionUserState;
 
-use argon2::PasswordVerifier;
-use argon2::password_hash::PasswordHasher;
 use deadpool_redis::redis::AsyncCommands;
 
 /**
@@ -11,11 +14,14 @@ use deadpool_redis::redis::AsyncCommands;
  * HTTP POST request. Registers the User in Central DB.
  *
  * # Detail
+ * - Validates the payload against `[password]`, 422 with every rejected field.
+ * - Refuses passwords of `password.breached_list` with 422, before anything is hashed.
  * - Validates the Redis Pool Connection, returns InternalServerError on Failure.
- * - Uses `session_id` Cookie to manage sessions, checks for it in the Cookie,
- * if the Cookie value isn't available, creates a new `session_id` and adds
- * to redis and creates the Cookie.
+ * - Reads the `session_id` Cookie to refuse sessions that are already signed in.
  * - Writes to Central Database if user is unregistered.
+ * - Returns 503 with `Retry-After` when the password hashing queue is full.
+ * - Rotates the session on success, issuing a fresh `session_id` Cookie.
+ * - Limited per email on top of the per IP & session limits, 429 when exceeded.
  *
 */
 #[actix_web::post("/auth/register")]
@@ -23,160 +29,70 @@ async fn http_get_user_register(
     __request_metadata: actix_web::HttpRequest,
     __request_payload: actix_web::web::Json<api_auth_types::HTTPUserRegister>,
     __server_state: actix_web::web::Data<server_types::ServerState>,
-) -> impl actix_web::Responder {
-    // get the redis connection pool
-    let mut redis_connection = match __server_state.redis_pool.get().await {
-        Ok(redis_connection) => {
-            tracing::debug!(component = "redis_pool", "Fetched redis connection");
-            redis_connection
-        }
-        Err(e) => {
-            tracing::error!(
-                error = %e,
-                component = "redis_connection_pool",
-                "failed to acquire VAR `redis_connection` from actix_web's thread"
-            );
-            return actix_web::HttpResponse::InternalServerError()
-                .body("Server Error, Refresh & Retry\n");
-        }
-    };
+) -> Result<actix_web::HttpResponse, ApiError> {
+    let registration = __request_payload.validate(&__server_state.password)?;
+    server_validation::not_breached(
+        &registration.password,
+        __server_state.breached_passwords.as_deref(),
+    )
+    .map_err(|message| ApiError::invalid_field("password", message))?;
 
-    let mut full_site_cookie: Option<actix_web::cookie::Cookie> = None;
-    let session_id = match __request_metadata
-        .cookie("session_id")
-        .map(|c| c.value().to_string())
+    if let Some(retry_after) = __server_state
+        .rate_limiter
+        .limit_email("/auth/register", &registration.email)
+        .await
     {
-        // Cookie `session_id` already exists
-        Some(id) => id,
-        // Cookie `session_id` doesnt exist
-        None => {
-            // Session ID Creation & Updating in Redis
-
-            let new_session_id = uuid::Uuid::now_v7().to_string();
-            let session_key = format!("session_id:{}", new_session_id);
-            let _: () = match redis_connection
-                .hset_multiple(
-                    &session_key,
-                    &[("state", SessionUserState::Anonymous.as_u32().to_string())],
-                )
-                .await
-            {
-                Ok(redis_value) => redis_value,
-                Err(e) => {
-                    tracing::error!(
-                        error = %e,
-                        component = "redis_functions",
-                        function = "hset_multiple",
-                        "function failed & returned error"
-                    );
-                    return actix_web::HttpResponse::InternalServerError()
-                        .body("Server Error, Refresh and Retry\n");
-                }
-            };
-
-            let _: () = match redis_connection
-                .expire(&session_key, __server_state.redis_expire_time)
-                .await
-            {
-                Ok(redis_value) => redis_value,
-                Err(e) => {
-                    tracing::error!(
-                        error = %e,
-                        component = "redis_functions",
-                        function = "expire",
-                        "function failed & returned error"
-                    );
-                    return actix_web::HttpResponse::InternalServerError()
-                        .body("Server Error, Refresh and Retry\n");
-                }
-            };
-
-            // Set Cookie
-            full_site_cookie = Some(
-                actix_web::cookie::Cookie::build("session_id", new_session_id.clone())
-                    .path("/")
-                    .max_age(actix_web::cookie::time::Duration::seconds(
-                        __server_state.redis_expire_time as i64,
-                    ))
-                    .same_site(actix_web::cookie::SameSite::Lax)
-                    .http_only(true)
-                    .finish(),
-            );
+        return Err(ApiError::TooManyRequests(retry_after));
+    }
 
-            new_session_id
-        }
-    };
+    // get the redis connection pool
+    let mut redis_connection = __server_state.redis_pool.get().await?;
 
-    let session_key = format!("session_id:{}", &session_id);
-    let user_state: u32 = match redis_connection.hget(&session_key, "state").await {
-        Ok(Some(state)) => state,
-        Ok(None) => {
-            tracing::info!(
-                component = "user_state",
-                "invalid session, `session_key` from cookie was not found in redis instance"
-            );
-            return actix_web::HttpResponse::BadRequest()
-                .body("Invalid Session, Refresh & Retry\n");
-        }
-        Err(e) => {
-            tracing::error!(
-                error = %e,
-                component = "redis_functions",
-                function = "hget",
-                "function failed & returned error"
-            );
-            return actix_web::HttpResponse::InternalServerError()
-                .body("Server Error, Refresh & Retry\n");
-        }
+    // the caller's current session, rotated away once registration succeeds
+    let previous_session_id = __request_metadata
+        .cookie("session_id")
+        .map(|c| c.value().to_string());
+
+    let user_state: u32 = match &previous_session_id {
+        None => SessionUserState::Anonymous.as_u32(),
+        Some(previous_session_id) => redis_connection
+            .hget::<_, _, Option<u32>>(server_sessions::session_key(previous_session_id), "state")
+            .await
+            .map_err(|e| ApiError::redis(e, "hget"))?
+            // expired or unknown, a fresh session is minted anyway
+            .unwrap_or(SessionUserState::Anonymous.as_u32()),
     };
 
-    if user_state == SessionUserState::Registered.as_u32() {
+    if let Some(
+        SessionUserState::Registered
+        | SessionUserState::PendingVerification
+        | SessionUserState::TwoFactorPending,
+    ) = SessionUserState::from_u32(user_state)
+    {
         tracing::info!(component = "user_state", "user tried to register twice");
-        return actix_web::HttpResponse::Conflict().body("You are already registered\n");
+        Err(ApiError::conflict(
+            "already_registered",
+            "You are already registered",
+        ))
     } else {
         // Write to Central DB
         let user_id = uuid::Uuid::now_v7().to_string();
-        let username = &__request_payload.username;
-        let password_string = &__request_payload.password;
-        let email = &__request_payload.email;
-        let birth_date = &__request_payload.birth_date;
-        let user_salt = argon2::password_hash::SaltString::generate(
-            &mut argon2::password_hash::rand_core::OsRng,
-        );
-
-        let password_string_clone = password_string.clone();
-        let user_salt_clone = user_salt.clone();
+        let username = &registration.username;
+        let password_string = &registration.password;
+        let email = &registration.email;
+        let birth_date = registration.birth_date;
 
-        // hash the password
-        // asynchronous version
-        let password = actix_web::web::block(move || {
-            argon2::Argon2::default()
-                .hash_password(password_string_clone.as_bytes(), &user_salt_clone)
-                .map(|hash| hash.to_string())
-        })
-        .await
-        .unwrap();
-
-        let password = match password {
-            Ok(hash) => {
-                tracing::info!(
-                    component = "generic",
-                    function = "argon2_hashing",
-                    "hashed password for user"
-                );
-                hash.to_string()
-            }
-            Err(e) => {
-                tracing::error!(
-                    error = %e,
-                    component = "generic",
-                    function = "argon2_hashing",
-                    "function failed & returned error"
-                );
-                return actix_web::HttpResponse::InternalServerError()
-                    .body("Server Error, Refresh & Retry\n");
-            }
-        };
+        // hash the password, on the hashing pool
+        let password = __server_state
+            .hashing
+            .hash(password_string.clone())
+            .await?
+            .map_err(|e| ApiError::internal("generic", "argon2_hashing", e))?;
+        tracing::info!(
+            component = "generic",
+            function = "argon2_hashing",
+            "hashed password for user"
+        );
 
         let sqlx_insert_query = r#"
             INSERT INTO users 
@@ -187,7 +103,7 @@ async fn http_get_user_register(
 
         // insert
         match sqlx::query(sqlx_insert_query)
-            .bind(user_id)
+            .bind(&user_id)
             .bind(username)
             .bind(password)
             .bind(email)
@@ -203,65 +119,74 @@ async fn http_get_user_register(
                     "user registration was added"
                 );
             }
-            Err(e) => {
-                if let Some(db_err) = e.as_database_error() {
-                    // user already exists
-                    if db_err.is_unique_violation() {
-                        tracing::error!(
-                            error = %e,
-                            component = "database",
-                            query = "INSERT INTO",
-                            table = "users",
-                            "user already exists"
-                        );
-                        return actix_web::HttpResponse::Conflict()
-                            .body(format!("Email {} already registered\n", email));
-                    }
-                }
-                tracing::error!(
+            // user already exists
+            Err(e)
+                if e.as_database_error()
+                    .is_some_and(|db_err| db_err.is_unique_violation()) =>
+            {
+                tracing::info!(
                     error = %e,
                     component = "database",
-                    query = "NONE",
+                    query = "INSERT INTO",
                     table = "users",
-                    "function failed & returned error"
+                    "user already exists"
                 );
-                return actix_web::HttpResponse::InternalServerError()
-                    .body("Server Error, Refresh & Retry\n");
+                return Err(ApiError::conflict(
+                    "email_taken",
+                    format!("Email {} already registered", email),
+                ));
             }
+            Err(e) => return Err(ApiError::database(e, "INSERT INTO", "users")),
         };
 
-        let _: () = match redis_connection
-            .hset(
-                &session_key,
-                "state",
-                SessionUserState::Registered.as_u32().to_string(),
-            )
-            .await
-        {
-            Ok(redis_value) => redis_value,
-            Err(e) => {
-                tracing::error!(
-                    error = %e,
-                    component = "redis_functions",
-                    function = "hset",
-                    "function failed & returned error"
-                );
-                return actix_web::HttpResponse::InternalServerError()
-                    .body("Server Error, Refresh and Retry\n");
-            }
-        };
-    }
+        // fresh session id, a planted cookie never becomes authenticated
+        let mut session_fields = server_sessions::client_fields(&__request_metadata);
+        session_fields.push((
+            "state",
+            SessionUserState::PendingVerification.as_u32().to_string(),
+        ));
+        session_fields.push(("user_id", user_id.clone()));
+        let session_id = server_sessions::rotate_session(
+            &mut redis_connection,
+            previous_session_id.as_deref(),
+            &session_fields,
+            __server_state.redis_expire_time,
+        )
+        .await
+        .map_err(|e| ApiError::redis(e, "rotate_session"))?;
 
-    match full_site_cookie {
-        Some(cookie) => {
-            return actix_web::HttpResponse::Ok()
-                .cookie(cookie)
-                .body("successful\n");
-        }
-        None => {
-            tracing::info!(component = "cookie", "user cookie already exists");
-            return actix_web::HttpResponse::Ok().body("successful\n");
+        server_sessions::track_user_session(
+            &mut redis_connection,
+            &user_id,
+            &session_id,
+            __server_state.redis_expire_time,
+        )
+        .await
+        .map_err(|e| ApiError::redis(e, "track_user_session"))?;
+
+        // the account exists either way, a lost email only delays verification
+        if !send_email_verification(
+            &mut redis_connection,
+            &__server_state,
+            &user_id,
+            username,
+            email,
+        )
+        .await
+        {
+            tracing::warn!(
+                component = "auth",
+                user_id = %user_id,
+                "registered without a verification email"
+            );
         }
+
+        Ok(actix_web::HttpResponse::Ok()
+            .cookie(server_sessions::session_cookie(
+                session_id,
+                __server_state.redis_expire_time,
+            ))
+            .body("successful\n"))
     }
 }
 
@@ -270,10 +195,13 @@ async fn http_get_user_register(
  * HTTP POST request. Logs in the User.
  *
  * # Detail
+ * - Validates the payload, the email is case-folded like on registration.
  * - Validates Redis Pool Connection.
- * - Uses `session_id` Cookie to manage sessions.
- * - Verifies password using Argon2.
- * - Updates Redis session state on success.
+ * - Verifies password using Argon2, 503 with `Retry-After` when the hashing queue is full.
+ * - Rotates the session on success, the previous `session_id` is deleted.
+ * - Issues a fresh HttpOnly cookie.
+ * - Repeated failures lock the email out for a growing time, 429 while locked.
+ * - With 2FA enabled the session waits in `TwoFactorPending` for `/auth/login/totp`.
  *
 */
 #[actix_web::post("/auth/login")]
@@ -281,105 +209,20 @@ async fn http_post_user_login(
     __request_metadata: actix_web::HttpRequest,
     __request_payload: actix_web::web::Json<api_auth_types::HTTPUserLogin>,
     __server_state: actix_web::web::Data<server_types::ServerState>,
-) -> impl actix_web::Responder {
-    // get the redis connection pool
-    let mut redis_connection = match __server_state.redis_pool.get().await {
-        Ok(redis_connection) => {
-            tracing::debug!(component = "redis_pool", "Fetched redis connection");
-            redis_connection
-        }
-        Err(e) => {
-            tracing::error!(
-                error = %e,
-                component = "redis_connection_pool",
-                "failed to acquire redis connection"
-            );
-            return actix_web::HttpResponse::InternalServerError()
-                .body("Server Error, Refresh & Retry\n");
-        }
-    };
+) -> Result<actix_web::HttpResponse, ApiError> {
+    let login = __request_payload.validate(&__server_state.password)?;
 
-    let mut full_site_cookie: Option<actix_web::cookie::Cookie> = None;
+    // get the redis connection pool
+    let mut redis_connection = __server_state.redis_pool.get().await?;
 
-    let session_id = match __request_metadata
+    // the caller's current session, rotated away once the password checks out
+    let previous_session_id = __request_metadata
         .cookie("session_id")
-        .map(|c| c.value().to_string())
-    {
-        Some(id) => {
-            tracing::debug!(
-                component = "session",
-                session_id = %id,
-                "existing session cookie found"
-            );
-            id
-        }
-        None => {
-            let new_session_id = uuid::Uuid::now_v7().to_string();
-            let session_key = format!("session_id:{}", new_session_id);
-
-            tracing::info!(
-                component = "session",
-                session_id = %new_session_id,
-                "no session cookie found, creating anonymous session"
-            );
-
-            let _: () = match redis_connection
-                .hset_multiple(
-                    &session_key,
-                    &[("state", SessionUserState::Anonymous.as_u32().to_string())],
-                )
-                .await
-            {
-                Ok(v) => v,
-                Err(e) => {
-                    tracing::error!(
-                        error = %e,
-                        component = "redis_functions",
-                        function = "hset_multiple",
-                        "failed to create anonymous session"
-                    );
-                    return actix_web::HttpResponse::InternalServerError()
-                        .body("Server Error, Refresh and Retry\n");
-                }
-            };
-
-            let _: () = match redis_connection
-                .expire(&session_key, __server_state.redis_expire_time)
-                .await
-            {
-                Ok(v) => v,
-                Err(e) => {
-                    tracing::error!(
-                        error = %e,
-                        component = "redis_functions",
-                        function = "expire",
-                        "failed to set session expiry"
-                    );
-                    return actix_web::HttpResponse::InternalServerError()
-                        .body("Server Error, Refresh and Retry\n");
-                }
-            };
-
-            full_site_cookie = Some(
-                actix_web::cookie::Cookie::build("session_id", new_session_id.clone())
-                    .path("/")
-                    .max_age(actix_web::cookie::time::Duration::seconds(
-                        __server_state.redis_expire_time as i64,
-                    ))
-                    .same_site(actix_web::cookie::SameSite::Lax)
-                    .http_only(true)
-                    .finish(),
-            );
-
-            new_session_id
-        }
-    };
-
-    let session_key = format!("session_id:{}", &session_id);
+        .map(|c| c.value().to_string());
 
     // fetch user from DB
-    let email = &__request_payload.email;
-    let password = &__request_payload.password;
+    let email = &login.email;
+    let password = &login.password;
 
     tracing::info!(
         component = "auth",
@@ -387,9 +230,26 @@ async fn http_post_user_login(
         "login attempt received"
     );
 
+    if let Some(retry_after) = __server_state
+        .rate_limiter
+        .limit_email("/auth/login", email)
+        .await
+    {
+        return Err(ApiError::TooManyRequests(retry_after));
+    }
+    if let Some(retry_after) = __server_state.rate_limiter.login_lockout(email).await {
+        tracing::info!(
+            component = "auth",
+            email = %email,
+            retry_after = retry_after,
+            "login refused: email locked out"
+        );
+        return Err(ApiError::TooManyRequests(retry_after));
+    }
+
     let user = match sqlx::query!(
         r#"
-        SELECT password
+        SELECT user_id, password, email_verified_at, totp_enabled_at
         FROM users
         WHERE email = $1
         "#,
@@ -397,104 +257,184 @@ async fn http_post_user_login(
     )
     .fetch_optional(&__server_state.central_db_pool)
     .await
+    .map_err(|e| ApiError::database(e, "SELECT", "users"))?
     {
-        Ok(Some(user)) => user,
-        Ok(None) => {
+        Some(user) => user,
+        None => {
             tracing::info!(
                 component = "auth",
                 email = %email,
                 "login failed: user not registered"
             );
-            return actix_web::HttpResponse::NotFound()
-                .body("User not registered\n");
-        }
-        Err(e) => {
-            tracing::error!(
-                error = %e,
-                component = "database",
-                query = "SELECT",
-                table = "users",
-                "database error during login"
-            );
-            return actix_web::HttpResponse::InternalServerError()
-                .body("Server Error, Refresh & Retry\n");
+            if let Some(lockout) = __server_state
+                .rate_limiter
+                .record_login_failure(email)
+                .await
+            {
+                return Err(ApiError::TooManyRequests(lockout));
+            }
+            return Err(ApiError::not_found(
+                "user_not_registered",
+                "User not registered",
+            ));
         }
     };
 
-    // password verification (blocking pool)
-    let password = password.clone();
-    let password_hash_string = user.password.clone();
-
-    let verify_result = actix_web::web::block(move || {
-        let parsed_hash =
-            argon2::password_hash::PasswordHash::new(&password_hash_string)
-                .map_err(|_| ())?;
-
-        argon2::Argon2::default()
-            .verify_password(password.as_bytes(), &parsed_hash)
-            .map_err(|_| ())
-    })
-    .await;
-
-    if verify_result.is_err() {
+    // password verification (hashing pool)
+    // users created through an OIDC provider have no password until they reset one
+    // a full queue answers 503 & is not counted as a login failure
+    let verification = match &user.password {
+        Some(password_hash_string) => {
+            __server_state
+                .hashing
+                .verify(password.clone(), password_hash_string.clone())
+                .await?
+        }
+        None => Verification::Mismatch,
+    };
+    if verification == Verification::Mismatch {
         tracing::info!(
             component = "auth",
             email = %email,
             "login failed: invalid credentials"
         );
-        return actix_web::HttpResponse::Unauthorized()
-            .body("Invalid credentials\n");
+        if let Some(lockout) = __server_state
+            .rate_limiter
+            .record_login_failure(email)
+            .await
+        {
+            return Err(ApiError::TooManyRequests(lockout));
+        }
+        return Err(ApiError::unauthorized(
+            "invalid_credentials",
+            "Invalid credentials",
+        ));
     }
 
     tracing::info!(
         component = "auth",
         email = %email,
-        session_id = %session_id,
         "password verification successful"
     );
-
-    // update session state
-    let _: () = match redis_connection
-        .hset(
-            &session_key,
-            "state",
-            SessionUserState::Registered.as_u32().to_string(),
-        )
-        .await
+    if verification == Verification::Outdated
+        && let Some(previous_hash) = &user.password
     {
-        Ok(v) => v,
-        Err(e) => {
-            tracing::error!(
-                error = %e,
-                component = "redis_functions",
-                function = "hset",
-                session_id = %session_id,
-                "failed to update session state"
-            );
-            return actix_web::HttpResponse::InternalServerError()
-                .body("Server Error, Refresh & Retry\n");
-        }
+        rehash_password(&__server_state, user.user_id, password, previous_hash).await;
+    }
+
+    // update session state, unconfirmed addresses stay pending
+    let session_state = match (user.totp_enabled_at, user.email_verified_at) {
+        (Some(_), _) => SessionUserState::TwoFactorPending,
+        (None, Some(_)) => SessionUserState::Registered,
+        (None, None) => SessionUserState::PendingVerification,
     };
+    // with 2FA the failures are only forgotten once the code checks out too
+    if user.totp_enabled_at.is_none() {
+        __server_state
+            .rate_limiter
+            .clear_login_failures(email)
+            .await;
+    }
+    // fresh session id, a planted cookie never becomes authenticated
+    let mut session_fields = server_sessions::client_fields(&__request_metadata);
+    session_fields.push(("state", session_state.as_u32().to_string()));
+    session_fields.push(("user_id", user.user_id.to_string()));
+    let session_id = server_sessions::rotate_session(
+        &mut redis_connection,
+        previous_session_id.as_deref(),
+        &session_fields,
+        __server_state.redis_expire_time,
+    )
+    .await
+    .map_err(|e| ApiError::redis(e, "rotate_session"))?;
 
-    match full_site_cookie {
-        Some(cookie) => {
-            tracing::debug!(
-                component = "cookie",
-                session_id = %session_id,
-                "issuing new session cookie"
+    server_sessions::track_user_session(
+        &mut redis_connection,
+        &user.user_id.to_string(),
+        &session_id,
+        __server_state.redis_expire_time,
+    )
+    .await
+    .map_err(|e| ApiError::redis(e, "track_user_session"))?;
+
+    tracing::debug!(
+        component = "cookie",
+        session_id = %session_id,
+        "issuing rotated session cookie"
+    );
+    if let SessionUserState::TwoFactorPending = session_state {
+        return Ok(actix_web::HttpResponse::Accepted()
+            .cookie(server_sessions::session_cookie(
+                session_id,
+                __server_state.redis_expire_time,
+            ))
+            .body("Two-Factor Code Required\n"));
+    }
+    Ok(actix_web::HttpResponse::Ok()
+        .cookie(server_sessions::session_cookie(
+            session_id,
+            __server_state.redis_expire_time,
+        ))
+        .body("successful\n"))
+}
+
+/**
+ * # Brief
+ * Hashes the password of a user again, with the current pepper & costs.
+ *
+ * # Detail
+ * - Only replaces the hash that was verified, a concurrent reset wins.
+ * - Failures are logged, the login goes on with the previous hash.
+ */
+async fn rehash_password(
+    server_state: &server_types::ServerState,
+    user_id: uuid::Uuid,
+    password: &str,
+    previous_hash: &str,
+) {
+    let password_hash = match server_state.hashing.hash(password.to_string()).await {
+        Ok(Ok(password_hash)) => password_hash,
+        Ok(Err(e)) => {
+            tracing::error!(
+                error = %e,
+                component = "generic",
+                function = "argon2_hashing",
+                "function failed & returned error"
             );
-            actix_web::HttpResponse::Ok()
-                .cookie(cookie)
-                .body("successful\n")
+            return;
         }
-        None => {
-            tracing::debug!(
-                component = "cookie",
-                session_id = %session_id,
-                "session cookie already present"
+        // retried on a later login
+        Err(e) => {
+            tracing::warn!(
+                error = %e,
+                component = "auth",
+                user_id = %user_id,
+                "password re-hash skipped"
             );
-            actix_web::HttpResponse::Ok().body("successful\n")
+            return;
         }
+    };
+
+    match sqlx::query("UPDATE users SET password = $1 WHERE user_id = $2 AND password = $3")
+        .bind(password_hash)
+        .bind(user_id)
+        .bind(previous_hash)
+        .execute(&server_state.central_db_pool)
+        .await
+    {
+        Ok(_) => tracing::info!(
+            component = "auth",
+            user_id = %user_id,
+            pepper = server_state.hashing.passwords().current_id(),
+            "password re-hashed with the current pepper & costs"
+        ),
+        Err(e) => tracing::error!(
+            error = %e,
+            component = "database",
+            query = "UPDATE",
+            table = "users",
+            "function failed & returned error"
+        ),
     }
 }
 
@@ -512,79 +452,321 @@ async fn http_post_user_login(
 async fn http_post_user_logout(
     __request_metadata: actix_web::HttpRequest,
     __server_state: actix_web::web::Data<server_types::ServerState>,
-) -> impl actix_web::Responder {
+) -> Result<actix_web::HttpResponse, ApiError> {
     // get redis connection
-    let mut redis_connection = match __server_state.redis_pool.get().await {
-        Ok(conn) => {
-            tracing::debug!(component = "redis_pool", "Fetched redis connection");
-            conn
+    let mut redis_connection = __server_state.redis_pool.get().await?;
+
+    let previous_session_id = __request_metadata
+        .cookie("session_id")
+        .map(|c| c.value().to_string());
+    match &previous_session_id {
+        Some(previous_session_id) => {
+            tracing::info!(
+                component = "session",
+                session_id = %previous_session_id,
+                "existing session found, invalidating"
+            );
         }
-        Err(e) => {
-            tracing::error!(
-                error = %e,
-                component = "redis_connection_pool",
-                "failed to acquire redis connection"
+        None => {
+            tracing::info!(
+                component = "session",
+                "logout requested without existing session cookie"
             );
-            return actix_web::HttpResponse::InternalServerError()
-                .body("Server Error, Refresh & Retry\n");
+        }
+    }
+
+    // delete the old session (hard invalidation) & mark the new one anonymous
+    let mut session_fields = server_sessions::client_fields(&__request_metadata);
+    session_fields.push(("state", SessionUserState::Anonymous.as_u32().to_string()));
+    let new_session_id = server_sessions::rotate_session(
+        &mut redis_connection,
+        previous_session_id.as_deref(),
+        &session_fields,
+        __server_state.redis_expire_time,
+    )
+    .await
+    .map_err(|e| ApiError::redis(e, "rotate_session"))?;
+
+    tracing::info!(
+        component = "session",
+        session_id = %new_session_id,
+        "logout successful, new anonymous session issued"
+    );
+
+    // issue new cookie
+    Ok(actix_web::HttpResponse::Ok()
+        .cookie(server_sessions::session_cookie(
+            new_session_id,
+            __server_state.redis_expire_time,
+        ))
+        .body("logged out\n"))
+}
+
+// redis keys of a pending reset, only the token's digest is stored
+fn password_reset_key(token_hash: &str) -> String {
+    format!("password_reset:{}", token_hash)
+}
+
+fn password_reset_user_key(user_id: &str) -> String {
+    format!("password_reset_user:{}", user_id)
+}
+
+fn email_verification_key(token_hash: &str) -> String {
+    format!("email_verification:{}", token_hash)
+}
+
+const TOKEN_BYTES: usize = 32;
+
+// single-use tokens sent by email, hex encoded
+fn new_token() -> String {
+    let mut token_bytes = [0u8; TOKEN_BYTES];
+    argon2::password_hash::rand_core::RngCore::fill_bytes(
+        &mut argon2::password_hash::rand_core::OsRng,
+        &mut token_bytes,
+    );
+    hex::encode(token_bytes)
+}
+
+fn is_well_formed_token(token: &str) -> bool {
+    token.len() == TOKEN_BYTES * 2 && token.bytes().all(|b| b.is_ascii_hexdigit())
+}
+
+fn hash_token(token: &str) -> String {
+    use sha2::Digest;
+    hex::encode(sha2::Sha256::digest(token.as_bytes()))
+}
+
+// unknown, expired, used & malformed tokens all look the same
+fn invalid_token() -> ApiError {
+    ApiError::bad_request("invalid_token", "Invalid or expired token")
+}
+const PASSWORD_FORGOT_RESPONSE: &str = "If the email is registered, a reset token was sent\n";
+
+/**
+ * # Brief
+ * HTTP POST request. Emails a password reset token.
+ *
+ * # Detail
+ * - Answers the same whether or not the email is registered.
+ * - The token is random, Redis only keeps its SHA-256 digest with a TTL.
+ * - Asking again replaces the previous token, only the newest one works.
+ * - Limited per email on top of the per IP & session limits, 429 when exceeded.
+ */
+#[actix_web::post("/auth/password/forgot")]
+async fn http_post_user_password_forgot(
+    __request_payload: actix_web::web::Json<api_auth_types::HTTPPasswordForgot>,
+    __server_state: actix_web::web::Data<server_types::ServerState>,
+) -> Result<actix_web::HttpResponse, ApiError> {
+    let email = &__request_payload.validate(&__server_state.password)?;
+
+    if let Some(retry_after) = __server_state
+        .rate_limiter
+        .limit_email("/auth/password/forgot", email)
+        .await
+    {
+        return Err(ApiError::TooManyRequests(retry_after));
+    }
+
+    let (user_id, username) = match sqlx::query_as::<_, (uuid::Uuid, String)>(
+        "SELECT user_id, username FROM users WHERE email = $1",
+    )
+    .bind(email)
+    .fetch_optional(&__server_state.central_db_pool)
+    .await
+    .map_err(|e| ApiError::database(e, "SELECT", "users"))?
+    {
+        Some(user) => user,
+        None => {
+            tracing::info!(
+                component = "auth",
+                "password reset requested for unregistered email"
+            );
+            return Ok(actix_web::HttpResponse::Accepted().body(PASSWORD_FORGOT_RESPONSE));
         }
     };
+    let user_id = user_id.to_string();
+
+    let mut redis_connection = __server_state.redis_pool.get().await?;
+
+    let token = new_token();
+    let token_hash = hash_token(&token);
+    let ttl = __server_state.password_reset_ttl;
+
+    // drop the previous token, if any
+    let previous: Option<String> = redis_connection
+        .get(password_reset_user_key(&user_id))
+        .await
+        .map_err(|e| ApiError::redis(e, "get"))?;
+    if let Some(previous) = previous {
+        let _: () = redis_connection
+            .del(password_reset_key(&previous))
+            .await
+            .map_err(|e| ApiError::redis(e, "del"))?;
+    }
+
+    let _: () = deadpool_redis::redis::pipe()
+        .set_ex(password_reset_key(&token_hash), &user_id, ttl as u64)
+        .ignore()
+        .set_ex(password_reset_user_key(&user_id), &token_hash, ttl as u64)
+        .ignore()
+        .query_async(&mut redis_connection)
+        .await
+        .map_err(|e| ApiError::redis(e, "set_ex"))?;
+
+    let reset_url = __server_state
+        .public_url
+        .join("auth/password/reset")
+        .map(|url| url.to_string())
+        .unwrap_or_default();
+    let message = MailMessage {
+        to: email.clone(),
+        subject: "Reset your Crimson password".to_string(),
+        body: format!(
+            "Hi {},\n\nUse this token to reset your password, it expires in {} minutes and works once:\n\n    {}\n\nSend it with your new password to {}\n\nIf you did not ask for a reset, ignore this email.",
+            username,
+            ttl / 60,
+            token,
+            reset_url
+        ),
+    };
+
+    let mailer = __server_state.mailer.clone();
+    actix_web::web::block(move || mailer.send(&message))
+        .await
+        .map_err(|e| ApiError::internal("mailer", "web::block", e))?
+        .map_err(|e| ApiError::internal("mailer", "send", e))?;
+    tracing::info!(
+        component = "auth",
+        user_id = %user_id,
+        "password reset token sent"
+    );
+    Ok(actix_web::HttpResponse::Accepted().body(PASSWORD_FORGOT_RESPONSE))
+}
+
+/**
+ * # Brief
+ * HTTP POST request. Sets a new password with a reset token.
+ *
+ * # Detail
+ * - The new password must pass `[password]` & not be in `password.breached_list`,
+ *   422 otherwise & the token stays usable.
+ * - The token is consumed atomically (GETDEL), a second use fails.
+ * - The new password is hashed with Argon2 like on registration, before the token
+ *   is spent, so a 503 of a full hashing queue leaves it usable.
+ * - Every session of the user is revoked, they sign in again with the new password.
+ */
+#[actix_web::post("/auth/password/reset")]
+async fn http_post_user_password_reset(
+    __request_payload: actix_web::web::Json<api_auth_types::HTTPPasswordReset>,
+    __server_state: actix_web::web::Data<server_types::ServerState>,
+) -> Result<actix_web::HttpResponse, ApiError> {
+    let token = &__request_payload.token;
+    if !is_well_formed_token(token) {
+        return Err(invalid_token());
+    }
+    // checked before the token is spent, a rejected password can be retried
+    let password_string = __request_payload.validate(&__server_state.password)?;
+    server_validation::not_breached(
+        &password_string,
+        __server_state.breached_passwords.as_deref(),
+    )
+    .map_err(|message| ApiError::invalid_field("password", message))?;
 
-    // check if a session already exists
-    if let Some(cookie) = __request_metadata.cookie("session_id") {
-        let old_session_id = cookie.value();
-        let old_session_key = format!("session_id:{}", old_session_id);
+    let mut redis_connection = __server_state.redis_pool.get().await?;
+    let token_key = password_reset_key(&hash_token(token));
 
+    // unknown tokens are refused before paying for a hash
+    let token_exists: bool = redis_connection
+        .exists(&token_key)
+        .await
+        .map_err(|e| ApiError::redis(e, "exists"))?;
+    if !token_exists {
         tracing::info!(
-            component = "session",
-            session_id = %old_session_id,
-            "existing session found, invalidating"
+            component = "auth",
+            "invalid or expired password reset token"
         );
+        return Err(invalid_token());
+    }
 
-        // delete old session from redis (hard invalidation)
-        let _: () = match redis_connection.del(&old_session_key).await {
-            Ok(v) => {
-                tracing::info!(
-                    component = "session",
-                    session_id = %old_session_id,
-                    "old session deleted"
-                );
-                v
-            }
-            Err(e) => {
-                tracing::error!(
-                    error = %e,
-                    component = "redis_functions",
-                    function = "del",
-                    session_id = %old_session_id,
-                    "failed to delete old session"
-                );
-                return actix_web::HttpResponse::InternalServerError()
-                    .body("Server Error, Refresh & Retry\n");
-            }
-        };
-    } else {
+    // hash the password, on the hashing pool
+    let password = __server_state
+        .hashing
+        .hash(password_string)
+        .await?
+        .map_err(|e| ApiError::internal("generic", "argon2_hashing", e))?;
+
+    let Some(user_id) = deadpool_redis::redis::cmd("GETDEL")
+        .arg(&token_key)
+        .query_async::<Option<String>>(&mut redis_connection)
+        .await
+        .map_err(|e| ApiError::redis(e, "getdel"))?
+    else {
         tracing::info!(
-            component = "session",
-            "logout requested without existing session cookie"
+            component = "auth",
+            "invalid or expired password reset token"
         );
-    }
+        return Err(invalid_token());
+    };
+    let _: () = redis_connection
+        .del(password_reset_user_key(&user_id))
+        .await
+        .map_err(|e| ApiError::redis(e, "del"))?;
 
-    // generate new session
-    let new_session_id = uuid::Uuid::now_v7().to_string();
-    let new_session_key = format!("session_id:{}", new_session_id);
+    let user_uuid = uuid::Uuid::parse_str(&user_id).map_err(|_| invalid_token())?;
 
-    tracing::debug!(
+    let result = sqlx::query("UPDATE users SET password = $1 WHERE user_id = $2")
+        .bind(password)
+        .bind(user_uuid)
+        .execute(&__server_state.central_db_pool)
+        .await
+        .map_err(|e| ApiError::database(e, "UPDATE", "users"))?;
+    if result.rows_affected() != 1 {
+        return Err(invalid_token());
+    }
+    tracing::info!(
+        component = "database",
+        query = "UPDATE",
+        table = "users",
+        user_id = %user_id,
+        "password reset"
+    );
+
+    let revoked = server_sessions::revoke_user_sessions(&mut redis_connection, &user_id)
+        .await
+        .map_err(|e| ApiError::redis(e, "revoke_user_sessions"))?;
+    tracing::info!(
         component = "session",
-        session_id = %new_session_id,
-        "creating new anonymous session"
+        user_id = %user_id,
+        revoked = revoked,
+        "sessions revoked after password reset"
     );
 
-    // mark new session as anonymous
+    Ok(actix_web::HttpResponse::Ok().body("password reset\n"))
+}
+
+/**
+ * # Brief
+ * Emails a verification link for `email`, true once it was handed to the mailer.
+ *
+ * # Detail
+ * - Redis only keeps the SHA-256 digest of the token, for `email_verification_ttl` seconds.
+ * - Failures are logged here, callers only decide whether they matter.
+ */
+async fn send_email_verification(
+    redis_connection: &mut deadpool_redis::Connection,
+    server_state: &server_types::ServerState,
+    user_id: &str,
+    username: &str,
+    email: &str,
+) -> bool {
+    let token = new_token();
+    let ttl = server_state.email_verification_ttl;
+
     let _: () = match redis_connection
-        .hset_multiple(
-            &new_session_key,
-            &[("state", SessionUserState::Anonymous.as_u32().to_string())],
+        .set_ex(
+            email_verification_key(&hash_token(&token)),
+            user_id,
+            ttl as u64,
         )
         .await
     {
@@ -593,51 +775,611 @@ async fn http_post_user_logout(
             tracing::error!(
                 error = %e,
                 component = "redis_functions",
-                function = "hset_multiple",
-                session_id = %new_session_id,
-                "failed to create new anonymous session"
+                function = "set_ex",
+                "function failed & returned error"
             );
-            return actix_web::HttpResponse::InternalServerError()
-                .body("Server Error, Refresh & Retry\n");
+            return false;
         }
     };
 
-    // set TTL
-    let _: () = match redis_connection
-        .expire(&new_session_key, __server_state.redis_expire_time)
-        .await
-    {
-        Ok(v) => v,
+    let mut verify_url = match server_state.public_url.join("auth/verify") {
+        Ok(url) => url,
         Err(e) => {
             tracing::error!(
                 error = %e,
-                component = "redis_functions",
-                function = "expire",
-                session_id = %new_session_id,
-                "failed to set session expiry"
+                component = "generic",
+                function = "url_join",
+                "function failed & returned error"
+            );
+            return false;
+        }
+    };
+    verify_url.query_pairs_mut().append_pair("token", &token);
+
+    let message = MailMessage {
+        to: email.to_string(),
+        subject: "Confirm your Crimson email address".to_string(),
+        body: format!(
+            "Hi {},\n\nConfirm your email address to start submitting jobs, the link expires in {} hours:\n\n    {}\n\nIf you did not sign up, ignore this email.",
+            username,
+            ttl / 3600,
+            verify_url
+        ),
+    };
+
+    let mailer = server_state.mailer.clone();
+    match actix_web::web::block(move || mailer.send(&message)).await {
+        Ok(Ok(())) => {
+            tracing::info!(
+                component = "auth",
+                user_id = %user_id,
+                "email verification sent"
             );
-            return actix_web::HttpResponse::InternalServerError()
-                .body("Server Error, Refresh & Retry\n");
+            true
         }
+        Ok(Err(e)) => {
+            tracing::error!(
+                error = %e,
+                component = "mailer",
+                function = "send",
+                "function failed & returned error"
+            );
+            false
+        }
+        Err(e) => {
+            tracing::error!(
+                error = %e,
+                component = "mailer",
+                function = "web::block",
+                "function failed & returned error"
+            );
+            false
+        }
+    }
+}
+
+/**
+ * # Brief
+ * HTTP GET request. Confirms an email address with the emailed token.
+ *
+ * # Detail
+ * - The token is consumed atomically (GETDEL), a second use fails.
+ * - Sets `email_verified_at` and upgrades the user's pending sessions to `Registered`.
+ */
+#[actix_web::get("/auth/verify")]
+async fn http_get_user_verify(
+    __request_query: actix_web::web::Query<api_auth_types::HTTPEmailVerify>,
+    __server_state: actix_web::web::Data<server_types::ServerState>,
+) -> Result<actix_web::HttpResponse, ApiError> {
+    let token = &__request_query.token;
+    if !is_well_formed_token(token) {
+        return Err(invalid_token());
+    }
+
+    let mut redis_connection = __server_state.redis_pool.get().await?;
+
+    let Some(user_id) = deadpool_redis::redis::cmd("GETDEL")
+        .arg(email_verification_key(&hash_token(token)))
+        .query_async::<Option<String>>(&mut redis_connection)
+        .await
+        .map_err(|e| ApiError::redis(e, "getdel"))?
+    else {
+        tracing::info!(
+            component = "auth",
+            "invalid or expired email verification token"
+        );
+        return Err(invalid_token());
     };
 
+    let user_uuid = uuid::Uuid::parse_str(&user_id).map_err(|_| invalid_token())?;
+
+    sqlx::query(
+        r#"
+        UPDATE users
+        SET email_verified_at = now()
+        WHERE user_id = $1 AND email_verified_at IS NULL
+        "#,
+    )
+    .bind(user_uuid)
+    .execute(&__server_state.central_db_pool)
+    .await
+    .map_err(|e| ApiError::database(e, "UPDATE", "users"))?;
     tracing::info!(
+        component = "database",
+        query = "UPDATE",
+        table = "users",
+        user_id = %user_id,
+        "email verified"
+    );
+
+    server_sessions::promote_user_sessions(
+        &mut redis_connection,
+        &user_id,
+        SessionUserState::PendingVerification,
+        SessionUserState::Registered,
+    )
+    .await
+    .map_err(|e| ApiError::redis(e, "promote_user_sessions"))?;
+
+    Ok(actix_web::HttpResponse::Ok().body("email verified\n"))
+}
+
+/**
+ * # Brief
+ * HTTP POST request. Emails a fresh verification link to the caller.
+ *
+ * # Detail
+ * - Only for sessions waiting in `PendingVerification`, 401 otherwise & 409 once verified.
+ * - Links sent before keep working until they expire.
+ * - Limited per email on top of the per IP & session limits, 429 when exceeded.
+ */
+#[actix_web::post("/auth/verify/resend")]
+async fn http_post_user_verify_resend(
+    __request_metadata: actix_web::HttpRequest,
+    __server_state: actix_web::web::Data<server_types::ServerState>,
+) -> Result<actix_web::HttpResponse, ApiError> {
+    let mut redis_connection = __server_state.redis_pool.get().await?;
+
+    let login_required = || ApiError::unauthorized(CODE_LOGIN_REQUIRED, "Login Required");
+    let already_verified = || ApiError::conflict("already_verified", "Email already verified");
+
+    let Some(session_id) = __request_metadata
+        .cookie("session_id")
+        .map(|c| c.value().to_string())
+    else {
+        return Err(login_required());
+    };
+    let session: std::collections::HashMap<String, String> = redis_connection
+        .hgetall(server_sessions::session_key(&session_id))
+        .await
+        .map_err(|e| ApiError::redis(e, "hgetall"))?;
+    let state = session
+        .get("state")
+        .and_then(|state| state.parse().ok())
+        .and_then(SessionUserState::from_u32);
+    let user_id = session
+        .get("user_id")
+        .and_then(|user_id| uuid::Uuid::parse_str(user_id).ok());
+    let user_id = match (state, user_id) {
+        (Some(SessionUserState::PendingVerification), Some(user_id)) => user_id,
+        (Some(SessionUserState::Registered), Some(_)) => return Err(already_verified()),
+        _ => return Err(login_required()),
+    };
+
+    let Some(user) = sqlx::query!(
+        r#"
+        SELECT username, email, email_verified_at
+        FROM users
+        WHERE user_id = $1
+        "#,
+        user_id
+    )
+    .fetch_optional(&__server_state.central_db_pool)
+    .await
+    .map_err(|e| ApiError::database(e, "SELECT", "users"))?
+    else {
+        return Err(login_required());
+    };
+    if user.email_verified_at.is_some() {
+        return Err(already_verified());
+    }
+
+    if let Some(retry_after) = __server_state
+        .rate_limiter
+        .limit_email("/auth/verify/resend", &user.email)
+        .await
+    {
+        return Err(ApiError::TooManyRequests(retry_after));
+    }
+
+    if !send_email_verification(
+        &mut redis_connection,
+        &__server_state,
+        &user_id.to_string(),
+        &user.username,
+        &user.email,
+    )
+    .await
+    {
+        return Err(ApiError::internal(
+            "mailer",
+            "send_email_verification",
+            "verification email not sent",
+        ));
+    }
+
+    Ok(actix_web::HttpResponse::Ok().body("verification email sent\n"))
+}
+
+/**
+ * # Brief
+ * HTTP GET request. Describes the caller's session.
+ *
+ * # Detail
+ * - Responds with the session's user, creation & last seen times, IP and user agent as JSON.
+ * - 401 / 403 come from the `AuthenticatedUser` extractor.
+ */
+#[actix_web::get("/auth/session")]
+async fn http_get_user_session(
+    __authenticated_user: server_sessions::AuthenticatedUser,
+) -> impl actix_web::Responder {
+    tracing::debug!(
         component = "session",
-        session_id = %new_session_id,
-        "logout successful, new anonymous session issued"
+        session_id = %__authenticated_user.session_id,
+        "session described"
     );
+    actix_web::HttpResponse::Ok().json(__authenticated_user)
+}
 
-    // issue new cookie
-    let cookie = actix_web::cookie::Cookie::build("session_id", new_session_id)
-        .path("/")
-        .max_age(actix_web::cookie::time::Duration::seconds(
-            __server_state.redis_expire_time as i64,
-        ))
-        .same_site(actix_web::cookie::SameSite::Lax)
-        .http_only(true)
-        .finish();
-
-    actix_web::HttpResponse::Ok()
-        .cookie(cookie)
-        .body("logged out\n")
-}
\ No newline at end of file
+/**
+ * # Brief
+ * HTTP GET request. Lists the caller's live sessions.
+ *
+ * # Detail
+ * - Every entry carries device (user agent), IP, creation & last seen times.
+ * - Sessions are named by an opaque handle, the `current` one is flagged.
+ */
+#[actix_web::get("/auth/sessions")]
+async fn http_get_user_sessions(
+    __authenticated_user: server_sessions::AuthenticatedUser,
+    __server_state: actix_web::web::Data<server_types::ServerState>,
+) -> Result<actix_web::HttpResponse, ApiError> {
+    let mut redis_connection = __server_state.redis_pool.get().await?;
+
+    let sessions = server_sessions::list_user_sessions(
+        &mut redis_connection,
+        &__authenticated_user.user_id.to_string(),
+        &__authenticated_user.session_id,
+    )
+    .await
+    .map_err(|e| ApiError::redis(e, "list_user_sessions"))?;
+    Ok(actix_web::HttpResponse::Ok().json(sessions))
+}
+
+/**
+ * # Brief
+ * HTTP DELETE request. Revokes one of the caller's sessions by handle.
+ *
+ * # Detail
+ * - Revoking the current session signs the caller out.
+ * - Handles of other users' sessions are reported as not found.
+ */
+#[actix_web::delete("/auth/sessions/{session}")]
+async fn http_delete_user_session(
+    __authenticated_user: server_sessions::AuthenticatedUser,
+    __request_path: actix_web::web::Path<String>,
+    __server_state: actix_web::web::Data<server_types::ServerState>,
+) -> Result<actix_web::HttpResponse, ApiError> {
+    let mut redis_connection = __server_state.redis_pool.get().await?;
+
+    let user_id = __authenticated_user.user_id.to_string();
+    let revoked =
+        server_sessions::revoke_user_session(&mut redis_connection, &user_id, &__request_path)
+            .await
+            .map_err(|e| ApiError::redis(e, "revoke_user_session"))?;
+    if !revoked {
+        return Err(ApiError::not_found(
+            "session_not_found",
+            "Session not found",
+        ));
+    }
+    tracing::info!(
+        component = "session",
+        user_id = %user_id,
+        session = %__request_path.as_str(),
+        "session revoked"
+    );
+    Ok(actix_web::HttpResponse::NoContent().finish())
+}
+
+/**
+ * # Brief
+ * HTTP DELETE request. Revokes every session of the caller but the current one.
+ *
+ * # Detail
+ * - "Log out other devices", responds with the number of revoked sessions.
+ */
+#[actix_web::delete("/auth/sessions")]
+async fn http_delete_user_sessions(
+    __authenticated_user: server_sessions::AuthenticatedUser,
+    __server_state: actix_web::web::Data<server_types::ServerState>,
+) -> Result<actix_web::HttpResponse, ApiError> {
+    let mut redis_connection = __server_state.redis_pool.get().await?;
+
+    let user_id = __authenticated_user.user_id.to_string();
+    let revoked = server_sessions::revoke_other_user_sessions(
+        &mut redis_connection,
+        &user_id,
+        &__authenticated_user.session_id,
+    )
+    .await
+    .map_err(|e| ApiError::redis(e, "revoke_other_user_sessions"))?;
+    tracing::info!(
+        component = "session",
+        user_id = %user_id,
+        revoked = revoked,
+        "other sessions revoked"
+    );
+    Ok(actix_web::HttpResponse::Ok().json(serde_json::json!({ "revoked": revoked })))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crimson::server_testing::{self, TestServer};
+
+    async fn login(
+        server: &TestServer,
+        email: &str,
+        password: &str,
+    ) -> actix_web::dev::ServiceResponse {
+        let app = actix_web::test::init_service(
+            actix_web::App::new()
+                .app_data(server.state.clone())
+                .service(http_post_user_login),
+        )
+        .await;
+        let request = actix_web::test::TestRequest::post()
+            .uri("/auth/login")
+            .set_json(api_auth_types::HTTPUserLogin {
+                email: email.to_string(),
+                password: password.to_string(),
+            })
+            .to_request();
+        actix_web::test::call_service(&app, request).await
+    }
+
+    #[actix_web::test]
+    async fn wrong_password_is_unauthorized() {
+        let server = TestServer::start("").await;
+        let email = server_testing::unique_email("login");
+        let user_id =
+            server_testing::insert_user(server.db(), &email, Some("correct horse"), true).await;
+
+        assert_eq!(
+            login(&server, &email, "wrong horse").await.status(),
+            actix_web::http::StatusCode::UNAUTHORIZED
+        );
+        assert_eq!(
+            login(&server, &email, "correct horse").await.status(),
+            actix_web::http::StatusCode::OK
+        );
+        server_testing::delete_user(server.db(), user_id).await;
+    }
+
+    #[actix_web::test]
+    async fn account_without_password_is_unauthorized() {
+        // created through an OpenID Connect provider
+        let server = TestServer::start("").await;
+        let email = server_testing::unique_email("login-oidc");
+        let user_id = server_testing::insert_user(server.db(), &email, None, true).await;
+
+        // refused before any hash is looked at
+        assert_eq!(
+            login(&server, &email, "").await.status(),
+            actix_web::http::StatusCode::UNPROCESSABLE_ENTITY
+        );
+        assert_eq!(
+            login(&server, &email, "anything").await.status(),
+            actix_web::http::StatusCode::UNAUTHORIZED
+        );
+        server_testing::delete_user(server.db(), user_id).await;
+    }
+
+    fn retry_after(response: &actix_web::dev::ServiceResponse) -> u64 {
+        response
+            .headers()
+            .get(actix_web::http::header::RETRY_AFTER)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.parse().ok())
+            .unwrap()
+    }
+
+    #[actix_web::test]
+    async fn repeated_wrong_passwords_lock_the_email() {
+        let server = TestServer::start(
+            r#"
+            [rate_limit]
+            lockout_threshold = 3
+            lockout_base = 30
+            lockout_max = 120
+            "#,
+        )
+        .await;
+        let email = server_testing::unique_email("lockout");
+        let user_id =
+            server_testing::insert_user(server.db(), &email, Some("correct horse"), true).await;
+
+        for _ in 1..3 {
+            assert_eq!(
+                login(&server, &email, "wrong horse").await.status(),
+                actix_web::http::StatusCode::UNAUTHORIZED
+            );
+        }
+        // the third failure locks the email for `lockout_base`
+        let locked = login(&server, &email, "wrong horse").await;
+        assert_eq!(
+            locked.status(),
+            actix_web::http::StatusCode::TOO_MANY_REQUESTS
+        );
+        assert_eq!(retry_after(&locked), 30);
+
+        // the right password waits for the lockout too
+        let refused = login(&server, &email, "correct horse").await;
+        assert_eq!(
+            refused.status(),
+            actix_web::http::StatusCode::TOO_MANY_REQUESTS
+        );
+        assert!((1..=30).contains(&retry_after(&refused)));
+
+        // other emails are not affected
+        let other = server_testing::unique_email("lockout-other");
+        assert_eq!(
+            login(&server, &other, "wrong horse").await.status(),
+            actix_web::http::StatusCode::NOT_FOUND
+        );
+        server_testing::delete_user(server.db(), user_id).await;
+    }
+
+    #[actix_web::test]
+    async fn lockout_doubles_on_further_failures() {
+        let server = TestServer::start(
+            r#"
+            [rate_limit]
+            lockout_threshold = 1
+            lockout_base = 30
+            lockout_max = 100
+            "#,
+        )
+        .await;
+        let email = server_testing::unique_email("lockout-doubling");
+        let user_id =
+            server_testing::insert_user(server.db(), &email, Some("correct horse"), true).await;
+
+        let first = login(&server, &email, "wrong horse").await;
+        assert_eq!(
+            first.status(),
+            actix_web::http::StatusCode::TOO_MANY_REQUESTS
+        );
+        assert_eq!(retry_after(&first), 30);
+
+        // a further failure is only possible once the lockout ran out
+        server.state.rate_limiter.record_login_failure(&email).await;
+        assert_eq!(
+            server.state.rate_limiter.record_login_failure(&email).await,
+            Some(100)
+        );
+        server_testing::delete_user(server.db(), user_id).await;
+    }
+
+    // the `session_id` a login issued
+    fn session_cookie(response: &actix_web::dev::ServiceResponse) -> String {
+        response
+            .response()
+            .cookies()
+            .find(|cookie| cookie.name() == "session_id")
+            .map(|cookie| cookie.value().to_string())
+            .unwrap()
+    }
+
+    async fn resend(
+        server: &TestServer,
+        session_id: Option<&str>,
+    ) -> actix_web::dev::ServiceResponse {
+        let app = actix_web::test::init_service(
+            actix_web::App::new()
+                .app_data(server.state.clone())
+                .service(http_post_user_verify_resend),
+        )
+        .await;
+        let mut request = actix_web::test::TestRequest::post().uri("/auth/verify/resend");
+        if let Some(session_id) = session_id {
+            request = request.cookie(actix_web::cookie::Cookie::new("session_id", session_id));
+        }
+        actix_web::test::call_service(&app, request.to_request()).await
+    }
+
+    #[actix_web::test]
+    async fn pending_session_gets_a_working_verification_link() {
+        let server = TestServer::start("").await;
+        let email = server_testing::unique_email("resend");
+        let user_id =
+            server_testing::insert_user(server.db(), &email, Some("correct horse"), false).await;
+
+        let pending = login(&server, &email, "correct horse").await;
+        assert_eq!(pending.status(), actix_web::http::StatusCode::OK);
+        let session_id = session_cookie(&pending);
+
+        assert_eq!(
+            resend(&server, Some(&session_id)).await.status(),
+            actix_web::http::StatusCode::OK
+        );
+        let sent = server.outbox.sent_to(&email);
+        assert_eq!(sent.len(), 1);
+        let token = sent[0]
+            .body
+            .split("token=")
+            .nth(1)
+            .and_then(|rest| rest.split_whitespace().next())
+            .unwrap();
+
+        let app = actix_web::test::init_service(
+            actix_web::App::new()
+                .app_data(server.state.clone())
+                .service(http_get_user_verify),
+        )
+        .await;
+        let request = actix_web::test::TestRequest::get()
+            .uri(&format!("/auth/verify?token={}", token))
+            .to_request();
+        assert_eq!(
+            actix_web::test::call_service(&app, request).await.status(),
+            actix_web::http::StatusCode::OK
+        );
+
+        // the session was promoted, nothing left to resend
+        assert_eq!(
+            resend(&server, Some(&session_id)).await.status(),
+            actix_web::http::StatusCode::CONFLICT
+        );
+        assert_eq!(server.outbox.sent_to(&email).len(), 1);
+        server_testing::delete_user(server.db(), user_id).await;
+    }
+
+    #[actix_web::test]
+    async fn resend_needs_a_pending_session() {
+        let server = TestServer::start("").await;
+        assert_eq!(
+            resend(&server, None).await.status(),
+            actix_web::http::StatusCode::UNAUTHORIZED
+        );
+        assert_eq!(
+            resend(&server, Some("unknown")).await.status(),
+            actix_web::http::StatusCode::UNAUTHORIZED
+        );
+
+        let email = server_testing::unique_email("resend-verified");
+        let user_id =
+            server_testing::insert_user(server.db(), &email, Some("correct horse"), true).await;
+        let registered = session_cookie(&login(&server, &email, "correct horse").await);
+        assert_eq!(
+            resend(&server, Some(&registered)).await.status(),
+            actix_web::http::StatusCode::CONFLICT
+        );
+        assert!(server.outbox.sent_to(&email).is_empty());
+        server_testing::delete_user(server.db(), user_id).await;
+    }
+
+    #[actix_web::test]
+    async fn resend_is_limited_per_email() {
+        let server = TestServer::start(
+            r#"
+            [rate_limit.routes."/auth/verify/resend"]
+            limit = 2
+            window = 600
+            "#,
+        )
+        .await;
+        let email = server_testing::unique_email("resend-limit");
+        let user_id =
+            server_testing::insert_user(server.db(), &email, Some("correct horse"), false).await;
+
+        // a new login does not reset the limit
+        for _ in 0..2 {
+            let session_id = session_cookie(&login(&server, &email, "correct horse").await);
+            assert_eq!(
+                resend(&server, Some(&session_id)).await.status(),
+                actix_web::http::StatusCode::OK
+            );
+        }
+        let session_id = session_cookie(&login(&server, &email, "correct horse").await);
+        let limited = resend(&server, Some(&session_id)).await;
+        assert_eq!(
+            limited.status(),
+            actix_web::http::StatusCode::TOO_MANY_REQUESTS
+        );
+        assert!((1..=600).contains(&retry_after(&limited)));
+        assert_eq!(server.outbox.sent_to(&email).len(), 2);
+        server_testing::delete_user(server.db(), user_id).await;
+    }
+}
@@ -1,3 +1,6 @@
+use super::server_config::PasswordSection;
+use super::server_errors::ApiError;
+use super::server_validation::{self, Fields, Validate};
 use serde::{Deserialize, Serialize};
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -14,3 +17,110 @@ pub struct HTTPUserLogin {
     pub password: String,
 }
 
+#[derive(Serialize, Deserialize, Debug)]
+pub struct HTTPPasswordForgot {
+    pub email: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct HTTPPasswordReset {
+    pub token: String,
+    pub password: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct HTTPEmailVerify {
+    pub token: String,
+}
+
+/// a registration that passed validation
+#[derive(Debug)]
+pub struct UserRegister {
+    pub username: String,
+    pub password: String,
+    pub birth_date: chrono::NaiveDate,
+    /// case-folded
+    pub email: String,
+}
+
+impl Validate for HTTPUserRegister {
+    type Valid = UserRegister;
+
+    fn validate(&self, policy: &PasswordSection) -> Result<UserRegister, ApiError> {
+        let mut fields = Fields::default();
+        let username = fields.check("username", server_validation::username(&self.username));
+        let password = fields.check(
+            "password",
+            server_validation::password(&self.password, policy),
+        );
+        let birth_date = fields.check(
+            "birth_date",
+            server_validation::birth_date(&self.birth_date),
+        );
+        let email = fields.check("email", server_validation::email(&self.email));
+
+        let valid = (|| {
+            Some(UserRegister {
+                username: username?,
+                password: password?,
+                birth_date: birth_date?,
+                email: email?,
+            })
+        })();
+        fields.finish(valid)
+    }
+}
+
+/// a login that passed validation, the password is checked against the stored hash only
+#[derive(Debug)]
+pub struct UserLogin {
+    /// case-folded
+    pub email: String,
+    pub password: String,
+}
+
+impl Validate for HTTPUserLogin {
+    type Valid = UserLogin;
+
+    fn validate(&self, _policy: &PasswordSection) -> Result<UserLogin, ApiError> {
+        let mut fields = Fields::default();
+        let email = fields.check("email", server_validation::email(&self.email));
+        let password = fields.check(
+            "password",
+            server_validation::current_password(&self.password),
+        );
+
+        let valid = (|| {
+            Some(UserLogin {
+                email: email?,
+                password: password?,
+            })
+        })();
+        fields.finish(valid)
+    }
+}
+
+impl Validate for HTTPPasswordForgot {
+    /// the case-folded email
+    type Valid = String;
+
+    fn validate(&self, _policy: &PasswordSection) -> Result<String, ApiError> {
+        let mut fields = Fields::default();
+        let email = fields.check("email", server_validation::email(&self.email));
+        fields.finish(email)
+    }
+}
+
+impl Validate for HTTPPasswordReset {
+    /// the new password, the token is checked by the handler
+    type Valid = String;
+
+    fn validate(&self, policy: &PasswordSection) -> Result<String, ApiError> {
+        let mut fields = Fields::default();
+        let password = fields.check(
+            "password",
+            server_validation::password(&self.password, policy),
+        );
+        fields.finish(password)
+    }
+}
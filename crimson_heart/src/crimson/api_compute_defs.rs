@@ -1,6 +1,452 @@
-use serde::{Serialize, Deserialize};
+use super::api_compute_types::{self, Job, JobState};
+use super::compute_registry;
+use super::server_api_keys::{self, AuthenticatedCaller};
+use super::server_errors::ApiError;
+use super::server_orgs;
+use super::server_roles::{JobsSubmit, Permitted, WorkersManage};
+use super::server_types;
 
-#[derive(Serialize, Deserialize, Debug)]
-struct SubmitJob {
-    
-}
\ No newline at end of file
+const JOB_COLUMNS: &str = r#"
+    job_id, user_id, org_id, name, command, required_slots, labels, state, worker_id,
+    exit_code, error, created_at, updated_at, started_at, finished_at
+"#;
+// the caller's workspace, binds the user as `$1` & the organization as `$2`:
+// an organization's jobs are shared by its members, personal ones stay private
+const TENANT_FILTER: &str =
+    "(org_id = $2 OR ($2::UUID IS NULL AND org_id IS NULL AND user_id = $1))";
+const DEFAULT_JOB_LIST_LIMIT: i64 = 50;
+const MAX_JOB_LIST_LIMIT: i64 = 500;
+
+/**
+ * # Brief
+ * HTTP POST request. Submits a Job for the logged in User.
+ *
+ * # Detail
+ * - The Job belongs to the caller's workspace, personal or the active organization.
+ * - Requires a `Registered` session or an API key with `jobs:write`,
+ *   the User needs `jobs:submit`.
+ * - The Job starts in the `queued` state.
+ * - Responds with the created Job as JSON.
+ */
+#[actix_web::post("/compute/jobs")]
+async fn http_post_compute_job(
+    __authenticated_caller: Permitted<JobsSubmit>,
+    __request_payload: actix_web::web::Json<api_compute_types::HTTPJobSubmit>,
+    __server_state: actix_web::web::Data<server_types::ServerState>,
+) -> Result<actix_web::HttpResponse, ApiError> {
+    let user_id = __authenticated_caller.user_id;
+    __authenticated_caller.require_scope(server_api_keys::SCOPE_JOBS_WRITE)?;
+
+    let payload = __request_payload.into_inner();
+    if payload.name.trim().is_empty() {
+        return Err(ApiError::invalid_field("name", "is required"));
+    }
+    if payload.command.trim().is_empty() {
+        return Err(ApiError::invalid_field("command", "is required"));
+    }
+    if payload.required_slots < 1 {
+        return Err(ApiError::invalid_field(
+            "required_slots",
+            "must be at least 1",
+        ));
+    }
+
+    let sqlx_insert_query = format!(
+        r#"
+        INSERT INTO jobs
+        (job_id, user_id, org_id, name, command, required_slots, labels, state)
+        VALUES ($1, $2, $3, $4, $5, $6, $7, $8)
+        RETURNING {}
+        "#,
+        JOB_COLUMNS
+    );
+
+    let job = sqlx::query_as::<_, Job>(&sqlx_insert_query)
+        .bind(uuid::Uuid::now_v7())
+        .bind(user_id)
+        .bind(__authenticated_caller.org_id)
+        .bind(&payload.name)
+        .bind(&payload.command)
+        .bind(payload.required_slots)
+        .bind(sqlx::types::Json(&payload.labels))
+        .bind(JobState::Queued.as_str())
+        .fetch_one(&__server_state.central_db_pool)
+        .await
+        .map_err(|e| ApiError::database(e, "INSERT INTO", "jobs"))?;
+
+    tracing::info!(
+        component = "database",
+        query = "INSERT INTO",
+        table = "jobs",
+        job_id = %job.job_id,
+        "job submitted"
+    );
+    Ok(actix_web::HttpResponse::Created().json(job))
+}
+
+/**
+ * # Brief
+ * HTTP GET request. Fetches a Job of the caller's workspace.
+ *
+ * # Detail
+ * - API keys need `jobs:read`.
+ */
+#[actix_web::get("/compute/jobs/{job_id}")]
+async fn http_get_compute_job(
+    __authenticated_caller: AuthenticatedCaller,
+    __request_path: actix_web::web::Path<uuid::Uuid>,
+    __server_state: actix_web::web::Data<server_types::ServerState>,
+) -> Result<actix_web::HttpResponse, ApiError> {
+    let user_id = __authenticated_caller.user_id;
+    __authenticated_caller.require_scope(server_api_keys::SCOPE_JOBS_READ)?;
+
+    let sqlx_select_query = format!(
+        "SELECT {} FROM jobs WHERE {} AND job_id = $3",
+        JOB_COLUMNS, TENANT_FILTER
+    );
+
+    let job = sqlx::query_as::<_, Job>(&sqlx_select_query)
+        .bind(user_id)
+        .bind(__authenticated_caller.org_id)
+        .bind(__request_path.into_inner())
+        .fetch_optional(&__server_state.central_db_pool)
+        .await
+        .map_err(|e| ApiError::database(e, "SELECT", "jobs"))?
+        .ok_or_else(job_not_found)?;
+    Ok(actix_web::HttpResponse::Ok().json(job))
+}
+
+/**
+ * # Brief
+ * HTTP GET request. Lists the Jobs of the caller's workspace, newest first.
+ *
+ * # Detail
+ * - `?state=` filters by Job state.
+ * - `?limit=` caps the result, defaults to 50, at most 500.
+ * - API keys need `jobs:read`.
+ */
+#[actix_web::get("/compute/jobs")]
+async fn http_get_compute_jobs(
+    __authenticated_caller: AuthenticatedCaller,
+    __request_query: actix_web::web::Query<api_compute_types::HTTPJobList>,
+    __server_state: actix_web::web::Data<server_types::ServerState>,
+) -> Result<actix_web::HttpResponse, ApiError> {
+    let user_id = __authenticated_caller.user_id;
+    __authenticated_caller.require_scope(server_api_keys::SCOPE_JOBS_READ)?;
+
+    let state = match __request_query.state.as_deref() {
+        Some(state) => match JobState::parse(state) {
+            Some(state) => Some(state.as_str()),
+            None => {
+                return Err(ApiError::invalid_field(
+                    "state",
+                    format!("unknown job state {}", state),
+                ));
+            }
+        },
+        None => None,
+    };
+    let limit = __request_query
+        .limit
+        .unwrap_or(DEFAULT_JOB_LIST_LIMIT)
+        .clamp(1, MAX_JOB_LIST_LIMIT);
+
+    let sqlx_select_query = format!(
+        r#"
+        SELECT {}
+        FROM jobs
+        WHERE {} AND ($3::STRING IS NULL OR state = $3)
+        ORDER BY created_at DESC
+        LIMIT $4
+        "#,
+        JOB_COLUMNS, TENANT_FILTER
+    );
+
+    let jobs = sqlx::query_as::<_, Job>(&sqlx_select_query)
+        .bind(user_id)
+        .bind(__authenticated_caller.org_id)
+        .bind(state)
+        .bind(limit)
+        .fetch_all(&__server_state.central_db_pool)
+        .await
+        .map_err(|e| ApiError::database(e, "SELECT", "jobs"))?;
+    Ok(actix_web::HttpResponse::Ok().json(jobs))
+}
+
+/**
+ * # Brief
+ * HTTP DELETE request. Cancels a Job of the caller's workspace.
+ *
+ * # Detail
+ * - Only non terminal Jobs can be cancelled, others return Conflict.
+ * - The row is kept, its state becomes `cancelled`.
+ * - The User needs `jobs:submit`, API keys also need `jobs:write`.
+ */
+#[actix_web::delete("/compute/jobs/{job_id}")]
+async fn http_delete_compute_job(
+    __authenticated_caller: Permitted<JobsSubmit>,
+    __request_path: actix_web::web::Path<uuid::Uuid>,
+    __server_state: actix_web::web::Data<server_types::ServerState>,
+) -> Result<actix_web::HttpResponse, ApiError> {
+    let user_id = __authenticated_caller.user_id;
+    __authenticated_caller.require_scope(server_api_keys::SCOPE_JOBS_WRITE)?;
+    let job_id = __request_path.into_inner();
+
+    let sqlx_update_query = format!(
+        r#"
+        UPDATE jobs
+        SET state = $4, updated_at = now(), finished_at = now()
+        WHERE {} AND job_id = $3 AND state = ANY($5)
+        RETURNING {}
+        "#,
+        TENANT_FILTER, JOB_COLUMNS
+    );
+
+    let cancelled = sqlx::query_as::<_, Job>(&sqlx_update_query)
+        .bind(user_id)
+        .bind(__authenticated_caller.org_id)
+        .bind(job_id)
+        .bind(JobState::Cancelled.as_str())
+        .bind(JobState::Cancelled.allowed_from_strs())
+        .fetch_optional(&__server_state.central_db_pool)
+        .await
+        .map_err(|e| ApiError::database(e, "UPDATE", "jobs"))?;
+    if let Some(job) = cancelled {
+        tracing::info!(
+            component = "database",
+            query = "UPDATE",
+            table = "jobs",
+            job_id = %job_id,
+            "job cancelled"
+        );
+        if let Some(worker_id) = job.worker_id.clone() {
+            let job_scheduler = __server_state.job_scheduler.clone();
+            actix_web::rt::spawn(async move {
+                job_scheduler.cancel_on_worker(job_id, &worker_id).await;
+            });
+        }
+        return Ok(actix_web::HttpResponse::Ok().json(job));
+    }
+
+    // nothing updated, either missing or already terminal
+    let state = sqlx::query_scalar::<_, String>(&format!(
+        "SELECT state FROM jobs WHERE {} AND job_id = $3",
+        TENANT_FILTER
+    ))
+    .bind(user_id)
+    .bind(__authenticated_caller.org_id)
+    .bind(job_id)
+    .fetch_optional(&__server_state.central_db_pool)
+    .await
+    .map_err(|e| ApiError::database(e, "SELECT", "jobs"))?
+    .ok_or_else(job_not_found)?;
+    Err(ApiError::conflict(
+        "job_finished",
+        format!("Job already {}", state),
+    ))
+}
+
+fn job_not_found() -> ApiError {
+    ApiError::not_found("job_not_found", "Job not found")
+}
+
+// constant time, the token guards the whole fleet
+fn tokens_match(expected: &[u8], presented: &[u8]) -> bool {
+    expected.len() == presented.len()
+        && expected
+            .iter()
+            .zip(presented)
+            .fold(0u8, |acc, (a, b)| acc | (a ^ b))
+            == 0
+}
+
+/**
+ * # Brief
+ * Checks the `Authorization: Bearer` worker token.
+ *
+ * # Detail
+ * - Worker endpoints are disabled when `compute.worker_token` isn't configured.
+ */
+fn authorize_worker(
+    request_metadata: &actix_web::HttpRequest,
+    server_state: &server_types::ServerState,
+) -> Result<(), ApiError> {
+    let expected = match &server_state.worker_token {
+        Some(token) => token,
+        None => {
+            tracing::warn!(
+                component = "compute_registry",
+                "worker request rejected, compute.worker_token is not configured"
+            );
+            return Err(ApiError::forbidden(
+                "worker_access_disabled",
+                "Worker access disabled",
+            ));
+        }
+    };
+
+    let presented = request_metadata
+        .headers()
+        .get(actix_web::http::header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+
+    match presented {
+        Some(presented) if tokens_match(expected.as_bytes(), presented.as_bytes()) => Ok(()),
+        _ => {
+            tracing::info!(component = "compute_registry", "invalid worker token");
+            Err(ApiError::unauthorized(
+                "invalid_worker_token",
+                "Invalid worker token",
+            ))
+        }
+    }
+}
+
+/**
+ * # Brief
+ * HTTP POST request. Registers a compute Worker.
+ *
+ * # Detail
+ * - Authenticated with the shared worker token.
+ * - Registering an existing id replaces its description and marks it alive.
+ * - `org_id` dedicates the Worker to that organization's Jobs.
+ */
+#[actix_web::post("/compute/workers")]
+async fn http_post_compute_worker(
+    __request_metadata: actix_web::HttpRequest,
+    __request_payload: actix_web::web::Json<api_compute_types::HTTPWorkerRegister>,
+    __server_state: actix_web::web::Data<server_types::ServerState>,
+) -> Result<actix_web::HttpResponse, ApiError> {
+    authorize_worker(&__request_metadata, &__server_state)?;
+
+    let payload = __request_payload.into_inner();
+    if !compute_registry::is_valid_worker_id(&payload.worker_id) {
+        return Err(ApiError::invalid_field(
+            "worker_id",
+            "must be 1-64 characters of [A-Za-z0-9._-]",
+        ));
+    }
+    if payload.capacity < 1 {
+        return Err(ApiError::invalid_field("capacity", "must be at least 1"));
+    }
+    if let Some(org_id) = payload.org_id
+        && !server_orgs::org_exists(&__server_state.central_db_pool, org_id)
+            .await
+            .map_err(|e| ApiError::database(e, "SELECT", "organizations"))?
+    {
+        return Err(ApiError::invalid_field("org_id", "unknown organization"));
+    }
+
+    let worker = __server_state
+        .worker_registry
+        .register(
+            &payload.worker_id,
+            payload.address.as_deref(),
+            payload.capacity,
+            &payload.labels,
+            payload.org_id,
+        )
+        .await
+        .map_err(|e| ApiError::internal("compute_registry", "register", e))?;
+
+    tracing::info!(
+        component = "compute_registry",
+        worker_id = %worker.worker_id,
+        capacity = worker.capacity,
+        "worker registered"
+    );
+    Ok(actix_web::HttpResponse::Ok().json(worker))
+}
+
+/**
+ * # Brief
+ * HTTP POST request. Heartbeat from a registered Worker.
+ *
+ * # Detail
+ * - Returns NotFound for unknown or dead Workers, they must register again.
+ */
+#[actix_web::post("/compute/workers/{worker_id}/heartbeat")]
+async fn http_post_compute_worker_heartbeat(
+    __request_metadata: actix_web::HttpRequest,
+    __request_path: actix_web::web::Path<String>,
+    __server_state: actix_web::web::Data<server_types::ServerState>,
+) -> Result<actix_web::HttpResponse, ApiError> {
+    authorize_worker(&__request_metadata, &__server_state)?;
+
+    let worker_id = __request_path.into_inner();
+    let alive = __server_state
+        .worker_registry
+        .heartbeat(&worker_id)
+        .await
+        .map_err(|e| ApiError::internal("compute_registry", "heartbeat", e))?;
+    if !alive {
+        tracing::info!(
+            component = "compute_registry",
+            worker_id = %worker_id,
+            "heartbeat from unknown or dead worker"
+        );
+        return Err(ApiError::not_found(
+            "worker_not_found",
+            "Unknown worker, register again",
+        ));
+    }
+    Ok(actix_web::HttpResponse::NoContent().finish())
+}
+
+/**
+ * # Brief
+ * HTTP GET request. Lists every registered Worker, alive or dead.
+ *
+ * # Detail
+ * - Only shared Workers & those dedicated to the caller's organization.
+ * - The User needs `workers:manage`, API keys also need `workers:read`.
+ */
+#[actix_web::get("/compute/workers")]
+async fn http_get_compute_workers(
+    __authenticated_caller: Permitted<WorkersManage>,
+    __server_state: actix_web::web::Data<server_types::ServerState>,
+) -> Result<actix_web::HttpResponse, ApiError> {
+    __authenticated_caller.require_scope(server_api_keys::SCOPE_WORKERS_READ)?;
+
+    let workers = __server_state
+        .worker_registry
+        .list()
+        .await
+        .map_err(|e| ApiError::internal("compute_registry", "list", e))?;
+    Ok(actix_web::HttpResponse::Ok().json(
+        workers
+            .into_iter()
+            .filter(|worker| worker.serves(__authenticated_caller.org_id))
+            .collect::<Vec<_>>(),
+    ))
+}
+
+/**
+ * # Brief
+ * HTTP GET request. Fetches a single Worker.
+ *
+ * # Detail
+ * - Another organization's Workers are NotFound.
+ * - The User needs `workers:manage`, API keys also need `workers:read`.
+ */
+#[actix_web::get("/compute/workers/{worker_id}")]
+async fn http_get_compute_worker(
+    __authenticated_caller: Permitted<WorkersManage>,
+    __request_path: actix_web::web::Path<String>,
+    __server_state: actix_web::web::Data<server_types::ServerState>,
+) -> Result<actix_web::HttpResponse, ApiError> {
+    __authenticated_caller.require_scope(server_api_keys::SCOPE_WORKERS_READ)?;
+
+    match __server_state
+        .worker_registry
+        .get(&__request_path.into_inner())
+        .await
+        .map_err(|e| ApiError::internal("compute_registry", "get", e))?
+    {
+        Some(worker) if worker.serves(__authenticated_caller.org_id) => {
+            Ok(actix_web::HttpResponse::Ok().json(worker))
+        }
+        _ => Err(ApiError::not_found("worker_not_found", "Worker not found")),
+    }
+}
@@ -0,0 +1,110 @@
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct HTTPJobSubmit {
+    pub name: String,
+    pub command: String,
+    #[serde(default = "default_required_slots")]
+    pub required_slots: i64,
+    #[serde(default)]
+    pub labels: std::collections::HashMap<String, String>,
+}
+
+fn default_required_slots() -> i64 {
+    1
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct HTTPJobList {
+    pub state: Option<String>,
+    pub limit: Option<i64>,
+}
+
+#[derive(Serialize, Debug, sqlx::FromRow)]
+pub struct Job {
+    pub job_id: uuid::Uuid,
+    pub user_id: uuid::Uuid,
+    /// None for personal Jobs
+    pub org_id: Option<uuid::Uuid>,
+    pub name: String,
+    pub command: String,
+    pub required_slots: i64,
+    pub labels: sqlx::types::Json<std::collections::HashMap<String, String>>,
+    pub state: String,
+    pub worker_id: Option<String>,
+    pub exit_code: Option<i64>,
+    pub error: Option<String>,
+    pub created_at: chrono::DateTime<chrono::Utc>,
+    pub updated_at: chrono::DateTime<chrono::Utc>,
+    pub started_at: Option<chrono::DateTime<chrono::Utc>>,
+    pub finished_at: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+// job lifecycle
+// - queued -> scheduled -> running -> succeeded | failed
+// - scheduled | running -> queued, when the worker is lost
+// - any non terminal state -> cancelled
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JobState {
+    Queued,
+    Scheduled,
+    Running,
+    Succeeded,
+    Failed,
+    Cancelled,
+}
+
+impl JobState {
+    #[inline]
+    pub fn as_str(self) -> &'static str {
+        match self {
+            JobState::Queued => "queued",
+            JobState::Scheduled => "scheduled",
+            JobState::Running => "running",
+            JobState::Succeeded => "succeeded",
+            JobState::Failed => "failed",
+            JobState::Cancelled => "cancelled",
+        }
+    }
+
+    #[inline]
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "queued" => Some(JobState::Queued),
+            "scheduled" => Some(JobState::Scheduled),
+            "running" => Some(JobState::Running),
+            "succeeded" => Some(JobState::Succeeded),
+            "failed" => Some(JobState::Failed),
+            "cancelled" => Some(JobState::Cancelled),
+            _ => None,
+        }
+    }
+
+    /// states a job may move to `self` from
+    pub fn allowed_from(self) -> &'static [JobState] {
+        match self {
+            JobState::Queued => &[JobState::Scheduled, JobState::Running],
+            JobState::Scheduled => &[JobState::Queued],
+            JobState::Running => &[JobState::Scheduled],
+            JobState::Succeeded => &[JobState::Running],
+            JobState::Failed => &[JobState::Scheduled, JobState::Running],
+            JobState::Cancelled => &[JobState::Queued, JobState::Scheduled, JobState::Running],
+        }
+    }
+
+    /// `allowed_from` as strings, for `state = ANY($n)` guards in SQL
+    pub fn allowed_from_strs(self) -> Vec<&'static str> {
+        self.allowed_from().iter().map(|s| s.as_str()).collect()
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct HTTPWorkerRegister {
+    pub worker_id: String,
+    pub address: Option<String>,
+    pub capacity: i64,
+    /// dedicates the Worker to one organization, shared by everyone when absent
+    pub org_id: Option<uuid::Uuid>,
+    #[serde(default)]
+    pub labels: std::collections::HashMap<String, String>,
+}
@@ -0,0 +1,183 @@
+use super::api_keys_types::{self, ApiKey};
+use super::server_api_keys;
+use super::server_errors::ApiError;
+use super::server_orgs;
+use super::server_sessions::AuthenticatedUser;
+use super::server_types;
+
+const API_KEY_COLUMNS: &str =
+    "key_id, name, prefix, scopes, org_id, expires_at, created_at, last_used_at";
+const MAX_API_KEY_NAME_LENGTH: usize = 64;
+const MAX_API_KEYS_PER_USER: i64 = 50;
+
+fn database_error(e: sqlx::Error, query: &'static str) -> ApiError {
+    ApiError::database(e, query, "api_keys")
+}
+
+/**
+ * # Brief
+ * HTTP POST request. Creates an API key for the logged in User.
+ *
+ * # Detail
+ * - Requires a cookie session, keys can't mint more keys.
+ * - Responds with the key once, only its SHA-256 is stored.
+ * - Scopes must be known & non empty, `expires_at` must lie in the future.
+ * - The key is bound to the session's active organization, if any.
+ */
+#[actix_web::post("/auth/keys")]
+async fn http_post_api_key(
+    __authenticated_user: AuthenticatedUser,
+    __request_payload: actix_web::web::Json<api_keys_types::HTTPApiKeyCreate>,
+    __server_state: actix_web::web::Data<server_types::ServerState>,
+) -> Result<actix_web::HttpResponse, ApiError> {
+    let user_id = __authenticated_user.user_id;
+    let payload = __request_payload.into_inner();
+
+    let name = payload.name.trim();
+    if name.is_empty() || name.chars().count() > MAX_API_KEY_NAME_LENGTH {
+        return Err(ApiError::invalid_field(
+            "name",
+            format!("must be 1 to {} characters", MAX_API_KEY_NAME_LENGTH),
+        ));
+    }
+    if payload.scopes.is_empty() {
+        return Err(ApiError::invalid_field(
+            "scopes",
+            "needs at least one scope",
+        ));
+    }
+    if let Some(unknown) = payload
+        .scopes
+        .iter()
+        .find(|scope| !server_api_keys::SCOPES.contains(&scope.as_str()))
+    {
+        return Err(ApiError::invalid_field(
+            "scopes",
+            format!(
+                "unknown scope {} (expected {})",
+                unknown,
+                server_api_keys::SCOPES.join(", ")
+            ),
+        ));
+    }
+    if payload
+        .expires_at
+        .is_some_and(|expires_at| expires_at <= chrono::Utc::now())
+    {
+        return Err(ApiError::invalid_field("expires_at", "is in the past"));
+    }
+    let mut scopes = payload.scopes;
+    scopes.sort();
+    scopes.dedup();
+
+    let count = sqlx::query_scalar::<_, i64>("SELECT count(*) FROM api_keys WHERE user_id = $1")
+        .bind(user_id)
+        .fetch_one(&__server_state.central_db_pool)
+        .await
+        .map_err(|e| database_error(e, "SELECT"))?;
+    if count >= MAX_API_KEYS_PER_USER {
+        return Err(ApiError::conflict(
+            "api_key_limit",
+            format!("At most {} API keys per user", MAX_API_KEYS_PER_USER),
+        ));
+    }
+    if let Some(org_id) = __authenticated_user.org_id
+        && server_orgs::member_role(&__server_state.central_db_pool, org_id, user_id)
+            .await
+            .map_err(|e| ApiError::database(e, "SELECT", "org_members"))?
+            .is_none()
+    {
+        return Err(ApiError::forbidden(
+            server_orgs::CODE_NOT_ORG_MEMBER,
+            "Not a member of the active organization, switch workspace",
+        ));
+    }
+
+    let (key, prefix) = server_api_keys::new_api_key();
+    let sqlx_insert_query = format!(
+        r#"
+        INSERT INTO api_keys
+        (key_id, user_id, name, prefix, key_hash, scopes, org_id, expires_at)
+        VALUES ($1, $2, $3, $4, $5, $6, $7, $8)
+        RETURNING {}
+        "#,
+        API_KEY_COLUMNS
+    );
+    let api_key = sqlx::query_as::<_, ApiKey>(&sqlx_insert_query)
+        .bind(uuid::Uuid::now_v7())
+        .bind(user_id)
+        .bind(name)
+        .bind(&prefix)
+        .bind(server_api_keys::hash_api_key(&key))
+        .bind(&scopes)
+        .bind(__authenticated_user.org_id)
+        .bind(payload.expires_at)
+        .fetch_one(&__server_state.central_db_pool)
+        .await
+        .map_err(|e| database_error(e, "INSERT INTO"))?;
+
+    tracing::info!(
+        component = "api_keys",
+        user_id = %user_id,
+        key_id = %api_key.key_id,
+        "api key created"
+    );
+    Ok(actix_web::HttpResponse::Created().json(api_keys_types::HTTPApiKeyCreated { key, api_key }))
+}
+
+/**
+ * # Brief
+ * HTTP GET request. Lists the logged in User's API keys, newest first.
+ */
+#[actix_web::get("/auth/keys")]
+async fn http_get_api_keys(
+    __authenticated_user: AuthenticatedUser,
+    __server_state: actix_web::web::Data<server_types::ServerState>,
+) -> Result<actix_web::HttpResponse, ApiError> {
+    let sqlx_select_query = format!(
+        "SELECT {} FROM api_keys WHERE user_id = $1 ORDER BY created_at DESC",
+        API_KEY_COLUMNS
+    );
+    let api_keys = sqlx::query_as::<_, ApiKey>(&sqlx_select_query)
+        .bind(__authenticated_user.user_id)
+        .fetch_all(&__server_state.central_db_pool)
+        .await
+        .map_err(|e| database_error(e, "SELECT"))?;
+    Ok(actix_web::HttpResponse::Ok().json(api_keys))
+}
+
+/**
+ * # Brief
+ * HTTP DELETE request. Revokes one of the logged in User's API keys.
+ *
+ * # Detail
+ * - Takes effect on the key's next request.
+ */
+#[actix_web::delete("/auth/keys/{key_id}")]
+async fn http_delete_api_key(
+    __authenticated_user: AuthenticatedUser,
+    __request_path: actix_web::web::Path<uuid::Uuid>,
+    __server_state: actix_web::web::Data<server_types::ServerState>,
+) -> Result<actix_web::HttpResponse, ApiError> {
+    let key_id = __request_path.into_inner();
+    let result = sqlx::query("DELETE FROM api_keys WHERE key_id = $1 AND user_id = $2")
+        .bind(key_id)
+        .bind(__authenticated_user.user_id)
+        .execute(&__server_state.central_db_pool)
+        .await
+        .map_err(|e| database_error(e, "DELETE"))?;
+    if result.rows_affected() != 1 {
+        return Err(ApiError::not_found(
+            "api_key_not_found",
+            "API key not found",
+        ));
+    }
+
+    tracing::info!(
+        component = "api_keys",
+        user_id = %__authenticated_user.user_id,
+        key_id = %key_id,
+        "api key revoked"
+    );
+    Ok(actix_web::HttpResponse::NoContent().finish())
+}
@@ -0,0 +1,32 @@
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct HTTPApiKeyCreate {
+    pub name: String,
+    /// subset of `jobs:read`, `jobs:write`, `workers:read`
+    pub scopes: Vec<String>,
+    /// never expires when absent
+    pub expires_at: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+/// an API key as listed to its owner, the key itself is never exposed
+#[derive(Serialize, Debug, sqlx::FromRow)]
+pub struct ApiKey {
+    pub key_id: uuid::Uuid,
+    pub name: String,
+    pub prefix: String,
+    pub scopes: Vec<String>,
+    /// the organization the key acts in, None for the personal workspace
+    pub org_id: Option<uuid::Uuid>,
+    pub expires_at: Option<chrono::DateTime<chrono::Utc>>,
+    pub created_at: chrono::DateTime<chrono::Utc>,
+    pub last_used_at: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+/// answer to a creation, the only time `key` is shown
+#[derive(Serialize, Debug)]
+pub struct HTTPApiKeyCreated {
+    pub key: String,
+    #[serde(flatten)]
+    pub api_key: ApiKey,
+}
@@ -0,0 +1,859 @@
+use super::api_oauth_types::{self, HTTPOauthError};
+use super::server_errors::{ApiError, CODE_INVALID_REQUEST};
+use super::server_oauth::{self, AuthorizationCode, TokenSigner, UserClaims};
+use super::server_sessions::AuthenticatedUser;
+use super::server_types;
+
+// the provider is off without `oauth.signing_key`
+fn signer(server_state: &server_types::ServerState) -> Result<&TokenSigner, ApiError> {
+    server_state
+        .oauth_signer
+        .as_ref()
+        .ok_or_else(|| ApiError::not_found("oidc_provider_disabled", "OpenID Provider Disabled"))
+}
+
+// tokens name the public url as their issuer
+fn issuer(server_state: &server_types::ServerState) -> String {
+    server_state
+        .public_url
+        .as_str()
+        .trim_end_matches('/')
+        .to_string()
+}
+
+// RFC 6749 5.2, JSON errors of the token endpoint
+fn token_error(
+    status: actix_web::http::StatusCode,
+    error: &str,
+    description: &str,
+) -> actix_web::HttpResponse {
+    actix_web::HttpResponse::build(status)
+        .insert_header((actix_web::http::header::CACHE_CONTROL, "no-store"))
+        .json(HTTPOauthError {
+            error: error.to_string(),
+            error_description: description.to_string(),
+        })
+}
+
+// RFC 6750 3, bearer token errors of the userinfo endpoint
+fn invalid_token(description: &str) -> actix_web::HttpResponse {
+    actix_web::HttpResponse::Unauthorized()
+        .insert_header((
+            actix_web::http::header::WWW_AUTHENTICATE,
+            format!(
+                "Bearer error=\"invalid_token\", error_description=\"{}\"",
+                description
+            ),
+        ))
+        .json(HTTPOauthError {
+            error: "invalid_token".to_string(),
+            error_description: description.to_string(),
+        })
+}
+
+/// claims released for `scopes`, `sub` aside
+fn user_claims(
+    scopes: &[String],
+    email: String,
+    username: String,
+    email_verified_at: Option<chrono::DateTime<chrono::Utc>>,
+) -> UserClaims {
+    let granted = |scope: &str| scopes.iter().any(|granted| granted == scope);
+    let mut claims = UserClaims::default();
+    if granted(server_oauth::SCOPE_EMAIL) {
+        claims.email = Some(email);
+        claims.email_verified = Some(email_verified_at.is_some());
+    }
+    if granted(server_oauth::SCOPE_PROFILE) {
+        claims.name = Some(username.clone());
+        claims.preferred_username = Some(username);
+    }
+    claims
+}
+
+/**
+ * # Brief
+ * HTTP GET request. OpenID Connect discovery document.
+ */
+#[actix_web::get("/.well-known/openid-configuration")]
+async fn http_get_openid_configuration(
+    __server_state: actix_web::web::Data<server_types::ServerState>,
+) -> Result<actix_web::HttpResponse, ApiError> {
+    signer(&__server_state)?;
+    let issuer = issuer(&__server_state);
+    let strings = |values: &[&str]| values.iter().map(|value| value.to_string()).collect();
+    let discovery = api_oauth_types::HTTPDiscovery {
+        authorization_endpoint: format!("{}/oauth/authorize", issuer),
+        token_endpoint: format!("{}/oauth/token", issuer),
+        userinfo_endpoint: format!("{}/oauth/userinfo", issuer),
+        jwks_uri: format!("{}/oauth/jwks", issuer),
+        issuer,
+        response_types_supported: strings(&["code"]),
+        grant_types_supported: strings(&["authorization_code"]),
+        subject_types_supported: strings(&["public"]),
+        id_token_signing_alg_values_supported: strings(&[server_oauth::SIGNING_ALGORITHM]),
+        scopes_supported: strings(&server_oauth::SCOPES),
+        token_endpoint_auth_methods_supported: strings(&[
+            "client_secret_basic",
+            "client_secret_post",
+            "none",
+        ]),
+        code_challenge_methods_supported: strings(&["S256"]),
+        claims_supported: strings(&[
+            "iss",
+            "sub",
+            "aud",
+            "exp",
+            "iat",
+            "auth_time",
+            "nonce",
+            "email",
+            "email_verified",
+            "name",
+            "preferred_username",
+        ]),
+    };
+    Ok(actix_web::HttpResponse::Ok().json(discovery))
+}
+
+/**
+ * # Brief
+ * HTTP GET request. Public keys ID & access tokens are signed with.
+ */
+#[actix_web::get("/oauth/jwks")]
+async fn http_get_oauth_jwks(
+    __server_state: actix_web::web::Data<server_types::ServerState>,
+) -> Result<actix_web::HttpResponse, ApiError> {
+    let signer = signer(&__server_state)?;
+    Ok(actix_web::HttpResponse::Ok().json(serde_json::json!({ "keys": [signer.jwk()] })))
+}
+
+/**
+ * # Brief
+ * HTTP GET request. Authorization endpoint, signs the session's User into a client.
+ *
+ * # Detail
+ * - An unknown client or redirect uri is answered here, every other error goes back to the client.
+ * - Public clients must send an S256 `code_challenge`, `plain` is refused.
+ * - Browsers without a `Registered` session go to `oauth.login_url` with `return_to`,
+ *   or back to the client with `login_required` when it isn't set or `prompt=none`.
+ * - Clients are our own services, there is no consent screen.
+ */
+#[actix_web::get("/oauth/authorize")]
+async fn http_get_oauth_authorize(
+    __request_metadata: actix_web::HttpRequest,
+    __request_query: actix_web::web::Query<api_oauth_types::HTTPAuthorizeRequest>,
+    __authenticated_user: Option<AuthenticatedUser>,
+    __server_state: actix_web::web::Data<server_types::ServerState>,
+) -> Result<actix_web::HttpResponse, ApiError> {
+    signer(&__server_state)?;
+    let request = __request_query.into_inner();
+
+    let Some(client_id) = request.client_id.as_deref() else {
+        return Err(ApiError::bad_request(
+            CODE_INVALID_REQUEST,
+            "client_id is required",
+        ));
+    };
+    let Some(client) = server_oauth::find_client(&__server_state.central_db_pool, client_id)
+        .await
+        .map_err(|e| ApiError::database(e, "SELECT", "oauth_clients"))?
+    else {
+        return Err(ApiError::bad_request("unknown_client", "Unknown client"));
+    };
+    // never redirect anywhere the client didn't register
+    let Some(redirect_uri) = request
+        .redirect_uri
+        .as_deref()
+        .filter(|redirect_uri| client.redirect_uris.iter().any(|uri| uri == redirect_uri))
+    else {
+        return Err(ApiError::bad_request(
+            "redirect_uri_not_registered",
+            "Redirect URI not registered",
+        ));
+    };
+
+    let redirect = |params: &[(&str, &str)]| {
+        let mut location = url::Url::parse(redirect_uri)
+            .map_err(|e| ApiError::internal("oauth", "parse_redirect_uri", e))?;
+        {
+            let mut query = location.query_pairs_mut();
+            for (key, value) in params {
+                query.append_pair(key, value);
+            }
+            if let Some(state) = &request.state {
+                query.append_pair("state", state);
+            }
+        }
+        Ok(actix_web::HttpResponse::Found()
+            .insert_header((actix_web::http::header::LOCATION, location.as_str()))
+            .finish())
+    };
+    let redirect_error = |error: &str, description: &str| {
+        redirect(&[("error", error), ("error_description", description)])
+    };
+
+    if request.response_type.as_deref() != Some("code") {
+        return redirect_error("unsupported_response_type", "only `code` is supported");
+    }
+    let scopes = server_oauth::parse_scope(request.scope.as_deref().unwrap_or_default());
+    if !scopes
+        .iter()
+        .any(|scope| scope == server_oauth::SCOPE_OPENID)
+    {
+        return redirect_error("invalid_scope", "`openid` is required");
+    }
+    if let Some(scope) = scopes.iter().find(|scope| !client.scopes.contains(scope)) {
+        return redirect_error(
+            "invalid_scope",
+            &format!("`{}` is not allowed for this client", scope),
+        );
+    }
+    match (
+        &request.code_challenge,
+        request.code_challenge_method.as_deref(),
+    ) {
+        (Some(_), Some("S256")) => {}
+        (Some(_), _) => {
+            return redirect_error("invalid_request", "code_challenge_method must be S256");
+        }
+        (None, _) if client.is_public() => {
+            return redirect_error("invalid_request", "public clients must use PKCE");
+        }
+        (None, _) => {}
+    }
+
+    let Some(user) = __authenticated_user else {
+        let login_url = __server_state.oauth.login_url.as_deref();
+        return match login_url {
+            Some(login_url) if request.prompt.as_deref() != Some("none") => {
+                let return_to = format!("{}{}", issuer(&__server_state), __request_metadata.uri());
+                let separator = if login_url.contains('?') { '&' } else { '?' };
+                let location = format!(
+                    "{}{}return_to={}",
+                    login_url,
+                    separator,
+                    url::form_urlencoded::byte_serialize(return_to.as_bytes()).collect::<String>()
+                );
+                Ok(actix_web::HttpResponse::Found()
+                    .insert_header((actix_web::http::header::LOCATION, location))
+                    .finish())
+            }
+            _ => redirect_error("login_required", "the user is not signed in"),
+        };
+    };
+
+    let mut redis_connection = __server_state.redis_pool.get().await?;
+    let authorization = AuthorizationCode {
+        client_id: client.client_id.clone(),
+        user_id: user.user_id,
+        redirect_uri: redirect_uri.to_string(),
+        scope: scopes.join(" "),
+        nonce: request.nonce.clone(),
+        code_challenge: request.code_challenge.clone(),
+        auth_time: user.created_at,
+    };
+    let code = server_oauth::store_code(
+        &mut redis_connection,
+        &authorization,
+        __server_state.oauth.code_ttl,
+    )
+    .await
+    .map_err(|e| ApiError::redis(e, "store_code"))?;
+
+    tracing::info!(
+        component = "oauth",
+        client_id = %client.client_id,
+        user_id = %user.user_id,
+        scope = %authorization.scope,
+        "authorization code issued"
+    );
+    redirect(&[("code", &code)])
+}
+
+// client credentials from `Authorization: Basic` or the form, RFC 6749 2.3.1
+fn client_credentials(
+    request: &actix_web::HttpRequest,
+    form: &api_oauth_types::HTTPTokenRequest,
+) -> Option<(String, Option<String>)> {
+    let basic = request
+        .headers()
+        .get(actix_web::http::header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Basic "))
+        .and_then(|encoded| data_encoding::BASE64.decode(encoded.trim().as_bytes()).ok())
+        .and_then(|decoded| String::from_utf8(decoded).ok());
+    if let Some(basic) = basic {
+        let (client_id, client_secret) = basic.split_once(':')?;
+        let decode = |value: &str| {
+            url::form_urlencoded::parse(format!("v={}", value).as_bytes())
+                .next()
+                .map(|(_, value)| value.into_owned())
+                .unwrap_or_default()
+        };
+        return Some((decode(client_id), Some(decode(client_secret))));
+    }
+    form.client_id
+        .clone()
+        .map(|client_id| (client_id, form.client_secret.clone()))
+}
+
+/**
+ * # Brief
+ * HTTP POST request. Token endpoint, redeems an authorization code for ID & access tokens.
+ *
+ * # Detail
+ * - Confidential clients authenticate with `client_secret_basic` or `client_secret_post`.
+ * - The code works once & only for the client & redirect uri it was issued for.
+ * - A code issued with a PKCE challenge needs the matching `code_verifier`.
+ * - Refusals are RFC 6749 5.2 JSON, server side failures the usual API errors.
+ */
+#[actix_web::post("/oauth/token")]
+async fn http_post_oauth_token(
+    __request_metadata: actix_web::HttpRequest,
+    __request_payload: actix_web::web::Form<api_oauth_types::HTTPTokenRequest>,
+    __server_state: actix_web::web::Data<server_types::ServerState>,
+) -> Result<actix_web::HttpResponse, ApiError> {
+    use actix_web::http::StatusCode;
+
+    let signer = signer(&__server_state)?;
+    let form = __request_payload.into_inner();
+    if form.grant_type.as_deref() != Some("authorization_code") {
+        return Ok(token_error(
+            StatusCode::BAD_REQUEST,
+            "unsupported_grant_type",
+            "only `authorization_code` is supported",
+        ));
+    }
+
+    let Some((client_id, client_secret)) = client_credentials(&__request_metadata, &form) else {
+        return Ok(token_error(
+            StatusCode::UNAUTHORIZED,
+            "invalid_client",
+            "client_id is required",
+        ));
+    };
+    let client = match server_oauth::find_client(&__server_state.central_db_pool, &client_id)
+        .await
+        .map_err(|e| ApiError::database(e, "SELECT", "oauth_clients"))?
+    {
+        Some(client) if client.authenticates(client_secret.as_deref()) => client,
+        _ => {
+            tracing::info!(
+                component = "oauth",
+                client_id = %client_id,
+                "client authentication failed"
+            );
+            return Ok(token_error(
+                StatusCode::UNAUTHORIZED,
+                "invalid_client",
+                "client authentication failed",
+            ));
+        }
+    };
+
+    let Some(code) = form.code.as_deref() else {
+        return Ok(token_error(
+            StatusCode::BAD_REQUEST,
+            "invalid_request",
+            "code is required",
+        ));
+    };
+    let mut redis_connection = __server_state.redis_pool.get().await?;
+    let Some(authorization) = server_oauth::take_code(&mut redis_connection, code)
+        .await
+        .map_err(|e| ApiError::redis(e, "take_code"))?
+    else {
+        return Ok(token_error(
+            StatusCode::BAD_REQUEST,
+            "invalid_grant",
+            "code is invalid, expired or used",
+        ));
+    };
+    if authorization.client_id != client.client_id
+        || form.redirect_uri.as_deref() != Some(authorization.redirect_uri.as_str())
+    {
+        return Ok(token_error(
+            StatusCode::BAD_REQUEST,
+            "invalid_grant",
+            "code was issued to another client or redirect uri",
+        ));
+    }
+    if let Some(code_challenge) = &authorization.code_challenge
+        && !form
+            .code_verifier
+            .as_deref()
+            .is_some_and(|verifier| server_oauth::verify_pkce(verifier, code_challenge))
+    {
+        return Ok(token_error(
+            StatusCode::BAD_REQUEST,
+            "invalid_grant",
+            "code_verifier doesn't match",
+        ));
+    }
+
+    let Some((email, username, email_verified_at)) =
+        sqlx::query_as::<_, (String, String, Option<chrono::DateTime<chrono::Utc>>)>(
+            "SELECT email, username, email_verified_at FROM users WHERE user_id = $1",
+        )
+        .bind(authorization.user_id)
+        .fetch_optional(&__server_state.central_db_pool)
+        .await
+        .map_err(|e| ApiError::database(e, "SELECT", "users"))?
+    else {
+        return Ok(token_error(
+            StatusCode::BAD_REQUEST,
+            "invalid_grant",
+            "user is gone",
+        ));
+    };
+
+    let issuer = issuer(&__server_state);
+    let now = chrono::Utc::now().timestamp();
+    let scopes = server_oauth::parse_scope(&authorization.scope);
+    let access_token_ttl = __server_state.oauth.access_token_ttl;
+    let access_token = signer
+        .sign(&server_oauth::AccessTokenClaims {
+            iss: issuer.clone(),
+            sub: authorization.user_id.to_string(),
+            aud: client.client_id.clone(),
+            client_id: client.client_id.clone(),
+            scope: authorization.scope.clone(),
+            iat: now,
+            exp: now + access_token_ttl,
+            jti: uuid::Uuid::now_v7().to_string(),
+        })
+        .map_err(|e| ApiError::internal("oauth", "sign", e))?;
+    let id_token = signer
+        .sign(&server_oauth::IdTokenClaims {
+            iss: issuer,
+            sub: authorization.user_id.to_string(),
+            aud: client.client_id.clone(),
+            iat: now,
+            exp: now + __server_state.oauth.id_token_ttl,
+            auth_time: authorization.auth_time,
+            nonce: authorization.nonce,
+            profile: user_claims(&scopes, email, username, email_verified_at),
+        })
+        .map_err(|e| ApiError::internal("oauth", "sign", e))?;
+
+    tracing::info!(
+        component = "oauth",
+        client_id = %client.client_id,
+        user_id = %authorization.user_id,
+        "tokens issued"
+    );
+    Ok(actix_web::HttpResponse::Ok()
+        .insert_header((actix_web::http::header::CACHE_CONTROL, "no-store"))
+        .json(api_oauth_types::HTTPTokenResponse {
+            access_token,
+            token_type: "Bearer".to_string(),
+            expires_in: access_token_ttl,
+            id_token,
+            scope: authorization.scope,
+        }))
+}
+
+/**
+ * # Brief
+ * HTTP GET or POST request. Userinfo endpoint, claims about the access token's User.
+ *
+ * # Detail
+ * - Takes the access token as `Authorization: Bearer`.
+ * - Claims follow the token's scopes, like the ID token.
+ * - Bad tokens are answered per RFC 6750 3, server side failures as the usual API errors.
+ */
+#[actix_web::route("/oauth/userinfo", method = "GET", method = "POST")]
+async fn http_get_oauth_userinfo(
+    __request_metadata: actix_web::HttpRequest,
+    __server_state: actix_web::web::Data<server_types::ServerState>,
+) -> Result<actix_web::HttpResponse, ApiError> {
+    let signer = signer(&__server_state)?;
+    let Some(access_token) = __request_metadata
+        .headers()
+        .get(actix_web::http::header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+    else {
+        return Ok(invalid_token("access token is required"));
+    };
+
+    let claims: server_oauth::AccessTokenClaims = match signer.verify(access_token.trim()) {
+        Ok(claims) => claims,
+        Err(e) => {
+            tracing::info!(error = %e, component = "oauth", "userinfo with a bad access token");
+            return Ok(invalid_token("access token is invalid"));
+        }
+    };
+    let scopes = server_oauth::parse_scope(&claims.scope);
+    if claims.iss != issuer(&__server_state)
+        || claims.exp <= chrono::Utc::now().timestamp()
+        || !scopes
+            .iter()
+            .any(|scope| scope == server_oauth::SCOPE_OPENID)
+    {
+        return Ok(invalid_token("access token is expired or not for userinfo"));
+    }
+    let Ok(user_id) = uuid::Uuid::parse_str(&claims.sub) else {
+        return Ok(invalid_token("access token is invalid"));
+    };
+
+    let Some((email, username, email_verified_at)) =
+        sqlx::query_as::<_, (String, String, Option<chrono::DateTime<chrono::Utc>>)>(
+            "SELECT email, username, email_verified_at FROM users WHERE user_id = $1",
+        )
+        .bind(user_id)
+        .fetch_optional(&__server_state.central_db_pool)
+        .await
+        .map_err(|e| ApiError::database(e, "SELECT", "users"))?
+    else {
+        return Ok(invalid_token("user is gone"));
+    };
+    let mut userinfo = serde_json::json!({ "sub": claims.sub });
+    if let (Some(userinfo), serde_json::Value::Object(user_claims)) = (
+        userinfo.as_object_mut(),
+        serde_json::json!(user_claims(&scopes, email, username, email_verified_at)),
+    ) {
+        userinfo.extend(user_claims);
+    }
+    Ok(actix_web::HttpResponse::Ok().json(userinfo))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crimson::server_testing::{self, TestServer};
+    use actix_web::http::StatusCode;
+
+    const CONFIG: &str = r#"
+        [oauth]
+        signing_key = "1111111111111111111111111111111111111111111111111111111111111111"
+    "#;
+    const REDIRECT_URI: &str = "https://app.example/callback";
+    const VERIFIER: &str = "dBjftJeZ4CVP-mJ0kBDGtu4Ulgp2tbZJ7CBnKeZK1A8";
+    const CHALLENGE: &str = "VVzqIBBNp197U5aJ-VuWtspLP_kACe-GEtekedOVskg";
+    const WRONG_VERIFIER: &str = "xxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxx";
+
+    async fn client(server: &TestServer, public: bool) -> server_oauth::OauthClient {
+        let scopes: Vec<String> = server_oauth::SCOPES.iter().map(|s| s.to_string()).collect();
+        server_oauth::create_client(
+            server.db(),
+            "test client",
+            &[REDIRECT_URI.to_string()],
+            &scopes,
+            public,
+        )
+        .await
+        .unwrap()
+        .0
+    }
+
+    async fn authorize(server: &TestServer, query: &[(&str, &str)]) -> (StatusCode, String) {
+        let app = actix_web::test::init_service(
+            actix_web::App::new()
+                .app_data(server.state.clone())
+                .service(http_get_oauth_authorize),
+        )
+        .await;
+        let query: String = url::form_urlencoded::Serializer::new(String::new())
+            .extend_pairs(query)
+            .finish();
+        let request = actix_web::test::TestRequest::get()
+            .uri(&format!("/oauth/authorize?{}", query))
+            .to_request();
+        let response = actix_web::test::call_service(&app, request).await;
+        let location = response
+            .headers()
+            .get(actix_web::http::header::LOCATION)
+            .and_then(|value| value.to_str().ok())
+            .unwrap_or_default()
+            .to_string();
+        (response.status(), location)
+    }
+
+    async fn token(server: &TestServer, form: &[(&str, &str)]) -> (StatusCode, serde_json::Value) {
+        let app = actix_web::test::init_service(
+            actix_web::App::new()
+                .app_data(server.state.clone())
+                .service(http_post_oauth_token),
+        )
+        .await;
+        let request = actix_web::test::TestRequest::post()
+            .uri("/oauth/token")
+            .set_form(form)
+            .to_request();
+        let response = actix_web::test::call_service(&app, request).await;
+        let status = response.status();
+        (status, actix_web::test::read_body_json(response).await)
+    }
+
+    async fn code(
+        server: &TestServer,
+        client: &server_oauth::OauthClient,
+        user_id: uuid::Uuid,
+        scope: &str,
+    ) -> String {
+        let mut redis_connection = server.state.redis_pool.get().await.unwrap();
+        server_oauth::store_code(
+            &mut redis_connection,
+            &AuthorizationCode {
+                client_id: client.client_id.clone(),
+                user_id,
+                redirect_uri: REDIRECT_URI.to_string(),
+                scope: scope.to_string(),
+                nonce: Some("nonce-1".to_string()),
+                code_challenge: client.is_public().then(|| CHALLENGE.to_string()),
+                auth_time: 1_700_000_000,
+            },
+            60,
+        )
+        .await
+        .unwrap()
+    }
+
+    #[test]
+    fn user_claims_follow_scopes() {
+        let claims = |scopes: &[&str]| {
+            let scopes: Vec<String> = scopes.iter().map(|s| s.to_string()).collect();
+            serde_json::to_value(user_claims(
+                &scopes,
+                "ada@example.com".to_string(),
+                "ada".to_string(),
+                None,
+            ))
+            .unwrap()
+        };
+        assert_eq!(claims(&["openid"]), serde_json::json!({}));
+        assert_eq!(
+            claims(&["openid", "email"]),
+            serde_json::json!({ "email": "ada@example.com", "email_verified": false })
+        );
+        assert_eq!(
+            claims(&["openid", "profile"]),
+            serde_json::json!({ "name": "ada", "preferred_username": "ada" })
+        );
+        assert_eq!(
+            claims(&["openid", "email", "profile"])
+                .as_object()
+                .unwrap()
+                .len(),
+            4
+        );
+    }
+
+    #[actix_web::test]
+    async fn authorize_only_redirects_to_registered_uris() {
+        let server = TestServer::start(CONFIG).await;
+        let client = client(&server, false).await;
+
+        let request = |redirect_uri: &'static str| {
+            [
+                ("response_type", "code"),
+                ("client_id", client.client_id.as_str()),
+                ("redirect_uri", redirect_uri),
+                ("scope", "openid"),
+                ("state", "state-1"),
+            ]
+        };
+        for redirect_uri in [
+            "https://app.example/callback/",
+            "https://app.example/callback?next=/",
+            "https://app.example/callback/../evil",
+            "https://app.example",
+            "https://APP.example/callback",
+            "http://app.example/callback",
+            "https://app.example.evil/callback",
+        ] {
+            let (status, location) = authorize(&server, &request(redirect_uri)).await;
+            assert_eq!(status, StatusCode::BAD_REQUEST, "{}", redirect_uri);
+            assert!(location.is_empty());
+        }
+        let (status, _) = authorize(&server, &[("client_id", "unknown")]).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+
+        // without a session & `oauth.login_url` the client hears `login_required`
+        let (status, location) = authorize(&server, &request(REDIRECT_URI)).await;
+        assert_eq!(status, StatusCode::FOUND);
+        assert!(location.starts_with(&format!("{}?error=login_required", REDIRECT_URI)));
+        assert!(location.ends_with("&state=state-1"));
+
+        server_oauth::delete_client(server.db(), &client.client_id)
+            .await
+            .unwrap();
+    }
+
+    #[actix_web::test]
+    async fn authorize_requires_s256_pkce_from_public_clients() {
+        let server = TestServer::start(CONFIG).await;
+        let public = client(&server, true).await;
+        let confidential = client(&server, false).await;
+
+        let error = |location: &str| {
+            url::Url::parse(location)
+                .unwrap()
+                .query_pairs()
+                .find(|(key, _)| key == "error")
+                .map(|(_, value)| value.into_owned())
+                .unwrap()
+        };
+        fn request<'a>(client_id: &'a str, pkce: &[(&'a str, &'a str)]) -> Vec<(&'a str, &'a str)> {
+            let mut request = vec![
+                ("response_type", "code"),
+                ("client_id", client_id),
+                ("redirect_uri", REDIRECT_URI),
+                ("scope", "openid"),
+            ];
+            request.extend_from_slice(pkce);
+            request
+        }
+        let public_id = public.client_id.as_str();
+        let confidential_id = confidential.client_id.as_str();
+
+        let (_, location) = authorize(&server, &request(public_id, &[])).await;
+        assert_eq!(error(&location), "invalid_request");
+        let plain = [
+            ("code_challenge", VERIFIER),
+            ("code_challenge_method", "plain"),
+        ];
+        let (_, location) = authorize(&server, &request(public_id, &plain)).await;
+        assert_eq!(error(&location), "invalid_request");
+        let (_, location) = authorize(&server, &request(confidential_id, &plain)).await;
+        assert_eq!(error(&location), "invalid_request");
+
+        // past the PKCE checks, stopped by the missing session
+        let s256 = [
+            ("code_challenge", CHALLENGE),
+            ("code_challenge_method", "S256"),
+        ];
+        let (_, location) = authorize(&server, &request(public_id, &s256)).await;
+        assert_eq!(error(&location), "login_required");
+        let (_, location) = authorize(&server, &request(confidential_id, &[])).await;
+        assert_eq!(error(&location), "login_required");
+
+        for client in [public, confidential] {
+            server_oauth::delete_client(server.db(), &client.client_id)
+                .await
+                .unwrap();
+        }
+    }
+
+    #[actix_web::test]
+    async fn token_needs_the_code_verifier_of_a_public_client() {
+        let server = TestServer::start(CONFIG).await;
+        let client = client(&server, true).await;
+        let email = server_testing::unique_email("oauth");
+        let user_id = server_testing::insert_user(server.db(), &email, None, true).await;
+        let redeem = |code: String, verifier: Option<&'static str>| {
+            let client_id = client.client_id.clone();
+            let server = &server;
+            async move {
+                let mut form = vec![
+                    ("grant_type", "authorization_code"),
+                    ("code", code.as_str()),
+                    ("client_id", client_id.as_str()),
+                    ("redirect_uri", REDIRECT_URI),
+                ];
+                if let Some(verifier) = verifier {
+                    form.push(("code_verifier", verifier));
+                }
+                token(server, &form).await
+            }
+        };
+
+        let (status, body) = redeem(code(&server, &client, user_id, "openid").await, None).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body["error"], "invalid_grant");
+
+        // a wrong verifier burns the code, the right one comes too late
+        let burnt = code(&server, &client, user_id, "openid").await;
+        let (status, body) = redeem(burnt.clone(), Some(WRONG_VERIFIER)).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body["error"], "invalid_grant");
+        let (status, _) = redeem(burnt, Some(VERIFIER)).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+
+        let (status, body) = redeem(
+            code(&server, &client, user_id, "email openid").await,
+            Some(VERIFIER),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        let signer = server.state.oauth_signer.as_ref().unwrap();
+        let id_token: serde_json::Value =
+            signer.verify(body["id_token"].as_str().unwrap()).unwrap();
+        assert_eq!(id_token["sub"], user_id.to_string());
+        assert_eq!(id_token["aud"], client.client_id);
+        assert_eq!(id_token["nonce"], "nonce-1");
+        assert_eq!(id_token["email"], email);
+        assert!(id_token.get("preferred_username").is_none());
+
+        server_testing::delete_user(server.db(), user_id).await;
+        server_oauth::delete_client(server.db(), &client.client_id)
+            .await
+            .unwrap();
+    }
+
+    #[actix_web::test]
+    async fn provider_errors_are_api_errors() {
+        let call = |server: &TestServer, uri: String| {
+            let state = server.state.clone();
+            async move {
+                let app = actix_web::test::init_service(
+                    actix_web::App::new()
+                        .app_data(state)
+                        .service(http_get_oauth_jwks)
+                        .service(http_get_oauth_authorize)
+                        .service(http_post_oauth_token),
+                )
+                .await;
+                let request = if uri == "/oauth/token" {
+                    actix_web::test::TestRequest::post()
+                        .uri(&uri)
+                        .set_form([("grant_type", "password")])
+                } else {
+                    actix_web::test::TestRequest::get().uri(&uri)
+                };
+                let response = actix_web::test::call_service(&app, request.to_request()).await;
+                let status = response.status();
+                let body: serde_json::Value = actix_web::test::read_body_json(response).await;
+                (status, body)
+            }
+        };
+
+        let disabled = TestServer::start("").await;
+        for uri in ["/oauth/jwks", "/oauth/authorize", "/oauth/token"] {
+            let (status, body) = call(&disabled, uri.to_string()).await;
+            assert_eq!(status, StatusCode::NOT_FOUND, "{}", uri);
+            assert_eq!(body["code"], "oidc_provider_disabled");
+        }
+
+        let server = TestServer::start(CONFIG).await;
+        let client = client(&server, false).await;
+        let (status, body) = call(&server, "/oauth/authorize".to_string()).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body["code"], "invalid_request");
+        let (status, body) = call(&server, "/oauth/authorize?client_id=unknown".to_string()).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body["code"], "unknown_client");
+        let (status, body) = call(
+            &server,
+            format!(
+                "/oauth/authorize?client_id={}&redirect_uri=https://evil.example",
+                client.client_id
+            ),
+        )
+        .await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body["code"], "redirect_uri_not_registered");
+
+        // the token endpoint keeps RFC 6749 5.2
+        let (status, body) = call(&server, "/oauth/token".to_string()).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body["error"], "unsupported_grant_type");
+        assert!(body.get("code").is_none());
+
+        server_oauth::delete_client(server.db(), &client.client_id)
+            .await
+            .unwrap();
+    }
+}
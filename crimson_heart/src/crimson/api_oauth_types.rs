@@ -0,0 +1,61 @@
+use serde::{Deserialize, Serialize};
+
+/// query of `/oauth/authorize`, checked by hand so errors can go back to the client
+#[derive(Serialize, Deserialize, Debug)]
+pub struct HTTPAuthorizeRequest {
+    pub response_type: Option<String>,
+    pub client_id: Option<String>,
+    pub redirect_uri: Option<String>,
+    pub scope: Option<String>,
+    pub state: Option<String>,
+    pub nonce: Option<String>,
+    pub code_challenge: Option<String>,
+    pub code_challenge_method: Option<String>,
+    /// only `none` is understood, it never sends the browser to the login page
+    pub prompt: Option<String>,
+}
+
+/// form of `/oauth/token`, client credentials may come as Basic auth instead
+#[derive(Serialize, Deserialize, Debug)]
+pub struct HTTPTokenRequest {
+    pub grant_type: Option<String>,
+    pub code: Option<String>,
+    pub redirect_uri: Option<String>,
+    pub client_id: Option<String>,
+    pub client_secret: Option<String>,
+    pub code_verifier: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct HTTPTokenResponse {
+    pub access_token: String,
+    pub token_type: String,
+    /// seconds the access token stays valid
+    pub expires_in: i64,
+    pub id_token: String,
+    pub scope: String,
+}
+
+/// RFC 6749 5.2 error body
+#[derive(Serialize, Deserialize, Debug)]
+pub struct HTTPOauthError {
+    pub error: String,
+    pub error_description: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct HTTPDiscovery {
+    pub issuer: String,
+    pub authorization_endpoint: String,
+    pub token_endpoint: String,
+    pub userinfo_endpoint: String,
+    pub jwks_uri: String,
+    pub response_types_supported: Vec<String>,
+    pub grant_types_supported: Vec<String>,
+    pub subject_types_supported: Vec<String>,
+    pub id_token_signing_alg_values_supported: Vec<String>,
+    pub scopes_supported: Vec<String>,
+    pub token_endpoint_auth_methods_supported: Vec<String>,
+    pub code_challenge_methods_supported: Vec<String>,
+    pub claims_supported: Vec<String>,
+}
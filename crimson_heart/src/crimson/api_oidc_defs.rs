@@ -0,0 +1,412 @@
+use super::api_oidc_types;
+use super::server_errors::ApiError;
+use super::server_oidc::{self, Identity};
+use super::server_sessions;
+use super::server_types::{self, SessionUserState};
+
+const MAX_USERNAME_LENGTH: usize = 64;
+
+fn provider_error(e: server_oidc::OidcError, provider: &str) -> ApiError {
+    tracing::error!(
+        error = %e,
+        component = "oidc",
+        provider = provider,
+        "function failed & returned error"
+    );
+    ApiError::bad_gateway("provider_unavailable", "Identity Provider Unavailable")
+}
+
+fn login_missing() -> ApiError {
+    ApiError::bad_request("login_missing", "Login expired or missing")
+}
+
+// the provider sends the browser back here, registered with the provider as is
+fn redirect_uri(
+    server_state: &server_types::ServerState,
+    provider: &str,
+) -> Result<String, ApiError> {
+    server_state
+        .public_url
+        .join(&format!("auth/oidc/{}/callback", provider))
+        .map(String::from)
+        .map_err(|e| ApiError::internal("url", "join", e))
+}
+
+/**
+ * # Brief
+ * HTTP GET request. Lists the configured identity providers.
+ */
+#[actix_web::get("/auth/oidc/providers")]
+async fn http_get_oidc_providers(
+    __server_state: actix_web::web::Data<server_types::ServerState>,
+) -> impl actix_web::Responder {
+    let providers: Vec<api_oidc_types::HTTPOidcProvider> = __server_state
+        .oidc
+        .providers()
+        .into_iter()
+        .map(|(name, display_name)| api_oidc_types::HTTPOidcProvider { name, display_name })
+        .collect();
+    actix_web::HttpResponse::Ok().json(providers)
+}
+
+/**
+ * # Brief
+ * HTTP GET request. Sends the browser to the provider's login page.
+ *
+ * # Detail
+ * - Keeps state, nonce & PKCE verifier in the session for `oidc.state_ttl` seconds.
+ * - Mints an anonymous session when there is none to hold them.
+ */
+#[actix_web::get("/auth/oidc/{provider}/login")]
+async fn http_get_oidc_login(
+    __request_metadata: actix_web::HttpRequest,
+    __request_path: actix_web::web::Path<String>,
+    __server_state: actix_web::web::Data<server_types::ServerState>,
+) -> Result<actix_web::HttpResponse, ApiError> {
+    let provider = __request_path.into_inner();
+    if __server_state.oidc.provider(&provider).is_none() {
+        return Err(ApiError::not_found(
+            "provider_not_found",
+            "Unknown identity provider",
+        ));
+    }
+    let redirect_uri = redirect_uri(&__server_state, &provider)?;
+    let pending = server_oidc::new_pending_login(&provider);
+    let authorization_url = __server_state
+        .oidc
+        .authorization_url(&pending, &redirect_uri)
+        .await
+        .map_err(|e| provider_error(e, &provider))?;
+
+    let mut redis_connection = __server_state.redis_pool.get().await?;
+    let state_ttl = __server_state.oidc.state_ttl();
+    let session_id = __request_metadata
+        .cookie("session_id")
+        .map(|c| c.value().to_string());
+    let stored = match &session_id {
+        Some(session_id) => server_sessions::set_session_oidc_login(
+            &mut redis_connection,
+            session_id,
+            &pending,
+            state_ttl,
+        )
+        .await
+        .map_err(|e| ApiError::redis(e, "set_session_oidc_login"))?,
+        None => false,
+    };
+
+    // no live session to hold the login, mint an anonymous one
+    let minted_session_id = if stored {
+        None
+    } else {
+        let mut session_fields = server_sessions::client_fields(&__request_metadata);
+        session_fields.push(("state", SessionUserState::Anonymous.as_u32().to_string()));
+        let session_id = server_sessions::rotate_session(
+            &mut redis_connection,
+            session_id.as_deref(),
+            &session_fields,
+            __server_state.redis_expire_time,
+        )
+        .await
+        .map_err(|e| ApiError::redis(e, "rotate_session"))?;
+        server_sessions::set_session_oidc_login(
+            &mut redis_connection,
+            &session_id,
+            &pending,
+            state_ttl,
+        )
+        .await
+        .map_err(|e| ApiError::redis(e, "set_session_oidc_login"))?;
+        Some(session_id)
+    };
+
+    let mut response = actix_web::HttpResponse::Found();
+    if let Some(session_id) = minted_session_id {
+        response.cookie(server_sessions::session_cookie(
+            session_id,
+            __server_state.redis_expire_time,
+        ));
+    }
+    Ok(response
+        .insert_header((actix_web::http::header::LOCATION, authorization_url))
+        .finish())
+}
+
+/**
+ * # Brief
+ * Finds the local User behind a provider identity, linking or creating one.
+ *
+ * # Detail
+ * - An identity seen before signs into the User it was linked to.
+ * - Otherwise the provider must vouch for the email: an existing User with that
+ *   email is linked, none creates a verified User without password.
+ * - Existing Users that never verified their email aren't linked, whoever
+ *   registered the address first may not own it.
+ */
+async fn resolve_user(
+    server_state: &server_types::ServerState,
+    provider: &str,
+    identity: &Identity,
+) -> Result<uuid::Uuid, ApiError> {
+    if let Some(user_id) = sqlx::query_scalar::<_, uuid::Uuid>(
+        r#"
+        UPDATE user_identities
+        SET last_login_at = now()
+        WHERE provider = $1 AND subject = $2
+        RETURNING user_id
+        "#,
+    )
+    .bind(provider)
+    .bind(&identity.subject)
+    .fetch_optional(&server_state.central_db_pool)
+    .await
+    .map_err(|e| ApiError::database(e, "UPDATE", "user_identities"))?
+    {
+        return Ok(user_id);
+    }
+
+    // stored case-folded, like on registration
+    let Some(email) = identity.verified_email() else {
+        tracing::info!(
+            component = "oidc",
+            provider = provider,
+            "login refused: provider did not vouch for an email"
+        );
+        return Err(ApiError::forbidden(
+            "email_unverified",
+            "Identity provider did not return a verified email",
+        ));
+    };
+    let email = &email;
+
+    let existing = sqlx::query_as::<_, (uuid::Uuid, Option<chrono::DateTime<chrono::Utc>>)>(
+        "SELECT user_id, email_verified_at FROM users WHERE email = $1",
+    )
+    .bind(email)
+    .fetch_optional(&server_state.central_db_pool)
+    .await
+    .map_err(|e| ApiError::database(e, "SELECT", "users"))?;
+
+    let mut transaction = server_state
+        .central_db_pool
+        .begin()
+        .await
+        .map_err(|e| ApiError::database(e, "BEGIN", "users"))?;
+    let user_id = match existing {
+        Some((user_id, Some(_))) => user_id,
+        Some((user_id, None)) => {
+            tracing::info!(
+                component = "oidc",
+                provider = provider,
+                user_id = %user_id,
+                "login refused: matching user never verified the email"
+            );
+            return Err(ApiError::conflict(
+                "email_taken",
+                "An unverified account uses this email, verify it before signing in with a provider",
+            ));
+        }
+        None => {
+            let username: String = identity
+                .username
+                .as_deref()
+                .filter(|username| !username.trim().is_empty())
+                .unwrap_or_else(|| email.split('@').next().unwrap_or(email))
+                .trim()
+                .chars()
+                .take(MAX_USERNAME_LENGTH)
+                .collect();
+            let user_id = uuid::Uuid::now_v7();
+            match sqlx::query(
+                r#"
+                INSERT INTO users (user_id, username, email, created_at, email_verified_at)
+                VALUES ($1, $2, $3, NOW(), NOW())
+                "#,
+            )
+            .bind(user_id)
+            .bind(&username)
+            .bind(email)
+            .execute(&mut *transaction)
+            .await
+            {
+                Ok(_) => user_id,
+                // registered concurrently, the next attempt links instead
+                Err(e)
+                    if e.as_database_error()
+                        .is_some_and(|e| e.is_unique_violation()) =>
+                {
+                    return Err(ApiError::conflict(
+                        "email_taken",
+                        format!("Email {} already registered", email),
+                    ));
+                }
+                Err(e) => return Err(ApiError::database(e, "INSERT INTO", "users")),
+            }
+        }
+    };
+
+    sqlx::query(
+        r#"
+        INSERT INTO user_identities (provider, subject, user_id, email, last_login_at)
+        VALUES ($1, $2, $3, $4, now())
+        "#,
+    )
+    .bind(provider)
+    .bind(&identity.subject)
+    .bind(user_id)
+    .bind(email)
+    .execute(&mut *transaction)
+    .await
+    .map_err(|e| ApiError::database(e, "INSERT INTO", "user_identities"))?;
+    transaction
+        .commit()
+        .await
+        .map_err(|e| ApiError::database(e, "COMMIT", "user_identities"))?;
+
+    tracing::info!(
+        component = "oidc",
+        provider = provider,
+        user_id = %user_id,
+        created = existing.is_none(),
+        "identity linked"
+    );
+    Ok(user_id)
+}
+
+/**
+ * # Brief
+ * HTTP GET request. The provider's redirect back, completes the login.
+ *
+ * # Detail
+ * - `state` must match the pending login of this session, which works once.
+ * - The code is redeemed with the PKCE verifier, the ID token checked against the provider keys.
+ * - Rotates the session & redirects to `oidc.login_redirect`.
+ * - With 2FA enabled the session still waits for a TOTP code, `GET /auth/session` tells.
+ */
+#[actix_web::get("/auth/oidc/{provider}/callback")]
+async fn http_get_oidc_callback(
+    __request_metadata: actix_web::HttpRequest,
+    __request_path: actix_web::web::Path<String>,
+    __request_query: actix_web::web::Query<api_oidc_types::HTTPOidcCallback>,
+    __server_state: actix_web::web::Data<server_types::ServerState>,
+) -> Result<actix_web::HttpResponse, ApiError> {
+    let provider = __request_path.into_inner();
+    let callback = __request_query.into_inner();
+    let Some(previous_session_id) = __request_metadata
+        .cookie("session_id")
+        .map(|c| c.value().to_string())
+    else {
+        return Err(login_missing());
+    };
+
+    let mut redis_connection = __server_state.redis_pool.get().await?;
+    let pending =
+        match server_sessions::take_session_oidc_login(&mut redis_connection, &previous_session_id)
+            .await
+            .map_err(|e| ApiError::redis(e, "take_session_oidc_login"))?
+        {
+            Some(pending)
+                if pending.provider == provider
+                    && callback.state.as_deref() == Some(pending.state.as_str()) =>
+            {
+                pending
+            }
+            _ => {
+                tracing::info!(
+                    component = "oidc",
+                    provider = %provider,
+                    "callback without a matching pending login"
+                );
+                return Err(login_missing());
+            }
+        };
+
+    let code = match (callback.code, callback.error) {
+        (Some(code), None) => code,
+        (_, error) => {
+            tracing::info!(
+                component = "oidc",
+                provider = %provider,
+                error = ?error,
+                error_description = ?callback.error_description,
+                "provider refused the login"
+            );
+            return Err(ApiError::unauthorized(
+                "provider_refused",
+                "Identity provider refused the login",
+            ));
+        }
+    };
+    let redirect_uri = redirect_uri(&__server_state, &provider)?;
+    let identity = match __server_state
+        .oidc
+        .finish_login(&pending, &code, &redirect_uri)
+        .await
+    {
+        Ok(identity) => identity,
+        Err(e @ server_oidc::OidcError::Invalid(_)) => {
+            tracing::warn!(
+                error = %e,
+                component = "oidc",
+                provider = %provider,
+                "id token rejected"
+            );
+            return Err(ApiError::unauthorized(
+                "invalid_id_token",
+                "Invalid ID token",
+            ));
+        }
+        Err(e) => return Err(provider_error(e, &provider)),
+    };
+
+    let user_id = resolve_user(&__server_state, &provider, &identity).await?;
+    let session_state = match sqlx::query_scalar::<_, Option<chrono::DateTime<chrono::Utc>>>(
+        "SELECT totp_enabled_at FROM users WHERE user_id = $1",
+    )
+    .bind(user_id)
+    .fetch_one(&__server_state.central_db_pool)
+    .await
+    .map_err(|e| ApiError::database(e, "SELECT", "users"))?
+    {
+        Some(_) => SessionUserState::TwoFactorPending,
+        None => SessionUserState::Registered,
+    };
+
+    let mut session_fields = server_sessions::client_fields(&__request_metadata);
+    session_fields.push(("state", session_state.as_u32().to_string()));
+    session_fields.push(("user_id", user_id.to_string()));
+    let session_id = server_sessions::rotate_session(
+        &mut redis_connection,
+        Some(&previous_session_id),
+        &session_fields,
+        __server_state.redis_expire_time,
+    )
+    .await
+    .map_err(|e| ApiError::redis(e, "rotate_session"))?;
+    server_sessions::track_user_session(
+        &mut redis_connection,
+        &user_id.to_string(),
+        &session_id,
+        __server_state.redis_expire_time,
+    )
+    .await
+    .map_err(|e| ApiError::redis(e, "track_user_session"))?;
+
+    tracing::info!(
+        component = "auth",
+        user_id = %user_id,
+        provider = %provider,
+        two_factor_pending = matches!(session_state, SessionUserState::TwoFactorPending),
+        "oidc login successful"
+    );
+    Ok(actix_web::HttpResponse::Found()
+        .cookie(server_sessions::session_cookie(
+            session_id,
+            __server_state.redis_expire_time,
+        ))
+        .insert_header((
+            actix_web::http::header::LOCATION,
+            __server_state.oidc.login_redirect(),
+        ))
+        .finish())
+}
@@ -0,0 +1,18 @@
+use serde::{Deserialize, Serialize};
+
+/// a provider offered on the login page
+#[derive(Serialize, Deserialize, Debug)]
+pub struct HTTPOidcProvider {
+    /// used in `/auth/oidc/<name>/login`
+    pub name: String,
+    pub display_name: String,
+}
+
+/// query of the provider's redirect back, `error` replaces `code` on refusal
+#[derive(Serialize, Deserialize, Debug)]
+pub struct HTTPOidcCallback {
+    pub code: Option<String>,
+    pub state: Option<String>,
+    pub error: Option<String>,
+    pub error_description: Option<String>,
+}
@@ -0,0 +1,526 @@
+use super::api_orgs_types;
+use super::server_errors::{ApiError, CODE_LOGIN_REQUIRED};
+use super::server_mailer::MailMessage;
+use super::server_orgs::{self, AcceptOutcome, MembershipChange, OrgRole};
+use super::server_sessions::{self, AuthenticatedUser};
+use super::server_types;
+use super::server_validation;
+
+const MAX_ORG_NAME_LENGTH: usize = 64;
+
+// outsiders can't tell an organization they're not in from one that doesn't exist
+async fn caller_role(
+    pool: &sqlx::PgPool,
+    org_id: uuid::Uuid,
+    user_id: uuid::Uuid,
+) -> Result<OrgRole, ApiError> {
+    server_orgs::member_role(pool, org_id, user_id)
+        .await
+        .map_err(|e| ApiError::database(e, "SELECT", "org_members"))?
+        .ok_or_else(|| ApiError::not_found("org_not_found", "Organization not found"))
+}
+
+fn require_manager(role: OrgRole) -> Result<(), ApiError> {
+    match role.manages_members() {
+        true => Ok(()),
+        false => Err(ApiError::forbidden(
+            "org_manager_required",
+            "Only owners & admins manage the organization",
+        )),
+    }
+}
+
+// admins may not touch owners, nor make anyone an owner
+fn require_assignable(caller: OrgRole, role: OrgRole) -> Result<(), ApiError> {
+    match caller.may_assign(role) {
+        true => Ok(()),
+        false => Err(ApiError::forbidden(
+            "org_owner_required",
+            "Only owners manage owners",
+        )),
+    }
+}
+
+fn member_not_found() -> ApiError {
+    ApiError::not_found("member_not_found", "Member not found")
+}
+
+fn membership_response(change: MembershipChange) -> Result<actix_web::HttpResponse, ApiError> {
+    match change {
+        MembershipChange::Done => Ok(actix_web::HttpResponse::NoContent().finish()),
+        MembershipChange::NotMember => Err(member_not_found()),
+        MembershipChange::LastOwner => Err(ApiError::conflict(
+            "last_owner",
+            "An organization keeps at least one owner",
+        )),
+    }
+}
+
+/**
+ * # Brief
+ * HTTP POST request. Creates an organization, the caller becomes its owner.
+ *
+ * # Detail
+ * - Names are 1 to 64 characters, they don't need to be unique.
+ */
+#[actix_web::post("/orgs")]
+async fn http_post_org(
+    __authenticated_user: AuthenticatedUser,
+    __request_payload: actix_web::web::Json<api_orgs_types::HTTPOrgCreate>,
+    __server_state: actix_web::web::Data<server_types::ServerState>,
+) -> Result<actix_web::HttpResponse, ApiError> {
+    let name = __request_payload.name.trim();
+    if name.is_empty() || name.chars().count() > MAX_ORG_NAME_LENGTH {
+        return Err(ApiError::invalid_field(
+            "name",
+            format!("must be 1 to {} characters", MAX_ORG_NAME_LENGTH),
+        ));
+    }
+
+    let org = server_orgs::create_org(
+        &__server_state.central_db_pool,
+        name,
+        __authenticated_user.user_id,
+    )
+    .await
+    .map_err(|e| ApiError::database(e, "INSERT INTO", "organizations"))?;
+
+    tracing::info!(
+        component = "orgs",
+        org_id = %org.org_id,
+        user_id = %__authenticated_user.user_id,
+        "organization created"
+    );
+    Ok(actix_web::HttpResponse::Created().json(org))
+}
+
+/**
+ * # Brief
+ * HTTP GET request. Lists the organizations the caller belongs to, with their role.
+ */
+#[actix_web::get("/orgs")]
+async fn http_get_orgs(
+    __authenticated_user: AuthenticatedUser,
+    __server_state: actix_web::web::Data<server_types::ServerState>,
+) -> Result<actix_web::HttpResponse, ApiError> {
+    let orgs = server_orgs::list_user_orgs(
+        &__server_state.central_db_pool,
+        __authenticated_user.user_id,
+    )
+    .await
+    .map_err(|e| ApiError::database(e, "SELECT", "org_members"))?;
+    Ok(actix_web::HttpResponse::Ok().json(orgs))
+}
+
+/**
+ * # Brief
+ * HTTP GET request. Lists the members of an organization.
+ *
+ * # Detail
+ * - Any member may list them, outsiders get NotFound.
+ */
+#[actix_web::get("/orgs/{org_id}/members")]
+async fn http_get_org_members(
+    __authenticated_user: AuthenticatedUser,
+    __request_path: actix_web::web::Path<uuid::Uuid>,
+    __server_state: actix_web::web::Data<server_types::ServerState>,
+) -> Result<actix_web::HttpResponse, ApiError> {
+    let org_id = __request_path.into_inner();
+    let pool = &__server_state.central_db_pool;
+    caller_role(pool, org_id, __authenticated_user.user_id).await?;
+
+    let members = server_orgs::list_members(pool, org_id)
+        .await
+        .map_err(|e| ApiError::database(e, "SELECT", "org_members"))?;
+    Ok(actix_web::HttpResponse::Ok().json(members))
+}
+
+/**
+ * # Brief
+ * HTTP PATCH request. Changes the role of a member.
+ *
+ * # Detail
+ * - Owners & admins manage members, only owners promote to or demote from owner.
+ * - The last owner can't be demoted, answers 409.
+ */
+#[actix_web::patch("/orgs/{org_id}/members/{user_id}")]
+async fn http_patch_org_member(
+    __authenticated_user: AuthenticatedUser,
+    __request_path: actix_web::web::Path<(uuid::Uuid, uuid::Uuid)>,
+    __request_payload: actix_web::web::Json<api_orgs_types::HTTPOrgMemberRole>,
+    __server_state: actix_web::web::Data<server_types::ServerState>,
+) -> Result<actix_web::HttpResponse, ApiError> {
+    let (org_id, user_id) = __request_path.into_inner();
+    let role = __request_payload.role;
+    let pool = &__server_state.central_db_pool;
+
+    let caller = caller_role(pool, org_id, __authenticated_user.user_id).await?;
+    require_manager(caller)?;
+    let current = server_orgs::member_role(pool, org_id, user_id)
+        .await
+        .map_err(|e| ApiError::database(e, "SELECT", "org_members"))?
+        .ok_or_else(member_not_found)?;
+    require_assignable(caller, current)?;
+    require_assignable(caller, role)?;
+
+    let change = server_orgs::set_member_role(pool, org_id, user_id, role)
+        .await
+        .map_err(|e| ApiError::database(e, "UPDATE", "org_members"))?;
+    tracing::info!(
+        component = "orgs",
+        org_id = %org_id,
+        user_id = %user_id,
+        role = role.as_str(),
+        changed_by = %__authenticated_user.user_id,
+        outcome = ?change,
+        "member role change"
+    );
+    membership_response(change)
+}
+
+/**
+ * # Brief
+ * HTTP DELETE request. Removes a member, or leaves when it's the caller.
+ *
+ * # Detail
+ * - Owners & admins remove others, only owners remove owners.
+ * - The last owner can't leave, answers 409.
+ * - Sessions of the removed member switched to the organization stop working.
+ */
+#[actix_web::delete("/orgs/{org_id}/members/{user_id}")]
+async fn http_delete_org_member(
+    __authenticated_user: AuthenticatedUser,
+    __request_path: actix_web::web::Path<(uuid::Uuid, uuid::Uuid)>,
+    __server_state: actix_web::web::Data<server_types::ServerState>,
+) -> Result<actix_web::HttpResponse, ApiError> {
+    let (org_id, user_id) = __request_path.into_inner();
+    let pool = &__server_state.central_db_pool;
+
+    let caller = caller_role(pool, org_id, __authenticated_user.user_id).await?;
+    if user_id != __authenticated_user.user_id {
+        require_manager(caller)?;
+        let current = server_orgs::member_role(pool, org_id, user_id)
+            .await
+            .map_err(|e| ApiError::database(e, "SELECT", "org_members"))?
+            .ok_or_else(member_not_found)?;
+        require_assignable(caller, current)?;
+    }
+
+    let change = server_orgs::remove_member(pool, org_id, user_id)
+        .await
+        .map_err(|e| ApiError::database(e, "DELETE FROM", "org_members"))?;
+    tracing::info!(
+        component = "orgs",
+        org_id = %org_id,
+        user_id = %user_id,
+        removed_by = %__authenticated_user.user_id,
+        outcome = ?change,
+        "member removal"
+    );
+    membership_response(change)
+}
+
+// mails the accept link, false when it couldn't be sent
+async fn send_invitation(
+    server_state: &server_types::ServerState,
+    org_id: uuid::Uuid,
+    email: &str,
+    role: OrgRole,
+    token: &str,
+) -> bool {
+    let org_name = match server_orgs::org_name(&server_state.central_db_pool, org_id).await {
+        Ok(name) => name.unwrap_or_default(),
+        Err(e) => {
+            tracing::error!(
+                error = %e,
+                component = "database",
+                query = "SELECT",
+                table = "organizations",
+                "function failed & returned error"
+            );
+            return false;
+        }
+    };
+    let mut accept_url = match server_state.public_url.join("orgs/invitations/accept") {
+        Ok(url) => url,
+        Err(e) => {
+            tracing::error!(
+                error = %e,
+                component = "generic",
+                function = "url_join",
+                "function failed & returned error"
+            );
+            return false;
+        }
+    };
+    accept_url.query_pairs_mut().append_pair("token", token);
+
+    let message = MailMessage {
+        to: email.to_string(),
+        subject: format!("You are invited to {} on Crimson", org_name),
+        body: format!(
+            "Hi,\n\nYou are invited to join {} as {}, sign in with this address & open the link, it expires in {} hours:\n\n    {}\n\nIf you did not expect this, ignore this email.",
+            org_name,
+            role.as_str(),
+            server_state.orgs.invitation_ttl / 3600,
+            accept_url
+        ),
+    };
+
+    let mailer = server_state.mailer.clone();
+    match actix_web::web::block(move || mailer.send(&message)).await {
+        Ok(Ok(())) => true,
+        Ok(Err(e)) => {
+            tracing::error!(
+                error = %e,
+                component = "mailer",
+                function = "send",
+                "function failed & returned error"
+            );
+            false
+        }
+        Err(e) => {
+            tracing::error!(
+                error = %e,
+                component = "mailer",
+                function = "web::block",
+                "function failed & returned error"
+            );
+            false
+        }
+    }
+}
+
+/**
+ * # Brief
+ * HTTP POST request. Invites an email address into the organization.
+ *
+ * # Detail
+ * - Owners & admins invite, only owners invite owners.
+ * - The link is mailed, it expires after `orgs.invitation_ttl` seconds.
+ * - Responds 201 with the invitation, the token is never returned.
+ */
+#[actix_web::post("/orgs/{org_id}/invitations")]
+async fn http_post_org_invitation(
+    __authenticated_user: AuthenticatedUser,
+    __request_path: actix_web::web::Path<uuid::Uuid>,
+    __request_payload: actix_web::web::Json<api_orgs_types::HTTPOrgInvite>,
+    __server_state: actix_web::web::Data<server_types::ServerState>,
+) -> Result<actix_web::HttpResponse, ApiError> {
+    let org_id = __request_path.into_inner();
+    let payload = __request_payload.into_inner();
+    let pool = &__server_state.central_db_pool;
+
+    let caller = caller_role(pool, org_id, __authenticated_user.user_id).await?;
+    require_manager(caller)?;
+    require_assignable(caller, payload.role)?;
+    let email = &server_validation::email(&payload.email)
+        .map_err(|message| ApiError::invalid_field("email", message))?;
+
+    let (invitation, token) = server_orgs::create_invitation(
+        pool,
+        org_id,
+        email,
+        payload.role,
+        __authenticated_user.user_id,
+        __server_state.orgs.invitation_ttl,
+    )
+    .await
+    .map_err(|e| ApiError::database(e, "INSERT INTO", "org_invitations"))?;
+    tracing::info!(
+        component = "orgs",
+        org_id = %org_id,
+        invitation_id = %invitation.invitation_id,
+        invited_by = %__authenticated_user.user_id,
+        "invitation created"
+    );
+    if !send_invitation(&__server_state, org_id, email, payload.role, &token).await {
+        tracing::warn!(
+            component = "orgs",
+            invitation_id = %invitation.invitation_id,
+            "invitation created without an email"
+        );
+    }
+
+    Ok(actix_web::HttpResponse::Created().json(invitation))
+}
+
+/**
+ * # Brief
+ * HTTP GET request. Lists the pending invitations of an organization.
+ *
+ * # Detail
+ * - Owners & admins only.
+ */
+#[actix_web::get("/orgs/{org_id}/invitations")]
+async fn http_get_org_invitations(
+    __authenticated_user: AuthenticatedUser,
+    __request_path: actix_web::web::Path<uuid::Uuid>,
+    __server_state: actix_web::web::Data<server_types::ServerState>,
+) -> Result<actix_web::HttpResponse, ApiError> {
+    let org_id = __request_path.into_inner();
+    let pool = &__server_state.central_db_pool;
+    require_manager(caller_role(pool, org_id, __authenticated_user.user_id).await?)?;
+
+    let invitations = server_orgs::list_invitations(pool, org_id)
+        .await
+        .map_err(|e| ApiError::database(e, "SELECT", "org_invitations"))?;
+    Ok(actix_web::HttpResponse::Ok().json(invitations))
+}
+
+/**
+ * # Brief
+ * HTTP DELETE request. Revokes a pending invitation.
+ *
+ * # Detail
+ * - Owners & admins only, accepted invitations are NotFound.
+ */
+#[actix_web::delete("/orgs/{org_id}/invitations/{invitation_id}")]
+async fn http_delete_org_invitation(
+    __authenticated_user: AuthenticatedUser,
+    __request_path: actix_web::web::Path<(uuid::Uuid, uuid::Uuid)>,
+    __server_state: actix_web::web::Data<server_types::ServerState>,
+) -> Result<actix_web::HttpResponse, ApiError> {
+    let (org_id, invitation_id) = __request_path.into_inner();
+    let pool = &__server_state.central_db_pool;
+    require_manager(caller_role(pool, org_id, __authenticated_user.user_id).await?)?;
+
+    let revoked = server_orgs::revoke_invitation(pool, org_id, invitation_id)
+        .await
+        .map_err(|e| ApiError::database(e, "DELETE FROM", "org_invitations"))?;
+    if !revoked {
+        return Err(ApiError::not_found(
+            "invitation_not_found",
+            "Invitation not found",
+        ));
+    }
+
+    tracing::info!(
+        component = "orgs",
+        org_id = %org_id,
+        invitation_id = %invitation_id,
+        revoked_by = %__authenticated_user.user_id,
+        "invitation revoked"
+    );
+    Ok(actix_web::HttpResponse::NoContent().finish())
+}
+
+/**
+ * # Brief
+ * HTTP GET request. Accepts an invitation, the link of the invitation email.
+ *
+ * # Detail
+ * - The caller must be signed in with the invited, verified, email address.
+ * - Tokens work once & expire, members keep their current role.
+ */
+#[actix_web::get("/orgs/invitations/accept")]
+async fn http_get_org_invitation_accept(
+    __authenticated_user: AuthenticatedUser,
+    __request_query: actix_web::web::Query<api_orgs_types::HTTPOrgInvitationAccept>,
+    __server_state: actix_web::web::Data<server_types::ServerState>,
+) -> Result<actix_web::HttpResponse, ApiError> {
+    let user_id = __authenticated_user.user_id;
+    let pool = &__server_state.central_db_pool;
+
+    let email = match sqlx::query_as::<_, (String, Option<chrono::DateTime<chrono::Utc>>)>(
+        "SELECT email, email_verified_at FROM users WHERE user_id = $1",
+    )
+    .bind(user_id)
+    .fetch_one(pool)
+    .await
+    .map_err(|e| ApiError::database(e, "SELECT", "users"))?
+    {
+        (email, Some(_)) => email,
+        (_, None) => {
+            return Err(ApiError::forbidden(
+                "email_verification_required",
+                "Verify your email address first",
+            ));
+        }
+    };
+
+    match server_orgs::accept_invitation(pool, &__request_query.token, user_id, &email)
+        .await
+        .map_err(|e| ApiError::database(e, "UPDATE", "org_invitations"))?
+    {
+        AcceptOutcome::Joined(org_id) => {
+            tracing::info!(
+                component = "orgs",
+                org_id = %org_id,
+                user_id = %user_id,
+                "invitation accepted"
+            );
+            Ok(actix_web::HttpResponse::Ok().json(api_orgs_types::HTTPOrgJoined { org_id }))
+        }
+        AcceptOutcome::Invalid => {
+            tracing::info!(component = "orgs", "invalid or expired invitation token");
+            Err(ApiError::bad_request(
+                "invalid_token",
+                "Invalid or expired token",
+            ))
+        }
+        AcceptOutcome::WrongEmail => {
+            tracing::info!(
+                component = "orgs",
+                user_id = %user_id,
+                "invitation presented by another address"
+            );
+            Err(ApiError::forbidden(
+                "wrong_email",
+                "Invitation was sent to another email address",
+            ))
+        }
+    }
+}
+
+/**
+ * # Brief
+ * HTTP POST request. Switches the session's workspace.
+ *
+ * # Detail
+ * - `org_id` must be an organization of the caller, null is the personal workspace.
+ * - Jobs, Workers & new API keys follow the workspace.
+ * - Responds with the session like `GET /auth/session`.
+ */
+#[actix_web::post("/auth/session/org")]
+async fn http_post_session_org(
+    __authenticated_user: AuthenticatedUser,
+    __request_payload: actix_web::web::Json<api_orgs_types::HTTPSessionOrg>,
+    __server_state: actix_web::web::Data<server_types::ServerState>,
+) -> Result<actix_web::HttpResponse, ApiError> {
+    let org_id = __request_payload.org_id;
+    if let Some(org_id) = org_id {
+        caller_role(
+            &__server_state.central_db_pool,
+            org_id,
+            __authenticated_user.user_id,
+        )
+        .await?;
+    }
+
+    let mut redis_connection = __server_state.redis_pool.get().await?;
+    let switched = server_sessions::set_session_org(
+        &mut redis_connection,
+        &__authenticated_user.session_id,
+        org_id,
+    )
+    .await
+    .map_err(|e| ApiError::redis(e, "set_session_org"))?;
+    // expired between the extractor & here
+    if !switched {
+        return Err(ApiError::unauthorized(
+            CODE_LOGIN_REQUIRED,
+            "Login Required",
+        ));
+    }
+
+    tracing::info!(
+        component = "session",
+        user_id = %__authenticated_user.user_id,
+        org_id = ?org_id,
+        "session switched workspace"
+    );
+    let mut session = __authenticated_user;
+    session.org_id = org_id;
+    Ok(actix_web::HttpResponse::Ok().json(session))
+}
@@ -0,0 +1,35 @@
+use super::server_orgs::OrgRole;
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct HTTPOrgCreate {
+    pub name: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct HTTPOrgMemberRole {
+    pub role: OrgRole,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct HTTPOrgInvite {
+    /// address the invitation link is mailed to, only its owner can accept
+    pub email: String,
+    pub role: OrgRole,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct HTTPOrgInvitationAccept {
+    pub token: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct HTTPOrgJoined {
+    pub org_id: uuid::Uuid,
+}
+
+/// body of `/auth/session/org`, null goes back to the personal workspace
+#[derive(Serialize, Deserialize, Debug)]
+pub struct HTTPSessionOrg {
+    pub org_id: Option<uuid::Uuid>,
+}
@@ -0,0 +1,595 @@
+use super::api_totp_types;
+use super::server_errors::{ApiError, CODE_LOGIN_REQUIRED};
+use super::server_sessions::{self, AuthenticatedUser};
+use super::server_totp;
+use super::server_types::{self, SessionUserState};
+
+use deadpool_redis::redis::AsyncCommands;
+
+#[derive(sqlx::FromRow)]
+struct UserTotp {
+    email: String,
+    totp_secret: Option<String>,
+    totp_enabled_at: Option<chrono::DateTime<chrono::Utc>>,
+    totp_last_step: Option<i64>,
+    email_verified_at: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+fn already_enabled() -> ApiError {
+    ApiError::conflict(
+        "totp_already_enabled",
+        "Two-Factor Authentication already enabled",
+    )
+}
+
+fn invalid_code() -> ApiError {
+    ApiError::unauthorized("invalid_code", "Invalid code")
+}
+
+// the pending session is missing, expired or not waiting for a second factor
+fn login_required() -> ApiError {
+    ApiError::unauthorized(CODE_LOGIN_REQUIRED, "Login Required")
+}
+
+async fn load_user_totp(
+    server_state: &server_types::ServerState,
+    user_id: &uuid::Uuid,
+) -> Result<Option<UserTotp>, ApiError> {
+    sqlx::query_as::<_, UserTotp>(
+        r#"
+        SELECT email, totp_secret, totp_enabled_at, totp_last_step, email_verified_at
+        FROM users
+        WHERE user_id = $1
+        "#,
+    )
+    .bind(user_id)
+    .fetch_optional(&server_state.central_db_pool)
+    .await
+    .map_err(|e| ApiError::database(e, "SELECT", "users"))
+}
+
+/**
+ * # Brief
+ * Checks a second factor, consuming it on success.
+ *
+ * # Detail
+ * - A code moves `totp_last_step` forward, the same code can't be replayed.
+ * - A recovery code is marked used, each one works once.
+ * - Returns Ok(false) for wrong, reused or missing codes.
+ */
+async fn consume_second_factor(
+    server_state: &server_types::ServerState,
+    user_id: &uuid::Uuid,
+    user_totp: &UserTotp,
+    second_factor: &api_totp_types::HTTPTotpCode,
+) -> Result<bool, ApiError> {
+    if let Some(code) = &second_factor.code {
+        let Some(totp_secret) = &user_totp.totp_secret else {
+            return Ok(false);
+        };
+        let secret = open_secret(server_state, user_id, totp_secret)?;
+        let Some(step) = server_totp::verify(
+            &secret,
+            code,
+            chrono::Utc::now().timestamp(),
+            user_totp.totp_last_step,
+        ) else {
+            return Ok(false);
+        };
+
+        // guarded, two requests racing with the same code can't both pass
+        return sqlx::query(
+            r#"
+            UPDATE users
+            SET totp_last_step = $2
+            WHERE user_id = $1 AND (totp_last_step IS NULL OR totp_last_step < $2)
+            "#,
+        )
+        .bind(user_id)
+        .bind(step)
+        .execute(&server_state.central_db_pool)
+        .await
+        .map(|result| result.rows_affected() == 1)
+        .map_err(|e| ApiError::database(e, "UPDATE", "users"));
+    }
+
+    if let Some(recovery_code) = &second_factor.recovery_code {
+        return sqlx::query(
+            r#"
+            UPDATE user_recovery_codes
+            SET used_at = now()
+            WHERE user_id = $1 AND code_hash = $2 AND used_at IS NULL
+            "#,
+        )
+        .bind(user_id)
+        .bind(server_totp::hash_recovery_code(recovery_code))
+        .execute(&server_state.central_db_pool)
+        .await
+        .map(|result| result.rows_affected() == 1)
+        .map_err(|e| ApiError::database(e, "UPDATE", "user_recovery_codes"));
+    }
+
+    Ok(false)
+}
+
+fn open_secret(
+    server_state: &server_types::ServerState,
+    user_id: &uuid::Uuid,
+    totp_secret: &str,
+) -> Result<Vec<u8>, ApiError> {
+    let Some(totp_key) = &server_state.totp_key else {
+        tracing::error!(
+            component = "totp",
+            user_id = %user_id,
+            "user has a totp secret but security.totp_key is not configured"
+        );
+        return Err(ApiError::internal(
+            "totp",
+            "open",
+            "security.totp_key is not configured",
+        ));
+    };
+    server_totp::open(totp_key, user_id, totp_secret)
+        .map_err(|e| ApiError::internal("totp", "open", e))
+}
+
+/**
+ * # Brief
+ * HTTP POST request. Starts TOTP enrollment for the logged in User.
+ *
+ * # Detail
+ * - Responds with the base32 secret & `otpauth://` URI for the authenticator app.
+ * - 2FA stays off until `/auth/totp/confirm` receives a valid code.
+ * - Enrolling again replaces an unconfirmed secret, 409 once 2FA is enabled.
+ * - 403 when `security.totp_key` isn't configured.
+ */
+#[actix_web::post("/auth/totp/enroll")]
+async fn http_post_totp_enroll(
+    __authenticated_user: AuthenticatedUser,
+    __server_state: actix_web::web::Data<server_types::ServerState>,
+) -> Result<actix_web::HttpResponse, ApiError> {
+    let Some(totp_key) = &__server_state.totp_key else {
+        return Err(ApiError::forbidden(
+            "totp_disabled",
+            "Two-Factor Authentication disabled",
+        ));
+    };
+    let user_id = __authenticated_user.user_id;
+
+    let Some(user_totp) = load_user_totp(&__server_state, &user_id).await? else {
+        return Err(ApiError::not_found(
+            "user_not_registered",
+            "User not registered",
+        ));
+    };
+    if user_totp.totp_enabled_at.is_some() {
+        return Err(already_enabled());
+    }
+
+    let secret = server_totp::new_secret();
+    let sealed = server_totp::seal(totp_key, &user_id, &secret)
+        .map_err(|e| ApiError::internal("totp", "seal", e))?;
+
+    let result = sqlx::query(
+        r#"
+        UPDATE users
+        SET totp_secret = $2, totp_last_step = NULL
+        WHERE user_id = $1 AND totp_enabled_at IS NULL
+        "#,
+    )
+    .bind(user_id)
+    .bind(sealed)
+    .execute(&__server_state.central_db_pool)
+    .await
+    .map_err(|e| ApiError::database(e, "UPDATE", "users"))?;
+    // confirmed by a concurrent request
+    if result.rows_affected() != 1 {
+        return Err(already_enabled());
+    }
+
+    tracing::info!(component = "totp", user_id = %user_id, "totp enrollment started");
+    Ok(
+        actix_web::HttpResponse::Ok().json(api_totp_types::HTTPTotpEnrollment {
+            secret: server_totp::encode_secret(&secret),
+            otpauth_uri: server_totp::otpauth_uri(
+                &__server_state.totp_issuer,
+                &user_totp.email,
+                &secret,
+            ),
+        }),
+    )
+}
+
+/**
+ * # Brief
+ * HTTP POST request. Confirms TOTP enrollment with a code from the app.
+ *
+ * # Detail
+ * - Enables 2FA & responds with fresh recovery codes, shown only this once.
+ * - Only the SHA-256 of each recovery code is stored.
+ * - 401 for a wrong code, 409 without a pending enrollment.
+ */
+#[actix_web::post("/auth/totp/confirm")]
+async fn http_post_totp_confirm(
+    __authenticated_user: AuthenticatedUser,
+    __request_payload: actix_web::web::Json<api_totp_types::HTTPTotpConfirm>,
+    __server_state: actix_web::web::Data<server_types::ServerState>,
+) -> Result<actix_web::HttpResponse, ApiError> {
+    let user_id = __authenticated_user.user_id;
+
+    let Some(user_totp) = load_user_totp(&__server_state, &user_id).await? else {
+        return Err(ApiError::not_found(
+            "user_not_registered",
+            "User not registered",
+        ));
+    };
+    let totp_secret = match (&user_totp.totp_secret, user_totp.totp_enabled_at) {
+        (Some(totp_secret), None) => totp_secret,
+        (_, Some(_)) => return Err(already_enabled()),
+        (None, None) => {
+            return Err(ApiError::conflict(
+                "totp_not_pending",
+                "No Two-Factor enrollment pending",
+            ));
+        }
+    };
+
+    let secret = open_secret(&__server_state, &user_id, totp_secret)?;
+    let Some(step) = server_totp::verify(
+        &secret,
+        &__request_payload.code,
+        chrono::Utc::now().timestamp(),
+        user_totp.totp_last_step,
+    ) else {
+        tracing::info!(component = "totp", user_id = %user_id, "totp confirmation failed");
+        return Err(invalid_code());
+    };
+
+    let recovery_codes = server_totp::new_recovery_codes();
+    let enabled: Result<bool, sqlx::Error> = async {
+        let mut transaction = __server_state.central_db_pool.begin().await?;
+        let enabled = sqlx::query(
+            r#"
+            UPDATE users
+            SET totp_enabled_at = now(), totp_last_step = $2
+            WHERE user_id = $1 AND totp_enabled_at IS NULL
+            "#,
+        )
+        .bind(user_id)
+        .bind(step)
+        .execute(&mut *transaction)
+        .await?;
+        if enabled.rows_affected() != 1 {
+            return Ok(false);
+        }
+
+        sqlx::query("DELETE FROM user_recovery_codes WHERE user_id = $1")
+            .bind(user_id)
+            .execute(&mut *transaction)
+            .await?;
+        for recovery_code in &recovery_codes {
+            sqlx::query("INSERT INTO user_recovery_codes (user_id, code_hash) VALUES ($1, $2)")
+                .bind(user_id)
+                .bind(server_totp::hash_recovery_code(recovery_code))
+                .execute(&mut *transaction)
+                .await?;
+        }
+        transaction.commit().await?;
+        Ok(true)
+    }
+    .await;
+
+    if !enabled.map_err(|e| ApiError::database(e, "UPDATE", "users"))? {
+        return Err(already_enabled());
+    }
+
+    tracing::info!(component = "totp", user_id = %user_id, "totp enabled");
+    Ok(
+        actix_web::HttpResponse::Ok()
+            .json(api_totp_types::HTTPTotpRecoveryCodes { recovery_codes }),
+    )
+}
+
+/**
+ * # Brief
+ * HTTP POST request. Second login step for Users with 2FA enabled.
+ *
+ * # Detail
+ * - Requires the `TwoFactorPending` session issued by `/auth/login`.
+ * - Accepts a current code or an unused recovery code.
+ * - Rotates the session on success, like a single factor login.
+ * - Failures count towards the email's login lockout, 429 while locked.
+ */
+#[actix_web::post("/auth/login/totp")]
+async fn http_post_user_login_totp(
+    __request_metadata: actix_web::HttpRequest,
+    __request_payload: actix_web::web::Json<api_totp_types::HTTPTotpCode>,
+    __server_state: actix_web::web::Data<server_types::ServerState>,
+) -> Result<actix_web::HttpResponse, ApiError> {
+    let mut redis_connection = __server_state.redis_pool.get().await?;
+
+    let Some(pending_session_id) = __request_metadata
+        .cookie("session_id")
+        .map(|c| c.value().to_string())
+    else {
+        return Err(login_required());
+    };
+    let session: std::collections::HashMap<String, String> = redis_connection
+        .hgetall(server_sessions::session_key(&pending_session_id))
+        .await
+        .map_err(|e| ApiError::redis(e, "hgetall"))?;
+    let state = session
+        .get("state")
+        .and_then(|state| state.parse().ok())
+        .and_then(SessionUserState::from_u32);
+    let user_id = session
+        .get("user_id")
+        .and_then(|user_id| uuid::Uuid::parse_str(user_id).ok());
+    let user_id = match (state, user_id) {
+        (Some(SessionUserState::TwoFactorPending), Some(user_id)) => user_id,
+        _ => return Err(login_required()),
+    };
+
+    let Some(user_totp) = load_user_totp(&__server_state, &user_id).await? else {
+        return Err(login_required());
+    };
+    if let Some(retry_after) = __server_state
+        .rate_limiter
+        .login_lockout(&user_totp.email)
+        .await
+    {
+        return Err(ApiError::TooManyRequests(retry_after));
+    }
+
+    if !consume_second_factor(&__server_state, &user_id, &user_totp, &__request_payload).await? {
+        tracing::info!(
+            component = "auth",
+            user_id = %user_id,
+            "login failed: invalid second factor"
+        );
+        if let Some(lockout) = __server_state
+            .rate_limiter
+            .record_login_failure(&user_totp.email)
+            .await
+        {
+            return Err(ApiError::TooManyRequests(lockout));
+        }
+        return Err(invalid_code());
+    }
+    __server_state
+        .rate_limiter
+        .clear_login_failures(&user_totp.email)
+        .await;
+
+    let session_state = match user_totp.email_verified_at {
+        Some(_) => SessionUserState::Registered,
+        None => SessionUserState::PendingVerification,
+    };
+    let mut session_fields = server_sessions::client_fields(&__request_metadata);
+    session_fields.push(("state", session_state.as_u32().to_string()));
+    session_fields.push(("user_id", user_id.to_string()));
+    let session_id = server_sessions::rotate_session(
+        &mut redis_connection,
+        Some(&pending_session_id),
+        &session_fields,
+        __server_state.redis_expire_time,
+    )
+    .await
+    .map_err(|e| ApiError::redis(e, "rotate_session"))?;
+
+    server_sessions::track_user_session(
+        &mut redis_connection,
+        &user_id.to_string(),
+        &session_id,
+        __server_state.redis_expire_time,
+    )
+    .await
+    .map_err(|e| ApiError::redis(e, "track_user_session"))?;
+
+    tracing::info!(component = "auth", user_id = %user_id, "second factor verified");
+    Ok(actix_web::HttpResponse::Ok()
+        .cookie(server_sessions::session_cookie(
+            session_id,
+            __server_state.redis_expire_time,
+        ))
+        .body("successful\n"))
+}
+
+/**
+ * # Brief
+ * HTTP POST request. Disables 2FA for the logged in User.
+ *
+ * # Detail
+ * - Requires a current code or an unused recovery code.
+ * - Drops the secret & every recovery code.
+ */
+#[actix_web::post("/auth/totp/disable")]
+async fn http_post_totp_disable(
+    __authenticated_user: AuthenticatedUser,
+    __request_payload: actix_web::web::Json<api_totp_types::HTTPTotpCode>,
+    __server_state: actix_web::web::Data<server_types::ServerState>,
+) -> Result<actix_web::HttpResponse, ApiError> {
+    let user_id = __authenticated_user.user_id;
+
+    let Some(user_totp) = load_user_totp(&__server_state, &user_id).await? else {
+        return Err(ApiError::not_found(
+            "user_not_registered",
+            "User not registered",
+        ));
+    };
+    if user_totp.totp_enabled_at.is_none() {
+        return Err(ApiError::conflict(
+            "totp_not_enabled",
+            "Two-Factor Authentication not enabled",
+        ));
+    }
+
+    if !consume_second_factor(&__server_state, &user_id, &user_totp, &__request_payload).await? {
+        return Err(invalid_code());
+    }
+
+    let disabled: Result<(), sqlx::Error> = async {
+        let mut transaction = __server_state.central_db_pool.begin().await?;
+        sqlx::query(
+            r#"
+            UPDATE users
+            SET totp_secret = NULL, totp_enabled_at = NULL, totp_last_step = NULL
+            WHERE user_id = $1
+            "#,
+        )
+        .bind(user_id)
+        .execute(&mut *transaction)
+        .await?;
+        sqlx::query("DELETE FROM user_recovery_codes WHERE user_id = $1")
+            .bind(user_id)
+            .execute(&mut *transaction)
+            .await?;
+        transaction.commit().await
+    }
+    .await;
+
+    disabled.map_err(|e| ApiError::database(e, "UPDATE", "users"))?;
+
+    tracing::info!(component = "totp", user_id = %user_id, "totp disabled");
+    Ok(actix_web::HttpResponse::Ok().body("Two-Factor Authentication disabled\n"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crimson::server_testing::{self, TestServer};
+
+    const CONFIG: &str = r#"
+        [security]
+        totp_key = "4242424242424242424242424242424242424242424242424242424242424242"
+    "#;
+
+    fn second_factor(
+        code: Option<&str>,
+        recovery_code: Option<&str>,
+    ) -> api_totp_types::HTTPTotpCode {
+        api_totp_types::HTTPTotpCode {
+            code: code.map(str::to_string),
+            recovery_code: recovery_code.map(str::to_string),
+        }
+    }
+
+    // 2FA enabled with `secret` & `recovery_codes`, as `/auth/totp/confirm` leaves it
+    async fn enrolled_user(
+        server: &TestServer,
+        secret: &[u8],
+        recovery_codes: &[String],
+    ) -> uuid::Uuid {
+        let pool = server.db();
+        let user_id =
+            server_testing::insert_user(pool, &server_testing::unique_email("totp"), None, true)
+                .await;
+        let sealed =
+            server_totp::seal(server.state.totp_key.as_ref().unwrap(), &user_id, secret).unwrap();
+        sqlx::query(
+            "UPDATE users SET totp_secret = $2, totp_enabled_at = now() WHERE user_id = $1",
+        )
+        .bind(user_id)
+        .bind(sealed)
+        .execute(pool)
+        .await
+        .unwrap();
+        for recovery_code in recovery_codes {
+            sqlx::query("INSERT INTO user_recovery_codes (user_id, code_hash) VALUES ($1, $2)")
+                .bind(user_id)
+                .bind(server_totp::hash_recovery_code(recovery_code))
+                .execute(pool)
+                .await
+                .unwrap();
+        }
+        user_id
+    }
+
+    async fn consume(
+        server: &TestServer,
+        user_id: uuid::Uuid,
+        second_factor: &api_totp_types::HTTPTotpCode,
+    ) -> bool {
+        // read again every time, like each login does
+        let user_totp = load_user_totp(&server.state, &user_id)
+            .await
+            .unwrap()
+            .unwrap();
+        consume_second_factor(&server.state, &user_id, &user_totp, second_factor)
+            .await
+            .unwrap()
+    }
+
+    #[actix_web::test]
+    async fn recovery_codes_work_once() {
+        let server = TestServer::start(CONFIG).await;
+        let recovery_codes = server_totp::new_recovery_codes();
+        let user_id = enrolled_user(&server, &server_totp::new_secret(), &recovery_codes).await;
+
+        // retyped by hand, dashes & case don't matter
+        let retyped = recovery_codes[0].replace('-', "").to_uppercase();
+        assert!(consume(&server, user_id, &second_factor(None, Some(&retyped))).await);
+        assert!(
+            !consume(
+                &server,
+                user_id,
+                &second_factor(None, Some(&recovery_codes[0]))
+            )
+            .await
+        );
+        // the others are untouched
+        assert!(
+            consume(
+                &server,
+                user_id,
+                &second_factor(None, Some(&recovery_codes[1]))
+            )
+            .await
+        );
+        assert!(
+            !consume(
+                &server,
+                user_id,
+                &second_factor(None, Some("abcd-efgh-ijkl-mnop"))
+            )
+            .await
+        );
+
+        // another user's codes don't work here
+        let other = enrolled_user(&server, &server_totp::new_secret(), &recovery_codes[5..]).await;
+        assert!(
+            !consume(
+                &server,
+                other,
+                &second_factor(None, Some(&recovery_codes[2]))
+            )
+            .await
+        );
+        assert!(
+            consume(
+                &server,
+                user_id,
+                &second_factor(None, Some(&recovery_codes[2]))
+            )
+            .await
+        );
+
+        server_testing::delete_user(server.db(), user_id).await;
+        server_testing::delete_user(server.db(), other).await;
+    }
+
+    #[actix_web::test]
+    async fn totp_codes_are_not_replayed() {
+        let server = TestServer::start(CONFIG).await;
+        let secret = server_totp::new_secret();
+        let user_id = enrolled_user(&server, &secret, &[]).await;
+
+        let code = server_totp::code(&secret, chrono::Utc::now().timestamp());
+
+        assert!(consume(&server, user_id, &second_factor(Some(&code), None)).await);
+        assert!(!consume(&server, user_id, &second_factor(Some(&code), None)).await);
+        assert!(!consume(&server, user_id, &second_factor(None, None)).await);
+
+        server_testing::delete_user(server.db(), user_id).await;
+    }
+}
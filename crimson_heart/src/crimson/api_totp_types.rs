@@ -0,0 +1,25 @@
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct HTTPTotpEnrollment {
+    /// base32, for manual entry
+    pub secret: String,
+    pub otpauth_uri: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct HTTPTotpConfirm {
+    pub code: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct HTTPTotpRecoveryCodes {
+    pub recovery_codes: Vec<String>,
+}
+
+/// either a current code from the app or an unused recovery code
+#[derive(Serialize, Deserialize, Debug)]
+pub struct HTTPTotpCode {
+    pub code: Option<String>,
+    pub recovery_code: Option<String>,
+}
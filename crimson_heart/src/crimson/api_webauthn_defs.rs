@@ -0,0 +1,507 @@
+use super::api_webauthn_types::{self, Credential};
+use super::server_errors::{ApiError, CODE_LOGIN_REQUIRED};
+use super::server_jws::base64url_encode;
+use super::server_sessions::{self, AuthenticatedUser};
+use super::server_types::{self, SessionUserState};
+use super::server_webauthn;
+
+const REGISTRATION: &str = "registration";
+const AUTHENTICATION: &str = "authentication";
+const PUBLIC_KEY: &str = "public-key";
+const MAX_CREDENTIAL_NAME_LENGTH: usize = 64;
+
+fn challenge_missing() -> ApiError {
+    ApiError::bad_request("challenge_missing", "Challenge expired or missing")
+}
+
+fn invalid_credential() -> ApiError {
+    ApiError::unauthorized("invalid_credential", "Invalid credential")
+}
+
+/**
+ * # Brief
+ * HTTP POST request. Starts registering a passkey for the logged in User.
+ *
+ * # Detail
+ * - Responds with `PublicKeyCredentialCreationOptions` as JSON.
+ * - The challenge is kept in the session for `webauthn.challenge_ttl` seconds.
+ * - Passkeys the User already has are excluded, an authenticator holds one.
+ */
+#[actix_web::post("/auth/webauthn/register/begin")]
+async fn http_post_webauthn_register_begin(
+    __authenticated_user: AuthenticatedUser,
+    __server_state: actix_web::web::Data<server_types::ServerState>,
+) -> Result<actix_web::HttpResponse, ApiError> {
+    let user_id = __authenticated_user.user_id;
+
+    let (username, email) = match sqlx::query_as::<_, (String, String)>(
+        "SELECT username, email FROM users WHERE user_id = $1",
+    )
+    .bind(user_id)
+    .fetch_optional(&__server_state.central_db_pool)
+    .await
+    .map_err(|e| ApiError::database(e, "SELECT", "users"))?
+    {
+        Some(user) => user,
+        None => {
+            return Err(ApiError::not_found(
+                "user_not_registered",
+                "User not registered",
+            ));
+        }
+    };
+    let credential_ids = sqlx::query_scalar::<_, String>(
+        "SELECT credential_id FROM user_credentials WHERE user_id = $1",
+    )
+    .bind(user_id)
+    .fetch_all(&__server_state.central_db_pool)
+    .await
+    .map_err(|e| ApiError::database(e, "SELECT", "user_credentials"))?;
+
+    let mut redis_connection = __server_state.redis_pool.get().await?;
+    let challenge = server_webauthn::new_challenge();
+    let stored = server_sessions::set_session_challenge(
+        &mut redis_connection,
+        &__authenticated_user.session_id,
+        REGISTRATION,
+        &challenge,
+        __server_state.webauthn.challenge_ttl,
+    )
+    .await
+    .map_err(|e| ApiError::redis(e, "set_session_challenge"))?;
+    // expired between the extractor & here
+    if !stored {
+        return Err(ApiError::unauthorized(
+            CODE_LOGIN_REQUIRED,
+            "Login Required",
+        ));
+    }
+
+    Ok(
+        actix_web::HttpResponse::Ok().json(api_webauthn_types::HTTPCreationOptions {
+            challenge,
+            rp: api_webauthn_types::HTTPRelyingParty {
+                id: __server_state.webauthn.rp_id.clone(),
+                name: __server_state.webauthn.rp_name.clone(),
+            },
+            user: api_webauthn_types::HTTPUserEntity {
+                id: base64url_encode(user_id.as_bytes()),
+                name: email,
+                display_name: username,
+            },
+            pub_key_cred_params: vec![api_webauthn_types::HTTPCredentialParameter {
+                credential_type: PUBLIC_KEY.to_string(),
+                alg: server_webauthn::ES256,
+            }],
+            timeout: __server_state.webauthn.challenge_ttl * 1000,
+            attestation: "none".to_string(),
+            exclude_credentials: credential_ids
+                .into_iter()
+                .map(|id| api_webauthn_types::HTTPCredentialDescriptor {
+                    credential_type: PUBLIC_KEY.to_string(),
+                    id,
+                })
+                .collect(),
+            authenticator_selection: api_webauthn_types::HTTPAuthenticatorSelection {
+                resident_key: "required".to_string(),
+                user_verification: "preferred".to_string(),
+            },
+        }),
+    )
+}
+
+/**
+ * # Brief
+ * HTTP POST request. Finishes registering a passkey.
+ *
+ * # Detail
+ * - Verifies the attestation response against the session's challenge.
+ * - The challenge is consumed whatever the outcome, retrying needs a new one.
+ * - 400 for an invalid response, 409 when the credential is already registered.
+ */
+#[actix_web::post("/auth/webauthn/register/finish")]
+async fn http_post_webauthn_register_finish(
+    __authenticated_user: AuthenticatedUser,
+    __request_payload: actix_web::web::Json<api_webauthn_types::HTTPRegistrationCredential>,
+    __server_state: actix_web::web::Data<server_types::ServerState>,
+) -> Result<actix_web::HttpResponse, ApiError> {
+    let user_id = __authenticated_user.user_id;
+
+    let mut redis_connection = __server_state.redis_pool.get().await?;
+    let challenge = server_sessions::take_session_challenge(
+        &mut redis_connection,
+        &__authenticated_user.session_id,
+        REGISTRATION,
+    )
+    .await
+    .map_err(|e| ApiError::redis(e, "take_session_challenge"))?
+    .ok_or_else(challenge_missing)?;
+
+    let response = &__request_payload.response;
+    let credential = (|| {
+        server_webauthn::verify_registration(
+            &__server_state.webauthn,
+            &challenge,
+            &server_webauthn::base64url_decode(&response.client_data_json)?,
+            &server_webauthn::base64url_decode(&response.attestation_object)?,
+        )
+    })();
+    let credential = match credential {
+        Ok(credential) => credential,
+        Err(e) => {
+            tracing::info!(
+                error = %e,
+                component = "webauthn",
+                user_id = %user_id,
+                "passkey registration rejected"
+            );
+            return Err(ApiError::bad_request(
+                "invalid_credential",
+                "Invalid credential",
+            ));
+        }
+    };
+    let credential_id = base64url_encode(&credential.credential_id);
+    if credential_id != __request_payload.id.trim_end_matches('=') {
+        return Err(ApiError::bad_request(
+            "invalid_credential",
+            "Invalid credential",
+        ));
+    }
+
+    let name = __request_payload
+        .name
+        .as_deref()
+        .map(str::trim)
+        .filter(|name| !name.is_empty())
+        .unwrap_or("passkey")
+        .chars()
+        .take(MAX_CREDENTIAL_NAME_LENGTH)
+        .collect::<String>();
+
+    let credential = sqlx::query_as::<_, Credential>(
+        r#"
+        INSERT INTO user_credentials (credential_id, user_id, public_key, name)
+        VALUES ($1, $2, $3, $4)
+        ON CONFLICT (credential_id) DO NOTHING
+        RETURNING credential_id, name, created_at, last_used_at
+        "#,
+    )
+    .bind(&credential_id)
+    .bind(user_id)
+    .bind(hex::encode(&credential.public_key))
+    .bind(&name)
+    .fetch_optional(&__server_state.central_db_pool)
+    .await
+    .map_err(|e| ApiError::database(e, "INSERT INTO", "user_credentials"))?;
+    let Some(credential) = credential else {
+        return Err(ApiError::conflict(
+            "credential_taken",
+            "Credential already registered",
+        ));
+    };
+
+    tracing::info!(
+        component = "webauthn",
+        user_id = %user_id,
+        credential_id = %credential.credential_id,
+        "passkey registered"
+    );
+    Ok(actix_web::HttpResponse::Created().json(credential))
+}
+
+/**
+ * # Brief
+ * HTTP POST request. Starts a passkey login.
+ *
+ * # Detail
+ * - Responds with `PublicKeyCredentialRequestOptions` as JSON.
+ * - The challenge goes into the caller's session, a fresh anonymous one is
+ *   issued when there is none.
+ */
+#[actix_web::post("/auth/webauthn/login/begin")]
+async fn http_post_webauthn_login_begin(
+    __request_metadata: actix_web::HttpRequest,
+    __server_state: actix_web::web::Data<server_types::ServerState>,
+) -> Result<actix_web::HttpResponse, ApiError> {
+    let mut redis_connection = __server_state.redis_pool.get().await?;
+    let challenge = server_webauthn::new_challenge();
+    let challenge_ttl = __server_state.webauthn.challenge_ttl;
+
+    let session_id = __request_metadata
+        .cookie("session_id")
+        .map(|c| c.value().to_string());
+    let stored = match &session_id {
+        Some(session_id) => server_sessions::set_session_challenge(
+            &mut redis_connection,
+            session_id,
+            AUTHENTICATION,
+            &challenge,
+            challenge_ttl,
+        )
+        .await
+        .map_err(|e| ApiError::redis(e, "set_session_challenge"))?,
+        None => false,
+    };
+
+    // no live session to hold the challenge, mint an anonymous one
+    let minted_session_id = if stored {
+        None
+    } else {
+        let mut session_fields = server_sessions::client_fields(&__request_metadata);
+        session_fields.push(("state", SessionUserState::Anonymous.as_u32().to_string()));
+        let session_id = server_sessions::rotate_session(
+            &mut redis_connection,
+            session_id.as_deref(),
+            &session_fields,
+            __server_state.redis_expire_time,
+        )
+        .await
+        .map_err(|e| ApiError::redis(e, "rotate_session"))?;
+        server_sessions::set_session_challenge(
+            &mut redis_connection,
+            &session_id,
+            AUTHENTICATION,
+            &challenge,
+            challenge_ttl,
+        )
+        .await
+        .map_err(|e| ApiError::redis(e, "set_session_challenge"))?;
+        Some(session_id)
+    };
+
+    let mut response = actix_web::HttpResponse::Ok();
+    if let Some(session_id) = minted_session_id {
+        response.cookie(server_sessions::session_cookie(
+            session_id,
+            __server_state.redis_expire_time,
+        ));
+    }
+    Ok(response.json(api_webauthn_types::HTTPRequestOptions {
+        challenge,
+        rp_id: __server_state.webauthn.rp_id.clone(),
+        timeout: challenge_ttl * 1000,
+        user_verification: "preferred".to_string(),
+        allow_credentials: Vec::new(),
+    }))
+}
+
+/**
+ * # Brief
+ * HTTP POST request. Finishes a passkey login.
+ *
+ * # Detail
+ * - Verifies the assertion signature with the stored public key.
+ * - A sign counter that doesn't move forward is refused, it hints at a cloned authenticator.
+ * - Rotates the session on success, like a password login.
+ * - With 2FA enabled, an assertion without user verification still waits for a TOTP code.
+ */
+#[actix_web::post("/auth/webauthn/login/finish")]
+async fn http_post_webauthn_login_finish(
+    __request_metadata: actix_web::HttpRequest,
+    __request_payload: actix_web::web::Json<api_webauthn_types::HTTPAssertionCredential>,
+    __server_state: actix_web::web::Data<server_types::ServerState>,
+) -> Result<actix_web::HttpResponse, ApiError> {
+    let Some(previous_session_id) = __request_metadata
+        .cookie("session_id")
+        .map(|c| c.value().to_string())
+    else {
+        return Err(challenge_missing());
+    };
+
+    let mut redis_connection = __server_state.redis_pool.get().await?;
+    let challenge = server_sessions::take_session_challenge(
+        &mut redis_connection,
+        &previous_session_id,
+        AUTHENTICATION,
+    )
+    .await
+    .map_err(|e| ApiError::redis(e, "take_session_challenge"))?
+    .ok_or_else(challenge_missing)?;
+
+    let credential_id = __request_payload.id.trim_end_matches('=');
+    let stored = sqlx::query_as::<_, (uuid::Uuid, String, i64)>(
+        "SELECT user_id, public_key, sign_count FROM user_credentials WHERE credential_id = $1",
+    )
+    .bind(credential_id)
+    .fetch_optional(&__server_state.central_db_pool)
+    .await
+    .map_err(|e| ApiError::database(e, "SELECT", "user_credentials"))?;
+    let Some((user_id, public_key, sign_count)) = stored else {
+        tracing::info!(component = "webauthn", "login with unknown passkey");
+        return Err(invalid_credential());
+    };
+
+    let response = &__request_payload.response;
+    let verified = (|| {
+        if let Some(user_handle) = &response.user_handle
+            && server_webauthn::base64url_decode(user_handle)? != user_id.as_bytes()
+        {
+            return Err(server_webauthn::WebauthnError::Invalid(
+                "user handle mismatch",
+            ));
+        }
+        let public_key = hex::decode(&public_key)
+            .map_err(|_| server_webauthn::WebauthnError::Invalid("stored public key is not hex"))?;
+        server_webauthn::verify_assertion(
+            &__server_state.webauthn,
+            &challenge,
+            &public_key,
+            &server_webauthn::base64url_decode(&response.client_data_json)?,
+            &server_webauthn::base64url_decode(&response.authenticator_data)?,
+            &server_webauthn::base64url_decode(&response.signature)?,
+        )
+    })();
+    let authenticator_data = match verified {
+        Ok(authenticator_data) => authenticator_data,
+        Err(e) => {
+            tracing::info!(
+                error = %e,
+                component = "webauthn",
+                user_id = %user_id,
+                "passkey login rejected"
+            );
+            return Err(invalid_credential());
+        }
+    };
+
+    let new_sign_count = authenticator_data.sign_count as i64;
+    if server_webauthn::sign_count_regressed(sign_count, new_sign_count) {
+        tracing::warn!(
+            component = "webauthn",
+            user_id = %user_id,
+            credential_id = %credential_id,
+            stored = sign_count,
+            presented = new_sign_count,
+            "passkey sign counter went backwards, possible cloned authenticator"
+        );
+        return Err(invalid_credential());
+    }
+    // guarded, a concurrent login with the same counter loses
+    let result = sqlx::query(
+        r#"
+        UPDATE user_credentials
+        SET sign_count = $2, last_used_at = now()
+        WHERE credential_id = $1 AND sign_count = $3
+        "#,
+    )
+    .bind(credential_id)
+    .bind(new_sign_count)
+    .bind(sign_count)
+    .execute(&__server_state.central_db_pool)
+    .await
+    .map_err(|e| ApiError::database(e, "UPDATE", "user_credentials"))?;
+    if result.rows_affected() != 1 {
+        return Err(invalid_credential());
+    }
+
+    let user = sqlx::query_as::<
+        _,
+        (
+            Option<chrono::DateTime<chrono::Utc>>,
+            Option<chrono::DateTime<chrono::Utc>>,
+        ),
+    >("SELECT email_verified_at, totp_enabled_at FROM users WHERE user_id = $1")
+    .bind(user_id)
+    .fetch_one(&__server_state.central_db_pool)
+    .await
+    .map_err(|e| ApiError::database(e, "SELECT", "users"))?;
+    let session_state = match user {
+        (_, Some(_)) if !authenticator_data.user_verified() => SessionUserState::TwoFactorPending,
+        (Some(_), _) => SessionUserState::Registered,
+        (None, _) => SessionUserState::PendingVerification,
+    };
+
+    let mut session_fields = server_sessions::client_fields(&__request_metadata);
+    session_fields.push(("state", session_state.as_u32().to_string()));
+    session_fields.push(("user_id", user_id.to_string()));
+    let session_id = server_sessions::rotate_session(
+        &mut redis_connection,
+        Some(&previous_session_id),
+        &session_fields,
+        __server_state.redis_expire_time,
+    )
+    .await
+    .map_err(|e| ApiError::redis(e, "rotate_session"))?;
+    server_sessions::track_user_session(
+        &mut redis_connection,
+        &user_id.to_string(),
+        &session_id,
+        __server_state.redis_expire_time,
+    )
+    .await
+    .map_err(|e| ApiError::redis(e, "track_user_session"))?;
+
+    tracing::info!(
+        component = "auth",
+        user_id = %user_id,
+        credential_id = %credential_id,
+        "passkey login successful"
+    );
+    let cookie = server_sessions::session_cookie(session_id, __server_state.redis_expire_time);
+    if let SessionUserState::TwoFactorPending = session_state {
+        return Ok(actix_web::HttpResponse::Accepted()
+            .cookie(cookie)
+            .body("Two-Factor Code Required\n"));
+    }
+    Ok(actix_web::HttpResponse::Ok()
+        .cookie(cookie)
+        .body("successful\n"))
+}
+
+/**
+ * # Brief
+ * HTTP GET request. Lists the logged in User's passkeys.
+ */
+#[actix_web::get("/auth/webauthn/credentials")]
+async fn http_get_webauthn_credentials(
+    __authenticated_user: AuthenticatedUser,
+    __server_state: actix_web::web::Data<server_types::ServerState>,
+) -> Result<actix_web::HttpResponse, ApiError> {
+    let credentials = sqlx::query_as::<_, Credential>(
+        r#"
+        SELECT credential_id, name, created_at, last_used_at
+        FROM user_credentials
+        WHERE user_id = $1
+        ORDER BY created_at
+        "#,
+    )
+    .bind(__authenticated_user.user_id)
+    .fetch_all(&__server_state.central_db_pool)
+    .await
+    .map_err(|e| ApiError::database(e, "SELECT", "user_credentials"))?;
+    Ok(actix_web::HttpResponse::Ok().json(credentials))
+}
+
+/**
+ * # Brief
+ * HTTP DELETE request. Removes one of the logged in User's passkeys.
+ */
+#[actix_web::delete("/auth/webauthn/credentials/{credential_id}")]
+async fn http_delete_webauthn_credential(
+    __authenticated_user: AuthenticatedUser,
+    __request_path: actix_web::web::Path<String>,
+    __server_state: actix_web::web::Data<server_types::ServerState>,
+) -> Result<actix_web::HttpResponse, ApiError> {
+    let result =
+        sqlx::query("DELETE FROM user_credentials WHERE credential_id = $1 AND user_id = $2")
+            .bind(__request_path.as_str())
+            .bind(__authenticated_user.user_id)
+            .execute(&__server_state.central_db_pool)
+            .await
+            .map_err(|e| ApiError::database(e, "DELETE", "user_credentials"))?;
+    if result.rows_affected() != 1 {
+        return Err(ApiError::not_found(
+            "credential_not_found",
+            "Credential not found",
+        ));
+    }
+
+    tracing::info!(
+        component = "webauthn",
+        user_id = %__authenticated_user.user_id,
+        credential_id = %__request_path.as_str(),
+        "passkey removed"
+    );
+    Ok(actix_web::HttpResponse::NoContent().finish())
+}
@@ -0,0 +1,110 @@
+use serde::{Deserialize, Serialize};
+
+/*
+ * Shapes follow the WebAuthn JSON encoding, binary fields are base64url, so
+ * the options can be handed to `PublicKeyCredential.parseCreationOptionsFromJSON`
+ * and `credential.toJSON()` posted back as is.
+ */
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct HTTPRelyingParty {
+    pub id: String,
+    pub name: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct HTTPUserEntity {
+    /// base64url of the user id bytes, returned as `userHandle` on login
+    pub id: String,
+    pub name: String,
+    pub display_name: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct HTTPCredentialParameter {
+    #[serde(rename = "type")]
+    pub credential_type: String,
+    pub alg: i64,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct HTTPCredentialDescriptor {
+    #[serde(rename = "type")]
+    pub credential_type: String,
+    pub id: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct HTTPAuthenticatorSelection {
+    pub resident_key: String,
+    pub user_verification: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct HTTPCreationOptions {
+    pub challenge: String,
+    pub rp: HTTPRelyingParty,
+    pub user: HTTPUserEntity,
+    pub pub_key_cred_params: Vec<HTTPCredentialParameter>,
+    /// milliseconds
+    pub timeout: i64,
+    pub attestation: String,
+    pub exclude_credentials: Vec<HTTPCredentialDescriptor>,
+    pub authenticator_selection: HTTPAuthenticatorSelection,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct HTTPRequestOptions {
+    pub challenge: String,
+    pub rp_id: String,
+    /// milliseconds
+    pub timeout: i64,
+    pub user_verification: String,
+    /// empty, passkeys are discoverable & name the user themselves
+    pub allow_credentials: Vec<HTTPCredentialDescriptor>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct HTTPAttestationResponse {
+    #[serde(rename = "clientDataJSON")]
+    pub client_data_json: String,
+    pub attestation_object: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct HTTPRegistrationCredential {
+    pub id: String,
+    pub response: HTTPAttestationResponse,
+    /// label shown in the credential list
+    pub name: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct HTTPAssertionResponse {
+    #[serde(rename = "clientDataJSON")]
+    pub client_data_json: String,
+    pub authenticator_data: String,
+    pub signature: String,
+    pub user_handle: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct HTTPAssertionCredential {
+    pub id: String,
+    pub response: HTTPAssertionResponse,
+}
+
+/// a registered passkey as shown to its owner, the key itself is never exposed
+#[derive(Serialize, Debug, sqlx::FromRow)]
+pub struct Credential {
+    pub credential_id: String,
+    pub name: String,
+    pub created_at: chrono::DateTime<chrono::Utc>,
+    pub last_used_at: Option<chrono::DateTime<chrono::Utc>>,
+}
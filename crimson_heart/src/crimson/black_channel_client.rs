@@ -0,0 +1,556 @@
+use super::black_channel_protocol::{DispatchJob, Frame, FrameCodec, ProtocolError};
+
+use futures_util::{SinkExt, StreamExt};
+
+type FramedStream = tokio_util::codec::Framed<tokio::net::TcpStream, FrameCodec>;
+
+#[derive(Debug, Clone)]
+pub struct ClientOptions {
+    /// sent in the handshake, identifies this crimson instance to nodes
+    pub client_id: String,
+    pub connections_per_node: usize,
+    pub connect_timeout: std::time::Duration,
+    pub heartbeat_interval: std::time::Duration,
+    pub reconnect_attempts: u32,
+    pub reconnect_backoff: std::time::Duration,
+}
+
+impl Default for ClientOptions {
+    fn default() -> Self {
+        ClientOptions {
+            client_id: format!("crimson-{}", uuid::Uuid::now_v7()),
+            connections_per_node: 2,
+            connect_timeout: std::time::Duration::from_secs(5),
+            heartbeat_interval: std::time::Duration::from_secs(10),
+            reconnect_attempts: 3,
+            reconnect_backoff: std::time::Duration::from_millis(200),
+        }
+    }
+}
+
+/// a frame received from a node, results & heartbeats arrive unsolicited
+#[derive(Debug, Clone)]
+pub struct NodeEvent {
+    pub address: String,
+    pub node_id: String,
+    pub frame: Frame,
+}
+
+#[derive(Debug)]
+pub enum ClientError {
+    Connect(std::io::Error),
+    Timeout,
+    Protocol(ProtocolError),
+    Handshake(String),
+}
+
+impl std::fmt::Display for ClientError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ClientError::Connect(e) => write!(f, "connect failed ({})", e),
+            ClientError::Timeout => write!(f, "timed out"),
+            ClientError::Protocol(e) => write!(f, "protocol error ({})", e),
+            ClientError::Handshake(reason) => write!(f, "handshake failed ({})", reason),
+        }
+    }
+}
+
+impl std::error::Error for ClientError {}
+
+impl From<ProtocolError> for ClientError {
+    fn from(e: ProtocolError) -> Self {
+        ClientError::Protocol(e)
+    }
+}
+
+struct NodeConnection {
+    outbound: tokio::sync::mpsc::Sender<Frame>,
+}
+
+#[derive(Default)]
+struct NodePool {
+    connections: Vec<NodeConnection>,
+    next: usize,
+}
+
+/**
+ * # Brief
+ * Async client for black_channel nodes.
+ *
+ * # Detail
+ * - Keeps up to `connections_per_node` connections per node address, used round robin.
+ * - Closed connections are pruned and re-established on the next send.
+ * - Every inbound frame is forwarded to the receiver returned by `new`.
+ */
+#[derive(Clone)]
+pub struct BlackChannelClient {
+    options: std::sync::Arc<ClientOptions>,
+    pools: std::sync::Arc<tokio::sync::Mutex<std::collections::HashMap<String, NodePool>>>,
+    events: tokio::sync::mpsc::UnboundedSender<NodeEvent>,
+}
+
+impl BlackChannelClient {
+    pub fn new(options: ClientOptions) -> (Self, tokio::sync::mpsc::UnboundedReceiver<NodeEvent>) {
+        let (events, events_rx) = tokio::sync::mpsc::unbounded_channel();
+        let client = BlackChannelClient {
+            options: std::sync::Arc::new(options),
+            pools: Default::default(),
+            events,
+        };
+        (client, events_rx)
+    }
+
+    pub async fn dispatch(&self, address: &str, job: DispatchJob) -> Result<(), ClientError> {
+        self.send(address, Frame::DispatchJob(job)).await
+    }
+
+    pub async fn cancel(&self, address: &str, job_id: uuid::Uuid) -> Result<(), ClientError> {
+        self.send(address, Frame::Cancel { job_id }).await
+    }
+
+    /**
+     * # Brief
+     * Sends a frame to the node at `address`.
+     *
+     * # Detail
+     * - Frames about the same job always use the same connection, so they stay ordered.
+     * - A send on a connection that died underneath us is retried once on a fresh one.
+     */
+    pub async fn send(&self, address: &str, frame: Frame) -> Result<(), ClientError> {
+        let affinity = match &frame {
+            Frame::DispatchJob(job) => Some(job.job_id.as_u128() as usize),
+            Frame::Cancel { job_id } => Some(job_id.as_u128() as usize),
+            _ => None,
+        };
+
+        let outbound = self.connection(address, affinity).await?;
+        let frame = match outbound.send(frame).await {
+            Ok(()) => return Ok(()),
+            Err(tokio::sync::mpsc::error::SendError(frame)) => frame,
+        };
+
+        tracing::debug!(
+            component = "black_channel",
+            address = %address,
+            "connection closed while sending, reconnecting"
+        );
+        let outbound = self.connection(address, affinity).await?;
+        outbound
+            .send(frame)
+            .await
+            .map_err(|_| ClientError::Connect(std::io::ErrorKind::BrokenPipe.into()))
+    }
+
+    // fills the pool before picking, so affinity maps onto a stable set
+    async fn connection(
+        &self,
+        address: &str,
+        affinity: Option<usize>,
+    ) -> Result<tokio::sync::mpsc::Sender<Frame>, ClientError> {
+        loop {
+            {
+                let mut pools = self.pools.lock().await;
+                let pool = pools.entry(address.to_string()).or_default();
+                pool.connections.retain(|c| !c.outbound.is_closed());
+
+                if pool.connections.len() >= self.options.connections_per_node.max(1) {
+                    let index = match affinity {
+                        Some(affinity) => affinity % pool.connections.len(),
+                        None => {
+                            pool.next = (pool.next + 1) % pool.connections.len();
+                            pool.next
+                        }
+                    };
+                    return Ok(pool.connections[index].outbound.clone());
+                }
+            }
+
+            // connect without holding the lock, other nodes stay reachable meanwhile
+            let connection = self.connect(address).await?;
+            self.pools
+                .lock()
+                .await
+                .entry(address.to_string())
+                .or_default()
+                .connections
+                .push(connection);
+        }
+    }
+
+    async fn connect(&self, address: &str) -> Result<NodeConnection, ClientError> {
+        let mut last_error = ClientError::Timeout;
+
+        for attempt in 0..self.options.reconnect_attempts.max(1) {
+            if attempt > 0 {
+                let backoff = self.options.reconnect_backoff * 2u32.pow(attempt - 1);
+                tokio::time::sleep(backoff).await;
+            }
+
+            match self.connect_once(address).await {
+                Ok(connection) => return Ok(connection),
+                Err(e) => {
+                    tracing::warn!(
+                        error = %e,
+                        component = "black_channel",
+                        address = %address,
+                        attempt = attempt + 1,
+                        "failed to connect to node"
+                    );
+                    last_error = e;
+                }
+            }
+        }
+
+        Err(last_error)
+    }
+
+    async fn connect_once(&self, address: &str) -> Result<NodeConnection, ClientError> {
+        let stream = tokio::time::timeout(
+            self.options.connect_timeout,
+            tokio::net::TcpStream::connect(address),
+        )
+        .await
+        .map_err(|_| ClientError::Timeout)?
+        .map_err(ClientError::Connect)?;
+        stream.set_nodelay(true).map_err(ClientError::Connect)?;
+
+        let mut framed = tokio_util::codec::Framed::new(stream, FrameCodec);
+        framed
+            .send(Frame::Handshake {
+                client_id: self.options.client_id.clone(),
+            })
+            .await?;
+
+        let reply = tokio::time::timeout(self.options.connect_timeout, framed.next())
+            .await
+            .map_err(|_| ClientError::Timeout)?;
+        let (node_id, capacity) = match reply {
+            Some(Ok(Frame::HandshakeAck { node_id, capacity })) => (node_id, capacity),
+            Some(Ok(frame)) => {
+                return Err(ClientError::Handshake(format!(
+                    "expected HandshakeAck, got {:?}",
+                    frame.frame_type()
+                )));
+            }
+            Some(Err(e)) => return Err(e.into()),
+            None => return Err(ClientError::Handshake("connection closed".to_string())),
+        };
+
+        tracing::info!(
+            component = "black_channel",
+            address = %address,
+            node_id = %node_id,
+            capacity = capacity,
+            "connected to node"
+        );
+
+        let (outbound, outbound_rx) = tokio::sync::mpsc::channel(64);
+        tokio::spawn(run_connection(
+            address.to_string(),
+            node_id,
+            framed,
+            outbound_rx,
+            self.events.clone(),
+            self.options.heartbeat_interval,
+        ));
+
+        Ok(NodeConnection { outbound })
+    }
+}
+
+/**
+ * # Brief
+ * Drives a single node connection until either side closes it.
+ *
+ * # Detail
+ * - Writes queued outbound frames, forwards inbound frames as `NodeEvent`s.
+ * - Sends a heartbeat every interval, gives up after three silent intervals.
+ */
+async fn run_connection(
+    address: String,
+    node_id: String,
+    framed: FramedStream,
+    mut outbound_rx: tokio::sync::mpsc::Receiver<Frame>,
+    events: tokio::sync::mpsc::UnboundedSender<NodeEvent>,
+    heartbeat_interval: std::time::Duration,
+) {
+    let (mut sink, mut stream) = framed.split();
+    let mut ticker = tokio::time::interval(heartbeat_interval);
+    let mut last_seen = tokio::time::Instant::now();
+
+    loop {
+        tokio::select! {
+            outbound = outbound_rx.recv() => {
+                let Some(frame) = outbound else { break };
+                if let Err(e) = sink.send(frame).await {
+                    tracing::warn!(
+                        error = %e,
+                        component = "black_channel",
+                        node_id = %node_id,
+                        "failed to write frame"
+                    );
+                    break;
+                }
+            }
+            inbound = stream.next() => {
+                match inbound {
+                    Some(Ok(frame)) => {
+                        last_seen = tokio::time::Instant::now();
+                        let _ = events.send(NodeEvent {
+                            address: address.clone(),
+                            node_id: node_id.clone(),
+                            frame,
+                        });
+                    }
+                    Some(Err(e)) => {
+                        tracing::warn!(
+                            error = %e,
+                            component = "black_channel",
+                            node_id = %node_id,
+                            "failed to read frame"
+                        );
+                        break;
+                    }
+                    None => break,
+                }
+            }
+            _ = ticker.tick() => {
+                if last_seen.elapsed() > heartbeat_interval * 3 {
+                    tracing::warn!(
+                        component = "black_channel",
+                        node_id = %node_id,
+                        "node went silent, closing connection"
+                    );
+                    break;
+                }
+                let heartbeat = Frame::Heartbeat {
+                    timestamp: chrono::Utc::now().timestamp() as u64,
+                    running_jobs: 0,
+                };
+                if sink.send(heartbeat).await.is_err() {
+                    break;
+                }
+            }
+        }
+    }
+
+    tracing::info!(
+        component = "black_channel",
+        address = %address,
+        node_id = %node_id,
+        "node connection closed"
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crimson::black_channel_mock::{MockNodeOptions, run_mock_node};
+    use crate::crimson::black_channel_protocol::{JobOutcome, JobResult};
+
+    const EVENT_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(5);
+
+    fn options() -> ClientOptions {
+        ClientOptions {
+            client_id: "crimson-test".to_string(),
+            connections_per_node: 1,
+            connect_timeout: std::time::Duration::from_secs(2),
+            reconnect_attempts: 1,
+            ..ClientOptions::default()
+        }
+    }
+
+    // mock node on an ephemeral port, returns its address
+    async fn mock_node(capacity: u32, job_duration: std::time::Duration) -> String {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        tokio::spawn(run_mock_node(
+            listener,
+            MockNodeOptions {
+                node_id: "mock-1".to_string(),
+                capacity,
+                job_duration,
+            },
+        ));
+        address
+    }
+
+    fn job(command: &str) -> DispatchJob {
+        DispatchJob {
+            job_id: uuid::Uuid::now_v7(),
+            required_slots: 1,
+            command: command.to_string(),
+        }
+    }
+
+    // next result of `job_id`, heartbeats & other jobs are skipped
+    async fn next_result(
+        events: &mut tokio::sync::mpsc::UnboundedReceiver<NodeEvent>,
+        job_id: uuid::Uuid,
+    ) -> (NodeEvent, JobResult) {
+        tokio::time::timeout(EVENT_TIMEOUT, async {
+            loop {
+                let event = events.recv().await.expect("event channel closed");
+                if let Frame::Result(result) = &event.frame
+                    && result.job_id == job_id
+                {
+                    let result = result.clone();
+                    return (event, result);
+                }
+            }
+        })
+        .await
+        .expect("no result from the node")
+    }
+
+    #[tokio::test]
+    async fn handshake_dispatch_and_result() {
+        let address = mock_node(4, std::time::Duration::from_millis(20)).await;
+        let (client, mut events) = BlackChannelClient::new(options());
+
+        let succeeding = job("echo hello");
+        client.dispatch(&address, succeeding.clone()).await.unwrap();
+        let (event, result) = next_result(&mut events, succeeding.job_id).await;
+        // the node id comes from the handshake ack
+        assert_eq!(event.node_id, "mock-1");
+        assert_eq!(event.address, address);
+        assert_eq!(result.outcome, JobOutcome::Started);
+        let (_, result) = next_result(&mut events, succeeding.job_id).await;
+        assert_eq!(result.outcome, JobOutcome::Succeeded);
+        assert_eq!(result.exit_code, 0);
+
+        let failing = job("false");
+        client.dispatch(&address, failing.clone()).await.unwrap();
+        assert_eq!(
+            next_result(&mut events, failing.job_id).await.1.outcome,
+            JobOutcome::Started
+        );
+        let (_, result) = next_result(&mut events, failing.job_id).await;
+        assert_eq!(result.outcome, JobOutcome::Failed);
+        assert_eq!(result.exit_code, 1);
+    }
+
+    #[tokio::test]
+    async fn full_node_rejects() {
+        let address = mock_node(1, std::time::Duration::from_secs(60)).await;
+        let (client, mut events) = BlackChannelClient::new(options());
+
+        let first = job("sleep");
+        client.dispatch(&address, first.clone()).await.unwrap();
+        assert_eq!(
+            next_result(&mut events, first.job_id).await.1.outcome,
+            JobOutcome::Started
+        );
+        let second = job("sleep");
+        client.dispatch(&address, second.clone()).await.unwrap();
+        assert_eq!(
+            next_result(&mut events, second.job_id).await.1.outcome,
+            JobOutcome::Rejected
+        );
+    }
+
+    #[tokio::test]
+    async fn cancel() {
+        let address = mock_node(4, std::time::Duration::from_secs(60)).await;
+        let (client, mut events) = BlackChannelClient::new(options());
+
+        let running = job("sleep");
+        client.dispatch(&address, running.clone()).await.unwrap();
+        assert_eq!(
+            next_result(&mut events, running.job_id).await.1.outcome,
+            JobOutcome::Started
+        );
+        client.cancel(&address, running.job_id).await.unwrap();
+        assert_eq!(
+            next_result(&mut events, running.job_id).await.1.outcome,
+            JobOutcome::Cancelled
+        );
+    }
+
+    #[tokio::test]
+    async fn handshake_needs_a_node() {
+        // bound then dropped, nothing listens there anymore
+        let address = tokio::net::TcpListener::bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .to_string();
+        let (client, _events) = BlackChannelClient::new(options());
+        assert!(matches!(
+            client.dispatch(&address, job("echo")).await,
+            Err(ClientError::Connect(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn reconnects_after_the_node_drops_the_connection() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let (dropped_sender, dropped) = tokio::sync::oneshot::channel();
+
+        // the first connection is answered by hand & hung up after one dispatch,
+        // the mock node serves every later one
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut framed = tokio_util::codec::Framed::new(stream, FrameCodec);
+            assert!(matches!(
+                framed.next().await,
+                Some(Ok(Frame::Handshake { .. }))
+            ));
+            framed
+                .send(Frame::HandshakeAck {
+                    node_id: "flaky".to_string(),
+                    capacity: 4,
+                })
+                .await
+                .unwrap();
+            assert!(matches!(
+                framed.next().await,
+                Some(Ok(Frame::DispatchJob(_)))
+            ));
+            drop(framed);
+            let _ = dropped_sender.send(());
+
+            run_mock_node(
+                listener,
+                MockNodeOptions {
+                    node_id: "mock-1".to_string(),
+                    capacity: 4,
+                    job_duration: std::time::Duration::from_millis(20),
+                },
+            )
+            .await
+        });
+
+        let (client, mut events) = BlackChannelClient::new(options());
+        client.dispatch(&address, job("lost")).await.unwrap();
+        dropped.await.unwrap();
+
+        // wait until the client noticed, the next send then takes a new connection
+        tokio::time::timeout(EVENT_TIMEOUT, async {
+            loop {
+                let closed = client.pools.lock().await[&address]
+                    .connections
+                    .iter()
+                    .all(|connection| connection.outbound.is_closed());
+                if closed {
+                    break;
+                }
+                tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("the client never noticed the closed connection");
+
+        let retried = job("echo again");
+        client.dispatch(&address, retried.clone()).await.unwrap();
+        let (event, result) = next_result(&mut events, retried.job_id).await;
+        assert_eq!(event.node_id, "mock-1");
+        assert_eq!(result.outcome, JobOutcome::Started);
+        assert_eq!(
+            next_result(&mut events, retried.job_id).await.1.outcome,
+            JobOutcome::Succeeded
+        );
+    }
+}
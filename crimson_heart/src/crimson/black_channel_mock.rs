@@ -0,0 +1,152 @@
+use super::black_channel_protocol::{Frame, FrameCodec, JobOutcome, JobResult};
+
+use futures_util::{SinkExt, StreamExt};
+
+/*
+ * In-process stand-in for a black_channel node, speaks the same protocol.
+ *
+ * - Every dispatched job reports `Started`, then `Succeeded` after `job_duration`.
+ * - A command of `false` finishes `Failed` with exit code 1.
+ * - Jobs beyond `capacity` slots are `Rejected`.
+ */
+#[derive(Debug, Clone)]
+pub struct MockNodeOptions {
+    pub node_id: String,
+    pub capacity: u32,
+    pub job_duration: std::time::Duration,
+}
+
+type RunningJobs = std::sync::Arc<std::sync::Mutex<std::collections::HashMap<uuid::Uuid, u32>>>;
+
+/**
+ * # Brief
+ * Accepts connections on `listener` until the task is dropped.
+ */
+pub async fn run_mock_node(
+    listener: tokio::net::TcpListener,
+    options: MockNodeOptions,
+) -> std::io::Result<()> {
+    let running: RunningJobs = Default::default();
+
+    loop {
+        let (stream, peer) = listener.accept().await?;
+        tracing::info!(component = "black_channel_mock", peer = %peer, "client connected");
+        tokio::spawn(serve_connection(stream, options.clone(), running.clone()));
+    }
+}
+
+fn result(job_id: uuid::Uuid, outcome: JobOutcome, exit_code: i32, message: &str) -> Frame {
+    Frame::Result(JobResult {
+        job_id,
+        outcome,
+        exit_code,
+        message: message.to_string(),
+    })
+}
+
+async fn serve_connection(
+    stream: tokio::net::TcpStream,
+    options: MockNodeOptions,
+    running: RunningJobs,
+) {
+    let (mut sink, mut stream) = tokio_util::codec::Framed::new(stream, FrameCodec).split();
+
+    match stream.next().await {
+        Some(Ok(Frame::Handshake { client_id })) => {
+            tracing::info!(
+                component = "black_channel_mock",
+                client_id = %client_id,
+                "handshake received"
+            );
+        }
+        _ => return,
+    }
+    let ack = Frame::HandshakeAck {
+        node_id: options.node_id.clone(),
+        capacity: options.capacity,
+    };
+    if sink.send(ack).await.is_err() {
+        return;
+    }
+
+    // job timers write through this channel, the sink has a single owner
+    let (outbound, mut outbound_rx) = tokio::sync::mpsc::unbounded_channel::<Frame>();
+    let writer = tokio::spawn(async move {
+        while let Some(frame) = outbound_rx.recv().await {
+            if sink.send(frame).await.is_err() {
+                break;
+            }
+        }
+    });
+
+    while let Some(Ok(frame)) = stream.next().await {
+        match frame {
+            Frame::DispatchJob(job) => {
+                let accepted = {
+                    let mut running = running.lock().unwrap_or_else(|e| e.into_inner());
+                    let used: u32 = running.values().sum();
+                    if used + job.required_slots > options.capacity {
+                        false
+                    } else {
+                        running.insert(job.job_id, job.required_slots);
+                        true
+                    }
+                };
+
+                if !accepted {
+                    let _ =
+                        outbound.send(result(job.job_id, JobOutcome::Rejected, -1, "node full"));
+                    continue;
+                }
+
+                let _ = outbound.send(result(job.job_id, JobOutcome::Started, 0, ""));
+                let outbound = outbound.clone();
+                let running = running.clone();
+                let job_duration = options.job_duration;
+                tokio::spawn(async move {
+                    tokio::time::sleep(job_duration).await;
+                    let still_running = running
+                        .lock()
+                        .unwrap_or_else(|e| e.into_inner())
+                        .remove(&job.job_id)
+                        .is_some();
+                    if still_running {
+                        let frame = if job.command.trim() == "false" {
+                            result(job.job_id, JobOutcome::Failed, 1, "command failed")
+                        } else {
+                            result(job.job_id, JobOutcome::Succeeded, 0, "")
+                        };
+                        let _ = outbound.send(frame);
+                    }
+                });
+            }
+            Frame::Cancel { job_id } => {
+                let was_running = running
+                    .lock()
+                    .unwrap_or_else(|e| e.into_inner())
+                    .remove(&job_id)
+                    .is_some();
+                if was_running {
+                    let _ = outbound.send(result(job_id, JobOutcome::Cancelled, -1, "cancelled"));
+                }
+            }
+            Frame::Heartbeat { .. } => {
+                let running_jobs = running.lock().unwrap_or_else(|e| e.into_inner()).len();
+                let _ = outbound.send(Frame::Heartbeat {
+                    timestamp: chrono::Utc::now().timestamp() as u64,
+                    running_jobs: running_jobs as u32,
+                });
+            }
+            other => {
+                tracing::warn!(
+                    component = "black_channel_mock",
+                    frame_type = ?other.frame_type(),
+                    "unexpected frame from client"
+                );
+            }
+        }
+    }
+
+    writer.abort();
+    tracing::info!(component = "black_channel_mock", "client disconnected");
+}
@@ -0,0 +1,549 @@
+use bytes::{Buf, BufMut};
+
+/*
+ * Wire protocol spoken between crimson_heart and black_channel nodes.
+ * Mirrored in `black_channel/core/protocol.h`, keep both in sync.
+ *
+ * Every frame is an 8 byte header followed by `length` payload bytes,
+ * all integers are big endian.
+ *
+ *   0       2         3      4          8
+ *   +-------+---------+------+----------+---------------+
+ *   | magic | version | type |  length  |  payload ...  |
+ *   +-------+---------+------+----------+---------------+
+ *
+ * Strings are length prefixed UTF-8 (u16 length, u32 for commands &
+ * messages), job ids are the 16 raw bytes of the UUID.
+ */
+pub const MAGIC: u16 = 0xBC0D;
+pub const PROTOCOL_VERSION: u8 = 1;
+pub const HEADER_LEN: usize = 8;
+pub const MAX_PAYLOAD_LEN: u32 = 16 * 1024 * 1024;
+
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrameType {
+    Handshake = 1,
+    HandshakeAck = 2,
+    DispatchJob = 3,
+    Cancel = 4,
+    Heartbeat = 5,
+    Result = 6,
+}
+
+impl FrameType {
+    #[inline]
+    pub fn as_u8(self) -> u8 {
+        self as u8
+    }
+
+    #[inline]
+    pub fn from_u8(n: u8) -> Option<Self> {
+        match n {
+            1 => Some(FrameType::Handshake),
+            2 => Some(FrameType::HandshakeAck),
+            3 => Some(FrameType::DispatchJob),
+            4 => Some(FrameType::Cancel),
+            5 => Some(FrameType::Heartbeat),
+            6 => Some(FrameType::Result),
+            _ => None,
+        }
+    }
+}
+
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JobOutcome {
+    Started = 1,
+    Succeeded = 2,
+    Failed = 3,
+    Cancelled = 4,
+    Rejected = 5,
+}
+
+impl JobOutcome {
+    #[inline]
+    pub fn as_u8(self) -> u8 {
+        self as u8
+    }
+
+    #[inline]
+    pub fn from_u8(n: u8) -> Option<Self> {
+        match n {
+            1 => Some(JobOutcome::Started),
+            2 => Some(JobOutcome::Succeeded),
+            3 => Some(JobOutcome::Failed),
+            4 => Some(JobOutcome::Cancelled),
+            5 => Some(JobOutcome::Rejected),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DispatchJob {
+    pub job_id: uuid::Uuid,
+    pub required_slots: u32,
+    pub command: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct JobResult {
+    pub job_id: uuid::Uuid,
+    pub outcome: JobOutcome,
+    pub exit_code: i32,
+    pub message: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Frame {
+    /// client -> node, first frame on every connection
+    Handshake { client_id: String },
+    /// node -> client, reply to `Handshake`
+    HandshakeAck { node_id: String, capacity: u32 },
+    /// client -> node
+    DispatchJob(DispatchJob),
+    /// client -> node
+    Cancel { job_id: uuid::Uuid },
+    /// both directions, keepalive
+    Heartbeat { timestamp: u64, running_jobs: u32 },
+    /// node -> client, progress & completion of a dispatched job
+    Result(JobResult),
+}
+
+impl Frame {
+    pub fn frame_type(&self) -> FrameType {
+        match self {
+            Frame::Handshake { .. } => FrameType::Handshake,
+            Frame::HandshakeAck { .. } => FrameType::HandshakeAck,
+            Frame::DispatchJob(_) => FrameType::DispatchJob,
+            Frame::Cancel { .. } => FrameType::Cancel,
+            Frame::Heartbeat { .. } => FrameType::Heartbeat,
+            Frame::Result(_) => FrameType::Result,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ProtocolError {
+    Io(std::io::Error),
+    BadMagic(u16),
+    UnsupportedVersion(u8),
+    UnknownFrameType(u8),
+    PayloadTooLarge(u32),
+    Malformed(&'static str),
+}
+
+impl std::fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ProtocolError::Io(e) => write!(f, "io error ({})", e),
+            ProtocolError::BadMagic(magic) => write!(f, "bad frame magic {:#06x}", magic),
+            ProtocolError::UnsupportedVersion(version) => {
+                write!(f, "unsupported protocol version {}", version)
+            }
+            ProtocolError::UnknownFrameType(frame_type) => {
+                write!(f, "unknown frame type {}", frame_type)
+            }
+            ProtocolError::PayloadTooLarge(length) => {
+                write!(f, "payload of {} bytes exceeds {}", length, MAX_PAYLOAD_LEN)
+            }
+            ProtocolError::Malformed(reason) => write!(f, "malformed payload ({})", reason),
+        }
+    }
+}
+
+impl std::error::Error for ProtocolError {}
+
+impl From<std::io::Error> for ProtocolError {
+    fn from(e: std::io::Error) -> Self {
+        ProtocolError::Io(e)
+    }
+}
+
+fn put_short_string(dst: &mut bytes::BytesMut, s: &str) -> Result<(), ProtocolError> {
+    let length: u16 = s
+        .len()
+        .try_into()
+        .map_err(|_| ProtocolError::Malformed("string longer than u16"))?;
+    dst.put_u16(length);
+    dst.put_slice(s.as_bytes());
+    Ok(())
+}
+
+fn put_long_string(dst: &mut bytes::BytesMut, s: &str) -> Result<(), ProtocolError> {
+    let length: u32 = s
+        .len()
+        .try_into()
+        .map_err(|_| ProtocolError::Malformed("string longer than u32"))?;
+    dst.put_u32(length);
+    dst.put_slice(s.as_bytes());
+    Ok(())
+}
+
+fn need(src: &bytes::Bytes, n: usize) -> Result<(), ProtocolError> {
+    if src.remaining() < n {
+        return Err(ProtocolError::Malformed("payload truncated"));
+    }
+    Ok(())
+}
+
+fn get_string(src: &mut bytes::Bytes, length: usize) -> Result<String, ProtocolError> {
+    need(src, length)?;
+    String::from_utf8(src.split_to(length).to_vec())
+        .map_err(|_| ProtocolError::Malformed("string is not utf-8"))
+}
+
+fn get_short_string(src: &mut bytes::Bytes) -> Result<String, ProtocolError> {
+    need(src, 2)?;
+    let length = src.get_u16() as usize;
+    get_string(src, length)
+}
+
+fn get_long_string(src: &mut bytes::Bytes) -> Result<String, ProtocolError> {
+    need(src, 4)?;
+    let length = src.get_u32() as usize;
+    get_string(src, length)
+}
+
+fn get_uuid(src: &mut bytes::Bytes) -> Result<uuid::Uuid, ProtocolError> {
+    need(src, 16)?;
+    let mut raw = [0u8; 16];
+    src.copy_to_slice(&mut raw);
+    Ok(uuid::Uuid::from_bytes(raw))
+}
+
+fn encode_payload(frame: &Frame, dst: &mut bytes::BytesMut) -> Result<(), ProtocolError> {
+    match frame {
+        Frame::Handshake { client_id } => put_short_string(dst, client_id)?,
+        Frame::HandshakeAck { node_id, capacity } => {
+            put_short_string(dst, node_id)?;
+            dst.put_u32(*capacity);
+        }
+        Frame::DispatchJob(job) => {
+            dst.put_slice(job.job_id.as_bytes());
+            dst.put_u32(job.required_slots);
+            put_long_string(dst, &job.command)?;
+        }
+        Frame::Cancel { job_id } => dst.put_slice(job_id.as_bytes()),
+        Frame::Heartbeat {
+            timestamp,
+            running_jobs,
+        } => {
+            dst.put_u64(*timestamp);
+            dst.put_u32(*running_jobs);
+        }
+        Frame::Result(result) => {
+            dst.put_slice(result.job_id.as_bytes());
+            dst.put_u8(result.outcome.as_u8());
+            dst.put_i32(result.exit_code);
+            put_long_string(dst, &result.message)?;
+        }
+    }
+    Ok(())
+}
+
+fn decode_payload(frame_type: FrameType, mut src: bytes::Bytes) -> Result<Frame, ProtocolError> {
+    let frame = match frame_type {
+        FrameType::Handshake => Frame::Handshake {
+            client_id: get_short_string(&mut src)?,
+        },
+        FrameType::HandshakeAck => {
+            let node_id = get_short_string(&mut src)?;
+            need(&src, 4)?;
+            Frame::HandshakeAck {
+                node_id,
+                capacity: src.get_u32(),
+            }
+        }
+        FrameType::DispatchJob => {
+            let job_id = get_uuid(&mut src)?;
+            need(&src, 4)?;
+            let required_slots = src.get_u32();
+            Frame::DispatchJob(DispatchJob {
+                job_id,
+                required_slots,
+                command: get_long_string(&mut src)?,
+            })
+        }
+        FrameType::Cancel => Frame::Cancel {
+            job_id: get_uuid(&mut src)?,
+        },
+        FrameType::Heartbeat => {
+            need(&src, 12)?;
+            Frame::Heartbeat {
+                timestamp: src.get_u64(),
+                running_jobs: src.get_u32(),
+            }
+        }
+        FrameType::Result => {
+            let job_id = get_uuid(&mut src)?;
+            need(&src, 5)?;
+            let outcome = JobOutcome::from_u8(src.get_u8())
+                .ok_or(ProtocolError::Malformed("unknown job outcome"))?;
+            let exit_code = src.get_i32();
+            Frame::Result(JobResult {
+                job_id,
+                outcome,
+                exit_code,
+                message: get_long_string(&mut src)?,
+            })
+        }
+    };
+
+    if src.has_remaining() {
+        return Err(ProtocolError::Malformed("trailing bytes after payload"));
+    }
+    Ok(frame)
+}
+
+/// length prefixed frame codec, for `tokio_util::codec::Framed`
+#[derive(Debug, Default, Clone, Copy)]
+pub struct FrameCodec;
+
+impl tokio_util::codec::Encoder<Frame> for FrameCodec {
+    type Error = ProtocolError;
+
+    fn encode(&mut self, frame: Frame, dst: &mut bytes::BytesMut) -> Result<(), Self::Error> {
+        let mut payload = bytes::BytesMut::new();
+        encode_payload(&frame, &mut payload)?;
+
+        let length: u32 = payload
+            .len()
+            .try_into()
+            .ok()
+            .filter(|length| *length <= MAX_PAYLOAD_LEN)
+            .ok_or(ProtocolError::PayloadTooLarge(u32::MAX))?;
+
+        dst.reserve(HEADER_LEN + payload.len());
+        dst.put_u16(MAGIC);
+        dst.put_u8(PROTOCOL_VERSION);
+        dst.put_u8(frame.frame_type().as_u8());
+        dst.put_u32(length);
+        dst.put_slice(&payload);
+        Ok(())
+    }
+}
+
+impl tokio_util::codec::Decoder for FrameCodec {
+    type Item = Frame;
+    type Error = ProtocolError;
+
+    fn decode(&mut self, src: &mut bytes::BytesMut) -> Result<Option<Frame>, Self::Error> {
+        if src.len() < HEADER_LEN {
+            src.reserve(HEADER_LEN - src.len());
+            return Ok(None);
+        }
+
+        // validate the header before waiting on the payload
+        let mut header = &src[..HEADER_LEN];
+        let magic = header.get_u16();
+        let version = header.get_u8();
+        let frame_type = header.get_u8();
+        let length = header.get_u32();
+
+        if magic != MAGIC {
+            return Err(ProtocolError::BadMagic(magic));
+        }
+        if version != PROTOCOL_VERSION {
+            return Err(ProtocolError::UnsupportedVersion(version));
+        }
+        let frame_type =
+            FrameType::from_u8(frame_type).ok_or(ProtocolError::UnknownFrameType(frame_type))?;
+        if length > MAX_PAYLOAD_LEN {
+            return Err(ProtocolError::PayloadTooLarge(length));
+        }
+
+        let frame_len = HEADER_LEN + length as usize;
+        if src.len() < frame_len {
+            src.reserve(frame_len - src.len());
+            return Ok(None);
+        }
+
+        let mut frame = src.split_to(frame_len);
+        frame.advance(HEADER_LEN);
+        decode_payload(frame_type, frame.freeze()).map(Some)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio_util::codec::{Decoder, Encoder};
+
+    fn job_id() -> uuid::Uuid {
+        uuid::Uuid::from_u128(0x0123_4567_89ab_cdef_0123_4567_89ab_cdef)
+    }
+
+    fn encode(frame: Frame) -> bytes::BytesMut {
+        let mut dst = bytes::BytesMut::new();
+        FrameCodec.encode(frame, &mut dst).unwrap();
+        dst
+    }
+
+    fn header(magic: u16, version: u8, frame_type: u8, length: u32) -> bytes::BytesMut {
+        let mut dst = bytes::BytesMut::new();
+        dst.put_u16(magic);
+        dst.put_u8(version);
+        dst.put_u8(frame_type);
+        dst.put_u32(length);
+        dst
+    }
+
+    #[test]
+    fn every_frame_round_trips() {
+        let frames = vec![
+            Frame::Handshake {
+                client_id: "crimson-test".to_string(),
+            },
+            Frame::HandshakeAck {
+                node_id: "node-1".to_string(),
+                capacity: 8,
+            },
+            Frame::DispatchJob(DispatchJob {
+                job_id: job_id(),
+                required_slots: 2,
+                command: "echo hello".to_string(),
+            }),
+            Frame::Cancel { job_id: job_id() },
+            Frame::Heartbeat {
+                timestamp: 1_700_000_000,
+                running_jobs: 3,
+            },
+            Frame::Result(JobResult {
+                job_id: job_id(),
+                outcome: JobOutcome::Failed,
+                exit_code: -9,
+                message: "killed".to_string(),
+            }),
+        ];
+
+        // all frames back to back, as they arrive on a connection
+        let mut src = bytes::BytesMut::new();
+        for frame in &frames {
+            src.extend_from_slice(&encode(frame.clone()));
+        }
+        for frame in frames {
+            assert_eq!(FrameCodec.decode(&mut src).unwrap(), Some(frame));
+        }
+        assert!(src.is_empty());
+        assert_eq!(FrameCodec.decode(&mut src).unwrap(), None);
+    }
+
+    #[test]
+    fn every_outcome_round_trips() {
+        for outcome in [
+            JobOutcome::Started,
+            JobOutcome::Succeeded,
+            JobOutcome::Failed,
+            JobOutcome::Cancelled,
+            JobOutcome::Rejected,
+        ] {
+            assert_eq!(JobOutcome::from_u8(outcome.as_u8()), Some(outcome));
+        }
+    }
+
+    #[test]
+    fn waits_for_a_whole_frame() {
+        let frame = Frame::Handshake {
+            client_id: "crimson-test".to_string(),
+        };
+        let encoded = encode(frame.clone());
+
+        let mut src = bytes::BytesMut::from(&encoded[..HEADER_LEN - 1]);
+        assert_eq!(FrameCodec.decode(&mut src).unwrap(), None);
+        src.extend_from_slice(&encoded[HEADER_LEN - 1..encoded.len() - 1]);
+        assert_eq!(FrameCodec.decode(&mut src).unwrap(), None);
+        src.extend_from_slice(&encoded[encoded.len() - 1..]);
+        assert_eq!(FrameCodec.decode(&mut src).unwrap(), Some(frame));
+    }
+
+    #[test]
+    fn rejects_bad_magic() {
+        let mut src = header(0xDEAD, PROTOCOL_VERSION, FrameType::Heartbeat.as_u8(), 12);
+        assert!(matches!(
+            FrameCodec.decode(&mut src),
+            Err(ProtocolError::BadMagic(0xDEAD))
+        ));
+    }
+
+    #[test]
+    fn rejects_bad_version() {
+        let mut src = header(
+            MAGIC,
+            PROTOCOL_VERSION + 1,
+            FrameType::Heartbeat.as_u8(),
+            12,
+        );
+        assert!(matches!(
+            FrameCodec.decode(&mut src),
+            Err(ProtocolError::UnsupportedVersion(version)) if version == PROTOCOL_VERSION + 1
+        ));
+    }
+
+    #[test]
+    fn rejects_unknown_frame_type() {
+        let mut src = header(MAGIC, PROTOCOL_VERSION, 0xFF, 0);
+        assert!(matches!(
+            FrameCodec.decode(&mut src),
+            Err(ProtocolError::UnknownFrameType(0xFF))
+        ));
+    }
+
+    #[test]
+    fn rejects_oversized_payload() {
+        // refused from the header alone, the payload is never waited for
+        let mut src = header(
+            MAGIC,
+            PROTOCOL_VERSION,
+            FrameType::DispatchJob.as_u8(),
+            MAX_PAYLOAD_LEN + 1,
+        );
+        assert!(matches!(
+            FrameCodec.decode(&mut src),
+            Err(ProtocolError::PayloadTooLarge(length)) if length == MAX_PAYLOAD_LEN + 1
+        ));
+
+        let command = "x".repeat(MAX_PAYLOAD_LEN as usize);
+        let frame = Frame::DispatchJob(DispatchJob {
+            job_id: job_id(),
+            required_slots: 1,
+            command,
+        });
+        assert!(matches!(
+            FrameCodec.encode(frame, &mut bytes::BytesMut::new()),
+            Err(ProtocolError::PayloadTooLarge(_))
+        ));
+    }
+
+    #[test]
+    fn rejects_truncated_payload() {
+        // the string claims 10 bytes, the frame only carries 3
+        let mut src = header(MAGIC, PROTOCOL_VERSION, FrameType::Handshake.as_u8(), 5);
+        src.put_u16(10);
+        src.put_slice(b"abc");
+        assert!(matches!(
+            FrameCodec.decode(&mut src),
+            Err(ProtocolError::Malformed("payload truncated"))
+        ));
+
+        // a heartbeat needs 12 bytes
+        let mut src = header(MAGIC, PROTOCOL_VERSION, FrameType::Heartbeat.as_u8(), 4);
+        src.put_u32(1);
+        assert!(matches!(
+            FrameCodec.decode(&mut src),
+            Err(ProtocolError::Malformed("payload truncated"))
+        ));
+    }
+
+    #[test]
+    fn rejects_trailing_bytes() {
+        let mut src = header(MAGIC, PROTOCOL_VERSION, FrameType::Cancel.as_u8(), 17);
+        src.put_slice(job_id().as_bytes());
+        src.put_u8(0);
+        assert!(matches!(
+            FrameCodec.decode(&mut src),
+            Err(ProtocolError::Malformed("trailing bytes after payload"))
+        ));
+    }
+}
@@ -0,0 +1,312 @@
+use deadpool_redis::redis::AsyncCommands;
+use serde::Serialize;
+
+/*
+ * Compute worker registry, shared by every crimson instance through Redis.
+ *
+ * - `compute_workers` set of every known worker id.
+ * - `compute_worker:{worker_id}` hash holding the worker's description.
+ */
+const WORKER_SET_KEY: &str = "compute_workers";
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum WorkerState {
+    Alive,
+    Dead,
+}
+
+impl WorkerState {
+    #[inline]
+    pub fn as_str(self) -> &'static str {
+        match self {
+            WorkerState::Alive => "alive",
+            WorkerState::Dead => "dead",
+        }
+    }
+
+    #[inline]
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "alive" => Some(WorkerState::Alive),
+            "dead" => Some(WorkerState::Dead),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct ComputeWorker {
+    pub worker_id: String,
+    pub address: Option<String>,
+    pub capacity: i64,
+    pub labels: std::collections::HashMap<String, String>,
+    /// only runs this organization's jobs, None for shared workers
+    pub org_id: Option<uuid::Uuid>,
+    pub state: WorkerState,
+    pub registered_at: i64,
+    pub last_heartbeat: i64,
+}
+
+#[derive(Debug)]
+pub enum RegistryError {
+    Pool(deadpool_redis::PoolError),
+    Redis(deadpool_redis::redis::RedisError),
+}
+
+impl ComputeWorker {
+    /// shared workers serve everyone, dedicated ones only their organization
+    pub fn serves(&self, org_id: Option<uuid::Uuid>) -> bool {
+        self.org_id.is_none() || self.org_id == org_id
+    }
+}
+
+impl std::fmt::Display for RegistryError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RegistryError::Pool(e) => write!(f, "redis pool error ({})", e),
+            RegistryError::Redis(e) => write!(f, "redis error ({})", e),
+        }
+    }
+}
+
+impl std::error::Error for RegistryError {}
+
+impl From<deadpool_redis::PoolError> for RegistryError {
+    fn from(e: deadpool_redis::PoolError) -> Self {
+        RegistryError::Pool(e)
+    }
+}
+
+impl From<deadpool_redis::redis::RedisError> for RegistryError {
+    fn from(e: deadpool_redis::redis::RedisError) -> Self {
+        RegistryError::Redis(e)
+    }
+}
+
+fn worker_key(worker_id: &str) -> String {
+    format!("compute_worker:{}", worker_id)
+}
+
+/// worker ids end up in redis keys & logs, keep them boring
+pub fn is_valid_worker_id(worker_id: &str) -> bool {
+    !worker_id.is_empty()
+        && worker_id.len() <= 64
+        && worker_id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+}
+
+#[derive(Clone)]
+pub struct WorkerRegistry {
+    redis_pool: deadpool_redis::Pool,
+    heartbeat_timeout: i64,
+}
+
+impl WorkerRegistry {
+    pub fn new(redis_pool: deadpool_redis::Pool, heartbeat_timeout: i64) -> Self {
+        WorkerRegistry {
+            redis_pool,
+            heartbeat_timeout,
+        }
+    }
+
+    /**
+     * # Brief
+     * Registers a worker, or re-registers it with a fresh description.
+     *
+     * # Detail
+     * - A dead worker that registers again becomes alive.
+     * - `registered_at` is kept across re-registrations.
+     * - `org_id` dedicates the worker to one organization, stored empty when shared.
+     */
+    pub async fn register(
+        &self,
+        worker_id: &str,
+        address: Option<&str>,
+        capacity: i64,
+        labels: &std::collections::HashMap<String, String>,
+        org_id: Option<uuid::Uuid>,
+    ) -> Result<ComputeWorker, RegistryError> {
+        let mut redis_connection = self.redis_pool.get().await?;
+        let now = chrono::Utc::now().timestamp();
+        let key = worker_key(worker_id);
+        let labels_json = serde_json::to_string(labels).unwrap_or_else(|_| "{}".to_string());
+
+        let _: bool = redis_connection.hset_nx(&key, "registered_at", now).await?;
+        let _: () = redis_connection
+            .hset_multiple(
+                &key,
+                &[
+                    ("worker_id", worker_id.to_string()),
+                    ("address", address.unwrap_or_default().to_string()),
+                    ("capacity", capacity.to_string()),
+                    ("labels", labels_json),
+                    ("org_id", org_id.map(|o| o.to_string()).unwrap_or_default()),
+                    ("state", WorkerState::Alive.as_str().to_string()),
+                    ("last_heartbeat", now.to_string()),
+                ],
+            )
+            .await?;
+        let _: () = redis_connection.sadd(WORKER_SET_KEY, worker_id).await?;
+        let registered_at: Option<i64> = redis_connection.hget(&key, "registered_at").await?;
+
+        Ok(ComputeWorker {
+            worker_id: worker_id.to_string(),
+            address: address.map(str::to_string),
+            capacity,
+            labels: labels.clone(),
+            org_id,
+            state: WorkerState::Alive,
+            registered_at: registered_at.unwrap_or(now),
+            last_heartbeat: now,
+        })
+    }
+
+    /**
+     * # Brief
+     * Records a heartbeat, returns false for unknown workers.
+     *
+     * # Detail
+     * - A dead worker must register again, its jobs were already requeued.
+     */
+    pub async fn heartbeat(&self, worker_id: &str) -> Result<bool, RegistryError> {
+        let mut redis_connection = self.redis_pool.get().await?;
+        let key = worker_key(worker_id);
+
+        let state: Option<String> = redis_connection.hget(&key, "state").await?;
+        match state.as_deref().and_then(WorkerState::parse) {
+            Some(WorkerState::Alive) => {
+                let _: () = redis_connection
+                    .hset(&key, "last_heartbeat", chrono::Utc::now().timestamp())
+                    .await?;
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    pub async fn get(&self, worker_id: &str) -> Result<Option<ComputeWorker>, RegistryError> {
+        let mut redis_connection = self.redis_pool.get().await?;
+        let fields: std::collections::HashMap<String, String> =
+            redis_connection.hgetall(worker_key(worker_id)).await?;
+        Ok(parse_worker(fields))
+    }
+
+    /// every registered worker, ordered by id
+    pub async fn list(&self) -> Result<Vec<ComputeWorker>, RegistryError> {
+        let mut redis_connection = self.redis_pool.get().await?;
+        let worker_ids: Vec<String> = redis_connection.smembers(WORKER_SET_KEY).await?;
+
+        let mut workers = Vec::with_capacity(worker_ids.len());
+        for worker_id in worker_ids {
+            let fields: std::collections::HashMap<String, String> =
+                redis_connection.hgetall(worker_key(&worker_id)).await?;
+            match parse_worker(fields) {
+                Some(worker) => workers.push(worker),
+                None => {
+                    // hash vanished, drop the dangling id
+                    let _: () = redis_connection.srem(WORKER_SET_KEY, &worker_id).await?;
+                }
+            }
+        }
+
+        workers.sort_by(|a, b| a.worker_id.cmp(&b.worker_id));
+        Ok(workers)
+    }
+
+    /**
+     * # Brief
+     * Marks alive workers that missed their heartbeats as dead.
+     *
+     * # Detail
+     * - Safe to run from every crimson instance at once.
+     * - Returns the ids that were marked dead by this call.
+     */
+    pub async fn reap(&self) -> Result<Vec<String>, RegistryError> {
+        let deadline = chrono::Utc::now().timestamp() - self.heartbeat_timeout;
+        let mut reaped = Vec::new();
+
+        // re-checked atomically, a heartbeat may land between list & update
+        let reap_script = r#"
+            if redis.call('HGET', KEYS[1], 'state') == 'alive'
+                and tonumber(redis.call('HGET', KEYS[1], 'last_heartbeat')) < tonumber(ARGV[1]) then
+                redis.call('HSET', KEYS[1], 'state', 'dead')
+                return 1
+            end
+            return 0
+        "#;
+
+        for worker in self.list().await? {
+            if worker.state == WorkerState::Alive && worker.last_heartbeat < deadline {
+                let mut redis_connection = self.redis_pool.get().await?;
+                let marked: i64 = deadpool_redis::redis::cmd("EVAL")
+                    .arg(reap_script)
+                    .arg(1)
+                    .arg(worker_key(&worker.worker_id))
+                    .arg(deadline)
+                    .query_async(&mut redis_connection)
+                    .await?;
+                if marked == 1 {
+                    reaped.push(worker.worker_id);
+                }
+            }
+        }
+
+        Ok(reaped)
+    }
+}
+
+fn parse_worker(mut fields: std::collections::HashMap<String, String>) -> Option<ComputeWorker> {
+    let worker_id = fields.remove("worker_id")?;
+    let address = fields.remove("address").filter(|a| !a.is_empty());
+    let capacity = fields.get("capacity")?.parse().ok()?;
+    let labels = fields
+        .get("labels")
+        .and_then(|labels| serde_json::from_str(labels).ok())
+        .unwrap_or_default();
+    let org_id = fields.get("org_id").and_then(|o| o.parse().ok());
+    let state = WorkerState::parse(fields.get("state")?)?;
+    let registered_at = fields.get("registered_at")?.parse().ok()?;
+    let last_heartbeat = fields.get("last_heartbeat")?.parse().ok()?;
+
+    Some(ComputeWorker {
+        worker_id,
+        address,
+        capacity,
+        labels,
+        org_id,
+        state,
+        registered_at,
+        last_heartbeat,
+    })
+}
+
+/**
+ * # Brief
+ * Background task marking workers dead once they miss heartbeats.
+ */
+pub async fn run_reaper(registry: WorkerRegistry, interval: std::time::Duration) {
+    let mut ticker = tokio::time::interval(interval);
+    loop {
+        ticker.tick().await;
+        match registry.reap().await {
+            Ok(reaped) => {
+                for worker_id in reaped {
+                    tracing::warn!(
+                        component = "compute_registry",
+                        worker_id = %worker_id,
+                        "worker missed heartbeats, marked dead"
+                    );
+                }
+            }
+            Err(e) => {
+                tracing::error!(
+                    error = %e,
+                    component = "compute_registry",
+                    "failed to reap dead workers"
+                );
+            }
+        }
+    }
+}
@@ -0,0 +1,704 @@
+use super::api_compute_types::JobState;
+use super::black_channel_client::{BlackChannelClient, NodeEvent};
+use super::black_channel_protocol::{DispatchJob, Frame, JobOutcome};
+use super::compute_registry::{ComputeWorker, WorkerRegistry, WorkerState};
+
+/*
+ * Places queued jobs on alive workers and follows them through black_channel.
+ *
+ * Every pass:
+ * - requeues jobs held by workers that are dead or gone,
+ * - claims queued jobs oldest first with a guarded UPDATE, so several
+ *   crimson instances can schedule concurrently without double placement,
+ * - dispatches each claim to its worker, undoing the claim when that fails.
+ *
+ * Results coming back from nodes drive the remaining transitions.
+ */
+
+/// a worker as seen by a placement policy
+#[derive(Debug, Clone)]
+pub struct WorkerLoad {
+    pub worker: ComputeWorker,
+    pub used_slots: i64,
+}
+
+impl WorkerLoad {
+    #[inline]
+    pub fn free_slots(&self) -> i64 {
+        self.worker.capacity - self.used_slots
+    }
+}
+
+#[derive(Debug, Clone, sqlx::FromRow)]
+pub struct QueuedJob {
+    pub job_id: uuid::Uuid,
+    pub command: String,
+    pub required_slots: i64,
+    pub labels: sqlx::types::Json<std::collections::HashMap<String, String>>,
+    pub org_id: Option<uuid::Uuid>,
+}
+
+/**
+ * # Brief
+ * Chooses a worker for a job.
+ *
+ * # Detail
+ * - `candidates` only holds alive workers with matching labels & enough free slots,
+ *   either shared or dedicated to the job's organization.
+ * - Returning None leaves the job queued for the next pass.
+ */
+pub trait PlacementPolicy: Send + Sync {
+    fn name(&self) -> &'static str;
+    fn place(&self, job: &QueuedJob, candidates: &[WorkerLoad]) -> Option<usize>;
+}
+
+/// first candidate in worker id order, packs jobs onto as few workers as possible
+pub struct FirstFit;
+
+impl PlacementPolicy for FirstFit {
+    fn name(&self) -> &'static str {
+        "first_fit"
+    }
+
+    fn place(&self, _job: &QueuedJob, candidates: &[WorkerLoad]) -> Option<usize> {
+        if candidates.is_empty() { None } else { Some(0) }
+    }
+}
+
+/// candidate with the lowest used / capacity ratio, spreads jobs across the fleet
+pub struct LeastLoaded;
+
+impl PlacementPolicy for LeastLoaded {
+    fn name(&self) -> &'static str {
+        "least_loaded"
+    }
+
+    fn place(&self, _job: &QueuedJob, candidates: &[WorkerLoad]) -> Option<usize> {
+        candidates
+            .iter()
+            .enumerate()
+            .min_by(|(_, a), (_, b)| {
+                // a.used / a.capacity < b.used / b.capacity, without floats
+                (a.used_slots * b.worker.capacity).cmp(&(b.used_slots * a.worker.capacity))
+            })
+            .map(|(index, _)| index)
+    }
+}
+
+pub fn placement_policy(name: &str) -> Option<Box<dyn PlacementPolicy>> {
+    match name {
+        "first_fit" => Some(Box::new(FirstFit)),
+        "least_loaded" => Some(Box::new(LeastLoaded)),
+        _ => None,
+    }
+}
+
+/// true once no worker has a free slot left
+fn fleet_is_full(loads: &[WorkerLoad]) -> bool {
+    loads.iter().all(|load| load.free_slots() <= 0)
+}
+
+/// workers with enough free slots & matching labels that may run the job
+fn candidates(job: &QueuedJob, loads: &[WorkerLoad]) -> Vec<WorkerLoad> {
+    loads
+        .iter()
+        .filter(|load| {
+            load.free_slots() >= job.required_slots
+                && load.worker.serves(job.org_id)
+                && job
+                    .labels
+                    .iter()
+                    .all(|(key, value)| load.worker.labels.get(key) == Some(value))
+        })
+        .cloned()
+        .collect()
+}
+
+#[derive(Clone)]
+pub struct JobScheduler {
+    central_db_pool: sqlx::PgPool,
+    registry: WorkerRegistry,
+    black_channel: BlackChannelClient,
+    policy: std::sync::Arc<dyn PlacementPolicy>,
+    batch_size: i64,
+}
+
+#[derive(Debug)]
+pub enum SchedulerError {
+    Database(sqlx::Error),
+    Registry(super::compute_registry::RegistryError),
+}
+
+impl std::fmt::Display for SchedulerError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SchedulerError::Database(e) => write!(f, "database error ({})", e),
+            SchedulerError::Registry(e) => write!(f, "registry error ({})", e),
+        }
+    }
+}
+
+impl std::error::Error for SchedulerError {}
+
+impl From<sqlx::Error> for SchedulerError {
+    fn from(e: sqlx::Error) -> Self {
+        SchedulerError::Database(e)
+    }
+}
+
+impl From<super::compute_registry::RegistryError> for SchedulerError {
+    fn from(e: super::compute_registry::RegistryError) -> Self {
+        SchedulerError::Registry(e)
+    }
+}
+
+const ACTIVE_STATES: [&str; 2] = ["scheduled", "running"];
+
+impl JobScheduler {
+    pub fn new(
+        central_db_pool: sqlx::PgPool,
+        registry: WorkerRegistry,
+        black_channel: BlackChannelClient,
+        policy: Box<dyn PlacementPolicy>,
+        batch_size: i64,
+    ) -> Self {
+        JobScheduler {
+            central_db_pool,
+            registry,
+            black_channel,
+            policy: policy.into(),
+            batch_size,
+        }
+    }
+
+    /**
+     * # Brief
+     * Runs one scheduling pass, returns the number of dispatched jobs.
+     */
+    pub async fn schedule_once(&self) -> Result<usize, SchedulerError> {
+        let alive: Vec<ComputeWorker> = self
+            .registry
+            .list()
+            .await?
+            .into_iter()
+            .filter(|w| w.state == WorkerState::Alive && w.address.is_some())
+            .collect();
+
+        self.requeue_orphans(&alive).await?;
+        if alive.is_empty() {
+            return Ok(0);
+        }
+
+        let mut loads = self.worker_loads(alive).await?;
+        let queued: Vec<QueuedJob> = sqlx::query_as(
+            r#"
+            SELECT job_id, command, required_slots, labels, org_id
+            FROM jobs
+            WHERE state = $1
+            ORDER BY created_at
+            LIMIT $2
+            "#,
+        )
+        .bind(JobState::Queued.as_str())
+        .bind(self.batch_size)
+        .fetch_all(&self.central_db_pool)
+        .await?;
+
+        let mut dispatched = 0;
+        for job in queued {
+            // backpressure, the fleet is full until something finishes
+            if fleet_is_full(&loads) {
+                tracing::debug!(
+                    component = "compute_scheduler",
+                    "fleet is full, leaving remaining jobs queued"
+                );
+                break;
+            }
+
+            let candidates = candidates(&job, &loads);
+            let Some(index) = self.policy.place(&job, &candidates) else {
+                continue;
+            };
+            let worker_id = candidates[index].worker.worker_id.clone();
+
+            if self.dispatch(&job, &candidates[index].worker).await? {
+                dispatched += 1;
+                if let Some(load) = loads.iter_mut().find(|l| l.worker.worker_id == worker_id) {
+                    load.used_slots += job.required_slots;
+                }
+            }
+        }
+
+        Ok(dispatched)
+    }
+
+    // jobs held by a worker that is no longer alive go back to the queue
+    async fn requeue_orphans(&self, alive: &[ComputeWorker]) -> Result<(), SchedulerError> {
+        let alive_ids: Vec<&str> = alive.iter().map(|w| w.worker_id.as_str()).collect();
+        let requeued = sqlx::query(
+            r#"
+            UPDATE jobs
+            SET state = $1, worker_id = NULL, started_at = NULL, updated_at = now()
+            WHERE state = ANY($2) AND NOT (worker_id = ANY($3))
+            "#,
+        )
+        .bind(JobState::Queued.as_str())
+        .bind(JobState::Queued.allowed_from_strs())
+        .bind(&alive_ids)
+        .execute(&self.central_db_pool)
+        .await?;
+
+        if requeued.rows_affected() > 0 {
+            tracing::warn!(
+                component = "compute_scheduler",
+                jobs = requeued.rows_affected(),
+                "requeued jobs from lost workers"
+            );
+        }
+        Ok(())
+    }
+
+    async fn worker_loads(
+        &self,
+        workers: Vec<ComputeWorker>,
+    ) -> Result<Vec<WorkerLoad>, SchedulerError> {
+        let used: Vec<(String, i64)> = sqlx::query_as(
+            r#"
+            SELECT worker_id, SUM(required_slots)::INT8
+            FROM jobs
+            WHERE state = ANY($1) AND worker_id IS NOT NULL
+            GROUP BY worker_id
+            "#,
+        )
+        .bind(&ACTIVE_STATES[..])
+        .fetch_all(&self.central_db_pool)
+        .await?;
+
+        Ok(workers
+            .into_iter()
+            .map(|worker| {
+                let used_slots = used
+                    .iter()
+                    .find(|(worker_id, _)| *worker_id == worker.worker_id)
+                    .map(|(_, slots)| *slots)
+                    .unwrap_or(0);
+                WorkerLoad { worker, used_slots }
+            })
+            .collect())
+    }
+
+    // claims the job for `worker` then dispatches it, false if another instance won the claim
+    async fn dispatch(
+        &self,
+        job: &QueuedJob,
+        worker: &ComputeWorker,
+    ) -> Result<bool, SchedulerError> {
+        let claimed = sqlx::query(
+            r#"
+            UPDATE jobs
+            SET state = $2, worker_id = $3, updated_at = now()
+            WHERE job_id = $1 AND state = ANY($4)
+            "#,
+        )
+        .bind(job.job_id)
+        .bind(JobState::Scheduled.as_str())
+        .bind(&worker.worker_id)
+        .bind(JobState::Scheduled.allowed_from_strs())
+        .execute(&self.central_db_pool)
+        .await?;
+
+        if claimed.rows_affected() == 0 {
+            return Ok(false);
+        }
+
+        let address = worker.address.as_deref().unwrap_or_default();
+        let frame = DispatchJob {
+            job_id: job.job_id,
+            required_slots: job.required_slots.try_into().unwrap_or(u32::MAX),
+            command: job.command.clone(),
+        };
+
+        match self.black_channel.dispatch(address, frame).await {
+            Ok(()) => {
+                tracing::info!(
+                    component = "compute_scheduler",
+                    job_id = %job.job_id,
+                    worker_id = %worker.worker_id,
+                    policy = self.policy.name(),
+                    "job dispatched"
+                );
+                Ok(true)
+            }
+            Err(e) => {
+                tracing::warn!(
+                    error = %e,
+                    component = "compute_scheduler",
+                    job_id = %job.job_id,
+                    worker_id = %worker.worker_id,
+                    "dispatch failed, requeueing job"
+                );
+                requeue(&self.central_db_pool, job.job_id, &worker.worker_id).await?;
+                Ok(false)
+            }
+        }
+    }
+
+    /**
+     * # Brief
+     * Tells the worker holding a cancelled job to stop it, best effort.
+     *
+     * # Detail
+     * - The job is already `cancelled` in the database, the node's `Cancelled` result is ignored.
+     * - Workers that are gone or have no address are skipped, nothing runs there anyway.
+     */
+    pub async fn cancel_on_worker(&self, job_id: uuid::Uuid, worker_id: &str) {
+        let address = match self.registry.get(worker_id).await {
+            Ok(Some(worker)) if worker.state == WorkerState::Alive => worker.address,
+            Ok(_) => None,
+            Err(e) => {
+                tracing::error!(
+                    error = %e,
+                    component = "compute_scheduler",
+                    worker_id = %worker_id,
+                    "function failed & returned error"
+                );
+                None
+            }
+        };
+        let Some(address) = address else {
+            return;
+        };
+
+        if let Err(e) = self.black_channel.cancel(&address, job_id).await {
+            tracing::warn!(
+                error = %e,
+                component = "compute_scheduler",
+                job_id = %job_id,
+                worker_id = %worker_id,
+                "failed to send cancel to worker"
+            );
+        }
+    }
+
+    /**
+     * # Brief
+     * Applies a node event to the job it refers to.
+     *
+     * # Detail
+     * - Transitions are guarded by the job state machine, stale results are ignored.
+     * - Only the node holding the job may move it, a late result from a node the job
+     *   was requeued away from must not touch its new placement.
+     * - `Rejected` puts the job back in the queue.
+     */
+    pub async fn handle_event(&self, event: NodeEvent) -> Result<(), SchedulerError> {
+        let Frame::Result(result) = event.frame else {
+            return Ok(());
+        };
+
+        let applied = match result.outcome {
+            JobOutcome::Started => sqlx::query(
+                r#"
+                UPDATE jobs
+                SET state = $2, started_at = now(), updated_at = now()
+                WHERE job_id = $1 AND state = ANY($3) AND worker_id = $4
+                "#,
+            )
+            .bind(result.job_id)
+            .bind(JobState::Running.as_str())
+            .bind(JobState::Running.allowed_from_strs())
+            .bind(&event.node_id)
+            .execute(&self.central_db_pool)
+            .await?
+            .rows_affected(),
+            JobOutcome::Succeeded | JobOutcome::Failed | JobOutcome::Cancelled => {
+                let next = match result.outcome {
+                    JobOutcome::Succeeded => JobState::Succeeded,
+                    JobOutcome::Failed => JobState::Failed,
+                    _ => JobState::Cancelled,
+                };
+                sqlx::query(
+                    r#"
+                    UPDATE jobs
+                    SET state = $2, exit_code = $3, error = $4, finished_at = now(), updated_at = now()
+                    WHERE job_id = $1 AND state = ANY($5) AND worker_id = $6
+                    "#,
+                )
+                .bind(result.job_id)
+                .bind(next.as_str())
+                .bind(result.exit_code as i64)
+                .bind(Some(result.message.as_str()).filter(|m| !m.is_empty()))
+                .bind(next.allowed_from_strs())
+                .bind(&event.node_id)
+                .execute(&self.central_db_pool)
+                .await?
+                .rows_affected()
+            }
+            JobOutcome::Rejected => {
+                requeue(&self.central_db_pool, result.job_id, &event.node_id).await?
+            }
+        };
+
+        tracing::info!(
+            component = "compute_scheduler",
+            job_id = %result.job_id,
+            node_id = %event.node_id,
+            outcome = ?result.outcome,
+            applied = applied == 1,
+            "job result received"
+        );
+        Ok(())
+    }
+}
+
+// puts the job back in the queue, as long as `worker_id` still holds it
+async fn requeue(
+    pool: &sqlx::PgPool,
+    job_id: uuid::Uuid,
+    worker_id: &str,
+) -> Result<u64, sqlx::Error> {
+    Ok(sqlx::query(
+        r#"
+        UPDATE jobs
+        SET state = $2, worker_id = NULL, started_at = NULL, updated_at = now()
+        WHERE job_id = $1 AND state = ANY($3) AND worker_id = $4
+        "#,
+    )
+    .bind(job_id)
+    .bind(JobState::Queued.as_str())
+    .bind(JobState::Queued.allowed_from_strs())
+    .bind(worker_id)
+    .execute(pool)
+    .await?
+    .rows_affected())
+}
+
+/**
+ * # Brief
+ * Background scheduling loop, one pass every `interval`.
+ */
+pub async fn run_scheduler(scheduler: JobScheduler, interval: std::time::Duration) {
+    let mut ticker = tokio::time::interval(interval);
+    ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    loop {
+        ticker.tick().await;
+        if let Err(e) = scheduler.schedule_once().await {
+            tracing::error!(
+                error = %e,
+                component = "compute_scheduler",
+                "scheduling pass failed"
+            );
+        }
+    }
+}
+
+/**
+ * # Brief
+ * Background loop applying node events until the client is dropped.
+ */
+pub async fn run_event_loop(
+    scheduler: JobScheduler,
+    mut events: tokio::sync::mpsc::UnboundedReceiver<NodeEvent>,
+) {
+    while let Some(event) = events.recv().await {
+        if let Err(e) = scheduler.handle_event(event).await {
+            tracing::error!(
+                error = %e,
+                component = "compute_scheduler",
+                "failed to apply node event"
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crimson::black_channel_protocol::JobResult;
+    use crate::crimson::server_testing::{TestServer, delete_user, insert_user, unique_email};
+
+    fn load(worker_id: &str, capacity: i64, used_slots: i64) -> WorkerLoad {
+        WorkerLoad {
+            worker: ComputeWorker {
+                worker_id: worker_id.to_string(),
+                address: Some(format!("{}:7070", worker_id)),
+                capacity,
+                labels: std::collections::HashMap::new(),
+                org_id: None,
+                state: WorkerState::Alive,
+                registered_at: 0,
+                last_heartbeat: 0,
+            },
+            used_slots,
+        }
+    }
+
+    fn job(required_slots: i64, labels: &[(&str, &str)]) -> QueuedJob {
+        QueuedJob {
+            job_id: uuid::Uuid::now_v7(),
+            command: "true".to_string(),
+            required_slots,
+            labels: sqlx::types::Json(
+                labels
+                    .iter()
+                    .map(|(k, v)| (k.to_string(), v.to_string()))
+                    .collect(),
+            ),
+            org_id: None,
+        }
+    }
+
+    #[test]
+    fn first_fit_takes_the_first_candidate() {
+        let loads = [load("a", 4, 3), load("b", 4, 0)];
+        assert_eq!(FirstFit.place(&job(1, &[]), &loads), Some(0));
+        assert_eq!(FirstFit.place(&job(1, &[]), &[]), None);
+    }
+
+    #[test]
+    fn least_loaded_compares_ratios_not_free_slots() {
+        // a: 4/8 used, b: 1/2 used, c: 3/16 used
+        let loads = [load("a", 8, 4), load("b", 2, 1), load("c", 16, 3)];
+        assert_eq!(LeastLoaded.place(&job(1, &[]), &loads), Some(2));
+        assert_eq!(LeastLoaded.place(&job(1, &[]), &loads[..2]), Some(0));
+        assert_eq!(LeastLoaded.place(&job(1, &[]), &[]), None);
+    }
+
+    #[test]
+    fn placement_policy_names_round_trip() {
+        for name in ["first_fit", "least_loaded"] {
+            assert_eq!(placement_policy(name).unwrap().name(), name);
+        }
+        assert!(placement_policy("random").is_none());
+    }
+
+    #[test]
+    fn candidates_need_enough_free_slots() {
+        let loads = [load("a", 4, 3), load("b", 4, 2), load("c", 4, 0)];
+        let ids: Vec<String> = candidates(&job(2, &[]), &loads)
+            .into_iter()
+            .map(|l| l.worker.worker_id)
+            .collect();
+        assert_eq!(ids, ["b", "c"]);
+    }
+
+    #[test]
+    fn candidates_need_every_job_label() {
+        let mut gpu = load("gpu", 4, 0);
+        gpu.worker
+            .labels
+            .insert("gpu".to_string(), "a100".to_string());
+        gpu.worker
+            .labels
+            .insert("zone".to_string(), "eu".to_string());
+        let loads = [load("plain", 4, 0), gpu];
+
+        let picked = |labels: &[(&str, &str)]| -> Vec<String> {
+            candidates(&job(1, labels), &loads)
+                .into_iter()
+                .map(|l| l.worker.worker_id)
+                .collect()
+        };
+        assert_eq!(picked(&[]), ["plain", "gpu"]);
+        assert_eq!(picked(&[("gpu", "a100")]), ["gpu"]);
+        assert_eq!(picked(&[("gpu", "a100"), ("zone", "eu")]), ["gpu"]);
+        assert!(picked(&[("gpu", "h100")]).is_empty());
+        assert!(picked(&[("gpu", "a100"), ("zone", "us")]).is_empty());
+    }
+
+    #[test]
+    fn candidates_respect_dedicated_workers() {
+        let org = uuid::Uuid::now_v7();
+        let mut dedicated = load("dedicated", 4, 0);
+        dedicated.worker.org_id = Some(org);
+        let loads = [dedicated, load("shared", 4, 0)];
+
+        let mut org_job = job(1, &[]);
+        org_job.org_id = Some(org);
+        assert_eq!(candidates(&org_job, &loads).len(), 2);
+
+        let mut other_job = job(1, &[]);
+        other_job.org_id = Some(uuid::Uuid::now_v7());
+        let picked = candidates(&other_job, &loads);
+        assert_eq!(picked.len(), 1);
+        assert_eq!(picked[0].worker.worker_id, "shared");
+        assert_eq!(candidates(&job(1, &[]), &loads).len(), 1);
+    }
+
+    #[test]
+    fn fleet_is_full_once_no_slot_is_free() {
+        assert!(fleet_is_full(&[load("a", 2, 2), load("b", 1, 3)]));
+        assert!(!fleet_is_full(&[load("a", 2, 2), load("b", 4, 3)]));
+        // a job too big for the free slots leaves the fleet open for smaller ones
+        let loads = [load("a", 4, 3)];
+        assert!(!fleet_is_full(&loads));
+        assert!(candidates(&job(2, &[]), &loads).is_empty());
+    }
+
+    fn event(node_id: &str, job_id: uuid::Uuid, outcome: JobOutcome) -> NodeEvent {
+        NodeEvent {
+            address: format!("{}:7070", node_id),
+            node_id: node_id.to_string(),
+            frame: Frame::Result(JobResult {
+                job_id,
+                outcome,
+                exit_code: 0,
+                message: String::new(),
+            }),
+        }
+    }
+
+    async fn job_row(pool: &sqlx::PgPool, job_id: uuid::Uuid) -> (String, Option<String>) {
+        sqlx::query_as("SELECT state, worker_id FROM jobs WHERE job_id = $1")
+            .bind(job_id)
+            .fetch_one(pool)
+            .await
+            .unwrap()
+    }
+
+    #[actix_web::test]
+    async fn events_from_another_node_leave_the_job_alone() {
+        let server = TestServer::start("").await;
+        let pool = server.db();
+        let user_id = insert_user(pool, &unique_email("scheduler"), None, true).await;
+        let job_id = uuid::Uuid::now_v7();
+        sqlx::query(
+            "INSERT INTO jobs (job_id, user_id, name, command, state, worker_id) \
+             VALUES ($1, $2, 'stale', 'true', 'scheduled', 'node-b')",
+        )
+        .bind(job_id)
+        .bind(user_id)
+        .execute(pool)
+        .await
+        .unwrap();
+        let scheduler = &server.state.job_scheduler;
+
+        // node-a held the job before it was requeued onto node-b
+        for outcome in [
+            JobOutcome::Started,
+            JobOutcome::Succeeded,
+            JobOutcome::Rejected,
+        ] {
+            scheduler
+                .handle_event(event("node-a", job_id, outcome))
+                .await
+                .unwrap();
+            assert_eq!(
+                job_row(pool, job_id).await,
+                ("scheduled".to_string(), Some("node-b".to_string()))
+            );
+        }
+
+        scheduler
+            .handle_event(event("node-b", job_id, JobOutcome::Started))
+            .await
+            .unwrap();
+        assert_eq!(job_row(pool, job_id).await.0, "running");
+        scheduler
+            .handle_event(event("node-b", job_id, JobOutcome::Succeeded))
+            .await
+            .unwrap();
+        assert_eq!(job_row(pool, job_id).await.0, "succeeded");
+
+        delete_user(pool, user_id).await;
+    }
+}
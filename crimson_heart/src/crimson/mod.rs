@@ -1,3 +1,44 @@
+pub mod api_admin_defs;
+pub mod api_admin_types;
 pub mod api_auth_defs;
 pub mod api_auth_types;
+pub mod api_compute_defs;
+pub mod api_compute_types;
+pub mod api_keys_defs;
+pub mod api_keys_types;
+pub mod api_oauth_defs;
+pub mod api_oauth_types;
+pub mod api_oidc_defs;
+pub mod api_oidc_types;
+pub mod api_orgs_defs;
+pub mod api_orgs_types;
+pub mod api_totp_defs;
+pub mod api_totp_types;
+pub mod api_webauthn_defs;
+pub mod api_webauthn_types;
+pub mod black_channel_client;
+pub mod black_channel_mock;
+pub mod black_channel_protocol;
+pub mod compute_registry;
+pub mod compute_scheduler;
+pub mod server_api_keys;
+pub mod server_breached;
+pub mod server_config;
+pub mod server_errors;
+pub mod server_hashing;
+pub mod server_jws;
+pub mod server_mailer;
+pub mod server_migrations;
+pub mod server_oauth;
+pub mod server_oidc;
+pub mod server_orgs;
+pub mod server_passwords;
+pub mod server_rate_limit;
+pub mod server_roles;
+pub mod server_sessions;
+#[cfg(test)]
+pub mod server_testing;
+pub mod server_totp;
 pub mod server_types;
+pub mod server_validation;
+pub mod server_webauthn;
@@ -0,0 +1,209 @@
+use super::server_errors::ApiError;
+use super::server_orgs;
+use super::server_sessions::AuthenticatedUser;
+use super::server_types::ServerState;
+
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+
+/*
+ * Personal API keys, `crimson_` followed by 64 hex characters.
+ *
+ * Only the SHA-256 of a key is stored, keys carry 256 random bits so a fast
+ * hash is enough. Scopes limit what a key may do, cookie sessions may do
+ * everything. A key works in the workspace it was created in, & stops
+ * working when its user leaves that organization.
+ */
+
+pub const KEY_PREFIX: &str = "crimson_";
+const KEY_BYTES: usize = 32;
+// characters of the key kept in clear for listings
+const DISPLAY_PREFIX_LENGTH: usize = KEY_PREFIX.len() + 8;
+
+pub const SCOPE_JOBS_READ: &str = "jobs:read";
+pub const SCOPE_JOBS_WRITE: &str = "jobs:write";
+pub const SCOPE_WORKERS_READ: &str = "workers:read";
+pub const SCOPES: [&str; 3] = [SCOPE_JOBS_READ, SCOPE_JOBS_WRITE, SCOPE_WORKERS_READ];
+
+const CODE_INVALID_API_KEY: &str = "invalid_api_key";
+
+/// a fresh key & its display prefix
+pub fn new_api_key() -> (String, String) {
+    let mut key_bytes = [0u8; KEY_BYTES];
+    OsRng.fill_bytes(&mut key_bytes);
+    let key = format!("{}{}", KEY_PREFIX, hex::encode(key_bytes));
+    let prefix = key[..DISPLAY_PREFIX_LENGTH].to_string();
+    (key, prefix)
+}
+
+pub fn hash_api_key(key: &str) -> String {
+    use sha2::Digest;
+    hex::encode(sha2::Sha256::digest(key.as_bytes()))
+}
+
+fn is_well_formed_api_key(key: &str) -> bool {
+    key.strip_prefix(KEY_PREFIX).is_some_and(|secret| {
+        secret.len() == KEY_BYTES * 2 && secret.bytes().all(|b| b.is_ascii_hexdigit())
+    })
+}
+
+/// a caller of the compute API, signed in by cookie or by API key
+#[derive(Debug, Clone)]
+pub struct AuthenticatedCaller {
+    pub user_id: uuid::Uuid,
+    /// the key used, None for cookie sessions
+    pub key_id: Option<uuid::Uuid>,
+    /// what the key may do, None for cookie sessions which may do everything
+    pub scopes: Option<Vec<String>>,
+    /// organization the caller works in, None for the personal workspace
+    pub org_id: Option<uuid::Uuid>,
+}
+
+impl AuthenticatedCaller {
+    /**
+     * # Brief
+     * 403 unless the caller may use `scope`.
+     */
+    pub fn require_scope(&self, scope: &str) -> Result<(), ApiError> {
+        match &self.scopes {
+            Some(scopes) if !scopes.iter().any(|granted| granted == scope) => {
+                tracing::info!(
+                    component = "api_keys",
+                    key_id = ?self.key_id,
+                    scope = scope,
+                    "api key lacks scope"
+                );
+                Err(ApiError::forbidden(
+                    "missing_scope",
+                    format!("Missing Scope {}", scope),
+                ))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    async fn load(request: actix_web::HttpRequest) -> Result<Self, ApiError> {
+        let bearer = request
+            .headers()
+            .get(actix_web::http::header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .map(str::to_string);
+        let Some(server_state) = request.app_data::<actix_web::web::Data<ServerState>>() else {
+            return Err(ApiError::internal(
+                "api_keys",
+                "app_data",
+                "server state missing from app data",
+            ));
+        };
+        let Some(key) = bearer else {
+            let user = AuthenticatedUser::load(request.clone()).await?;
+            // the session may have switched before the user was removed
+            if let Some(org_id) = user.org_id
+                && server_orgs::member_role(&server_state.central_db_pool, org_id, user.user_id)
+                    .await
+                    .map_err(|e| ApiError::database(e, "SELECT", "org_members"))?
+                    .is_none()
+            {
+                tracing::info!(
+                    component = "orgs",
+                    user_id = %user.user_id,
+                    org_id = %org_id,
+                    "session switched to an organization it left"
+                );
+                return Err(ApiError::forbidden(
+                    server_orgs::CODE_NOT_ORG_MEMBER,
+                    "Not a member of the active organization, switch workspace",
+                ));
+            }
+            return Ok(AuthenticatedCaller {
+                user_id: user.user_id,
+                key_id: None,
+                scopes: None,
+                org_id: user.org_id,
+            });
+        };
+
+        if !is_well_formed_api_key(&key) {
+            return Err(ApiError::unauthorized(
+                CODE_INVALID_API_KEY,
+                "Invalid API Key",
+            ));
+        }
+
+        // unverified accounts can't use keys, the same as their sessions,
+        // nor can former members use the keys of their organization
+        let found = sqlx::query_as::<_, (uuid::Uuid, uuid::Uuid, Vec<String>, Option<uuid::Uuid>)>(
+            r#"
+            SELECT api_keys.key_id, api_keys.user_id, api_keys.scopes, api_keys.org_id
+            FROM api_keys
+            JOIN users ON users.user_id = api_keys.user_id
+            LEFT JOIN org_members
+              ON org_members.org_id = api_keys.org_id AND org_members.user_id = api_keys.user_id
+            WHERE api_keys.key_hash = $1
+              AND (api_keys.expires_at IS NULL OR api_keys.expires_at > now())
+              AND users.email_verified_at IS NOT NULL
+              AND (api_keys.org_id IS NULL OR org_members.user_id IS NOT NULL)
+            "#,
+        )
+        .bind(hash_api_key(&key))
+        .fetch_optional(&server_state.central_db_pool)
+        .await
+        .map_err(|e| ApiError::database(e, "SELECT", "api_keys"))?;
+        let Some((key_id, user_id, scopes, org_id)) = found else {
+            tracing::info!(component = "api_keys", "unknown or expired api key");
+            return Err(ApiError::unauthorized(
+                CODE_INVALID_API_KEY,
+                "Invalid API Key",
+            ));
+        };
+
+        // at most one write a minute per key, not one per request
+        if let Err(e) = sqlx::query(
+            r#"
+            UPDATE api_keys
+            SET last_used_at = now()
+            WHERE key_id = $1
+              AND (last_used_at IS NULL OR last_used_at < now() - INTERVAL '1 minute')
+            "#,
+        )
+        .bind(key_id)
+        .execute(&server_state.central_db_pool)
+        .await
+        {
+            tracing::warn!(
+                error = %e,
+                component = "database",
+                query = "UPDATE",
+                table = "api_keys",
+                "failed to record api key use"
+            );
+        }
+
+        Ok(AuthenticatedCaller {
+            user_id,
+            key_id: Some(key_id),
+            scopes: Some(scopes),
+            org_id,
+        })
+    }
+}
+
+/**
+ * # Brief
+ * Extracts the caller from `Authorization: Bearer <API key>` or the `session_id` Cookie.
+ *
+ * # Detail
+ * - A bearer header is never mixed with the cookie, a bad key is a 401.
+ * - Without one, behaves like `AuthenticatedUser`.
+ */
+impl actix_web::FromRequest for AuthenticatedCaller {
+    type Error = ApiError;
+    type Future = std::pin::Pin<Box<dyn std::future::Future<Output = Result<Self, Self::Error>>>>;
+
+    fn from_request(
+        request: &actix_web::HttpRequest,
+        _payload: &mut actix_web::dev::Payload,
+    ) -> Self::Future {
+        Box::pin(AuthenticatedCaller::load(request.clone()))
+    }
+}
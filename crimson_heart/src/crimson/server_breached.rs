@@ -0,0 +1,213 @@
+use sha2::Digest;
+use std::io::BufRead;
+
+/*
+ * Breached & common passwords, loaded from a local corpus so checks work offline.
+ *
+ * The corpus is a text file with one password per line (e.g. SecLists or a
+ * breach dump), kept in memory as a bloom filter: a few bits per entry instead
+ * of the passwords themselves. A lookup may wrongly report a match about once
+ * every `1 / FALSE_POSITIVE_RATE` passwords, it never misses a listed one.
+ *
+ * Lines are hashed as raw bytes, dumps like rockyou.txt are not valid UTF-8
+ * throughout. Their non UTF-8 entries simply never match a submitted password.
+ */
+
+const FALSE_POSITIVE_RATE: f64 = 0.001;
+
+#[derive(Debug)]
+pub struct BreachedPasswords {
+    bits: Vec<u64>,
+    bit_count: u64,
+    hash_count: u32,
+    entries: usize,
+}
+
+// line endings only, passwords may start or end with spaces
+fn corpus_entry(line: &[u8]) -> Option<&[u8]> {
+    let entry = line.strip_suffix(b"\n").unwrap_or(line);
+    let entry = entry.strip_suffix(b"\r").unwrap_or(entry);
+    (!entry.is_empty()).then_some(entry)
+}
+
+// calls `f` with every entry of the corpus at `path`
+fn for_each_entry(path: &std::path::Path, mut f: impl FnMut(&[u8])) -> std::io::Result<()> {
+    let mut reader = std::io::BufReader::new(std::fs::File::open(path)?);
+    let mut line = Vec::new();
+    loop {
+        line.clear();
+        if reader.read_until(b'\n', &mut line)? == 0 {
+            return Ok(());
+        }
+        if let Some(entry) = corpus_entry(&line) {
+            f(entry);
+        }
+    }
+}
+
+// two independent hashes from one SHA-256 digest, combined per probe
+fn digest_pair(password: &[u8]) -> (u64, u64) {
+    let digest = sha2::Sha256::digest(password);
+    let mut first = [0u8; 8];
+    let mut second = [0u8; 8];
+    first.copy_from_slice(&digest[..8]);
+    second.copy_from_slice(&digest[8..16]);
+    // odd, so probes never collapse onto the same bit
+    (u64::from_le_bytes(first), u64::from_le_bytes(second) | 1)
+}
+
+impl BreachedPasswords {
+    /**
+     * # Brief
+     * Loads the corpus at `path` into a bloom filter.
+     *
+     * # Detail
+     * - The file is read twice, once to size the filter & once to fill it.
+     * - Empty lines are skipped, everything else is taken verbatim, in any encoding.
+     */
+    pub fn load(path: &std::path::Path) -> std::io::Result<BreachedPasswords> {
+        let mut entries = 0usize;
+        for_each_entry(path, |_| entries += 1)?;
+
+        let mut breached = BreachedPasswords::with_capacity(entries);
+        for_each_entry(path, |entry| breached.insert(entry))?;
+        Ok(breached)
+    }
+
+    // optimal size & probe count for `FALSE_POSITIVE_RATE`
+    fn with_capacity(entries: usize) -> BreachedPasswords {
+        let ln2 = std::f64::consts::LN_2;
+        let entries = entries.max(1) as f64;
+        let bit_count = (-entries * FALSE_POSITIVE_RATE.ln() / (ln2 * ln2)).ceil() as u64;
+        let bit_count = bit_count.max(64);
+        let hash_count = (bit_count as f64 / entries * ln2).round().max(1.0) as u32;
+        BreachedPasswords {
+            bits: vec![0; bit_count.div_ceil(64) as usize],
+            bit_count,
+            hash_count,
+            entries: 0,
+        }
+    }
+
+    fn probes(&self, password: &[u8]) -> impl Iterator<Item = u64> + use<> {
+        let (first, second) = digest_pair(password);
+        let bit_count = self.bit_count;
+        (0..u64::from(self.hash_count))
+            .map(move |i| first.wrapping_add(i.wrapping_mul(second)) % bit_count)
+    }
+
+    fn insert(&mut self, password: &[u8]) {
+        for bit in self.probes(password) {
+            self.bits[(bit / 64) as usize] |= 1 << (bit % 64);
+        }
+        self.entries += 1;
+    }
+
+    fn contains_exact(&self, password: &[u8]) -> bool {
+        self.probes(password)
+            .all(|bit| self.bits[(bit / 64) as usize] & (1 << (bit % 64)) != 0)
+    }
+
+    /// true when the password, or its lowercase spelling, is in the corpus
+    pub fn contains(&self, password: &str) -> bool {
+        self.contains_exact(password.as_bytes())
+            || self.contains_exact(password.to_lowercase().as_bytes())
+    }
+
+    /// number of corpus lines loaded
+    pub fn entries(&self) -> usize {
+        self.entries
+    }
+
+    /// memory used by the filter, in bytes
+    pub fn size(&self) -> usize {
+        self.bits.len() * std::mem::size_of::<u64>()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // corpus file removed on drop
+    struct Corpus(std::path::PathBuf);
+
+    impl Corpus {
+        fn new(name: &str, content: &[u8]) -> Corpus {
+            let path = std::env::temp_dir().join(format!(
+                "crimson-breached-{}-{}.txt",
+                name,
+                std::process::id()
+            ));
+            std::fs::write(&path, content).unwrap();
+            Corpus(path)
+        }
+    }
+
+    impl Drop for Corpus {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.0);
+        }
+    }
+
+    #[test]
+    fn loads_non_utf8_lines() {
+        // `caf\xe9` is Latin-1, as found in rockyou.txt
+        let corpus = Corpus::new("latin1", b"password\r\ncaf\xe9\n\nletmein");
+        let breached = BreachedPasswords::load(&corpus.0).unwrap();
+        assert_eq!(breached.entries(), 3);
+        assert!(breached.contains("password"));
+        assert!(breached.contains("letmein"));
+        assert!(!breached.contains("café"));
+    }
+
+    #[test]
+    fn finds_every_inserted_entry() {
+        let mut breached = BreachedPasswords::with_capacity(1000);
+        for i in 0..1000 {
+            breached.insert(format!("listed-{}", i).as_bytes());
+        }
+        assert_eq!(breached.entries(), 1000);
+        assert!((0..1000).all(|i| breached.contains(&format!("listed-{}", i))));
+
+        // hashes are deterministic, so is the false positive count
+        let false_positives = (0..10_000)
+            .filter(|i| breached.contains(&format!("unlisted-{}", i)))
+            .count();
+        assert!(false_positives < 50, "{} false positives", false_positives);
+    }
+
+    #[test]
+    fn falls_back_to_the_lowercase_spelling() {
+        let mut breached = BreachedPasswords::with_capacity(2);
+        breached.insert(b"password1");
+        breached.insert(b"LetMeIn");
+
+        assert!(breached.contains("password1"));
+        assert!(breached.contains("Password1"));
+        assert!(breached.contains("PASSWORD1"));
+        // the corpus spelling is not folded, only the submitted one
+        assert!(breached.contains("LetMeIn"));
+        assert!(!breached.contains("letmein"));
+        assert!(!breached.contains("password2"));
+    }
+
+    #[test]
+    fn keeps_surrounding_spaces() {
+        let corpus = Corpus::new("spaces", b" secret \nhunter2\n");
+        let breached = BreachedPasswords::load(&corpus.0).unwrap();
+        assert_eq!(breached.entries(), 2);
+        assert!(breached.contains(" secret "));
+        assert!(!breached.contains("secret"));
+        assert!(breached.contains("hunter2"));
+    }
+
+    #[test]
+    fn empty_corpus_matches_nothing() {
+        let corpus = Corpus::new("empty", b"\n\r\n");
+        let breached = BreachedPasswords::load(&corpus.0).unwrap();
+        assert_eq!(breached.entries(), 0);
+        assert!(breached.size() > 0);
+        assert!(!breached.contains("password"));
+    }
+}
@@ -0,0 +1,1829 @@
+use serde::Deserialize;
+
+// environment variable names
+const CENTRAL_DB_INSTANCE_KEY: &str = "CENTRAL_DATABASE_INSTANCE";
+const MAX_THREADS_KEY: &str = "CRIMSON_MAX_THREADS";
+const REDIS_CLUSTER_KEY: &str = "REDIS_CLUSTER_INSTANCE";
+const REDIS_POOL_SIZE_KEY: &str = "REDIS_POOL_SIZE";
+const LOKI_URL_KEY: &str = "LOKI_URL";
+const CRIMSON_HASH_SALT_KEY: &str = "CRIMSON_HASH_SALT";
+const CRIMSON_HASH_SALT_ID_KEY: &str = "CRIMSON_HASH_SALT_ID";
+const TRACE_LEVEL_KEY: &str = "TRACE_LEVEL";
+const BIND_ADDRESS_KEY: &str = "CRIMSON_BIND_ADDRESS";
+const WORKERS_KEY: &str = "CRIMSON_WORKERS";
+const SESSION_TTL_KEY: &str = "CRIMSON_SESSION_TTL";
+const CONFIG_PATH_KEY: &str = "CRIMSON_CONFIG";
+const AUTO_MIGRATE_KEY: &str = "CRIMSON_AUTO_MIGRATE";
+const WORKER_TOKEN_KEY: &str = "CRIMSON_WORKER_TOKEN";
+const HEARTBEAT_TIMEOUT_KEY: &str = "CRIMSON_HEARTBEAT_TIMEOUT";
+const SCHEDULER_POLICY_KEY: &str = "CRIMSON_SCHEDULER_POLICY";
+const MAIL_TRANSPORT_KEY: &str = "CRIMSON_MAIL_TRANSPORT";
+const MAIL_FILE_PATH_KEY: &str = "CRIMSON_MAIL_FILE_PATH";
+const PUBLIC_URL_KEY: &str = "CRIMSON_PUBLIC_URL";
+const RATE_LIMIT_ENABLED_KEY: &str = "CRIMSON_RATE_LIMIT_ENABLED";
+const TOTP_KEY_KEY: &str = "CRIMSON_TOTP_KEY";
+const WEBAUTHN_RP_ID_KEY: &str = "CRIMSON_WEBAUTHN_RP_ID";
+const WEBAUTHN_ORIGIN_KEY: &str = "CRIMSON_WEBAUTHN_ORIGIN";
+const OAUTH_SIGNING_KEY_KEY: &str = "CRIMSON_OAUTH_SIGNING_KEY";
+const BOOTSTRAP_ADMIN_KEY: &str = "CRIMSON_BOOTSTRAP_ADMIN";
+const BREACHED_PASSWORDS_KEY: &str = "CRIMSON_BREACHED_PASSWORDS";
+
+const DEFAULT_CONFIG_PATH: &str = "config/crimson.toml";
+const DEFAULT_BIND_ADDRESS: &str = "127.0.0.1:8080";
+const DEFAULT_SESSION_TTL: i64 = 86400;
+const DEFAULT_REDIS_POOL_SIZE: usize = 64;
+const DEFAULT_HEARTBEAT_TIMEOUT: i64 = 30;
+const DEFAULT_REAPER_INTERVAL: u64 = 10;
+const DEFAULT_SCHEDULER_POLICY: &str = "first_fit";
+const DEFAULT_SCHEDULER_INTERVAL_MS: u64 = 1000;
+const DEFAULT_SCHEDULER_BATCH_SIZE: i64 = 100;
+const SCHEDULER_POLICIES: [&str; 2] = ["first_fit", "least_loaded"];
+const DEFAULT_MAIL_TRANSPORT: &str = "stdout";
+const DEFAULT_MAIL_FROM: &str = "crimson@localhost";
+const DEFAULT_PUBLIC_URL: &str = "http://127.0.0.1:8080";
+const MAIL_TRANSPORTS: [&str; 2] = ["stdout", "file"];
+const DEFAULT_HASH_SALT_ID: &str = "1";
+const DEFAULT_PASSWORD_RESET_TTL: i64 = 1800;
+const DEFAULT_EMAIL_VERIFICATION_TTL: i64 = 86400;
+const DEFAULT_TOTP_ISSUER: &str = "crimson";
+const DEFAULT_WEBAUTHN_RP_ID: &str = "localhost";
+const DEFAULT_WEBAUTHN_RP_NAME: &str = "crimson";
+const DEFAULT_WEBAUTHN_ORIGIN: &str = "http://localhost:8080";
+const DEFAULT_WEBAUTHN_CHALLENGE_TTL: i64 = 300;
+const DEFAULT_OIDC_STATE_TTL: i64 = 600;
+const DEFAULT_OIDC_JWKS_TTL: u64 = 3600;
+const DEFAULT_OIDC_LOGIN_REDIRECT: &str = "/";
+const DEFAULT_OAUTH_CODE_TTL: i64 = 60;
+const DEFAULT_OAUTH_ACCESS_TOKEN_TTL: i64 = 3600;
+const DEFAULT_OAUTH_ID_TOKEN_TTL: i64 = 3600;
+// keeps every user submitting jobs, as before roles existed
+const DEFAULT_RBAC_PERMISSIONS: [&str; 1] = ["jobs:submit"];
+const DEFAULT_ORGS_INVITATION_TTL: i64 = 604800;
+const DEFAULT_PASSWORD_MIN_LENGTH: usize = 8;
+const DEFAULT_PASSWORD_MAX_LENGTH: usize = 128;
+// jobs waiting per hashing thread, each adds one hash time to the worst latency
+const DEFAULT_HASHING_QUEUE_PER_WORKER: usize = 8;
+// path, requests, window in seconds
+const DEFAULT_ROUTE_LIMITS: [(&str, u32, u64); 8] = [
+    ("/auth/login", 10, 60),
+    ("/auth/register", 5, 60),
+    ("/auth/verify/resend", 3, 900),
+    ("/auth/password/forgot", 5, 300),
+    ("/auth/password/reset", 10, 300),
+    ("/auth/login/totp", 10, 60),
+    ("/auth/webauthn/login/begin", 20, 60),
+    ("/auth/webauthn/login/finish", 10, 60),
+];
+const DEFAULT_LOCKOUT_THRESHOLD: u32 = 5;
+const DEFAULT_LOCKOUT_BASE: u64 = 30;
+const DEFAULT_LOCKOUT_MAX: u64 = 3600;
+
+/// command line flags, highest precedence layer, accepted before or after the subcommand
+#[derive(clap::Parser, Debug, Default)]
+#[command(name = "crimson_heart", about = "Crimson Heart control server")]
+pub struct ServerArgs {
+    #[command(subcommand)]
+    pub command: Option<ServerCommand>,
+
+    /// path to the TOML configuration file
+    #[arg(long, global = true)]
+    pub config: Option<std::path::PathBuf>,
+
+    /// address the HTTP server binds to, e.g. 127.0.0.1:8080
+    #[arg(long, global = true)]
+    pub bind: Option<String>,
+
+    /// number of actix worker threads
+    #[arg(long, global = true)]
+    pub workers: Option<usize>,
+
+    /// session lifetime in seconds
+    #[arg(long, global = true)]
+    pub session_ttl: Option<i64>,
+
+    /// central database url
+    #[arg(long, global = true)]
+    pub database_url: Option<String>,
+
+    /// central database connection pool size
+    #[arg(long, global = true)]
+    pub database_pool_size: Option<u32>,
+
+    /// redis url
+    #[arg(long, global = true)]
+    pub redis_url: Option<String>,
+
+    /// redis connection pool size
+    #[arg(long, global = true)]
+    pub redis_pool_size: Option<usize>,
+
+    /// loki push url
+    #[arg(long, global = true)]
+    pub loki_url: Option<String>,
+
+    /// tracing filter, e.g. `info` or `debug`
+    #[arg(long, global = true)]
+    pub trace_level: Option<String>,
+}
+
+#[derive(clap::Subcommand, Debug)]
+pub enum ServerCommand {
+    /// run the HTTP server (default)
+    Serve,
+    /// inspect or apply central database migrations
+    Migrate {
+        #[command(subcommand)]
+        action: MigrateAction,
+    },
+    /// talk to black_channel nodes, or pretend to be one
+    BlackChannel {
+        #[command(subcommand)]
+        action: BlackChannelAction,
+    },
+    /// register & remove clients of the OpenID provider
+    OauthClient {
+        #[command(subcommand)]
+        action: OauthClientAction,
+    },
+    /// grant & revoke roles, bootstraps the first admin
+    Role {
+        #[command(subcommand)]
+        action: RoleAction,
+    },
+    /// time password hashing with candidate Argon2 parameters on this host
+    BenchHash {
+        /// memory cost in KiB, comma separated candidates, defaults to `password.argon2_memory`
+        #[arg(long, value_delimiter = ',')]
+        memory: Vec<u32>,
+        /// passes over memory, comma separated candidates
+        #[arg(long, value_delimiter = ',')]
+        iterations: Vec<u32>,
+        /// lanes, comma separated candidates
+        #[arg(long, value_delimiter = ',')]
+        parallelism: Vec<u32>,
+        /// hashes timed per candidate
+        #[arg(long, default_value_t = 5, value_parser = clap::value_parser!(u32).range(1..))]
+        samples: u32,
+    },
+}
+
+#[derive(clap::Subcommand, Debug)]
+pub enum OauthClientAction {
+    /// register a client, prints its id & secret, the secret is not shown again
+    Add {
+        #[arg(long)]
+        name: String,
+        /// exact redirect uri the client may use, repeatable
+        #[arg(long = "redirect-uri", required = true)]
+        redirect_uris: Vec<String>,
+        /// scope the client may request, repeatable, defaults to every scope
+        #[arg(long = "scope")]
+        scopes: Vec<String>,
+        /// no secret, the client must use PKCE
+        #[arg(long)]
+        public: bool,
+    },
+    /// list registered clients
+    List,
+    /// remove a client, tokens it holds stay valid until they expire
+    Remove { client_id: String },
+}
+
+#[derive(clap::Subcommand, Debug)]
+pub enum RoleAction {
+    /// list roles, their permissions & holders
+    List,
+    /// grant a role to the user with `email`, e.g. the first `admin`
+    Grant {
+        #[arg(long)]
+        email: String,
+        #[arg(long)]
+        role: String,
+    },
+    /// revoke a role, the last `admin` is kept
+    Revoke {
+        #[arg(long)]
+        email: String,
+        #[arg(long)]
+        role: String,
+    },
+}
+
+#[derive(clap::Subcommand, Debug)]
+pub enum BlackChannelAction {
+    /// run an in-process mock black_channel node
+    MockNode {
+        #[arg(long, default_value = "127.0.0.1:8000")]
+        listen: String,
+        #[arg(long, default_value_t = 4)]
+        capacity: u32,
+        /// seconds every mock job runs for
+        #[arg(long, default_value_t = 2)]
+        job_seconds: u64,
+    },
+    /// handshake with a node, dispatch & cancel probe jobs, print the replies
+    Probe {
+        #[arg(long, default_value = "127.0.0.1:8000")]
+        address: String,
+    },
+}
+
+#[derive(clap::Subcommand, Debug)]
+pub enum MigrateAction {
+    /// list applied & pending migrations
+    Status,
+    /// apply every pending migration
+    Up,
+    /// revert applied migrations down to (and keeping) `--to`
+    Down {
+        #[arg(long)]
+        to: i64,
+    },
+}
+
+#[derive(Debug, Clone)]
+pub struct ServerConfig {
+    pub server: ServerSection,
+    pub database: DatabaseSection,
+    pub redis: RedisSection,
+    pub telemetry: TelemetrySection,
+    pub security: SecuritySection,
+    pub compute: ComputeSection,
+    pub scheduler: SchedulerSection,
+    pub mail: MailSection,
+    pub rate_limit: RateLimitSection,
+    pub webauthn: WebauthnSection,
+    pub oidc: OidcSection,
+    pub oauth: OauthSection,
+    pub rbac: RbacSection,
+    pub orgs: OrgsSection,
+    pub password: PasswordSection,
+}
+
+#[derive(Debug, Clone)]
+pub struct ServerSection {
+    pub bind_address: std::net::SocketAddr,
+    pub workers: usize,
+    pub session_ttl: i64,
+}
+
+#[derive(Debug, Clone)]
+pub struct DatabaseSection {
+    pub url: String,
+    pub pool_size: u32,
+    pub auto_migrate: bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct RedisSection {
+    pub url: String,
+    pub pool_size: usize,
+}
+
+#[derive(Debug, Clone)]
+pub struct TelemetrySection {
+    pub loki_url: url::Url,
+    pub trace_level: String,
+}
+
+#[derive(Debug, Clone)]
+pub struct SecuritySection {
+    /// pepper of new password hashes, the Argon2 secret
+    pub hash_salt: String,
+    /// id of `hash_salt`, recorded in every hash it makes
+    pub hash_salt_id: String,
+    /// retired peppers by id, hashes made with them still verify until re-hashed on login
+    pub previous_hash_salts: std::collections::HashMap<String, String>,
+    /// seconds a password reset token stays valid
+    pub password_reset_ttl: i64,
+    /// seconds an email verification token stays valid
+    pub email_verification_ttl: i64,
+    /// AES-256 key sealing TOTP secrets in the database, 2FA is disabled without it
+    pub totp_key: Option<[u8; 32]>,
+    /// issuer shown by authenticator apps
+    pub totp_issuer: String,
+}
+
+#[derive(Debug, Clone)]
+pub struct ComputeSection {
+    /// shared secret compute workers present as `Authorization: Bearer`
+    pub worker_token: Option<String>,
+    /// seconds without a heartbeat before a worker is marked dead
+    pub heartbeat_timeout: i64,
+    /// seconds between dead worker sweeps
+    pub reaper_interval: u64,
+}
+
+#[derive(Debug, Clone)]
+pub struct SchedulerSection {
+    /// placement policy, `first_fit` or `least_loaded`
+    pub policy: String,
+    /// milliseconds between scheduling passes
+    pub interval_ms: u64,
+    /// queued jobs considered per pass
+    pub batch_size: i64,
+}
+
+#[derive(Debug, Clone)]
+pub struct MailSection {
+    /// `stdout` or `file`
+    pub transport: String,
+    pub from_address: String,
+    /// mailbox file for the `file` transport
+    pub file_path: Option<std::path::PathBuf>,
+    /// externally reachable base url, links in emails point here
+    pub public_url: url::Url,
+}
+
+/// at most `limit` requests per sliding `window` seconds
+#[derive(Deserialize, Debug, Clone, Copy)]
+#[serde(deny_unknown_fields)]
+pub struct RouteLimit {
+    pub limit: u32,
+    pub window: u64,
+}
+
+#[derive(Debug, Clone)]
+pub struct RateLimitSection {
+    pub enabled: bool,
+    /// request path -> limit, e.g. `/auth/login`
+    pub routes: std::collections::HashMap<String, RouteLimit>,
+    /// consecutive login failures before an email is locked out
+    pub lockout_threshold: u32,
+    /// seconds of the first lockout, doubled by every further failure
+    pub lockout_base: u64,
+    /// longest lockout in seconds, also how long failures are remembered
+    pub lockout_max: u64,
+    /// peers whose `Forwarded` / `X-Forwarded-For` are believed, anybody else is keyed on its own address
+    pub trusted_proxies: Vec<std::net::IpAddr>,
+}
+
+#[derive(Debug, Clone)]
+pub struct WebauthnSection {
+    /// relying party id, the domain passkeys are scoped to
+    pub rp_id: String,
+    /// relying party name shown by the authenticator
+    pub rp_name: String,
+    /// origin the browser reports, scheme://host[:port]
+    pub origin: String,
+    /// seconds a registration or login challenge stays valid
+    pub challenge_ttl: i64,
+}
+
+fn default_oidc_scopes() -> Vec<String> {
+    vec![
+        "openid".to_string(),
+        "email".to_string(),
+        "profile".to_string(),
+    ]
+}
+
+/// an OpenID Connect provider users may sign in with
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct OidcProvider {
+    /// label for the login button, defaults to the provider name
+    #[serde(default)]
+    pub display_name: Option<String>,
+    /// discovery is fetched from `<issuer>/.well-known/openid-configuration`
+    pub issuer: String,
+    pub client_id: String,
+    /// confidential clients only, PKCE is used either way
+    #[serde(default)]
+    pub client_secret: Option<String>,
+    #[serde(default = "default_oidc_scopes")]
+    pub scopes: Vec<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct OidcSection {
+    /// provider name -> provider, the name appears in `/auth/oidc/<name>/...`
+    pub providers: std::collections::HashMap<String, OidcProvider>,
+    /// seconds a pending login (state, nonce & PKCE verifier) stays valid
+    pub state_ttl: i64,
+    /// seconds discovery documents & signing keys are cached
+    pub jwks_ttl: u64,
+    /// where the browser is sent after a successful login, a path or an absolute url
+    pub login_redirect: String,
+}
+
+#[derive(Debug, Clone)]
+pub struct OauthSection {
+    /// P-256 private key signing issued tokens, the provider is disabled without it
+    pub signing_key: Option<[u8; 32]>,
+    /// seconds an authorization code stays redeemable
+    pub code_ttl: i64,
+    /// seconds access tokens stay valid
+    pub access_token_ttl: i64,
+    /// seconds ID tokens stay valid
+    pub id_token_ttl: i64,
+    /// login page for browsers without a session, gets `return_to`
+    pub login_url: Option<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct RbacSection {
+    /// permissions every signed in user has, on top of their roles
+    pub default_permissions: Vec<String>,
+    /// email of a user made admin on start while nobody is
+    pub bootstrap_admin: Option<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct OrgsSection {
+    /// seconds an organization invitation stays acceptable
+    pub invitation_ttl: i64,
+}
+
+#[derive(Debug, Clone)]
+pub struct PasswordSection {
+    /// shortest accepted password, in characters
+    pub min_length: usize,
+    /// longest accepted password, in characters
+    pub max_length: usize,
+    pub require_lowercase: bool,
+    pub require_uppercase: bool,
+    pub require_digit: bool,
+    /// anything that is neither a letter nor a digit
+    pub require_symbol: bool,
+    /// corpus of breached & common passwords, one per line, new passwords found in it are refused
+    pub breached_list: Option<std::path::PathBuf>,
+    /// Argon2id costs of new hashes, weaker stored hashes are re-hashed on login
+    pub argon2: argon2::Params,
+    /// threads hashing passwords, nothing else runs on them
+    pub hashing_workers: usize,
+    /// hashing jobs allowed to wait for a thread, more are answered 503
+    pub hashing_queue: usize,
+}
+
+/// every problem found while resolving the configuration, reported at once
+#[derive(Debug, Default)]
+pub struct ConfigError {
+    pub errors: Vec<String>,
+}
+
+impl std::fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(
+            f,
+            "[crimson]: configuration has {} error(s)",
+            self.errors.len()
+        )?;
+        for error in &self.errors {
+            writeln!(f, "  - {}", error)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+// a single layer of configuration, every value is optional so layers can be
+// stacked as file <- environment <- command line
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+struct ConfigLayer {
+    server: ServerLayer,
+    database: DatabaseLayer,
+    redis: RedisLayer,
+    telemetry: TelemetryLayer,
+    security: SecurityLayer,
+    compute: ComputeLayer,
+    scheduler: SchedulerLayer,
+    mail: MailLayer,
+    rate_limit: RateLimitLayer,
+    webauthn: WebauthnLayer,
+    oidc: OidcLayer,
+    oauth: OauthLayer,
+    rbac: RbacLayer,
+    orgs: OrgsLayer,
+    password: PasswordLayer,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+struct ServerLayer {
+    bind_address: Option<String>,
+    workers: Option<usize>,
+    session_ttl: Option<i64>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+struct DatabaseLayer {
+    url: Option<String>,
+    pool_size: Option<u32>,
+    auto_migrate: Option<bool>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+struct RedisLayer {
+    url: Option<String>,
+    pool_size: Option<usize>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+struct TelemetryLayer {
+    loki_url: Option<String>,
+    trace_level: Option<String>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+struct SecurityLayer {
+    hash_salt: Option<String>,
+    hash_salt_id: Option<String>,
+    previous_hash_salts: Option<std::collections::HashMap<String, String>>,
+    password_reset_ttl: Option<i64>,
+    email_verification_ttl: Option<i64>,
+    totp_key: Option<String>,
+    totp_issuer: Option<String>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+struct ComputeLayer {
+    worker_token: Option<String>,
+    heartbeat_timeout: Option<i64>,
+    reaper_interval: Option<u64>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+struct SchedulerLayer {
+    policy: Option<String>,
+    interval_ms: Option<u64>,
+    batch_size: Option<i64>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+struct MailLayer {
+    transport: Option<String>,
+    from_address: Option<String>,
+    file_path: Option<std::path::PathBuf>,
+    public_url: Option<String>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+struct RateLimitLayer {
+    enabled: Option<bool>,
+    routes: Option<std::collections::HashMap<String, RouteLimit>>,
+    lockout_threshold: Option<u32>,
+    lockout_base: Option<u64>,
+    lockout_max: Option<u64>,
+    trusted_proxies: Option<Vec<std::net::IpAddr>>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+struct WebauthnLayer {
+    rp_id: Option<String>,
+    rp_name: Option<String>,
+    origin: Option<String>,
+    challenge_ttl: Option<i64>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+struct OidcLayer {
+    providers: Option<std::collections::HashMap<String, OidcProvider>>,
+    state_ttl: Option<i64>,
+    jwks_ttl: Option<u64>,
+    login_redirect: Option<String>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+struct OauthLayer {
+    signing_key: Option<String>,
+    code_ttl: Option<i64>,
+    access_token_ttl: Option<i64>,
+    id_token_ttl: Option<i64>,
+    login_url: Option<String>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+struct RbacLayer {
+    default_permissions: Option<Vec<String>>,
+    bootstrap_admin: Option<String>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+struct OrgsLayer {
+    invitation_ttl: Option<i64>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+struct PasswordLayer {
+    min_length: Option<usize>,
+    max_length: Option<usize>,
+    require_lowercase: Option<bool>,
+    require_uppercase: Option<bool>,
+    require_digit: Option<bool>,
+    require_symbol: Option<bool>,
+    breached_list: Option<std::path::PathBuf>,
+    argon2_memory: Option<u32>,
+    argon2_iterations: Option<u32>,
+    argon2_parallelism: Option<u32>,
+    hashing_workers: Option<usize>,
+    hashing_queue: Option<usize>,
+}
+
+impl ConfigLayer {
+    // values already present in `self` are overridden by those in `other`
+    fn merge(&mut self, other: ConfigLayer) {
+        fn pick<T>(lower: &mut Option<T>, higher: Option<T>) {
+            if higher.is_some() {
+                *lower = higher;
+            }
+        }
+
+        pick(&mut self.server.bind_address, other.server.bind_address);
+        pick(&mut self.server.workers, other.server.workers);
+        pick(&mut self.server.session_ttl, other.server.session_ttl);
+        pick(&mut self.database.url, other.database.url);
+        pick(&mut self.database.pool_size, other.database.pool_size);
+        pick(&mut self.database.auto_migrate, other.database.auto_migrate);
+        pick(&mut self.redis.url, other.redis.url);
+        pick(&mut self.redis.pool_size, other.redis.pool_size);
+        pick(&mut self.telemetry.loki_url, other.telemetry.loki_url);
+        pick(&mut self.telemetry.trace_level, other.telemetry.trace_level);
+        pick(&mut self.security.hash_salt, other.security.hash_salt);
+        pick(&mut self.security.hash_salt_id, other.security.hash_salt_id);
+        pick(
+            &mut self.security.previous_hash_salts,
+            other.security.previous_hash_salts,
+        );
+        pick(
+            &mut self.security.password_reset_ttl,
+            other.security.password_reset_ttl,
+        );
+        pick(
+            &mut self.security.email_verification_ttl,
+            other.security.email_verification_ttl,
+        );
+        pick(&mut self.security.totp_key, other.security.totp_key);
+        pick(&mut self.security.totp_issuer, other.security.totp_issuer);
+        pick(&mut self.compute.worker_token, other.compute.worker_token);
+        pick(
+            &mut self.compute.heartbeat_timeout,
+            other.compute.heartbeat_timeout,
+        );
+        pick(
+            &mut self.compute.reaper_interval,
+            other.compute.reaper_interval,
+        );
+        pick(&mut self.scheduler.policy, other.scheduler.policy);
+        pick(&mut self.scheduler.interval_ms, other.scheduler.interval_ms);
+        pick(&mut self.scheduler.batch_size, other.scheduler.batch_size);
+        pick(&mut self.mail.transport, other.mail.transport);
+        pick(&mut self.mail.from_address, other.mail.from_address);
+        pick(&mut self.mail.file_path, other.mail.file_path);
+        pick(&mut self.mail.public_url, other.mail.public_url);
+        pick(&mut self.rate_limit.enabled, other.rate_limit.enabled);
+        pick(&mut self.rate_limit.routes, other.rate_limit.routes);
+        pick(
+            &mut self.rate_limit.lockout_threshold,
+            other.rate_limit.lockout_threshold,
+        );
+        pick(
+            &mut self.rate_limit.lockout_base,
+            other.rate_limit.lockout_base,
+        );
+        pick(
+            &mut self.rate_limit.lockout_max,
+            other.rate_limit.lockout_max,
+        );
+        pick(
+            &mut self.rate_limit.trusted_proxies,
+            other.rate_limit.trusted_proxies,
+        );
+        pick(&mut self.webauthn.rp_id, other.webauthn.rp_id);
+        pick(&mut self.webauthn.rp_name, other.webauthn.rp_name);
+        pick(&mut self.webauthn.origin, other.webauthn.origin);
+        pick(
+            &mut self.webauthn.challenge_ttl,
+            other.webauthn.challenge_ttl,
+        );
+        pick(&mut self.oidc.providers, other.oidc.providers);
+        pick(&mut self.oidc.state_ttl, other.oidc.state_ttl);
+        pick(&mut self.oidc.jwks_ttl, other.oidc.jwks_ttl);
+        pick(&mut self.oidc.login_redirect, other.oidc.login_redirect);
+        pick(&mut self.oauth.signing_key, other.oauth.signing_key);
+        pick(&mut self.oauth.code_ttl, other.oauth.code_ttl);
+        pick(
+            &mut self.oauth.access_token_ttl,
+            other.oauth.access_token_ttl,
+        );
+        pick(&mut self.oauth.id_token_ttl, other.oauth.id_token_ttl);
+        pick(&mut self.oauth.login_url, other.oauth.login_url);
+        pick(
+            &mut self.rbac.default_permissions,
+            other.rbac.default_permissions,
+        );
+        pick(&mut self.rbac.bootstrap_admin, other.rbac.bootstrap_admin);
+        pick(&mut self.orgs.invitation_ttl, other.orgs.invitation_ttl);
+        pick(&mut self.password.min_length, other.password.min_length);
+        pick(&mut self.password.max_length, other.password.max_length);
+        pick(
+            &mut self.password.require_lowercase,
+            other.password.require_lowercase,
+        );
+        pick(
+            &mut self.password.require_uppercase,
+            other.password.require_uppercase,
+        );
+        pick(
+            &mut self.password.require_digit,
+            other.password.require_digit,
+        );
+        pick(
+            &mut self.password.require_symbol,
+            other.password.require_symbol,
+        );
+        pick(
+            &mut self.password.breached_list,
+            other.password.breached_list,
+        );
+        pick(
+            &mut self.password.argon2_memory,
+            other.password.argon2_memory,
+        );
+        pick(
+            &mut self.password.argon2_iterations,
+            other.password.argon2_iterations,
+        );
+        pick(
+            &mut self.password.argon2_parallelism,
+            other.password.argon2_parallelism,
+        );
+        pick(
+            &mut self.password.hashing_workers,
+            other.password.hashing_workers,
+        );
+        pick(
+            &mut self.password.hashing_queue,
+            other.password.hashing_queue,
+        );
+    }
+
+    fn from_file(path: &std::path::Path, errors: &mut Vec<String>) -> ConfigLayer {
+        let contents = match std::fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(e) => {
+                errors.push(format!("cannot read {} ({})", path.display(), e));
+                return ConfigLayer::default();
+            }
+        };
+
+        match toml::from_str(&contents) {
+            Ok(layer) => layer,
+            Err(e) => {
+                errors.push(format!("cannot parse {} ({})", path.display(), e));
+                ConfigLayer::default()
+            }
+        }
+    }
+
+    fn from_env(errors: &mut Vec<String>) -> ConfigLayer {
+        fn string(key: &str) -> Option<String> {
+            std::env::var(key).ok()
+        }
+
+        fn parsed<T: std::str::FromStr>(key: &str, errors: &mut Vec<String>) -> Option<T>
+        where
+            T::Err: std::fmt::Display,
+        {
+            let var = std::env::var(key).ok()?;
+            match var.parse() {
+                Ok(value) => Some(value),
+                Err(e) => {
+                    errors.push(format!("environment variable {} is invalid ({})", key, e));
+                    None
+                }
+            }
+        }
+
+        ConfigLayer {
+            server: ServerLayer {
+                bind_address: string(BIND_ADDRESS_KEY),
+                workers: parsed(WORKERS_KEY, errors),
+                session_ttl: parsed(SESSION_TTL_KEY, errors),
+            },
+            database: DatabaseLayer {
+                url: string(CENTRAL_DB_INSTANCE_KEY),
+                pool_size: parsed(MAX_THREADS_KEY, errors),
+                auto_migrate: parsed(AUTO_MIGRATE_KEY, errors),
+            },
+            redis: RedisLayer {
+                url: string(REDIS_CLUSTER_KEY),
+                pool_size: parsed(REDIS_POOL_SIZE_KEY, errors),
+            },
+            telemetry: TelemetryLayer {
+                loki_url: string(LOKI_URL_KEY),
+                trace_level: string(TRACE_LEVEL_KEY),
+            },
+            security: SecurityLayer {
+                hash_salt: string(CRIMSON_HASH_SALT_KEY),
+                hash_salt_id: string(CRIMSON_HASH_SALT_ID_KEY),
+                previous_hash_salts: None,
+                password_reset_ttl: None,
+                email_verification_ttl: None,
+                totp_key: string(TOTP_KEY_KEY),
+                totp_issuer: None,
+            },
+            compute: ComputeLayer {
+                worker_token: string(WORKER_TOKEN_KEY),
+                heartbeat_timeout: parsed(HEARTBEAT_TIMEOUT_KEY, errors),
+                reaper_interval: None,
+            },
+            scheduler: SchedulerLayer {
+                policy: string(SCHEDULER_POLICY_KEY),
+                interval_ms: None,
+                batch_size: None,
+            },
+            mail: MailLayer {
+                transport: string(MAIL_TRANSPORT_KEY),
+                from_address: None,
+                file_path: string(MAIL_FILE_PATH_KEY).map(Into::into),
+                public_url: string(PUBLIC_URL_KEY),
+            },
+            rate_limit: RateLimitLayer {
+                enabled: parsed(RATE_LIMIT_ENABLED_KEY, errors),
+                routes: None,
+                lockout_threshold: None,
+                lockout_base: None,
+                lockout_max: None,
+                trusted_proxies: None,
+            },
+            webauthn: WebauthnLayer {
+                rp_id: string(WEBAUTHN_RP_ID_KEY),
+                rp_name: None,
+                origin: string(WEBAUTHN_ORIGIN_KEY),
+                challenge_ttl: None,
+            },
+            oidc: OidcLayer {
+                providers: None,
+                state_ttl: None,
+                jwks_ttl: None,
+                login_redirect: None,
+            },
+            oauth: OauthLayer {
+                signing_key: string(OAUTH_SIGNING_KEY_KEY),
+                code_ttl: None,
+                access_token_ttl: None,
+                id_token_ttl: None,
+                login_url: None,
+            },
+            rbac: RbacLayer {
+                default_permissions: None,
+                bootstrap_admin: string(BOOTSTRAP_ADMIN_KEY),
+            },
+            orgs: OrgsLayer {
+                invitation_ttl: None,
+            },
+            password: PasswordLayer {
+                min_length: None,
+                max_length: None,
+                require_lowercase: None,
+                require_uppercase: None,
+                require_digit: None,
+                require_symbol: None,
+                breached_list: string(BREACHED_PASSWORDS_KEY).map(Into::into),
+                argon2_memory: None,
+                argon2_iterations: None,
+                argon2_parallelism: None,
+                hashing_workers: None,
+                hashing_queue: None,
+            },
+        }
+    }
+
+    fn from_args(args: &ServerArgs) -> ConfigLayer {
+        ConfigLayer {
+            server: ServerLayer {
+                bind_address: args.bind.clone(),
+                workers: args.workers,
+                session_ttl: args.session_ttl,
+            },
+            database: DatabaseLayer {
+                url: args.database_url.clone(),
+                pool_size: args.database_pool_size,
+                auto_migrate: None,
+            },
+            redis: RedisLayer {
+                url: args.redis_url.clone(),
+                pool_size: args.redis_pool_size,
+            },
+            telemetry: TelemetryLayer {
+                loki_url: args.loki_url.clone(),
+                trace_level: args.trace_level.clone(),
+            },
+            security: SecurityLayer {
+                hash_salt: None,
+                hash_salt_id: None,
+                previous_hash_salts: None,
+                password_reset_ttl: None,
+                email_verification_ttl: None,
+                totp_key: None,
+                totp_issuer: None,
+            },
+            compute: ComputeLayer {
+                worker_token: None,
+                heartbeat_timeout: None,
+                reaper_interval: None,
+            },
+            scheduler: SchedulerLayer {
+                policy: None,
+                interval_ms: None,
+                batch_size: None,
+            },
+            mail: MailLayer {
+                transport: None,
+                from_address: None,
+                file_path: None,
+                public_url: None,
+            },
+            rate_limit: RateLimitLayer {
+                enabled: None,
+                routes: None,
+                lockout_threshold: None,
+                lockout_base: None,
+                lockout_max: None,
+                trusted_proxies: None,
+            },
+            webauthn: WebauthnLayer {
+                rp_id: None,
+                rp_name: None,
+                origin: None,
+                challenge_ttl: None,
+            },
+            oidc: OidcLayer {
+                providers: None,
+                state_ttl: None,
+                jwks_ttl: None,
+                login_redirect: None,
+            },
+            oauth: OauthLayer {
+                signing_key: None,
+                code_ttl: None,
+                access_token_ttl: None,
+                id_token_ttl: None,
+                login_url: None,
+            },
+            rbac: RbacLayer {
+                default_permissions: None,
+                bootstrap_admin: None,
+            },
+            orgs: OrgsLayer {
+                invitation_ttl: None,
+            },
+            password: PasswordLayer {
+                min_length: None,
+                max_length: None,
+                require_lowercase: None,
+                require_uppercase: None,
+                require_digit: None,
+                require_symbol: None,
+                breached_list: None,
+                argon2_memory: None,
+                argon2_iterations: None,
+                argon2_parallelism: None,
+                hashing_workers: None,
+                hashing_queue: None,
+            },
+        }
+    }
+}
+
+fn required<T>(value: Option<T>, name: &str, env_key: &str, errors: &mut Vec<String>) -> Option<T> {
+    if value.is_none() {
+        errors.push(format!(
+            "{} is not set (set it in the config file or {})",
+            name, env_key
+        ));
+    }
+    value
+}
+
+fn positive<T: PartialOrd + Default + std::fmt::Display>(
+    value: T,
+    name: &str,
+    errors: &mut Vec<String>,
+) -> T {
+    if value <= T::default() {
+        errors.push(format!("{} must be greater than zero, got {}", name, value));
+    }
+    value
+}
+
+impl ServerLayer {
+    fn resolve(self, errors: &mut Vec<String>) -> Option<ServerSection> {
+        let bind_address = self
+            .bind_address
+            .unwrap_or_else(|| DEFAULT_BIND_ADDRESS.to_string());
+        let bind_address = match bind_address.parse::<std::net::SocketAddr>() {
+            Ok(address) => Some(address),
+            Err(e) => {
+                errors.push(format!(
+                    "server.bind_address `{}` is invalid ({})",
+                    bind_address, e
+                ));
+                None
+            }
+        };
+
+        let workers = self.workers.unwrap_or_else(|| {
+            std::thread::available_parallelism()
+                .map(|n| n.get())
+                .unwrap_or(1)
+        });
+        let workers = positive(workers, "server.workers", errors);
+        let session_ttl = positive(
+            self.session_ttl.unwrap_or(DEFAULT_SESSION_TTL),
+            "server.session_ttl",
+            errors,
+        );
+
+        Some(ServerSection {
+            bind_address: bind_address?,
+            workers,
+            session_ttl,
+        })
+    }
+}
+
+impl DatabaseLayer {
+    fn resolve(self, errors: &mut Vec<String>) -> Option<DatabaseSection> {
+        let url = required(self.url, "database.url", CENTRAL_DB_INSTANCE_KEY, errors);
+        let pool_size = required(
+            self.pool_size,
+            "database.pool_size",
+            MAX_THREADS_KEY,
+            errors,
+        )
+        .map(|size| positive(size, "database.pool_size", errors));
+
+        Some(DatabaseSection {
+            url: url?,
+            pool_size: pool_size?,
+            auto_migrate: self.auto_migrate.unwrap_or(true),
+        })
+    }
+}
+
+impl RedisLayer {
+    fn resolve(self, errors: &mut Vec<String>) -> Option<RedisSection> {
+        let url = required(self.url, "redis.url", REDIS_CLUSTER_KEY, errors);
+        let pool_size = positive(
+            self.pool_size.unwrap_or(DEFAULT_REDIS_POOL_SIZE),
+            "redis.pool_size",
+            errors,
+        );
+
+        Some(RedisSection {
+            url: url?,
+            pool_size,
+        })
+    }
+}
+
+impl TelemetryLayer {
+    fn resolve(self, errors: &mut Vec<String>) -> Option<TelemetrySection> {
+        let loki_url = required(self.loki_url, "telemetry.loki_url", LOKI_URL_KEY, errors)
+            .and_then(|loki_url| match url::Url::parse(&loki_url) {
+                Ok(url) => Some(url),
+                Err(e) => {
+                    errors.push(format!(
+                        "telemetry.loki_url `{}` is invalid ({})",
+                        loki_url, e
+                    ));
+                    None
+                }
+            });
+        let trace_level = required(
+            self.trace_level,
+            "telemetry.trace_level",
+            TRACE_LEVEL_KEY,
+            errors,
+        )
+        .and_then(|trace_level| {
+            match tracing_subscriber::EnvFilter::try_new(&trace_level) {
+                Ok(_) => Some(trace_level),
+                Err(e) => {
+                    errors.push(format!(
+                        "telemetry.trace_level `{}` is invalid ({})",
+                        trace_level, e
+                    ));
+                    None
+                }
+            }
+        });
+
+        Some(TelemetrySection {
+            loki_url: loki_url?,
+            trace_level: trace_level?,
+        })
+    }
+}
+
+impl SecurityLayer {
+    fn resolve(self, errors: &mut Vec<String>) -> Option<SecuritySection> {
+        let hash_salt = required(
+            self.hash_salt,
+            "security.hash_salt",
+            CRIMSON_HASH_SALT_KEY,
+            errors,
+        );
+        if hash_salt
+            .as_ref()
+            .is_some_and(|hash_salt| hash_salt.is_empty())
+        {
+            errors.push("security.hash_salt must not be empty".to_string());
+        }
+        let hash_salt_id = self
+            .hash_salt_id
+            .unwrap_or_else(|| DEFAULT_HASH_SALT_ID.to_string());
+        let previous_hash_salts = self.previous_hash_salts.unwrap_or_default();
+        // ids end up in the `keyid` parameter of the hash, at most 8 bytes
+        for id in std::iter::once(&hash_salt_id).chain(previous_hash_salts.keys()) {
+            if id.is_empty()
+                || id.len() > argon2::Params::MAX_KEYID_LEN
+                || !id.bytes().all(|b| b.is_ascii_alphanumeric())
+            {
+                errors.push(format!(
+                    "hash salt id `{}` must be 1 to {} letters or digits",
+                    id,
+                    argon2::Params::MAX_KEYID_LEN
+                ));
+            }
+        }
+        if previous_hash_salts.contains_key(&hash_salt_id) {
+            errors.push(format!(
+                "security.previous_hash_salts can't contain the current id `{}`",
+                hash_salt_id
+            ));
+        }
+        if previous_hash_salts.values().any(|pepper| pepper.is_empty()) {
+            errors.push("security.previous_hash_salts must not contain empty salts".to_string());
+        }
+        let password_reset_ttl = positive(
+            self.password_reset_ttl
+                .unwrap_or(DEFAULT_PASSWORD_RESET_TTL),
+            "security.password_reset_ttl",
+            errors,
+        );
+        let email_verification_ttl = positive(
+            self.email_verification_ttl
+                .unwrap_or(DEFAULT_EMAIL_VERIFICATION_TTL),
+            "security.email_verification_ttl",
+            errors,
+        );
+        // 64 hex characters, e.g. `openssl rand -hex 32`
+        let totp_key = match self.totp_key.filter(|key| !key.is_empty()) {
+            None => None,
+            Some(key) => {
+                let mut totp_key = [0u8; 32];
+                match hex::decode_to_slice(&key, &mut totp_key) {
+                    Ok(()) => Some(totp_key),
+                    Err(e) => {
+                        errors.push(format!("security.totp_key must be 32 bytes of hex ({})", e));
+                        None
+                    }
+                }
+            }
+        };
+
+        Some(SecuritySection {
+            hash_salt: hash_salt?,
+            hash_salt_id,
+            previous_hash_salts,
+            password_reset_ttl,
+            email_verification_ttl,
+            totp_key,
+            totp_issuer: self
+                .totp_issuer
+                .unwrap_or_else(|| DEFAULT_TOTP_ISSUER.to_string()),
+        })
+    }
+}
+
+impl ComputeLayer {
+    fn resolve(self, errors: &mut Vec<String>) -> Option<ComputeSection> {
+        let heartbeat_timeout = positive(
+            self.heartbeat_timeout.unwrap_or(DEFAULT_HEARTBEAT_TIMEOUT),
+            "compute.heartbeat_timeout",
+            errors,
+        );
+        let reaper_interval = positive(
+            self.reaper_interval.unwrap_or(DEFAULT_REAPER_INTERVAL),
+            "compute.reaper_interval",
+            errors,
+        );
+
+        Some(ComputeSection {
+            worker_token: self.worker_token.filter(|token| !token.is_empty()),
+            heartbeat_timeout,
+            reaper_interval,
+        })
+    }
+}
+
+impl SchedulerLayer {
+    fn resolve(self, errors: &mut Vec<String>) -> Option<SchedulerSection> {
+        let policy = self
+            .policy
+            .unwrap_or_else(|| DEFAULT_SCHEDULER_POLICY.to_string());
+        if !SCHEDULER_POLICIES.contains(&policy.as_str()) {
+            errors.push(format!(
+                "scheduler.policy `{}` is invalid (expected one of {})",
+                policy,
+                SCHEDULER_POLICIES.join(", ")
+            ));
+            return None;
+        }
+
+        let interval_ms = positive(
+            self.interval_ms.unwrap_or(DEFAULT_SCHEDULER_INTERVAL_MS),
+            "scheduler.interval_ms",
+            errors,
+        );
+        let batch_size = positive(
+            self.batch_size.unwrap_or(DEFAULT_SCHEDULER_BATCH_SIZE),
+            "scheduler.batch_size",
+            errors,
+        );
+
+        Some(SchedulerSection {
+            policy,
+            interval_ms,
+            batch_size,
+        })
+    }
+}
+
+impl MailLayer {
+    fn resolve(self, errors: &mut Vec<String>) -> Option<MailSection> {
+        let transport = self
+            .transport
+            .unwrap_or_else(|| DEFAULT_MAIL_TRANSPORT.to_string());
+        if !MAIL_TRANSPORTS.contains(&transport.as_str()) {
+            errors.push(format!(
+                "mail.transport `{}` is invalid (expected one of {})",
+                transport,
+                MAIL_TRANSPORTS.join(", ")
+            ));
+            return None;
+        }
+        if transport == "file" && self.file_path.is_none() {
+            errors.push(format!(
+                "mail.file_path is not set (required by the file transport, or {})",
+                MAIL_FILE_PATH_KEY
+            ));
+            return None;
+        }
+
+        let public_url = self
+            .public_url
+            .unwrap_or_else(|| DEFAULT_PUBLIC_URL.to_string());
+        let public_url = match url::Url::parse(&public_url) {
+            Ok(url) => url,
+            Err(e) => {
+                errors.push(format!(
+                    "mail.public_url `{}` is invalid ({})",
+                    public_url, e
+                ));
+                return None;
+            }
+        };
+
+        Some(MailSection {
+            transport,
+            from_address: self
+                .from_address
+                .unwrap_or_else(|| DEFAULT_MAIL_FROM.to_string()),
+            file_path: self.file_path,
+            public_url,
+        })
+    }
+}
+
+impl RateLimitLayer {
+    fn resolve(self, errors: &mut Vec<String>) -> Option<RateLimitSection> {
+        // configured routes override the defaults one by one
+        let mut routes: std::collections::HashMap<String, RouteLimit> = DEFAULT_ROUTE_LIMITS
+            .iter()
+            .map(|(path, limit, window)| {
+                (
+                    path.to_string(),
+                    RouteLimit {
+                        limit: *limit,
+                        window: *window,
+                    },
+                )
+            })
+            .collect();
+        routes.extend(self.routes.unwrap_or_default());
+        for (path, route) in &routes {
+            positive(
+                route.limit,
+                &format!("rate_limit.routes.\"{}\".limit", path),
+                errors,
+            );
+            positive(
+                route.window,
+                &format!("rate_limit.routes.\"{}\".window", path),
+                errors,
+            );
+        }
+
+        let lockout_threshold = positive(
+            self.lockout_threshold.unwrap_or(DEFAULT_LOCKOUT_THRESHOLD),
+            "rate_limit.lockout_threshold",
+            errors,
+        );
+        let lockout_base = positive(
+            self.lockout_base.unwrap_or(DEFAULT_LOCKOUT_BASE),
+            "rate_limit.lockout_base",
+            errors,
+        );
+        let lockout_max = positive(
+            self.lockout_max.unwrap_or(DEFAULT_LOCKOUT_MAX),
+            "rate_limit.lockout_max",
+            errors,
+        );
+
+        Some(RateLimitSection {
+            enabled: self.enabled.unwrap_or(true),
+            routes,
+            lockout_threshold,
+            lockout_base,
+            lockout_max,
+            trusted_proxies: self.trusted_proxies.unwrap_or_default(),
+        })
+    }
+}
+
+impl WebauthnLayer {
+    fn resolve(self, errors: &mut Vec<String>) -> Option<WebauthnSection> {
+        let rp_id = self
+            .rp_id
+            .unwrap_or_else(|| DEFAULT_WEBAUTHN_RP_ID.to_string());
+        let origin = self
+            .origin
+            .unwrap_or_else(|| DEFAULT_WEBAUTHN_ORIGIN.to_string());
+        let origin = match url::Url::parse(&origin) {
+            Ok(url) => url,
+            Err(e) => {
+                errors.push(format!("webauthn.origin `{}` is invalid ({})", origin, e));
+                return None;
+            }
+        };
+        // the rp id must be the origin's host or a parent domain of it
+        let host = origin.host_str().unwrap_or_default();
+        if host != rp_id && !host.ends_with(&format!(".{}", rp_id)) {
+            errors.push(format!(
+                "webauthn.rp_id `{}` is not a registrable suffix of webauthn.origin `{}`",
+                rp_id, origin
+            ));
+            return None;
+        }
+        let challenge_ttl = positive(
+            self.challenge_ttl.unwrap_or(DEFAULT_WEBAUTHN_CHALLENGE_TTL),
+            "webauthn.challenge_ttl",
+            errors,
+        );
+
+        Some(WebauthnSection {
+            rp_id,
+            rp_name: self
+                .rp_name
+                .unwrap_or_else(|| DEFAULT_WEBAUTHN_RP_NAME.to_string()),
+            origin: origin.origin().ascii_serialization(),
+            challenge_ttl,
+        })
+    }
+}
+
+impl OidcLayer {
+    fn resolve(self, errors: &mut Vec<String>) -> Option<OidcSection> {
+        let providers = self.providers.unwrap_or_default();
+        let mut valid = true;
+        for (name, provider) in &providers {
+            let field = |key: &str| format!("oidc.providers.{}.{}", name, key);
+            if name.is_empty()
+                || !name
+                    .bytes()
+                    .all(|b| b.is_ascii_lowercase() || b.is_ascii_digit() || b == b'-' || b == b'_')
+            {
+                errors.push(format!(
+                    "oidc provider name `{}` is invalid (expected lowercase letters, digits, - or _)",
+                    name
+                ));
+                valid = false;
+            }
+            match url::Url::parse(&provider.issuer) {
+                Ok(issuer)
+                    if matches!(issuer.scheme(), "http" | "https")
+                        && issuer.query().is_none()
+                        && issuer.fragment().is_none() => {}
+                Ok(_) => {
+                    errors.push(format!(
+                        "{} `{}` must be an http(s) url without query or fragment",
+                        field("issuer"),
+                        provider.issuer
+                    ));
+                    valid = false;
+                }
+                Err(e) => {
+                    errors.push(format!(
+                        "{} `{}` is invalid ({})",
+                        field("issuer"),
+                        provider.issuer,
+                        e
+                    ));
+                    valid = false;
+                }
+            }
+            if provider.client_id.is_empty() {
+                errors.push(format!("{} is empty", field("client_id")));
+                valid = false;
+            }
+            if !provider.scopes.iter().any(|scope| scope == "openid") {
+                errors.push(format!("{} must include `openid`", field("scopes")));
+                valid = false;
+            }
+        }
+
+        let login_redirect = self
+            .login_redirect
+            .unwrap_or_else(|| DEFAULT_OIDC_LOGIN_REDIRECT.to_string());
+        if !login_redirect.starts_with('/') && url::Url::parse(&login_redirect).is_err() {
+            errors.push(format!(
+                "oidc.login_redirect `{}` is neither a path nor a url",
+                login_redirect
+            ));
+            valid = false;
+        }
+        let state_ttl = positive(
+            self.state_ttl.unwrap_or(DEFAULT_OIDC_STATE_TTL),
+            "oidc.state_ttl",
+            errors,
+        );
+        let jwks_ttl = positive(
+            self.jwks_ttl.unwrap_or(DEFAULT_OIDC_JWKS_TTL),
+            "oidc.jwks_ttl",
+            errors,
+        );
+
+        valid.then_some(OidcSection {
+            providers,
+            state_ttl,
+            jwks_ttl,
+            login_redirect,
+        })
+    }
+}
+
+impl OauthLayer {
+    fn resolve(self, errors: &mut Vec<String>) -> Option<OauthSection> {
+        // 64 hex characters, e.g. `openssl rand -hex 32`
+        let signing_key = match self.signing_key.filter(|key| !key.is_empty()) {
+            None => None,
+            Some(key) => {
+                let mut signing_key = [0u8; 32];
+                match hex::decode_to_slice(&key, &mut signing_key) {
+                    Ok(()) if p256::SecretKey::from_slice(&signing_key).is_ok() => {
+                        Some(signing_key)
+                    }
+                    Ok(()) => {
+                        errors
+                            .push("oauth.signing_key is not a valid P-256 private key".to_string());
+                        None
+                    }
+                    Err(e) => {
+                        errors.push(format!("oauth.signing_key must be 32 bytes of hex ({})", e));
+                        None
+                    }
+                }
+            }
+        };
+        if let Some(login_url) = &self.login_url
+            && !login_url.starts_with('/')
+            && url::Url::parse(login_url).is_err()
+        {
+            errors.push(format!(
+                "oauth.login_url `{}` is neither a path nor a url",
+                login_url
+            ));
+        }
+        let code_ttl = positive(
+            self.code_ttl.unwrap_or(DEFAULT_OAUTH_CODE_TTL),
+            "oauth.code_ttl",
+            errors,
+        );
+        let access_token_ttl = positive(
+            self.access_token_ttl
+                .unwrap_or(DEFAULT_OAUTH_ACCESS_TOKEN_TTL),
+            "oauth.access_token_ttl",
+            errors,
+        );
+        let id_token_ttl = positive(
+            self.id_token_ttl.unwrap_or(DEFAULT_OAUTH_ID_TOKEN_TTL),
+            "oauth.id_token_ttl",
+            errors,
+        );
+
+        Some(OauthSection {
+            signing_key,
+            code_ttl,
+            access_token_ttl,
+            id_token_ttl,
+            login_url: self.login_url,
+        })
+    }
+}
+
+impl RbacLayer {
+    fn resolve(self, errors: &mut Vec<String>) -> Option<RbacSection> {
+        let default_permissions = self.default_permissions.unwrap_or_else(|| {
+            DEFAULT_RBAC_PERMISSIONS
+                .iter()
+                .map(|permission| permission.to_string())
+                .collect()
+        });
+        for permission in &default_permissions {
+            if permission == super::server_roles::PERMISSION_USERS_ADMIN {
+                errors.push(format!(
+                    "rbac.default_permissions can't grant `{}` to everybody",
+                    permission
+                ));
+            } else if !super::server_roles::PERMISSIONS.contains(&permission.as_str()) {
+                errors.push(format!(
+                    "rbac.default_permissions has unknown permission `{}` (expected one of {:?})",
+                    permission,
+                    super::server_roles::PERMISSIONS
+                ));
+            }
+        }
+
+        Some(RbacSection {
+            default_permissions,
+            bootstrap_admin: self.bootstrap_admin.filter(|email| !email.is_empty()),
+        })
+    }
+}
+
+impl OrgsLayer {
+    fn resolve(self, errors: &mut Vec<String>) -> Option<OrgsSection> {
+        let invitation_ttl = positive(
+            self.invitation_ttl.unwrap_or(DEFAULT_ORGS_INVITATION_TTL),
+            "orgs.invitation_ttl",
+            errors,
+        );
+
+        Some(OrgsSection { invitation_ttl })
+    }
+}
+
+impl PasswordLayer {
+    fn resolve(self, errors: &mut Vec<String>) -> Option<PasswordSection> {
+        let min_length = positive(
+            self.min_length.unwrap_or(DEFAULT_PASSWORD_MIN_LENGTH),
+            "password.min_length",
+            errors,
+        );
+        let max_length = self.max_length.unwrap_or(DEFAULT_PASSWORD_MAX_LENGTH);
+        if max_length < min_length {
+            errors.push(format!(
+                "password.max_length ({}) is below password.min_length ({})",
+                max_length, min_length
+            ));
+        } else if max_length > super::server_validation::MAX_PASSWORD_LENGTH {
+            errors.push(format!(
+                "password.max_length must be at most {}, got {}",
+                super::server_validation::MAX_PASSWORD_LENGTH,
+                max_length
+            ));
+        }
+
+        // library defaults, the OWASP minimum for Argon2id
+        let argon2 = match argon2::Params::new(
+            self.argon2_memory.unwrap_or(argon2::Params::DEFAULT_M_COST),
+            self.argon2_iterations
+                .unwrap_or(argon2::Params::DEFAULT_T_COST),
+            self.argon2_parallelism
+                .unwrap_or(argon2::Params::DEFAULT_P_COST),
+            None,
+        ) {
+            Ok(argon2) => argon2,
+            Err(e) => {
+                errors.push(format!(
+                    "password.argon2_memory, argon2_iterations & argon2_parallelism are invalid ({})",
+                    e
+                ));
+                return None;
+            }
+        };
+
+        let hashing_workers = positive(
+            self.hashing_workers.unwrap_or_else(|| {
+                std::thread::available_parallelism().map_or(1, std::num::NonZeroUsize::get)
+            }),
+            "password.hashing_workers",
+            errors,
+        );
+        let hashing_queue = positive(
+            self.hashing_queue
+                .unwrap_or(hashing_workers * DEFAULT_HASHING_QUEUE_PER_WORKER),
+            "password.hashing_queue",
+            errors,
+        );
+
+        Some(PasswordSection {
+            min_length,
+            max_length,
+            require_lowercase: self.require_lowercase.unwrap_or(false),
+            require_uppercase: self.require_uppercase.unwrap_or(false),
+            require_digit: self.require_digit.unwrap_or(false),
+            require_symbol: self.require_symbol.unwrap_or(false),
+            breached_list: self
+                .breached_list
+                .filter(|path| !path.as_os_str().is_empty()),
+            argon2,
+            hashing_workers,
+            hashing_queue,
+        })
+    }
+}
+
+impl ConfigLayer {
+    // file <- environment <- command line
+    fn load(args: &ServerArgs, errors: &mut Vec<String>) -> ConfigLayer {
+        let mut layer = ConfigLayer::default();
+
+        let explicit_path = args
+            .config
+            .clone()
+            .or_else(|| std::env::var(CONFIG_PATH_KEY).ok().map(Into::into));
+        match explicit_path {
+            Some(path) => layer.merge(ConfigLayer::from_file(&path, errors)),
+            None => {
+                let default_path = std::path::Path::new(DEFAULT_CONFIG_PATH);
+                if default_path.exists() {
+                    layer.merge(ConfigLayer::from_file(default_path, errors));
+                }
+            }
+        }
+
+        layer.merge(ConfigLayer::from_env(errors));
+        layer.merge(ConfigLayer::from_args(args));
+        layer
+    }
+}
+
+// sections resolve to None only after pushing an error
+fn finish<T>(resolved: Option<T>, errors: Vec<String>) -> Result<T, ConfigError> {
+    match resolved {
+        Some(value) if errors.is_empty() => Ok(value),
+        _ => Err(ConfigError { errors }),
+    }
+}
+
+impl ServerConfig {
+    /**
+     * # Brief
+     * Resolves the server configuration.
+     *
+     * # Detail
+     * - Layers are applied in order: TOML file, environment, command line.
+     * - The file comes from `--config`, `CRIMSON_CONFIG` or `./config/crimson.toml`.
+     * - Every field is checked up front, problems are collected into one `ConfigError`.
+     */
+    pub fn load(args: &ServerArgs) -> Result<ServerConfig, ConfigError> {
+        let mut errors = Vec::new();
+        let layer = ConfigLayer::load(args, &mut errors);
+
+        let server = layer.server.resolve(&mut errors);
+        let database = layer.database.resolve(&mut errors);
+        let redis = layer.redis.resolve(&mut errors);
+        let telemetry = layer.telemetry.resolve(&mut errors);
+        let security = layer.security.resolve(&mut errors);
+        let compute = layer.compute.resolve(&mut errors);
+        let scheduler = layer.scheduler.resolve(&mut errors);
+        let mail = layer.mail.resolve(&mut errors);
+        let rate_limit = layer.rate_limit.resolve(&mut errors);
+        let webauthn = layer.webauthn.resolve(&mut errors);
+        let oidc = layer.oidc.resolve(&mut errors);
+        let oauth = layer.oauth.resolve(&mut errors);
+        let rbac = layer.rbac.resolve(&mut errors);
+        let orgs = layer.orgs.resolve(&mut errors);
+        let password = layer.password.resolve(&mut errors);
+
+        let resolved = (|| {
+            Some(ServerConfig {
+                server: server?,
+                database: database?,
+                redis: redis?,
+                telemetry: telemetry?,
+                security: security?,
+                compute: compute?,
+                scheduler: scheduler?,
+                mail: mail?,
+                rate_limit: rate_limit?,
+                webauthn: webauthn?,
+                oidc: oidc?,
+                oauth: oauth?,
+                rbac: rbac?,
+                orgs: orgs?,
+                password: password?,
+            })
+        })();
+        finish(resolved, errors)
+    }
+}
+
+impl PasswordSection {
+    /// resolves only the `[password]` section, for `bench-hash`
+    pub fn load(args: &ServerArgs) -> Result<PasswordSection, ConfigError> {
+        let mut errors = Vec::new();
+        let layer = ConfigLayer::load(args, &mut errors);
+        let password = layer.password.resolve(&mut errors);
+        finish(password, errors)
+    }
+}
+
+impl DatabaseSection {
+    /// resolves only the `[database]` section, for commands that never serve
+    pub fn load(args: &ServerArgs) -> Result<DatabaseSection, ConfigError> {
+        let mut errors = Vec::new();
+        let layer = ConfigLayer::load(args, &mut errors);
+        let database = layer.database.resolve(&mut errors);
+        finish(database, errors)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::{CommandFactory, Parser};
+
+    #[test]
+    fn flags_are_accepted_after_the_subcommand() {
+        ServerArgs::command().debug_assert();
+
+        let args = ServerArgs::try_parse_from([
+            "crimson_heart",
+            "serve",
+            "--bind",
+            "0.0.0.0:8080",
+            "--workers",
+            "8",
+            "--trace-level",
+            "debug",
+        ])
+        .unwrap();
+        assert!(matches!(args.command, Some(ServerCommand::Serve)));
+        let layer = ConfigLayer::from_args(&args);
+        assert_eq!(layer.server.bind_address.as_deref(), Some("0.0.0.0:8080"));
+        assert_eq!(layer.server.workers, Some(8));
+        assert_eq!(layer.telemetry.trace_level.as_deref(), Some("debug"));
+
+        let args = ServerArgs::try_parse_from([
+            "crimson_heart",
+            "migrate",
+            "--database-pool-size",
+            "2",
+            "down",
+            "--to",
+            "3",
+        ])
+        .unwrap();
+        assert_eq!(args.database_pool_size, Some(2));
+        assert!(matches!(
+            args.command,
+            Some(ServerCommand::Migrate {
+                action: MigrateAction::Down { to: 3 }
+            })
+        ));
+    }
+
+    #[test]
+    fn flags_are_accepted_before_the_subcommand() {
+        let args =
+            ServerArgs::try_parse_from(["crimson_heart", "--bind", "127.0.0.1:9000", "serve"])
+                .unwrap();
+        assert_eq!(args.bind.as_deref(), Some("127.0.0.1:9000"));
+
+        let args = ServerArgs::try_parse_from(["crimson_heart", "--workers", "2"]).unwrap();
+        assert!(args.command.is_none());
+        assert_eq!(args.workers, Some(2));
+    }
+}
@@ -0,0 +1,533 @@
+use std::borrow::Cow;
+
+/*
+ * Errors answered by the HTTP API.
+ *
+ * Every error is the JSON body `{"code", "message", "request_id", "fields"}`.
+ * Clients branch on `code`, `message` is meant for humans and may change.
+ * `request_id` matches the `request_id` of the server logs, `fields` is only
+ * present when single input fields were rejected.
+ *
+ * Server side failures are logged with their cause when answered & the caller
+ * only sees a generic message. `/oauth/token` & `/oauth/userinfo` keep the
+ * RFC 6749 / 6750 bodies for the errors those specify.
+ */
+
+pub const CODE_INVALID_REQUEST: &str = "invalid_request";
+pub const CODE_INVALID_FIELDS: &str = "invalid_fields";
+pub const CODE_LOGIN_REQUIRED: &str = "login_required";
+pub const CODE_NOT_FOUND: &str = "not_found";
+pub const CODE_RATE_LIMITED: &str = "rate_limited";
+pub const CODE_SERVER_ERROR: &str = "server_error";
+pub const CODE_SERVER_BUSY: &str = "server_busy";
+// seconds a client waits after `server_busy`, the queue drains within that
+const SERVER_BUSY_RETRY_AFTER: u64 = 1;
+
+tokio::task_local! {
+    // set by `request_scope` for everything the request runs
+    static REQUEST_ID: String;
+}
+
+/// a rejected input field
+#[derive(serde::Serialize, Debug, Clone)]
+pub struct FieldError {
+    pub field: Cow<'static, str>,
+    pub message: Cow<'static, str>,
+}
+
+impl FieldError {
+    pub fn new(field: impl Into<Cow<'static, str>>, message: impl Into<Cow<'static, str>>) -> Self {
+        FieldError {
+            field: field.into(),
+            message: message.into(),
+        }
+    }
+}
+
+#[derive(serde::Serialize, Debug)]
+pub struct ErrorBody {
+    pub code: &'static str,
+    pub message: Cow<'static, str>,
+    pub request_id: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub fields: Vec<FieldError>,
+}
+
+/**
+ * # Brief
+ * An error answered by a handler or an extractor, `code` is stable.
+ *
+ * # Detail
+ * - Client errors carry their code & message.
+ * - Server errors carry their cause, which is logged & never answered.
+ */
+#[derive(Debug)]
+pub enum ApiError {
+    /// 400
+    BadRequest(&'static str, Cow<'static, str>),
+    /// 422, `invalid_fields` with one entry per rejected field
+    InvalidFields(Vec<FieldError>),
+    /// 401
+    Unauthorized(&'static str, Cow<'static, str>),
+    /// 403
+    Forbidden(&'static str, Cow<'static, str>),
+    /// 404
+    NotFound(&'static str, Cow<'static, str>),
+    /// 409
+    Conflict(&'static str, Cow<'static, str>),
+    /// 429 with `Retry-After` in whole seconds
+    TooManyRequests(u64),
+    /// 502, another service failed us, e.g. an OpenID provider
+    BadGateway(&'static str, Cow<'static, str>),
+    /// 503 with `Retry-After`, shedding load, e.g. the password hashing queue is full
+    ServiceUnavailable,
+    /// 500
+    Database {
+        source: sqlx::Error,
+        query: &'static str,
+        table: &'static str,
+    },
+    /// 500
+    Redis {
+        source: deadpool_redis::redis::RedisError,
+        function: &'static str,
+    },
+    /// 500
+    RedisPool(deadpool_redis::PoolError),
+    /// 500, anything else failing on our side
+    Internal {
+        component: &'static str,
+        function: &'static str,
+        source: String,
+    },
+}
+
+impl ApiError {
+    pub fn bad_request(code: &'static str, message: impl Into<Cow<'static, str>>) -> Self {
+        ApiError::BadRequest(code, message.into())
+    }
+
+    /// a single rejected field
+    pub fn invalid_field(
+        field: impl Into<Cow<'static, str>>,
+        message: impl Into<Cow<'static, str>>,
+    ) -> Self {
+        ApiError::InvalidFields(vec![FieldError::new(field, message)])
+    }
+
+    pub fn unauthorized(code: &'static str, message: impl Into<Cow<'static, str>>) -> Self {
+        ApiError::Unauthorized(code, message.into())
+    }
+
+    pub fn forbidden(code: &'static str, message: impl Into<Cow<'static, str>>) -> Self {
+        ApiError::Forbidden(code, message.into())
+    }
+
+    pub fn not_found(code: &'static str, message: impl Into<Cow<'static, str>>) -> Self {
+        ApiError::NotFound(code, message.into())
+    }
+
+    pub fn conflict(code: &'static str, message: impl Into<Cow<'static, str>>) -> Self {
+        ApiError::Conflict(code, message.into())
+    }
+
+    pub fn bad_gateway(code: &'static str, message: impl Into<Cow<'static, str>>) -> Self {
+        ApiError::BadGateway(code, message.into())
+    }
+
+    pub fn database(source: sqlx::Error, query: &'static str, table: &'static str) -> Self {
+        ApiError::Database {
+            source,
+            query,
+            table,
+        }
+    }
+
+    pub fn redis(source: deadpool_redis::redis::RedisError, function: &'static str) -> Self {
+        ApiError::Redis { source, function }
+    }
+
+    pub fn internal(
+        component: &'static str,
+        function: &'static str,
+        source: impl std::fmt::Display,
+    ) -> Self {
+        ApiError::Internal {
+            component,
+            function,
+            source: source.to_string(),
+        }
+    }
+
+    #[inline]
+    pub fn code(&self) -> &'static str {
+        match self {
+            ApiError::BadRequest(code, _)
+            | ApiError::Unauthorized(code, _)
+            | ApiError::Forbidden(code, _)
+            | ApiError::NotFound(code, _)
+            | ApiError::Conflict(code, _)
+            | ApiError::BadGateway(code, _) => code,
+            ApiError::InvalidFields(_) => CODE_INVALID_FIELDS,
+            ApiError::TooManyRequests(_) => CODE_RATE_LIMITED,
+            ApiError::ServiceUnavailable => CODE_SERVER_BUSY,
+            ApiError::Database { .. }
+            | ApiError::Redis { .. }
+            | ApiError::RedisPool(_)
+            | ApiError::Internal { .. } => CODE_SERVER_ERROR,
+        }
+    }
+
+    fn message(&self) -> Cow<'static, str> {
+        match self {
+            ApiError::BadRequest(_, message)
+            | ApiError::Unauthorized(_, message)
+            | ApiError::Forbidden(_, message)
+            | ApiError::NotFound(_, message)
+            | ApiError::Conflict(_, message)
+            | ApiError::BadGateway(_, message) => message.clone(),
+            ApiError::InvalidFields(_) => Cow::Borrowed("Some fields are invalid"),
+            ApiError::TooManyRequests(_) => Cow::Borrowed("Too Many Requests, Retry Later"),
+            ApiError::ServiceUnavailable => Cow::Borrowed("Server Busy, Retry Shortly"),
+            ApiError::Database { .. }
+            | ApiError::Redis { .. }
+            | ApiError::RedisPool(_)
+            | ApiError::Internal { .. } => Cow::Borrowed("Server Error, Refresh & Retry"),
+        }
+    }
+
+    // server errors with their cause, the way handlers used to log them,
+    // upstream failures are logged by the caller who knows the upstream
+    fn log(&self) {
+        match self {
+            ApiError::Database {
+                source,
+                query,
+                table,
+            } => tracing::error!(
+                error = %source,
+                component = "database",
+                query = query,
+                table = table,
+                "function failed & returned error"
+            ),
+            ApiError::Redis { source, function } => tracing::error!(
+                error = %source,
+                component = "redis_functions",
+                function = function,
+                "function failed & returned error"
+            ),
+            ApiError::RedisPool(source) => tracing::error!(
+                error = %source,
+                component = "redis_connection_pool",
+                "failed to acquire redis connection"
+            ),
+            ApiError::Internal {
+                component,
+                function,
+                source,
+            } => tracing::error!(
+                error = %source,
+                component = component,
+                function = function,
+                "function failed & returned error"
+            ),
+            _ => tracing::debug!(
+                code = self.code(),
+                message = %self.message(),
+                "request rejected"
+            ),
+        }
+    }
+}
+
+impl std::fmt::Display for ApiError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} ({})", self.code(), self.message())
+    }
+}
+
+impl From<deadpool_redis::PoolError> for ApiError {
+    fn from(e: deadpool_redis::PoolError) -> Self {
+        ApiError::RedisPool(e)
+    }
+}
+
+impl From<super::server_hashing::HashingError> for ApiError {
+    fn from(e: super::server_hashing::HashingError) -> Self {
+        match e {
+            super::server_hashing::HashingError::Saturated => ApiError::ServiceUnavailable,
+            super::server_hashing::HashingError::Failed => {
+                ApiError::internal("password_hashing", "run", e)
+            }
+        }
+    }
+}
+
+impl actix_web::ResponseError for ApiError {
+    fn status_code(&self) -> actix_web::http::StatusCode {
+        use actix_web::http::StatusCode;
+        match self {
+            ApiError::BadRequest(..) => StatusCode::BAD_REQUEST,
+            ApiError::InvalidFields(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            ApiError::Unauthorized(..) => StatusCode::UNAUTHORIZED,
+            ApiError::Forbidden(..) => StatusCode::FORBIDDEN,
+            ApiError::NotFound(..) => StatusCode::NOT_FOUND,
+            ApiError::Conflict(..) => StatusCode::CONFLICT,
+            ApiError::TooManyRequests(_) => StatusCode::TOO_MANY_REQUESTS,
+            ApiError::BadGateway(..) => StatusCode::BAD_GATEWAY,
+            ApiError::ServiceUnavailable => StatusCode::SERVICE_UNAVAILABLE,
+            ApiError::Database { .. }
+            | ApiError::Redis { .. }
+            | ApiError::RedisPool(_)
+            | ApiError::Internal { .. } => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn error_response(&self) -> actix_web::HttpResponse {
+        self.log();
+        let mut response = actix_web::HttpResponse::build(self.status_code());
+        match self {
+            ApiError::TooManyRequests(retry_after) => {
+                response
+                    .insert_header((actix_web::http::header::RETRY_AFTER, (*retry_after).max(1)));
+            }
+            ApiError::ServiceUnavailable => {
+                response.insert_header((
+                    actix_web::http::header::RETRY_AFTER,
+                    SERVER_BUSY_RETRY_AFTER,
+                ));
+            }
+            _ => {}
+        }
+        response.json(ErrorBody {
+            code: self.code(),
+            message: self.message(),
+            request_id: REQUEST_ID
+                .try_with(Clone::clone)
+                .ok()
+                .filter(|request_id| !request_id.is_empty()),
+            fields: match self {
+                ApiError::InvalidFields(fields) => fields.clone(),
+                _ => Vec::new(),
+            },
+        })
+    }
+}
+
+/**
+ * # Brief
+ * Middleware making the request id of `TracingLogger` available to `ApiError`.
+ *
+ * # Detail
+ * - Wrapped inside `TracingLogger`, which generates the id.
+ */
+pub async fn request_scope(
+    request: actix_web::dev::ServiceRequest,
+    next: actix_web::middleware::Next<impl actix_web::body::MessageBody + 'static>,
+) -> Result<actix_web::dev::ServiceResponse<impl actix_web::body::MessageBody>, actix_web::Error> {
+    let request_id = actix_web::HttpMessage::extensions(&request)
+        .get::<tracing_actix_web::RequestId>()
+        .map(|request_id| request_id.to_string())
+        .unwrap_or_default();
+    REQUEST_ID.scope(request_id, next.call(request)).await
+}
+
+/**
+ * # Brief
+ * Answers malformed JSON bodies & query strings as `invalid_request`.
+ *
+ * # Detail
+ * - Registered as the error handler of actix's `JsonConfig` & `QueryConfig`.
+ */
+pub fn extractor_error(
+    error: impl std::fmt::Display,
+    _request: &actix_web::HttpRequest,
+) -> actix_web::Error {
+    ApiError::bad_request(CODE_INVALID_REQUEST, error.to_string()).into()
+}
+
+/// `PathConfig` error handler, a path that doesn't parse names nothing
+pub fn path_error(
+    _error: actix_web::error::PathError,
+    _request: &actix_web::HttpRequest,
+) -> actix_web::Error {
+    ApiError::not_found(CODE_NOT_FOUND, "Not Found").into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::ResponseError;
+    use actix_web::http::StatusCode;
+
+    async fn body(error: &ApiError) -> serde_json::Value {
+        let response = error.error_response();
+        let bytes = actix_web::body::to_bytes(response.into_body())
+            .await
+            .unwrap();
+        serde_json::from_slice(&bytes).unwrap()
+    }
+
+    fn retry_after(error: &ApiError) -> Option<String> {
+        error
+            .error_response()
+            .headers()
+            .get(actix_web::http::header::RETRY_AFTER)
+            .map(|value| value.to_str().unwrap().to_string())
+    }
+
+    #[actix_web::test]
+    async fn maps_variants_to_statuses() {
+        for (error, status, code) in [
+            (
+                ApiError::bad_request("bad", "Bad"),
+                StatusCode::BAD_REQUEST,
+                "bad",
+            ),
+            (
+                ApiError::invalid_field("email", "Email is invalid"),
+                StatusCode::UNPROCESSABLE_ENTITY,
+                CODE_INVALID_FIELDS,
+            ),
+            (
+                ApiError::unauthorized(CODE_LOGIN_REQUIRED, "Login Required"),
+                StatusCode::UNAUTHORIZED,
+                CODE_LOGIN_REQUIRED,
+            ),
+            (
+                ApiError::forbidden("denied", "Denied"),
+                StatusCode::FORBIDDEN,
+                "denied",
+            ),
+            (
+                ApiError::not_found(CODE_NOT_FOUND, "Not Found"),
+                StatusCode::NOT_FOUND,
+                CODE_NOT_FOUND,
+            ),
+            (
+                ApiError::conflict("taken", "Taken"),
+                StatusCode::CONFLICT,
+                "taken",
+            ),
+            (
+                ApiError::TooManyRequests(5),
+                StatusCode::TOO_MANY_REQUESTS,
+                CODE_RATE_LIMITED,
+            ),
+            (
+                ApiError::bad_gateway("upstream", "Upstream"),
+                StatusCode::BAD_GATEWAY,
+                "upstream",
+            ),
+            (
+                ApiError::ServiceUnavailable,
+                StatusCode::SERVICE_UNAVAILABLE,
+                CODE_SERVER_BUSY,
+            ),
+            (
+                ApiError::database(sqlx::Error::RowNotFound, "SELECT", "users"),
+                StatusCode::INTERNAL_SERVER_ERROR,
+                CODE_SERVER_ERROR,
+            ),
+            (
+                ApiError::internal("test", "function", "cause"),
+                StatusCode::INTERNAL_SERVER_ERROR,
+                CODE_SERVER_ERROR,
+            ),
+        ] {
+            assert_eq!(error.status_code(), status, "{}", error);
+            assert_eq!(error.error_response().status(), status, "{}", error);
+            assert_eq!(error.code(), code);
+            assert_eq!(body(&error).await["code"], code);
+        }
+    }
+
+    #[actix_web::test]
+    async fn server_errors_hide_their_cause() {
+        let error = ApiError::internal("test", "function", "secret connection string");
+        let body = body(&error).await;
+        assert_eq!(body["message"], "Server Error, Refresh & Retry");
+        assert!(!body.to_string().contains("secret"));
+    }
+
+    #[test]
+    fn throttled_answers_carry_retry_after() {
+        assert_eq!(
+            retry_after(&ApiError::TooManyRequests(42)).as_deref(),
+            Some("42")
+        );
+        // never tell a client to retry right away
+        assert_eq!(
+            retry_after(&ApiError::TooManyRequests(0)).as_deref(),
+            Some("1")
+        );
+        assert_eq!(
+            retry_after(&ApiError::ServiceUnavailable),
+            Some(SERVER_BUSY_RETRY_AFTER.to_string())
+        );
+        assert_eq!(retry_after(&ApiError::bad_request("bad", "Bad")), None);
+    }
+
+    #[actix_web::test]
+    async fn fields_are_listed_only_when_rejected() {
+        let error = ApiError::InvalidFields(vec![
+            FieldError::new("email", "Email is invalid"),
+            FieldError::new("password", "Password is too short"),
+        ]);
+        assert_eq!(
+            body(&error).await["fields"],
+            serde_json::json!([
+                { "field": "email", "message": "Email is invalid" },
+                { "field": "password", "message": "Password is too short" },
+            ])
+        );
+        assert!(
+            body(&ApiError::conflict("taken", "Taken"))
+                .await
+                .get("fields")
+                .is_none()
+        );
+    }
+
+    #[actix_web::test]
+    async fn request_id_comes_from_the_request_scope() {
+        let error = ApiError::not_found(CODE_NOT_FOUND, "Not Found");
+        assert_eq!(body(&error).await["request_id"], serde_json::Value::Null);
+
+        let scoped = REQUEST_ID
+            .scope("request-1".to_string(), body(&error))
+            .await;
+        assert_eq!(scoped["request_id"], "request-1");
+        // no id from the logger, no id in the body
+        let empty = REQUEST_ID.scope(String::new(), body(&error)).await;
+        assert_eq!(empty["request_id"], serde_json::Value::Null);
+    }
+
+    #[actix_web::test]
+    async fn request_scope_exposes_the_logger_id() {
+        let app = actix_web::test::init_service(
+            actix_web::App::new()
+                .wrap(actix_web::middleware::from_fn(request_scope))
+                .wrap(tracing_actix_web::TracingLogger::default())
+                .route(
+                    "/",
+                    actix_web::web::get().to(|| async {
+                        Err::<actix_web::HttpResponse, _>(ApiError::not_found(
+                            CODE_NOT_FOUND,
+                            "Not Found",
+                        ))
+                    }),
+                ),
+        )
+        .await;
+        let response = actix_web::test::call_service(
+            &app,
+            actix_web::test::TestRequest::get().uri("/").to_request(),
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        let body: serde_json::Value = actix_web::test::read_body_json(response).await;
+        assert!(!body["request_id"].as_str().unwrap().is_empty());
+    }
+}
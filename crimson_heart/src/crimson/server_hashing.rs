@@ -0,0 +1,329 @@
+use super::server_passwords::{Passwords, Verification};
+
+/*
+ * Dedicated pool for password hashing.
+ *
+ * Argon2 is slow on purpose, left on the blocking pool of actix a burst of
+ * logins queues without bound & every request waits for all of them. Here a
+ * fixed number of threads hash, at most `password.hashing_queue` jobs wait for
+ * them & anything beyond is refused at once, answered `503` by the handlers.
+ *
+ * Metrics, registered next to the HTTP ones on `/metrics`:
+ * - `crimson_password_hash_queue_depth` jobs waiting for a thread.
+ * - `crimson_password_hash_wait_seconds` time jobs waited.
+ * - `crimson_password_hash_seconds{op="hash|verify"}` time spent hashing.
+ * - `crimson_password_hash_rejected_total` jobs refused, the queue being full.
+ */
+
+type Job = Box<dyn FnOnce(&Passwords) + Send>;
+
+#[derive(Debug)]
+pub enum HashingError {
+    /// the queue is full, retry later
+    Saturated,
+    /// the job panicked, its thread carries on
+    Failed,
+}
+
+impl std::fmt::Display for HashingError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            HashingError::Saturated => write!(f, "password hashing queue is full"),
+            HashingError::Failed => write!(f, "password hashing job panicked"),
+        }
+    }
+}
+
+impl std::error::Error for HashingError {}
+
+#[derive(Clone)]
+struct HashingMetrics {
+    queue_depth: prometheus::IntGauge,
+    wait_seconds: prometheus::Histogram,
+    hash_seconds: prometheus::HistogramVec,
+    rejected_total: prometheus::IntCounter,
+}
+
+impl HashingMetrics {
+    fn new(registry: &prometheus::Registry) -> Result<HashingMetrics, prometheus::Error> {
+        // argon2 takes tens to hundreds of ms, the queue may add seconds
+        let buckets = vec![
+            0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+        ];
+        let metrics = HashingMetrics {
+            queue_depth: prometheus::IntGauge::new(
+                "crimson_password_hash_queue_depth",
+                "password hashing jobs waiting for a thread",
+            )?,
+            wait_seconds: prometheus::Histogram::with_opts(
+                prometheus::HistogramOpts::new(
+                    "crimson_password_hash_wait_seconds",
+                    "time password hashing jobs waited for a thread",
+                )
+                .buckets(buckets.clone()),
+            )?,
+            hash_seconds: prometheus::HistogramVec::new(
+                prometheus::HistogramOpts::new(
+                    "crimson_password_hash_seconds",
+                    "time spent hashing or verifying a password",
+                )
+                .buckets(buckets),
+                &["op"],
+            )?,
+            rejected_total: prometheus::IntCounter::new(
+                "crimson_password_hash_rejected_total",
+                "password hashing jobs refused because the queue was full",
+            )?,
+        };
+        registry.register(Box::new(metrics.queue_depth.clone()))?;
+        registry.register(Box::new(metrics.wait_seconds.clone()))?;
+        registry.register(Box::new(metrics.hash_seconds.clone()))?;
+        registry.register(Box::new(metrics.rejected_total.clone()))?;
+        Ok(metrics)
+    }
+}
+
+/// handle to the hashing threads, cheap to clone
+#[derive(Clone)]
+pub struct HashingPool {
+    sender: std::sync::mpsc::SyncSender<Job>,
+    passwords: std::sync::Arc<Passwords>,
+    metrics: HashingMetrics,
+}
+
+impl HashingPool {
+    /**
+     * # Brief
+     * Starts `workers` hashing threads behind a queue of `queue` jobs.
+     *
+     * # Detail
+     * - Threads live as long as the process, they stop once every handle is dropped.
+     * - Metrics are registered in `registry`, e.g. the one of `/metrics`.
+     */
+    pub fn new(
+        passwords: Passwords,
+        workers: usize,
+        queue: usize,
+        registry: &prometheus::Registry,
+    ) -> std::io::Result<HashingPool> {
+        let metrics = HashingMetrics::new(registry).map_err(std::io::Error::other)?;
+        let passwords = std::sync::Arc::new(passwords);
+        let (sender, receiver) = std::sync::mpsc::sync_channel::<Job>(queue);
+        let receiver = std::sync::Arc::new(std::sync::Mutex::new(receiver));
+
+        for worker in 0..workers {
+            let receiver = receiver.clone();
+            let passwords = passwords.clone();
+            std::thread::Builder::new()
+                .name(format!("crimson-hashing-{}", worker))
+                .spawn(move || {
+                    loop {
+                        // the lock is only held while waiting, never while hashing
+                        let job = match receiver.lock() {
+                            Ok(receiver) => receiver.recv(),
+                            Err(_) => return,
+                        };
+                        match job {
+                            // a panic drops the result sender, the caller sees `Failed`
+                            Ok(job) => {
+                                let _ =
+                                    std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+                                        job(&passwords)
+                                    }));
+                            }
+                            Err(_) => return,
+                        }
+                    }
+                })?;
+        }
+
+        Ok(HashingPool {
+            sender,
+            passwords,
+            metrics,
+        })
+    }
+
+    pub fn passwords(&self) -> &Passwords {
+        &self.passwords
+    }
+
+    // queues `job` unless the queue is full, `op` labels the hash time
+    async fn run<T: Send + 'static>(
+        &self,
+        op: &'static str,
+        job: impl FnOnce(&Passwords) -> T + Send + 'static,
+    ) -> Result<T, HashingError> {
+        let (result_sender, result_receiver) = tokio::sync::oneshot::channel();
+        let metrics = self.metrics.clone();
+        let queued_at = std::time::Instant::now();
+
+        // counted before queueing, the job may start right away
+        self.metrics.queue_depth.inc();
+        let queued = self.sender.try_send(Box::new(move |passwords: &Passwords| {
+            metrics.queue_depth.dec();
+            metrics
+                .wait_seconds
+                .observe(queued_at.elapsed().as_secs_f64());
+            let timer = metrics.hash_seconds.with_label_values(&[op]).start_timer();
+            let result = job(passwords);
+            timer.observe_duration();
+            // the request may be gone already, nothing to answer then
+            let _ = result_sender.send(result);
+        }));
+        if queued.is_err() {
+            self.metrics.queue_depth.dec();
+            self.metrics.rejected_total.inc();
+            return Err(HashingError::Saturated);
+        }
+
+        result_receiver.await.map_err(|_| HashingError::Failed)
+    }
+
+    /// `Passwords::hash` on the pool
+    pub async fn hash(
+        &self,
+        password: String,
+    ) -> Result<Result<String, argon2::password_hash::Error>, HashingError> {
+        self.run("hash", move |passwords| passwords.hash(&password))
+            .await
+    }
+
+    /// `Passwords::verify` on the pool
+    pub async fn verify(
+        &self,
+        password: String,
+        stored: String,
+    ) -> Result<Verification, HashingError> {
+        self.run("verify", move |passwords| {
+            passwords.verify(&password, &stored)
+        })
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crimson::server_config::{PasswordSection, SecuritySection};
+
+    // one thread behind a one job queue, metrics in a registry of its own
+    fn pool(registry: &prometheus::Registry) -> HashingPool {
+        let security = SecuritySection {
+            hash_salt: "pepper".into(),
+            hash_salt_id: "1".into(),
+            previous_hash_salts: Default::default(),
+            password_reset_ttl: 3600,
+            email_verification_ttl: 3600,
+            totp_key: None,
+            totp_issuer: "crimson".into(),
+        };
+        let password = PasswordSection {
+            min_length: 8,
+            max_length: 128,
+            require_lowercase: false,
+            require_uppercase: false,
+            require_digit: false,
+            require_symbol: false,
+            breached_list: None,
+            argon2: argon2::Params::new(64, 1, 1, None).unwrap(),
+            hashing_workers: 1,
+            hashing_queue: 1,
+        };
+        HashingPool::new(Passwords::new(&security, &password), 1, 1, registry).unwrap()
+    }
+
+    #[tokio::test]
+    async fn refuses_jobs_beyond_the_queue() {
+        let registry = prometheus::Registry::new();
+        let pool = pool(&registry);
+        let metrics = pool.metrics.clone();
+
+        // the first job holds the only thread until released
+        let (started_sender, started) = tokio::sync::oneshot::channel();
+        let (release, released) = std::sync::mpsc::channel::<()>();
+        let first = tokio::spawn({
+            let pool = pool.clone();
+            async move {
+                pool.run("verify", move |_| {
+                    let _ = started_sender.send(());
+                    released.recv().unwrap();
+                    1
+                })
+                .await
+            }
+        });
+        started.await.unwrap();
+        assert_eq!(metrics.queue_depth.get(), 0);
+
+        // the second one waits in the queue
+        let second = tokio::spawn({
+            let pool = pool.clone();
+            async move { pool.run("verify", |_| 2).await }
+        });
+        while metrics.queue_depth.get() == 0 {
+            tokio::task::yield_now().await;
+        }
+        assert_eq!(metrics.queue_depth.get(), 1);
+
+        // the third one is refused at once
+        assert!(matches!(
+            pool.run("verify", |_| 3).await,
+            Err(HashingError::Saturated)
+        ));
+        assert_eq!(metrics.rejected_total.get(), 1);
+        assert_eq!(metrics.queue_depth.get(), 1);
+
+        release.send(()).unwrap();
+        assert_eq!(first.await.unwrap().unwrap(), 1);
+        assert_eq!(second.await.unwrap().unwrap(), 2);
+        assert_eq!(metrics.queue_depth.get(), 0);
+        assert_eq!(metrics.rejected_total.get(), 1);
+        assert_eq!(metrics.wait_seconds.get_sample_count(), 2);
+        assert_eq!(
+            metrics
+                .hash_seconds
+                .with_label_values(&["verify"])
+                .get_sample_count(),
+            2
+        );
+
+        // drained, real work goes through again
+        let hash = pool.hash("hunter2".into()).await.unwrap().unwrap();
+        assert_eq!(
+            pool.verify("hunter2".into(), hash).await.unwrap(),
+            Verification::Current
+        );
+        assert_eq!(metrics.queue_depth.get(), 0);
+        assert_eq!(registry.gather().len(), 4);
+    }
+
+    #[tokio::test]
+    async fn panicking_jobs_fail_alone() {
+        let registry = prometheus::Registry::new();
+        let pool = pool(&registry);
+        let failed = pool.run("verify", |_| -> u32 { panic!("boom") }).await;
+        assert!(matches!(failed, Err(HashingError::Failed)));
+        assert_eq!(pool.run("verify", |_| 1).await.unwrap(), 1);
+        assert_eq!(pool.metrics.queue_depth.get(), 0);
+    }
+
+    #[test]
+    fn saturation_is_answered_503_with_retry_after() {
+        use actix_web::ResponseError;
+
+        let error = crate::crimson::server_errors::ApiError::from(HashingError::Saturated);
+        let response = error.error_response();
+        assert_eq!(
+            response.status(),
+            actix_web::http::StatusCode::SERVICE_UNAVAILABLE
+        );
+        assert_eq!(
+            response
+                .headers()
+                .get(actix_web::http::header::RETRY_AFTER)
+                .unwrap(),
+            "1"
+        );
+    }
+}
@@ -0,0 +1,118 @@
+use std::io::Write;
+
+/// a plain text email
+#[derive(Debug, Clone)]
+pub struct MailMessage {
+    pub to: String,
+    pub subject: String,
+    pub body: String,
+}
+
+#[derive(Debug)]
+pub enum MailError {
+    Io(std::io::Error),
+}
+
+impl std::fmt::Display for MailError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MailError::Io(e) => write!(f, "io error ({})", e),
+        }
+    }
+}
+
+impl std::error::Error for MailError {}
+
+impl From<std::io::Error> for MailError {
+    fn from(e: std::io::Error) -> Self {
+        MailError::Io(e)
+    }
+}
+
+/**
+ * # Brief
+ * Delivers outgoing email.
+ *
+ * # Detail
+ * - `send` may block, callers run it on the blocking pool.
+ * - Implementations must not log the body, it carries single-use tokens.
+ */
+pub trait Mailer: Send + Sync {
+    fn send(&self, message: &MailMessage) -> Result<(), MailError>;
+}
+
+fn render(from: &str, message: &MailMessage) -> String {
+    format!(
+        "From: {}\nTo: {}\nDate: {}\nSubject: {}\n\n{}\n",
+        from,
+        message.to,
+        chrono::Utc::now().to_rfc2822(),
+        message.subject,
+        message.body
+    )
+}
+
+/// prints every message to stdout, for local development
+pub struct StdoutMailer {
+    pub from: String,
+}
+
+impl Mailer for StdoutMailer {
+    fn send(&self, message: &MailMessage) -> Result<(), MailError> {
+        let mut stdout = std::io::stdout().lock();
+        writeln!(stdout, "{}", render(&self.from, message))?;
+        stdout.flush()?;
+        Ok(())
+    }
+}
+
+/// appends every message to a file, mbox style, for local testing
+pub struct FileMailer {
+    pub from: String,
+    pub path: std::path::PathBuf,
+    // one writer at a time, messages must not interleave
+    lock: std::sync::Mutex<()>,
+}
+
+impl FileMailer {
+    pub fn new(from: String, path: std::path::PathBuf) -> Self {
+        FileMailer {
+            from,
+            path,
+            lock: std::sync::Mutex::new(()),
+        }
+    }
+}
+
+impl Mailer for FileMailer {
+    fn send(&self, message: &MailMessage) -> Result<(), MailError> {
+        let _guard = self.lock.lock().unwrap_or_else(|e| e.into_inner());
+        let mut file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        writeln!(file, "{}", render(&self.from, message))?;
+        Ok(())
+    }
+}
+
+/**
+ * # Brief
+ * Builds the mailer named by `mail.transport`.
+ */
+pub fn mailer(
+    transport: &str,
+    from: &str,
+    path: Option<&std::path::Path>,
+) -> Option<std::sync::Arc<dyn Mailer>> {
+    match transport {
+        "stdout" => Some(std::sync::Arc::new(StdoutMailer {
+            from: from.to_string(),
+        })),
+        "file" => Some(std::sync::Arc::new(FileMailer::new(
+            from.to_string(),
+            path?.to_path_buf(),
+        ))),
+        _ => None,
+    }
+}
@@ -0,0 +1,605 @@
+/*
+ * Embedded migrations for the central database.
+ *
+ * Every migration is compiled into the binary and applied in version order,
+ * applied versions are tracked in `crimson_schema_migrations`.
+ *
+ * CockroachDB does not guarantee atomic schema changes inside explicit
+ * transactions, and rejects DML on a table altered earlier in the same
+ * transaction. So each statement runs on its own (implicit transaction) and
+ * the version is recorded only after all of its statements succeeded. A
+ * migration that fails halfway is simply retried, which is why every
+ * statement must be idempotent (`IF NOT EXISTS` / `IF EXISTS`).
+ */
+
+pub struct Migration {
+    pub version: i64,
+    pub name: &'static str,
+    pub up: &'static str,
+    pub down: &'static str,
+}
+
+pub const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        name: "create_users",
+        up: include_str!("../../migrations/0001_create_users.sql"),
+        down: include_str!("../../migrations/0001_create_users.down.sql"),
+    },
+    Migration {
+        version: 2,
+        name: "create_jobs",
+        up: include_str!("../../migrations/0002_create_jobs.sql"),
+        down: include_str!("../../migrations/0002_create_jobs.down.sql"),
+    },
+    Migration {
+        version: 3,
+        name: "add_email_verified_at",
+        up: include_str!("../../migrations/0003_add_email_verified_at.sql"),
+        down: include_str!("../../migrations/0003_add_email_verified_at.down.sql"),
+    },
+    Migration {
+        version: 4,
+        name: "add_totp",
+        up: include_str!("../../migrations/0004_add_totp.sql"),
+        down: include_str!("../../migrations/0004_add_totp.down.sql"),
+    },
+    Migration {
+        version: 5,
+        name: "create_user_credentials",
+        up: include_str!("../../migrations/0005_create_user_credentials.sql"),
+        down: include_str!("../../migrations/0005_create_user_credentials.down.sql"),
+    },
+    Migration {
+        version: 6,
+        name: "create_api_keys",
+        up: include_str!("../../migrations/0006_create_api_keys.sql"),
+        down: include_str!("../../migrations/0006_create_api_keys.down.sql"),
+    },
+    Migration {
+        version: 7,
+        name: "create_user_identities",
+        up: include_str!("../../migrations/0007_create_user_identities.sql"),
+        down: include_str!("../../migrations/0007_create_user_identities.down.sql"),
+    },
+    Migration {
+        version: 8,
+        name: "create_oauth_clients",
+        up: include_str!("../../migrations/0008_create_oauth_clients.sql"),
+        down: include_str!("../../migrations/0008_create_oauth_clients.down.sql"),
+    },
+    Migration {
+        version: 9,
+        name: "create_roles",
+        up: include_str!("../../migrations/0009_create_roles.sql"),
+        down: include_str!("../../migrations/0009_create_roles.down.sql"),
+    },
+    Migration {
+        version: 10,
+        name: "create_organizations",
+        up: include_str!("../../migrations/0010_create_organizations.sql"),
+        down: include_str!("../../migrations/0010_create_organizations.down.sql"),
+    },
+    Migration {
+        version: 11,
+        name: "normalize_user_emails",
+        up: include_str!("../../migrations/0011_normalize_user_emails.sql"),
+        down: include_str!("../../migrations/0011_normalize_user_emails.down.sql"),
+    },
+];
+
+// single row lock, CockroachDB has no advisory locks
+const LOCK_ID: i64 = 1;
+const LOCK_RETRIES: u32 = 60;
+const LOCK_STALE_AFTER: &str = "10 minutes";
+
+#[derive(Debug)]
+pub enum MigrationError {
+    Database(sqlx::Error),
+    SchemaAhead { database: i64, binary: i64 },
+    SchemaBehind { pending: Vec<i64> },
+    UnknownVersion(i64),
+    LockTimeout,
+}
+
+impl std::fmt::Display for MigrationError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MigrationError::Database(e) => write!(f, "database error ({})", e),
+            MigrationError::SchemaAhead { database, binary } => write!(
+                f,
+                "database schema is at version {} but this binary only knows up to {}, refusing to continue",
+                database, binary
+            ),
+            MigrationError::SchemaBehind { pending } => write!(
+                f,
+                "database schema is missing migrations {:?}, run `migrate up` or enable database.auto_migrate",
+                pending
+            ),
+            MigrationError::UnknownVersion(version) => {
+                write!(f, "migration version {} does not exist", version)
+            }
+            MigrationError::LockTimeout => write!(
+                f,
+                "timed out waiting for the migration lock, is another instance migrating?"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for MigrationError {}
+
+impl From<sqlx::Error> for MigrationError {
+    fn from(e: sqlx::Error) -> Self {
+        MigrationError::Database(e)
+    }
+}
+
+pub struct MigrationStatus {
+    pub version: i64,
+    pub name: String,
+    pub applied_at: Option<String>,
+}
+
+fn latest_version() -> i64 {
+    MIGRATIONS.last().map(|m| m.version).unwrap_or(0)
+}
+
+fn pending_versions(applied: &[i64]) -> Vec<i64> {
+    MIGRATIONS
+        .iter()
+        .map(|m| m.version)
+        .filter(|version| !applied.contains(version))
+        .collect()
+}
+
+async fn ensure_tracking_tables(pool: &sqlx::PgPool) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS crimson_schema_migrations (
+            version INT8 PRIMARY KEY,
+            name STRING NOT NULL,
+            applied_at TIMESTAMPTZ NOT NULL DEFAULT now()
+        )
+        "#,
+    )
+    .execute(pool)
+    .await?;
+
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS crimson_schema_lock (
+            lock_id INT8 PRIMARY KEY,
+            holder STRING NOT NULL,
+            locked_at TIMESTAMPTZ NOT NULL DEFAULT now()
+        )
+        "#,
+    )
+    .execute(pool)
+    .await?;
+
+    Ok(())
+}
+
+async fn applied_versions(pool: &sqlx::PgPool) -> Result<Vec<(i64, String, String)>, sqlx::Error> {
+    sqlx::query_as(
+        r#"
+        SELECT version, name, applied_at::STRING
+        FROM crimson_schema_migrations
+        ORDER BY version
+        "#,
+    )
+    .fetch_all(pool)
+    .await
+}
+
+async fn acquire_lock(pool: &sqlx::PgPool, holder: &str) -> Result<(), MigrationError> {
+    for _ in 0..LOCK_RETRIES {
+        // a crashed migrator must not block every later start
+        sqlx::query(&format!(
+            "DELETE FROM crimson_schema_lock WHERE locked_at < now() - INTERVAL '{}'",
+            LOCK_STALE_AFTER
+        ))
+        .execute(pool)
+        .await?;
+
+        let acquired = sqlx::query(
+            r#"
+            INSERT INTO crimson_schema_lock (lock_id, holder)
+            VALUES ($1, $2)
+            ON CONFLICT (lock_id) DO NOTHING
+            "#,
+        )
+        .bind(LOCK_ID)
+        .bind(holder)
+        .execute(pool)
+        .await?;
+
+        if acquired.rows_affected() == 1 {
+            return Ok(());
+        }
+
+        tracing::info!(component = "migrations", "waiting for migration lock");
+        tokio::time::sleep(std::time::Duration::from_secs(1)).await;
+    }
+
+    Err(MigrationError::LockTimeout)
+}
+
+async fn release_lock(pool: &sqlx::PgPool, holder: &str) -> Result<(), sqlx::Error> {
+    sqlx::query("DELETE FROM crimson_schema_lock WHERE lock_id = $1 AND holder = $2")
+        .bind(LOCK_ID)
+        .bind(holder)
+        .execute(pool)
+        .await?;
+    Ok(())
+}
+
+// splits a migration file into statements, `;` inside quotes or comments is ignored,
+// comments themselves are dropped
+fn split_statements(sql: &str) -> Vec<String> {
+    let mut statements = Vec::new();
+    let mut current = String::new();
+    let mut chars = sql.chars().peekable();
+    let mut quote: Option<char> = None;
+
+    while let Some(c) = chars.next() {
+        match quote {
+            Some(q) => {
+                current.push(c);
+                if c == q {
+                    quote = None;
+                }
+            }
+            None => match c {
+                '\'' | '"' => {
+                    quote = Some(c);
+                    current.push(c);
+                }
+                '-' if chars.peek() == Some(&'-') => {
+                    for skipped in chars.by_ref() {
+                        if skipped == '\n' {
+                            current.push('\n');
+                            break;
+                        }
+                    }
+                }
+                // block comments nest in PostgreSQL, a space keeps tokens apart
+                '/' if chars.peek() == Some(&'*') => {
+                    chars.next();
+                    let mut depth = 1;
+                    while depth > 0 {
+                        match (chars.next(), chars.peek()) {
+                            (Some('*'), Some('/')) => {
+                                chars.next();
+                                depth -= 1;
+                            }
+                            (Some('/'), Some('*')) => {
+                                chars.next();
+                                depth += 1;
+                            }
+                            (Some(_), _) => {}
+                            (None, _) => break,
+                        }
+                    }
+                    current.push(' ');
+                }
+                ';' => {
+                    if !current.trim().is_empty() {
+                        statements.push(current.trim().to_string());
+                    }
+                    current.clear();
+                }
+                _ => current.push(c),
+            },
+        }
+    }
+
+    if !current.trim().is_empty() {
+        statements.push(current.trim().to_string());
+    }
+    statements
+}
+
+async fn execute_script(pool: &sqlx::PgPool, sql: &str) -> Result<(), sqlx::Error> {
+    for statement in split_statements(sql) {
+        sqlx::raw_sql(&statement).execute(pool).await?;
+    }
+    Ok(())
+}
+
+/**
+ * # Brief
+ * Lists every known migration with its applied timestamp.
+ *
+ * # Detail
+ * - Versions recorded in the database but unknown to this binary are listed too.
+ */
+pub async fn status(pool: &sqlx::PgPool) -> Result<Vec<MigrationStatus>, MigrationError> {
+    ensure_tracking_tables(pool).await?;
+    let applied = applied_versions(pool).await?;
+
+    let mut rows: Vec<MigrationStatus> = MIGRATIONS
+        .iter()
+        .map(|migration| MigrationStatus {
+            version: migration.version,
+            name: migration.name.to_string(),
+            applied_at: applied
+                .iter()
+                .find(|(version, _, _)| *version == migration.version)
+                .map(|(_, _, applied_at)| applied_at.clone()),
+        })
+        .collect();
+
+    for (version, name, applied_at) in applied {
+        if !MIGRATIONS.iter().any(|m| m.version == version) {
+            rows.push(MigrationStatus {
+                version,
+                name: format!("{} (unknown to this binary)", name),
+                applied_at: Some(applied_at),
+            });
+        }
+    }
+
+    Ok(rows)
+}
+
+/**
+ * # Brief
+ * Applies every pending migration in version order.
+ *
+ * # Detail
+ * - Holds the `crimson_schema_lock` row for the whole run.
+ * - Refuses to run when the database is ahead of this binary.
+ * - Returns the versions that were applied.
+ */
+pub async fn up(pool: &sqlx::PgPool) -> Result<Vec<i64>, MigrationError> {
+    ensure_tracking_tables(pool).await?;
+
+    let holder = uuid::Uuid::now_v7().to_string();
+    acquire_lock(pool, &holder).await?;
+    let result = up_locked(pool).await;
+    release_lock(pool, &holder).await?;
+    result
+}
+
+async fn up_locked(pool: &sqlx::PgPool) -> Result<Vec<i64>, MigrationError> {
+    let applied = applied_versions(pool).await?;
+    if let Some((database, _, _)) = applied.last()
+        && *database > latest_version()
+    {
+        return Err(MigrationError::SchemaAhead {
+            database: *database,
+            binary: latest_version(),
+        });
+    }
+
+    let mut newly_applied = Vec::new();
+    for migration in MIGRATIONS {
+        if applied
+            .iter()
+            .any(|(version, _, _)| *version == migration.version)
+        {
+            continue;
+        }
+
+        tracing::info!(
+            component = "migrations",
+            version = migration.version,
+            name = migration.name,
+            "applying migration"
+        );
+        execute_script(pool, migration.up).await?;
+
+        sqlx::query("INSERT INTO crimson_schema_migrations (version, name) VALUES ($1, $2)")
+            .bind(migration.version)
+            .bind(migration.name)
+            .execute(pool)
+            .await?;
+        newly_applied.push(migration.version);
+    }
+
+    Ok(newly_applied)
+}
+
+/**
+ * # Brief
+ * Fails unless the database schema matches this binary.
+ *
+ * # Detail
+ * - Used on startup when `database.auto_migrate` is off.
+ * - A newer schema is `SchemaAhead`, any migration still to apply is `SchemaBehind`.
+ */
+pub async fn verify(pool: &sqlx::PgPool) -> Result<(), MigrationError> {
+    ensure_tracking_tables(pool).await?;
+    let applied = applied_versions(pool).await?;
+
+    match applied.last() {
+        Some((database, _, _)) if *database > latest_version() => {
+            Err(MigrationError::SchemaAhead {
+                database: *database,
+                binary: latest_version(),
+            })
+        }
+        _ => {
+            let applied: Vec<i64> = applied.iter().map(|(version, _, _)| *version).collect();
+            let pending = pending_versions(&applied);
+            if pending.is_empty() {
+                Ok(())
+            } else {
+                Err(MigrationError::SchemaBehind { pending })
+            }
+        }
+    }
+}
+
+/**
+ * # Brief
+ * Number of users migration 11 could not case-fold the email of.
+ *
+ * # Detail
+ * - Listed in `user_email_conflicts`, login can't reach them until resolved.
+ * - `None` while migration 11 is not applied, the table doesn't exist yet.
+ */
+pub async fn email_conflicts(pool: &sqlx::PgPool) -> Result<Option<i64>, sqlx::Error> {
+    ensure_tracking_tables(pool).await?;
+    let applied = applied_versions(pool).await?;
+    if !applied.iter().any(|(version, _, _)| *version == 11) {
+        return Ok(None);
+    }
+
+    sqlx::query_scalar("SELECT count(*) FROM user_email_conflicts")
+        .fetch_one(pool)
+        .await
+        .map(Some)
+}
+
+/**
+ * # Brief
+ * Reverts applied migrations newer than `to`, newest first.
+ *
+ * # Detail
+ * - `to = 0` reverts everything.
+ * - Returns the versions that were reverted.
+ */
+pub async fn down(pool: &sqlx::PgPool, to: i64) -> Result<Vec<i64>, MigrationError> {
+    if to != 0 && !MIGRATIONS.iter().any(|m| m.version == to) {
+        return Err(MigrationError::UnknownVersion(to));
+    }
+    ensure_tracking_tables(pool).await?;
+
+    let holder = uuid::Uuid::now_v7().to_string();
+    acquire_lock(pool, &holder).await?;
+    let result = down_locked(pool, to).await;
+    release_lock(pool, &holder).await?;
+    result
+}
+
+async fn down_locked(pool: &sqlx::PgPool, to: i64) -> Result<Vec<i64>, MigrationError> {
+    let applied = applied_versions(pool).await?;
+
+    let mut reverted = Vec::new();
+    for (version, _, _) in applied.iter().rev() {
+        if *version <= to {
+            break;
+        }
+
+        // a newer binary applied this, we do not know how to revert it
+        let migration = match MIGRATIONS.iter().find(|m| m.version == *version) {
+            Some(migration) => migration,
+            None => {
+                return Err(MigrationError::SchemaAhead {
+                    database: *version,
+                    binary: latest_version(),
+                });
+            }
+        };
+
+        tracing::info!(
+            component = "migrations",
+            version = migration.version,
+            name = migration.name,
+            "reverting migration"
+        );
+        execute_script(pool, migration.down).await?;
+
+        sqlx::query("DELETE FROM crimson_schema_migrations WHERE version = $1")
+            .bind(migration.version)
+            .execute(pool)
+            .await?;
+        reverted.push(migration.version);
+    }
+
+    Ok(reverted)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn splits_on_semicolons() {
+        assert_eq!(
+            split_statements("CREATE TABLE a (x INT);\n\nCREATE TABLE b (y INT);\n"),
+            vec!["CREATE TABLE a (x INT)", "CREATE TABLE b (y INT)"]
+        );
+        assert!(split_statements(" ;\n;; ").is_empty());
+    }
+
+    #[test]
+    fn keeps_a_trailing_statement_without_semicolon() {
+        assert_eq!(
+            split_statements("SELECT 1;\nSELECT 2\n"),
+            vec!["SELECT 1", "SELECT 2"]
+        );
+    }
+
+    #[test]
+    fn ignores_semicolons_in_quotes() {
+        assert_eq!(
+            split_statements("INSERT INTO a VALUES ('x;y', 'it''s;');\nSELECT \"a;b\" FROM a;"),
+            vec![
+                "INSERT INTO a VALUES ('x;y', 'it''s;')",
+                "SELECT \"a;b\" FROM a",
+            ]
+        );
+    }
+
+    #[test]
+    fn ignores_semicolons_in_line_comments() {
+        assert_eq!(
+            split_statements(
+                "-- first; still a comment\nSELECT 1; -- trailing; comment\nSELECT 2;"
+            ),
+            vec!["SELECT 1", "SELECT 2"]
+        );
+        assert_eq!(
+            split_statements("SELECT '--;'; SELECT 2"),
+            vec!["SELECT '--;'", "SELECT 2"]
+        );
+    }
+
+    #[test]
+    fn ignores_semicolons_in_block_comments() {
+        assert_eq!(
+            split_statements(
+                "/* header; */ SELECT 1;\nSELECT/* a; b */2;\n/* outer /* inner; */ still; */ SELECT 3"
+            ),
+            vec!["SELECT 1", "SELECT 2", "SELECT 3"]
+        );
+        assert_eq!(
+            split_statements("SELECT '/*;'; SELECT 2"),
+            vec!["SELECT '/*;'", "SELECT 2"]
+        );
+        // unterminated, the rest of the file is comment
+        assert_eq!(split_statements("SELECT 1; /* SELECT 2;"), vec!["SELECT 1"]);
+    }
+
+    #[test]
+    fn lists_the_migrations_still_to_apply() {
+        let all: Vec<i64> = MIGRATIONS.iter().map(|m| m.version).collect();
+        assert!(pending_versions(&all).is_empty());
+        assert_eq!(pending_versions(&[]), all);
+        assert_eq!(
+            pending_versions(&all[..all.len() - 1]),
+            vec![latest_version()]
+        );
+        // a hole left by a failed migration counts too
+        assert_eq!(pending_versions(&all[1..]), vec![all[0]]);
+    }
+
+    #[test]
+    fn bundled_migrations_split() {
+        for migration in MIGRATIONS {
+            for sql in [migration.up, migration.down] {
+                let statements = split_statements(sql);
+                assert!(!statements.is_empty(), "{}", migration.name);
+                assert!(
+                    statements.iter().all(|statement| !statement.ends_with(';')),
+                    "{}",
+                    migration.name
+                );
+            }
+        }
+    }
+}
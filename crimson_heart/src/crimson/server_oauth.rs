@@ -0,0 +1,587 @@
+use super::server_jws::{CompactJws, base64url_encode};
+
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+use p256::ecdsa::signature::{Signer, Verifier};
+use sha2::Digest;
+
+/*
+ * OpenID Connect provider, authorization code flow for our own services.
+ *
+ * Tokens are ES256 JWTs signed with `oauth.signing_key`. Access tokens are
+ * self contained & checked by signature only, they can't be revoked before
+ * they expire, keep `oauth.access_token_ttl` short.
+ *
+ * Redis layout: `oauth_code:{sha256(code)}` hash of a pending authorization
+ * code, expires after `oauth.code_ttl` seconds & is deleted when redeemed.
+ */
+
+pub const SCOPE_OPENID: &str = "openid";
+pub const SCOPE_EMAIL: &str = "email";
+pub const SCOPE_PROFILE: &str = "profile";
+pub const SCOPES: [&str; 3] = [SCOPE_OPENID, SCOPE_EMAIL, SCOPE_PROFILE];
+pub const SIGNING_ALGORITHM: &str = "ES256";
+
+const CODE_BYTES: usize = 32;
+const CLIENT_SECRET_BYTES: usize = 32;
+// RFC 7636 4.1
+const PKCE_VERIFIER_LENGTH: std::ops::RangeInclusive<usize> = 43..=128;
+
+#[derive(Debug)]
+pub enum OauthError {
+    /// the token doesn't verify, the reason is for logs only
+    Invalid(&'static str),
+}
+
+impl std::fmt::Display for OauthError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            OauthError::Invalid(reason) => write!(f, "invalid token ({})", reason),
+        }
+    }
+}
+
+impl std::error::Error for OauthError {}
+
+fn invalid<T>(reason: &'static str) -> Result<T, OauthError> {
+    Err(OauthError::Invalid(reason))
+}
+
+fn sha256_hex(value: &str) -> String {
+    hex::encode(sha2::Sha256::digest(value.as_bytes()))
+}
+
+/// space separated scopes, the form they travel in
+pub fn parse_scope(scope: &str) -> Vec<String> {
+    let mut scopes: Vec<String> = scope.split_whitespace().map(str::to_string).collect();
+    scopes.sort();
+    scopes.dedup();
+    scopes
+}
+
+/// the `code_challenge` sent to `/oauth/authorize` is the S256 of `verifier`
+pub fn verify_pkce(verifier: &str, challenge: &str) -> bool {
+    PKCE_VERIFIER_LENGTH.contains(&verifier.len())
+        && base64url_encode(&sha2::Sha256::digest(verifier.as_bytes())) == challenge
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug)]
+struct TokenHeader {
+    alg: String,
+    typ: String,
+    kid: String,
+}
+
+/// claims of an access token, the audience is the client it was issued to
+#[derive(serde::Serialize, serde::Deserialize, Debug)]
+pub struct AccessTokenClaims {
+    pub iss: String,
+    pub sub: String,
+    pub aud: String,
+    pub client_id: String,
+    pub scope: String,
+    pub iat: i64,
+    pub exp: i64,
+    pub jti: String,
+}
+
+#[derive(serde::Serialize, Debug)]
+pub struct IdTokenClaims {
+    pub iss: String,
+    pub sub: String,
+    pub aud: String,
+    pub iat: i64,
+    pub exp: i64,
+    pub auth_time: i64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub nonce: Option<String>,
+    #[serde(flatten)]
+    pub profile: UserClaims,
+}
+
+/// claims about the user, released by scope, shared by ID tokens & userinfo
+#[derive(serde::Serialize, Debug, Default)]
+pub struct UserClaims {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub email: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub email_verified: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub preferred_username: Option<String>,
+}
+
+/// the ES256 key tokens are signed with
+#[derive(Clone)]
+pub struct TokenSigner {
+    signing_key: p256::ecdsa::SigningKey,
+    /// derived from the public key, changes when the key does
+    kid: String,
+}
+
+impl TokenSigner {
+    pub fn new(signing_key: &[u8; 32]) -> Option<Self> {
+        let signing_key = p256::ecdsa::SigningKey::from_slice(signing_key).ok()?;
+        let point = signing_key.verifying_key().to_encoded_point(false);
+        let kid = hex::encode(&sha2::Sha256::digest(point.as_bytes())[..8]);
+        Some(TokenSigner { signing_key, kid })
+    }
+
+    /// the public key as a JWK, for the JWKS endpoint
+    pub fn jwk(&self) -> serde_json::Value {
+        let point = self.signing_key.verifying_key().to_encoded_point(false);
+        serde_json::json!({
+            "kty": "EC",
+            "crv": "P-256",
+            "use": "sig",
+            "alg": SIGNING_ALGORITHM,
+            "kid": self.kid,
+            "x": point.x().map(|x| base64url_encode(x)),
+            "y": point.y().map(|y| base64url_encode(y)),
+        })
+    }
+
+    pub fn sign<T: serde::Serialize>(&self, claims: &T) -> Result<String, serde_json::Error> {
+        let header = TokenHeader {
+            alg: SIGNING_ALGORITHM.to_string(),
+            typ: "JWT".to_string(),
+            kid: self.kid.clone(),
+        };
+        let signing_input = format!(
+            "{}.{}",
+            base64url_encode(&serde_json::to_vec(&header)?),
+            base64url_encode(&serde_json::to_vec(claims)?)
+        );
+        let signature: p256::ecdsa::Signature = self.signing_key.sign(signing_input.as_bytes());
+        Ok(format!(
+            "{}.{}",
+            signing_input,
+            base64url_encode(&signature.to_bytes())
+        ))
+    }
+
+    /**
+     * # Brief
+     * Checks the signature of a token this signer issued, returns its claims.
+     *
+     * # Detail
+     * - Claims like `exp` are left to the caller.
+     */
+    pub fn verify<T: serde::de::DeserializeOwned>(&self, token: &str) -> Result<T, OauthError> {
+        let jws = CompactJws::parse(token).map_err(OauthError::Invalid)?;
+        let header: TokenHeader =
+            serde_json::from_slice(&jws.header).or(invalid("header is malformed"))?;
+        if header.alg != SIGNING_ALGORITHM || header.kid != self.kid {
+            return invalid("not signed by this key");
+        }
+        let signature = p256::ecdsa::Signature::from_slice(&jws.signature)
+            .or(invalid("signature is malformed"))?;
+        self.signing_key
+            .verifying_key()
+            .verify(jws.signing_input, &signature)
+            .or(invalid("signature mismatch"))?;
+        serde_json::from_slice(&jws.payload).or(invalid("claims are malformed"))
+    }
+}
+
+/// what an authorization code stands for until it is redeemed
+#[derive(Debug, Clone)]
+pub struct AuthorizationCode {
+    pub client_id: String,
+    pub user_id: uuid::Uuid,
+    pub redirect_uri: String,
+    pub scope: String,
+    pub nonce: Option<String>,
+    /// S256 PKCE challenge, required from public clients
+    pub code_challenge: Option<String>,
+    /// unix seconds the user signed in
+    pub auth_time: i64,
+}
+
+#[inline]
+fn code_key(code: &str) -> String {
+    format!("oauth_code:{}", sha256_hex(code))
+}
+
+/**
+ * # Brief
+ * Stores `authorization` under a fresh code, returns the code.
+ */
+pub async fn store_code(
+    redis_connection: &mut deadpool_redis::Connection,
+    authorization: &AuthorizationCode,
+    code_ttl: i64,
+) -> Result<String, deadpool_redis::redis::RedisError> {
+    let mut code_bytes = [0u8; CODE_BYTES];
+    OsRng.fill_bytes(&mut code_bytes);
+    let code = base64url_encode(&code_bytes);
+
+    let key = code_key(&code);
+    let fields = [
+        ("client_id", authorization.client_id.clone()),
+        ("user_id", authorization.user_id.to_string()),
+        ("redirect_uri", authorization.redirect_uri.clone()),
+        ("scope", authorization.scope.clone()),
+        ("nonce", authorization.nonce.clone().unwrap_or_default()),
+        (
+            "code_challenge",
+            authorization.code_challenge.clone().unwrap_or_default(),
+        ),
+        ("auth_time", authorization.auth_time.to_string()),
+    ];
+    let _: () = deadpool_redis::redis::pipe()
+        .atomic()
+        .hset_multiple(&key, &fields)
+        .ignore()
+        .expire(&key, code_ttl)
+        .ignore()
+        .query_async(redis_connection)
+        .await?;
+    Ok(code)
+}
+
+/**
+ * # Brief
+ * Removes & returns what `code` stands for, None when unknown or expired.
+ *
+ * # Detail
+ * - Works once, a replayed code finds nothing.
+ */
+pub async fn take_code(
+    redis_connection: &mut deadpool_redis::Connection,
+    code: &str,
+) -> Result<Option<AuthorizationCode>, deadpool_redis::redis::RedisError> {
+    let key = code_key(code);
+    let (fields,): (std::collections::HashMap<String, String>,) = deadpool_redis::redis::pipe()
+        .atomic()
+        .hgetall(&key)
+        .del(&key)
+        .ignore()
+        .query_async(redis_connection)
+        .await?;
+
+    let field = |name: &str| fields.get(name).cloned().filter(|value| !value.is_empty());
+    let (Some(client_id), Some(user_id), Some(redirect_uri), Some(scope), Some(auth_time)) = (
+        field("client_id"),
+        field("user_id").and_then(|user_id| uuid::Uuid::parse_str(&user_id).ok()),
+        field("redirect_uri"),
+        field("scope"),
+        field("auth_time").and_then(|auth_time| auth_time.parse().ok()),
+    ) else {
+        return Ok(None);
+    };
+    Ok(Some(AuthorizationCode {
+        client_id,
+        user_id,
+        redirect_uri,
+        scope,
+        nonce: field("nonce"),
+        code_challenge: field("code_challenge"),
+        auth_time,
+    }))
+}
+
+/// a registered relying party
+#[derive(serde::Serialize, Debug, sqlx::FromRow)]
+pub struct OauthClient {
+    pub client_id: String,
+    pub name: String,
+    #[serde(skip)]
+    pub secret_hash: Option<String>,
+    pub redirect_uris: Vec<String>,
+    pub scopes: Vec<String>,
+    pub created_at: chrono::DateTime<chrono::Utc>,
+}
+
+impl OauthClient {
+    /// public clients hold no secret & must use PKCE
+    pub fn is_public(&self) -> bool {
+        self.secret_hash.is_none()
+    }
+
+    /// confidential clients must present their secret, public ones none
+    pub fn authenticates(&self, client_secret: Option<&str>) -> bool {
+        match (&self.secret_hash, client_secret) {
+            (Some(secret_hash), Some(client_secret)) => *secret_hash == sha256_hex(client_secret),
+            (None, None) => true,
+            _ => false,
+        }
+    }
+}
+
+const CLIENT_COLUMNS: &str = "client_id, name, secret_hash, redirect_uris, scopes, created_at";
+
+pub async fn find_client(
+    pool: &sqlx::PgPool,
+    client_id: &str,
+) -> Result<Option<OauthClient>, sqlx::Error> {
+    sqlx::query_as::<_, OauthClient>(&format!(
+        "SELECT {} FROM oauth_clients WHERE client_id = $1",
+        CLIENT_COLUMNS
+    ))
+    .bind(client_id)
+    .fetch_optional(pool)
+    .await
+}
+
+pub async fn list_clients(pool: &sqlx::PgPool) -> Result<Vec<OauthClient>, sqlx::Error> {
+    sqlx::query_as::<_, OauthClient>(&format!(
+        "SELECT {} FROM oauth_clients ORDER BY created_at",
+        CLIENT_COLUMNS
+    ))
+    .fetch_all(pool)
+    .await
+}
+
+/**
+ * # Brief
+ * Registers a client, returns it & its secret unless it is public.
+ *
+ * # Detail
+ * - The secret is only ever returned here, its SHA-256 is stored.
+ */
+pub async fn create_client(
+    pool: &sqlx::PgPool,
+    name: &str,
+    redirect_uris: &[String],
+    scopes: &[String],
+    public: bool,
+) -> Result<(OauthClient, Option<String>), sqlx::Error> {
+    let client_secret = (!public).then(|| {
+        let mut secret_bytes = [0u8; CLIENT_SECRET_BYTES];
+        OsRng.fill_bytes(&mut secret_bytes);
+        hex::encode(secret_bytes)
+    });
+    let client = sqlx::query_as::<_, OauthClient>(&format!(
+        r#"
+        INSERT INTO oauth_clients (client_id, name, secret_hash, redirect_uris, scopes)
+        VALUES ($1, $2, $3, $4, $5)
+        RETURNING {}
+        "#,
+        CLIENT_COLUMNS
+    ))
+    .bind(uuid::Uuid::now_v7().to_string())
+    .bind(name)
+    .bind(client_secret.as_deref().map(sha256_hex))
+    .bind(redirect_uris)
+    .bind(scopes)
+    .fetch_one(pool)
+    .await?;
+    Ok((client, client_secret))
+}
+
+/// false when there was no such client
+pub async fn delete_client(pool: &sqlx::PgPool, client_id: &str) -> Result<bool, sqlx::Error> {
+    let result = sqlx::query("DELETE FROM oauth_clients WHERE client_id = $1")
+        .bind(client_id)
+        .execute(pool)
+        .await?;
+    Ok(result.rows_affected() == 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crimson::server_testing::TestServer;
+
+    fn signer(seed: u8) -> TokenSigner {
+        TokenSigner::new(&[seed; 32]).unwrap()
+    }
+
+    fn claims(scope: &str) -> AccessTokenClaims {
+        AccessTokenClaims {
+            iss: "https://crimson.example".to_string(),
+            sub: uuid::Uuid::now_v7().to_string(),
+            aud: "client-1".to_string(),
+            client_id: "client-1".to_string(),
+            scope: scope.to_string(),
+            iat: 1_700_000_000,
+            exp: 1_700_000_300,
+            jti: uuid::Uuid::now_v7().to_string(),
+        }
+    }
+
+    fn assert_invalid<T: std::fmt::Debug>(result: Result<T, OauthError>, expected: &str) {
+        match result {
+            Err(OauthError::Invalid(reason)) => assert_eq!(reason, expected),
+            other => panic!("expected `{}`, got {:?}", expected, other),
+        }
+    }
+
+    // the token with its header replaced, signature kept
+    fn with_header(token: &str, header: serde_json::Value) -> String {
+        let (_, rest) = token.split_once('.').unwrap();
+        format!(
+            "{}.{}",
+            base64url_encode(header.to_string().as_bytes()),
+            rest
+        )
+    }
+
+    #[test]
+    fn signed_tokens_verify() {
+        let signer = signer(0x11);
+        let issued = claims("openid email");
+        let token = signer.sign(&issued).unwrap();
+
+        let verified: AccessTokenClaims = signer.verify(&token).unwrap();
+        assert_eq!(verified.sub, issued.sub);
+        assert_eq!(verified.scope, "openid email");
+        assert_eq!(verified.jti, issued.jti);
+
+        let jwk = signer.jwk();
+        assert_eq!(jwk["alg"], SIGNING_ALGORITHM);
+        assert_eq!(jwk["kid"], signer.kid);
+    }
+
+    #[test]
+    fn rejects_tokens_of_other_keys() {
+        let token = signer(0x22).sign(&claims("openid")).unwrap();
+        assert_invalid(
+            signer(0x11).verify::<AccessTokenClaims>(&token),
+            "not signed by this key",
+        );
+
+        // our kid on somebody else's signature
+        let forged = with_header(
+            &token,
+            serde_json::json!({ "alg": SIGNING_ALGORITHM, "typ": "JWT", "kid": signer(0x11).kid }),
+        );
+        assert_invalid(
+            signer(0x11).verify::<AccessTokenClaims>(&forged),
+            "signature mismatch",
+        );
+    }
+
+    #[test]
+    fn rejects_other_algorithms() {
+        let signer = signer(0x11);
+        let token = signer.sign(&claims("openid")).unwrap();
+        for alg in ["none", "HS256", "RS256", "es256"] {
+            let downgraded = with_header(
+                &token,
+                serde_json::json!({ "alg": alg, "typ": "JWT", "kid": signer.kid }),
+            );
+            assert_invalid(
+                signer.verify::<AccessTokenClaims>(&downgraded),
+                "not signed by this key",
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_tampered_claims() {
+        let signer = signer(0x11);
+        let token = signer.sign(&claims("openid")).unwrap();
+        let mut parts: Vec<&str> = token.split('.').collect();
+        let widened = base64url_encode(
+            serde_json::to_string(&claims("openid email profile"))
+                .unwrap()
+                .as_bytes(),
+        );
+        parts[1] = &widened;
+        assert_invalid(
+            signer.verify::<AccessTokenClaims>(&parts.join(".")),
+            "signature mismatch",
+        );
+        assert_invalid(
+            signer.verify::<AccessTokenClaims>(&parts[..2].join(".")),
+            "not a compact JWS",
+        );
+    }
+
+    #[test]
+    fn pkce_needs_the_s256_of_the_verifier() {
+        let verifier = "dBjftJeZ4CVP-mJ0kBDGtu4Ulgp2tbZJ7CBnKeZK1A8";
+        let challenge = "VVzqIBBNp197U5aJ-VuWtspLP_kACe-GEtekedOVskg";
+        assert!(verify_pkce(verifier, challenge));
+        // `plain`, the challenge is the verifier itself
+        assert!(!verify_pkce(verifier, verifier));
+        assert!(!verify_pkce(&verifier.replace('d', "e"), challenge));
+
+        // RFC 7636 4.1, 43 to 128 characters
+        for length in [42, 129] {
+            let verifier = "a".repeat(length);
+            let challenge = base64url_encode(&sha2::Sha256::digest(verifier.as_bytes()));
+            assert!(!verify_pkce(&verifier, &challenge), "{}", length);
+        }
+        for length in [43, 128] {
+            let verifier = "a".repeat(length);
+            let challenge = base64url_encode(&sha2::Sha256::digest(verifier.as_bytes()));
+            assert!(verify_pkce(&verifier, &challenge), "{}", length);
+        }
+    }
+
+    #[test]
+    fn scopes_are_split_sorted_and_deduplicated() {
+        assert_eq!(
+            parse_scope(" profile openid  email openid "),
+            ["email", "openid", "profile"]
+        );
+        assert!(parse_scope("").is_empty());
+    }
+
+    #[test]
+    fn clients_authenticate_by_kind() {
+        let client = |secret_hash: Option<String>| OauthClient {
+            client_id: "client-1".to_string(),
+            name: "client".to_string(),
+            secret_hash,
+            redirect_uris: Vec::new(),
+            scopes: Vec::new(),
+            created_at: chrono::Utc::now(),
+        };
+        let confidential = client(Some(sha256_hex("s3cret")));
+        assert!(!confidential.is_public());
+        assert!(confidential.authenticates(Some("s3cret")));
+        assert!(!confidential.authenticates(Some("other")));
+        assert!(!confidential.authenticates(None));
+
+        let public = client(None);
+        assert!(public.is_public());
+        assert!(public.authenticates(None));
+        assert!(!public.authenticates(Some("s3cret")));
+    }
+
+    #[actix_web::test]
+    async fn codes_are_redeemed_once() {
+        let server = TestServer::start("").await;
+        let mut redis_connection = server.state.redis_pool.get().await.unwrap();
+        let authorization = AuthorizationCode {
+            client_id: "client-1".to_string(),
+            user_id: uuid::Uuid::now_v7(),
+            redirect_uri: "https://app.example/callback".to_string(),
+            scope: "email openid".to_string(),
+            nonce: None,
+            code_challenge: Some("challenge".to_string()),
+            auth_time: 1_700_000_000,
+        };
+        let code = store_code(&mut redis_connection, &authorization, 60)
+            .await
+            .unwrap();
+
+        let taken = take_code(&mut redis_connection, &code)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(taken.user_id, authorization.user_id);
+        assert_eq!(taken.redirect_uri, authorization.redirect_uri);
+        assert_eq!(taken.scope, authorization.scope);
+        assert_eq!(taken.nonce, None);
+        assert_eq!(taken.code_challenge.as_deref(), Some("challenge"));
+        assert_eq!(taken.auth_time, authorization.auth_time);
+
+        assert!(
+            take_code(&mut redis_connection, &code)
+                .await
+                .unwrap()
+                .is_none()
+        );
+        assert!(
+            take_code(&mut redis_connection, "unknown")
+                .await
+                .unwrap()
+                .is_none()
+        );
+    }
+}
@@ -0,0 +1,611 @@
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+use sha2::Digest;
+
+/*
+ * Organizations, team workspaces.
+ *
+ * A session works either in the personal workspace or in one organization
+ * it switched to (`org_id` session field). Jobs & API keys carry the
+ * workspace they were created in, compute queries always filter on it.
+ *
+ * Workers are shared by everyone unless registered for one organization.
+ */
+
+const INVITATION_TOKEN_BYTES: usize = 32;
+
+/// the session or API key works in an organization the user left
+pub const CODE_NOT_ORG_MEMBER: &str = "not_org_member";
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum OrgRole {
+    /// everything, including making & removing owners
+    Owner,
+    /// invites & removes members
+    Admin,
+    /// uses the organization's jobs & workers
+    Member,
+}
+
+impl OrgRole {
+    #[inline]
+    pub fn as_str(self) -> &'static str {
+        match self {
+            OrgRole::Owner => "owner",
+            OrgRole::Admin => "admin",
+            OrgRole::Member => "member",
+        }
+    }
+
+    #[inline]
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "owner" => Some(OrgRole::Owner),
+            "admin" => Some(OrgRole::Admin),
+            "member" => Some(OrgRole::Member),
+            _ => None,
+        }
+    }
+
+    /// may invite, remove & change the role of others
+    #[inline]
+    pub fn manages_members(self) -> bool {
+        matches!(self, OrgRole::Owner | OrgRole::Admin)
+    }
+
+    /// may hand out `role` or change the role of someone holding it
+    #[inline]
+    pub fn may_assign(self, role: OrgRole) -> bool {
+        match role {
+            OrgRole::Owner => self == OrgRole::Owner,
+            OrgRole::Admin | OrgRole::Member => self.manages_members(),
+        }
+    }
+}
+
+/// an organization as seen by one of its members
+#[derive(serde::Serialize, Debug, sqlx::FromRow)]
+pub struct Organization {
+    pub org_id: uuid::Uuid,
+    pub name: String,
+    pub created_at: chrono::DateTime<chrono::Utc>,
+    /// the caller's role
+    pub role: String,
+}
+
+#[derive(serde::Serialize, Debug, sqlx::FromRow)]
+pub struct OrgMember {
+    pub user_id: uuid::Uuid,
+    pub username: String,
+    pub email: String,
+    pub role: String,
+    pub joined_at: chrono::DateTime<chrono::Utc>,
+}
+
+/// a pending invitation, the token is never listed
+#[derive(serde::Serialize, Debug, sqlx::FromRow)]
+pub struct OrgInvitation {
+    pub invitation_id: uuid::Uuid,
+    pub email: String,
+    pub role: String,
+    pub invited_by: Option<uuid::Uuid>,
+    pub created_at: chrono::DateTime<chrono::Utc>,
+    pub expires_at: chrono::DateTime<chrono::Utc>,
+}
+
+fn hash_invitation_token(token: &str) -> String {
+    hex::encode(sha2::Sha256::digest(token.as_bytes()))
+}
+
+/**
+ * # Brief
+ * Creates an organization owned by `user_id`.
+ */
+pub async fn create_org(
+    pool: &sqlx::PgPool,
+    name: &str,
+    user_id: uuid::Uuid,
+) -> Result<Organization, sqlx::Error> {
+    let mut transaction = pool.begin().await?;
+    let org = sqlx::query_as::<_, Organization>(
+        r#"
+        INSERT INTO organizations (org_id, name, created_by)
+        VALUES ($1, $2, $3)
+        RETURNING org_id, name, created_at, $4 AS role
+        "#,
+    )
+    .bind(uuid::Uuid::now_v7())
+    .bind(name)
+    .bind(user_id)
+    .bind(OrgRole::Owner.as_str())
+    .fetch_one(&mut *transaction)
+    .await?;
+    sqlx::query("INSERT INTO org_members (org_id, user_id, role) VALUES ($1, $2, $3)")
+        .bind(org.org_id)
+        .bind(user_id)
+        .bind(OrgRole::Owner.as_str())
+        .execute(&mut *transaction)
+        .await?;
+    transaction.commit().await?;
+    Ok(org)
+}
+
+/// organizations `user_id` belongs to, by name
+pub async fn list_user_orgs(
+    pool: &sqlx::PgPool,
+    user_id: uuid::Uuid,
+) -> Result<Vec<Organization>, sqlx::Error> {
+    sqlx::query_as::<_, Organization>(
+        r#"
+        SELECT organizations.org_id, organizations.name, organizations.created_at, org_members.role
+        FROM org_members
+        JOIN organizations ON organizations.org_id = org_members.org_id
+        WHERE org_members.user_id = $1
+        ORDER BY organizations.name, organizations.org_id
+        "#,
+    )
+    .bind(user_id)
+    .fetch_all(pool)
+    .await
+}
+
+/// the role of `user_id` in `org_id`, None when not a member
+pub async fn member_role(
+    pool: &sqlx::PgPool,
+    org_id: uuid::Uuid,
+    user_id: uuid::Uuid,
+) -> Result<Option<OrgRole>, sqlx::Error> {
+    let role = sqlx::query_scalar::<_, String>(
+        "SELECT role FROM org_members WHERE org_id = $1 AND user_id = $2",
+    )
+    .bind(org_id)
+    .bind(user_id)
+    .fetch_optional(pool)
+    .await?;
+    Ok(role.as_deref().and_then(OrgRole::parse))
+}
+
+pub async fn org_exists(pool: &sqlx::PgPool, org_id: uuid::Uuid) -> Result<bool, sqlx::Error> {
+    sqlx::query_scalar::<_, bool>("SELECT EXISTS (SELECT 1 FROM organizations WHERE org_id = $1)")
+        .bind(org_id)
+        .fetch_one(pool)
+        .await
+}
+
+pub async fn list_members(
+    pool: &sqlx::PgPool,
+    org_id: uuid::Uuid,
+) -> Result<Vec<OrgMember>, sqlx::Error> {
+    sqlx::query_as::<_, OrgMember>(
+        r#"
+        SELECT users.user_id, users.username, users.email, org_members.role, org_members.joined_at
+        FROM org_members
+        JOIN users ON users.user_id = org_members.user_id
+        WHERE org_members.org_id = $1
+        ORDER BY org_members.joined_at
+        "#,
+    )
+    .bind(org_id)
+    .fetch_all(pool)
+    .await
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum MembershipChange {
+    Done,
+    NotMember,
+    /// an organization always keeps an owner
+    LastOwner,
+}
+
+// the owner count & the write are one statement, serializable transactions
+// keep two concurrent changes from both passing it
+const KEEPS_AN_OWNER: &str = r#"
+    (role <> 'owner' OR (SELECT count(*) FROM org_members WHERE org_id = $1 AND role = 'owner') > 1)
+"#;
+
+async fn membership_change(
+    pool: &sqlx::PgPool,
+    org_id: uuid::Uuid,
+    user_id: uuid::Uuid,
+    rows_affected: u64,
+) -> Result<MembershipChange, sqlx::Error> {
+    if rows_affected == 1 {
+        return Ok(MembershipChange::Done);
+    }
+    Ok(match member_role(pool, org_id, user_id).await? {
+        Some(_) => MembershipChange::LastOwner,
+        None => MembershipChange::NotMember,
+    })
+}
+
+/**
+ * # Brief
+ * Changes the role of a member, the last owner keeps theirs.
+ */
+pub async fn set_member_role(
+    pool: &sqlx::PgPool,
+    org_id: uuid::Uuid,
+    user_id: uuid::Uuid,
+    role: OrgRole,
+) -> Result<MembershipChange, sqlx::Error> {
+    let result = sqlx::query(&format!(
+        r#"
+        UPDATE org_members SET role = $3
+        WHERE org_id = $1 AND user_id = $2 AND ($3 = 'owner' OR {})
+        "#,
+        KEEPS_AN_OWNER
+    ))
+    .bind(org_id)
+    .bind(user_id)
+    .bind(role.as_str())
+    .execute(pool)
+    .await?;
+    membership_change(pool, org_id, user_id, result.rows_affected()).await
+}
+
+/**
+ * # Brief
+ * Removes a member, the last owner can't leave.
+ *
+ * # Detail
+ * - Their sessions switched to the organization are refused on next use.
+ */
+pub async fn remove_member(
+    pool: &sqlx::PgPool,
+    org_id: uuid::Uuid,
+    user_id: uuid::Uuid,
+) -> Result<MembershipChange, sqlx::Error> {
+    let result = sqlx::query(&format!(
+        "DELETE FROM org_members WHERE org_id = $1 AND user_id = $2 AND {}",
+        KEEPS_AN_OWNER
+    ))
+    .bind(org_id)
+    .bind(user_id)
+    .execute(pool)
+    .await?;
+    membership_change(pool, org_id, user_id, result.rows_affected()).await
+}
+
+/**
+ * # Brief
+ * Invites `email` to join as `role`, returns the invitation & its token.
+ *
+ * # Detail
+ * - The token is only returned here, its SHA-256 is stored.
+ * - Expires after `ttl` seconds.
+ */
+pub async fn create_invitation(
+    pool: &sqlx::PgPool,
+    org_id: uuid::Uuid,
+    email: &str,
+    role: OrgRole,
+    invited_by: uuid::Uuid,
+    ttl: i64,
+) -> Result<(OrgInvitation, String), sqlx::Error> {
+    let mut token_bytes = [0u8; INVITATION_TOKEN_BYTES];
+    OsRng.fill_bytes(&mut token_bytes);
+    let token = hex::encode(token_bytes);
+
+    let invitation = sqlx::query_as::<_, OrgInvitation>(
+        r#"
+        INSERT INTO org_invitations
+        (invitation_id, org_id, email, role, token_hash, invited_by, expires_at)
+        VALUES ($1, $2, $3, $4, $5, $6, now() + $7 * INTERVAL '1 second')
+        RETURNING invitation_id, email, role, invited_by, created_at, expires_at
+        "#,
+    )
+    .bind(uuid::Uuid::now_v7())
+    .bind(org_id)
+    .bind(email)
+    .bind(role.as_str())
+    .bind(hash_invitation_token(&token))
+    .bind(invited_by)
+    .bind(ttl)
+    .fetch_one(pool)
+    .await?;
+    Ok((invitation, token))
+}
+
+/// invitations neither accepted nor expired, newest first
+pub async fn list_invitations(
+    pool: &sqlx::PgPool,
+    org_id: uuid::Uuid,
+) -> Result<Vec<OrgInvitation>, sqlx::Error> {
+    sqlx::query_as::<_, OrgInvitation>(
+        r#"
+        SELECT invitation_id, email, role, invited_by, created_at, expires_at
+        FROM org_invitations
+        WHERE org_id = $1 AND accepted_at IS NULL AND expires_at > now()
+        ORDER BY created_at DESC
+        "#,
+    )
+    .bind(org_id)
+    .fetch_all(pool)
+    .await
+}
+
+/// false when there was no such pending invitation
+pub async fn revoke_invitation(
+    pool: &sqlx::PgPool,
+    org_id: uuid::Uuid,
+    invitation_id: uuid::Uuid,
+) -> Result<bool, sqlx::Error> {
+    let result = sqlx::query(
+        r#"
+        DELETE FROM org_invitations
+        WHERE invitation_id = $1 AND org_id = $2 AND accepted_at IS NULL
+        "#,
+    )
+    .bind(invitation_id)
+    .bind(org_id)
+    .execute(pool)
+    .await?;
+    Ok(result.rows_affected() == 1)
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum AcceptOutcome {
+    Joined(uuid::Uuid),
+    /// unknown, expired or already used
+    Invalid,
+    /// sent to another address than the caller's
+    WrongEmail,
+}
+
+/**
+ * # Brief
+ * Redeems an invitation token for the user `user_id` with address `email`.
+ *
+ * # Detail
+ * - Works once, for the invited address only (case insensitive).
+ * - A user who already is a member keeps their role.
+ */
+pub async fn accept_invitation(
+    pool: &sqlx::PgPool,
+    token: &str,
+    user_id: uuid::Uuid,
+    email: &str,
+) -> Result<AcceptOutcome, sqlx::Error> {
+    let mut transaction = pool.begin().await?;
+    let invitation = sqlx::query_as::<_, (uuid::Uuid, uuid::Uuid, String, String)>(
+        r#"
+        SELECT invitation_id, org_id, email, role
+        FROM org_invitations
+        WHERE token_hash = $1 AND accepted_at IS NULL AND expires_at > now()
+        FOR UPDATE
+        "#,
+    )
+    .bind(hash_invitation_token(token))
+    .fetch_optional(&mut *transaction)
+    .await?;
+    let Some((invitation_id, org_id, invited_email, role)) = invitation else {
+        return Ok(AcceptOutcome::Invalid);
+    };
+    if !invited_email.eq_ignore_ascii_case(email) {
+        return Ok(AcceptOutcome::WrongEmail);
+    }
+
+    sqlx::query(
+        r#"
+        INSERT INTO org_members (org_id, user_id, role)
+        VALUES ($1, $2, $3)
+        ON CONFLICT (org_id, user_id) DO NOTHING
+        "#,
+    )
+    .bind(org_id)
+    .bind(user_id)
+    .bind(role)
+    .execute(&mut *transaction)
+    .await?;
+    sqlx::query("UPDATE org_invitations SET accepted_at = now() WHERE invitation_id = $1")
+        .bind(invitation_id)
+        .execute(&mut *transaction)
+        .await?;
+    transaction.commit().await?;
+    Ok(AcceptOutcome::Joined(org_id))
+}
+
+/// name of an organization, for invitation emails
+pub async fn org_name(
+    pool: &sqlx::PgPool,
+    org_id: uuid::Uuid,
+) -> Result<Option<String>, sqlx::Error> {
+    sqlx::query_scalar::<_, String>("SELECT name FROM organizations WHERE org_id = $1")
+        .bind(org_id)
+        .fetch_optional(pool)
+        .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crimson::server_testing::{self, TestServer};
+
+    const ROLES: [OrgRole; 3] = [OrgRole::Owner, OrgRole::Admin, OrgRole::Member];
+
+    #[test]
+    fn roles_round_trip() {
+        for role in ROLES {
+            assert_eq!(OrgRole::parse(role.as_str()), Some(role));
+        }
+        assert_eq!(OrgRole::parse("Owner"), None);
+        assert_eq!(OrgRole::parse(""), None);
+    }
+
+    #[test]
+    fn only_owners_hand_out_ownership() {
+        for role in ROLES {
+            assert!(OrgRole::Owner.may_assign(role), "owner -> {:?}", role);
+            assert_eq!(
+                OrgRole::Admin.may_assign(role),
+                role != OrgRole::Owner,
+                "admin -> {:?}",
+                role
+            );
+            assert!(!OrgRole::Member.may_assign(role), "member -> {:?}", role);
+        }
+        assert!(OrgRole::Owner.manages_members());
+        assert!(OrgRole::Admin.manages_members());
+        assert!(!OrgRole::Member.manages_members());
+    }
+
+    async fn delete_org(pool: &sqlx::PgPool, org_id: uuid::Uuid) {
+        sqlx::query("DELETE FROM organizations WHERE org_id = $1")
+            .bind(org_id)
+            .execute(pool)
+            .await
+            .unwrap();
+    }
+
+    #[actix_web::test]
+    async fn invitations_work_once_for_the_invited_address() {
+        let server = TestServer::start("").await;
+        let pool = server.db();
+        let owner_email = server_testing::unique_email("org-owner");
+        let invited_email = server_testing::unique_email("org-invited");
+        let other_email = server_testing::unique_email("org-other");
+        let owner_id = server_testing::insert_user(pool, &owner_email, None, true).await;
+        let invited_id = server_testing::insert_user(pool, &invited_email, None, true).await;
+        let other_id = server_testing::insert_user(pool, &other_email, None, true).await;
+        let org = create_org(pool, "invitations", owner_id).await.unwrap();
+
+        let (_, token) = create_invitation(
+            pool,
+            org.org_id,
+            &invited_email,
+            OrgRole::Admin,
+            owner_id,
+            3600,
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            accept_invitation(pool, &token, other_id, &other_email)
+                .await
+                .unwrap(),
+            AcceptOutcome::WrongEmail
+        );
+        // the wrong taker didn't use it up
+        assert_eq!(
+            accept_invitation(pool, &token, invited_id, &invited_email.to_uppercase())
+                .await
+                .unwrap(),
+            AcceptOutcome::Joined(org.org_id)
+        );
+        assert_eq!(
+            member_role(pool, org.org_id, invited_id).await.unwrap(),
+            Some(OrgRole::Admin)
+        );
+        assert_eq!(
+            accept_invitation(pool, &token, invited_id, &invited_email)
+                .await
+                .unwrap(),
+            AcceptOutcome::Invalid
+        );
+        assert!(list_invitations(pool, org.org_id).await.unwrap().is_empty());
+
+        let (_, expired) = create_invitation(
+            pool,
+            org.org_id,
+            &other_email,
+            OrgRole::Member,
+            owner_id,
+            -1,
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            accept_invitation(pool, &expired, other_id, &other_email)
+                .await
+                .unwrap(),
+            AcceptOutcome::Invalid
+        );
+        assert_eq!(member_role(pool, org.org_id, other_id).await.unwrap(), None);
+        assert_eq!(
+            accept_invitation(pool, "not-a-token", other_id, &other_email)
+                .await
+                .unwrap(),
+            AcceptOutcome::Invalid
+        );
+
+        delete_org(pool, org.org_id).await;
+        for user_id in [owner_id, invited_id, other_id] {
+            server_testing::delete_user(pool, user_id).await;
+        }
+    }
+
+    #[actix_web::test]
+    async fn organizations_keep_an_owner() {
+        let server = TestServer::start("").await;
+        let pool = server.db();
+        let owner_id = server_testing::insert_user(
+            pool,
+            &server_testing::unique_email("org-last"),
+            None,
+            true,
+        )
+        .await;
+        let member_id = server_testing::insert_user(
+            pool,
+            &server_testing::unique_email("org-next"),
+            None,
+            true,
+        )
+        .await;
+        let stranger_id = uuid::Uuid::now_v7();
+        let org = create_org(pool, "owners", owner_id).await.unwrap();
+
+        assert_eq!(
+            remove_member(pool, org.org_id, owner_id).await.unwrap(),
+            MembershipChange::LastOwner
+        );
+        assert_eq!(
+            set_member_role(pool, org.org_id, owner_id, OrgRole::Member)
+                .await
+                .unwrap(),
+            MembershipChange::LastOwner
+        );
+        assert_eq!(
+            remove_member(pool, org.org_id, stranger_id).await.unwrap(),
+            MembershipChange::NotMember
+        );
+
+        // a second owner lets the first one step down
+        sqlx::query("INSERT INTO org_members (org_id, user_id, role) VALUES ($1, $2, 'member')")
+            .bind(org.org_id)
+            .bind(member_id)
+            .execute(pool)
+            .await
+            .unwrap();
+        assert_eq!(
+            set_member_role(pool, org.org_id, member_id, OrgRole::Owner)
+                .await
+                .unwrap(),
+            MembershipChange::Done
+        );
+        assert_eq!(
+            set_member_role(pool, org.org_id, owner_id, OrgRole::Member)
+                .await
+                .unwrap(),
+            MembershipChange::Done
+        );
+        assert_eq!(
+            remove_member(pool, org.org_id, member_id).await.unwrap(),
+            MembershipChange::LastOwner
+        );
+        assert_eq!(
+            remove_member(pool, org.org_id, owner_id).await.unwrap(),
+            MembershipChange::Done
+        );
+        assert_eq!(
+            member_role(pool, org.org_id, member_id).await.unwrap(),
+            Some(OrgRole::Owner)
+        );
+
+        delete_org(pool, org.org_id).await;
+        for user_id in [owner_id, member_id] {
+            server_testing::delete_user(pool, user_id).await;
+        }
+    }
+}
@@ -0,0 +1,364 @@
+use super::server_config::{PasswordSection, SecuritySection};
+use argon2::PasswordVerifier;
+use argon2::password_hash::{PasswordHash, PasswordHasher, SaltString};
+
+/*
+ * Password hashing, Argon2id with a server side pepper.
+ *
+ * The pepper (`security.hash_salt`) is the Argon2 secret & never reaches the
+ * database. Every hash records the id of its pepper in the PHC `keyid`
+ * parameter, e.g. `$argon2id$v=19$m=19456,t=2,p=1,keyid=MQ$<salt>$<hash>`.
+ *
+ * Peppers rotate: the current one hashes, `security.previous_hash_salts` only
+ * verify, and users are re-hashed with the current one on their next login.
+ * Hashes without `keyid` predate the pepper & verify without a secret.
+ *
+ * Costs come from `[password]`, hashes are verified with the costs they were
+ * made with & re-hashed on login when any cost is below the configured one,
+ * or when they are not Argon2id v19.
+ */
+
+// hashed by `bench`, the content does not change the cost
+const BENCH_PASSWORD: &str = "correct horse battery staple";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Verification {
+    Mismatch,
+    /// matches & was hashed with the current pepper
+    Current,
+    /// matches, but should be hashed again with the current pepper & costs
+    Outdated,
+}
+
+pub struct Passwords {
+    params: argon2::Params,
+    current_id: String,
+    // current & previous peppers by id
+    peppers: std::collections::HashMap<String, Vec<u8>>,
+}
+
+impl Passwords {
+    pub fn new(security: &SecuritySection, password: &PasswordSection) -> Passwords {
+        let mut peppers: std::collections::HashMap<String, Vec<u8>> = security
+            .previous_hash_salts
+            .iter()
+            .map(|(id, pepper)| (id.clone(), pepper.as_bytes().to_vec()))
+            .collect();
+        peppers.insert(
+            security.hash_salt_id.clone(),
+            security.hash_salt.as_bytes().to_vec(),
+        );
+        Passwords {
+            params: password.argon2.clone(),
+            current_id: security.hash_salt_id.clone(),
+            peppers,
+        }
+    }
+
+    /// id recorded in new hashes
+    pub fn current_id(&self) -> &str {
+        &self.current_id
+    }
+
+    /**
+     * # Brief
+     * Hashes a password with a fresh salt & the current pepper.
+     *
+     * # Detail
+     * - CPU bound, run it on the blocking pool.
+     * - Returns the PHC string stored in `users.password`.
+     */
+    pub fn hash(&self, password: &str) -> Result<String, argon2::password_hash::Error> {
+        let salt = SaltString::generate(&mut argon2::password_hash::rand_core::OsRng);
+        let params = argon2::ParamsBuilder::new()
+            .m_cost(self.params.m_cost())
+            .t_cost(self.params.t_cost())
+            .p_cost(self.params.p_cost())
+            .keyid(argon2::KeyId::new(self.current_id.as_bytes())?)
+            .build()?;
+        let argon2 = argon2::Argon2::new_with_secret(
+            &self.peppers[&self.current_id],
+            argon2::Algorithm::Argon2id,
+            argon2::Version::V0x13,
+            params,
+        )?;
+        Ok(argon2
+            .hash_password(password.as_bytes(), &salt)?
+            .to_string())
+    }
+
+    /**
+     * # Brief
+     * Checks a password against a stored hash.
+     *
+     * # Detail
+     * - CPU bound, run it on the blocking pool.
+     * - The pepper is picked by the `keyid` of the hash, unknown ids never match.
+     * - Malformed hashes never match.
+     */
+    pub fn verify(&self, password: &str, stored: &str) -> Verification {
+        let Ok(hash) = PasswordHash::new(stored) else {
+            return Verification::Mismatch;
+        };
+        let Ok(params) = argon2::Params::try_from(&hash) else {
+            return Verification::Mismatch;
+        };
+
+        let keyid = params.keyid();
+        let argon2 = if keyid.is_empty() {
+            argon2::Argon2::default()
+        } else {
+            let Some(pepper) = std::str::from_utf8(keyid)
+                .ok()
+                .and_then(|id| self.peppers.get(id))
+            else {
+                tracing::warn!(
+                    component = "passwords",
+                    keyid = %String::from_utf8_lossy(keyid),
+                    "hash uses an unknown pepper, add it to security.previous_hash_salts"
+                );
+                return Verification::Mismatch;
+            };
+            // algorithm, version & params of the hash itself are used to verify
+            match argon2::Argon2::new_with_secret(
+                pepper,
+                argon2::Algorithm::default(),
+                argon2::Version::default(),
+                argon2::Params::default(),
+            ) {
+                Ok(argon2) => argon2,
+                Err(_) => return Verification::Mismatch,
+            }
+        };
+
+        if argon2.verify_password(password.as_bytes(), &hash).is_err() {
+            return Verification::Mismatch;
+        }
+        let outdated = keyid != self.current_id.as_bytes()
+            || hash.algorithm != argon2::Algorithm::Argon2id.ident()
+            || hash.version != Some(argon2::Version::V0x13.into())
+            || params.m_cost() < self.params.m_cost()
+            || params.t_cost() < self.params.t_cost()
+            || params.p_cost() < self.params.p_cost();
+        match outdated {
+            true => Verification::Outdated,
+            false => Verification::Current,
+        }
+    }
+}
+
+/**
+ * # Brief
+ * Times `samples` hashes with `params`, for `bench-hash`.
+ *
+ * # Detail
+ * - Hashes the way `Passwords::hash` does, with a throwaway pepper.
+ * - Blocks the calling thread for the whole run.
+ */
+pub fn bench(
+    params: &argon2::Params,
+    samples: u32,
+) -> Result<Vec<std::time::Duration>, argon2::password_hash::Error> {
+    let argon2 = argon2::Argon2::new_with_secret(
+        b"bench",
+        argon2::Algorithm::Argon2id,
+        argon2::Version::V0x13,
+        params.clone(),
+    )?;
+    (0..samples)
+        .map(|_| {
+            let salt = SaltString::generate(&mut argon2::password_hash::rand_core::OsRng);
+            let started = std::time::Instant::now();
+            argon2.hash_password(BENCH_PASSWORD.as_bytes(), &salt)?;
+            Ok(started.elapsed())
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // small costs, tests only check which hashes are accepted
+    fn params(m_cost: u32, t_cost: u32, p_cost: u32) -> argon2::Params {
+        argon2::Params::new(m_cost, t_cost, p_cost, None).unwrap()
+    }
+
+    // "2" is the current pepper, "1" the previous one
+    fn passwords(current_id: &str, params: argon2::Params) -> Passwords {
+        Passwords {
+            params,
+            current_id: current_id.into(),
+            peppers: [("1", "previous pepper"), ("2", "current pepper")]
+                .into_iter()
+                .map(|(id, pepper)| (id.to_string(), pepper.as_bytes().to_vec()))
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn current_pepper_is_current() {
+        let passwords = passwords("2", params(64, 1, 1));
+        let hash = passwords.hash("hunter2").unwrap();
+        assert!(hash.contains("keyid=Mg"));
+        assert_eq!(passwords.verify("hunter2", &hash), Verification::Current);
+        assert_eq!(passwords.verify("hunter3", &hash), Verification::Mismatch);
+    }
+
+    #[test]
+    fn hash_without_keyid_is_outdated() {
+        let salt = SaltString::generate(&mut argon2::password_hash::rand_core::OsRng);
+        let hash = argon2::Argon2::new(
+            argon2::Algorithm::Argon2id,
+            argon2::Version::V0x13,
+            params(64, 1, 1),
+        )
+        .hash_password(b"hunter2", &salt)
+        .unwrap()
+        .to_string();
+
+        let passwords = passwords("2", params(64, 1, 1));
+        assert_eq!(passwords.verify("hunter2", &hash), Verification::Outdated);
+        assert_eq!(passwords.verify("hunter3", &hash), Verification::Mismatch);
+    }
+
+    #[test]
+    fn previous_pepper_is_outdated_until_rehashed() {
+        let hash = passwords("1", params(64, 1, 1)).hash("hunter2").unwrap();
+
+        let rotated = passwords("2", params(64, 1, 1));
+        assert_eq!(rotated.verify("hunter2", &hash), Verification::Outdated);
+        assert_eq!(rotated.verify("hunter3", &hash), Verification::Mismatch);
+
+        let rehashed = rotated.hash("hunter2").unwrap();
+        assert_eq!(rotated.verify("hunter2", &rehashed), Verification::Current);
+    }
+
+    #[test]
+    fn unknown_or_wrong_pepper_never_matches() {
+        let mut retired = passwords("3", params(64, 1, 1));
+        retired
+            .peppers
+            .insert("3".into(), b"retired pepper".to_vec());
+        let hash = retired.hash("hunter2").unwrap();
+        assert_eq!(
+            passwords("2", params(64, 1, 1)).verify("hunter2", &hash),
+            Verification::Mismatch
+        );
+
+        // same id, different secret
+        let mut leaked = passwords("2", params(64, 1, 1));
+        leaked
+            .peppers
+            .insert("2".into(), b"guessed pepper".to_vec());
+        let hash = leaked.hash("hunter2").unwrap();
+        assert_eq!(
+            passwords("2", params(64, 1, 1)).verify("hunter2", &hash),
+            Verification::Mismatch
+        );
+    }
+
+    #[test]
+    fn malformed_hashes_never_match() {
+        let passwords = passwords("2", params(64, 1, 1));
+        for stored in [
+            "",
+            "hunter2",
+            "$argon2id$v=19$m=64,t=1,p=1",
+            "$argon2id$v=19$m=1,t=1,p=1,keyid=Mg$c2FsdHNhbHQ$AAAAAAAAAAAAAAAAAAAAAA",
+            "$bcrypt$v=19$m=64,t=1,p=1$c2FsdHNhbHQ$AAAAAAAAAAAAAAAAAAAAAA",
+        ] {
+            assert_eq!(
+                passwords.verify("hunter2", stored),
+                Verification::Mismatch,
+                "{}",
+                stored
+            );
+        }
+    }
+
+    // hashes with the current pepper, but any algorithm & costs
+    fn hash_with(
+        passwords: &Passwords,
+        algorithm: argon2::Algorithm,
+        m_cost: u32,
+        t_cost: u32,
+        p_cost: u32,
+    ) -> String {
+        let salt = SaltString::generate(&mut argon2::password_hash::rand_core::OsRng);
+        let params = argon2::ParamsBuilder::new()
+            .m_cost(m_cost)
+            .t_cost(t_cost)
+            .p_cost(p_cost)
+            .keyid(argon2::KeyId::new(passwords.current_id.as_bytes()).unwrap())
+            .build()
+            .unwrap();
+        argon2::Argon2::new_with_secret(
+            &passwords.peppers[&passwords.current_id],
+            algorithm,
+            argon2::Version::V0x13,
+            params,
+        )
+        .unwrap()
+        .hash_password(b"hunter2", &salt)
+        .unwrap()
+        .to_string()
+    }
+
+    #[test]
+    fn weaker_costs_are_outdated() {
+        let passwords = passwords("2", params(64, 2, 2));
+        let argon2id = argon2::Algorithm::Argon2id;
+        for (m_cost, t_cost, p_cost) in [(32, 2, 2), (64, 1, 2), (64, 2, 1)] {
+            let hash = hash_with(&passwords, argon2id, m_cost, t_cost, p_cost);
+            assert_eq!(
+                passwords.verify("hunter2", &hash),
+                Verification::Outdated,
+                "{}",
+                hash
+            );
+            assert_eq!(passwords.verify("hunter3", &hash), Verification::Mismatch);
+        }
+    }
+
+    #[test]
+    fn configured_or_stronger_costs_are_current() {
+        let passwords = passwords("2", params(64, 2, 2));
+        let argon2id = argon2::Algorithm::Argon2id;
+        for (m_cost, t_cost, p_cost) in [(64, 2, 2), (128, 2, 2), (64, 3, 2), (64, 2, 4)] {
+            let hash = hash_with(&passwords, argon2id, m_cost, t_cost, p_cost);
+            assert_eq!(
+                passwords.verify("hunter2", &hash),
+                Verification::Current,
+                "{}",
+                hash
+            );
+        }
+    }
+
+    #[test]
+    fn other_argon2_variants_are_outdated() {
+        let passwords = passwords("2", params(64, 1, 1));
+        for algorithm in [argon2::Algorithm::Argon2i, argon2::Algorithm::Argon2d] {
+            let hash = hash_with(&passwords, algorithm, 64, 1, 1);
+            assert_eq!(passwords.verify("hunter2", &hash), Verification::Outdated);
+            assert_eq!(passwords.verify("hunter3", &hash), Verification::Mismatch);
+        }
+    }
+
+    #[test]
+    fn rehash_uses_the_configured_costs() {
+        let weak = passwords("2", params(32, 1, 1));
+        let hash = weak.hash("hunter2").unwrap();
+        assert!(hash.contains("m=32,t=1,p=1"));
+
+        let passwords = passwords("2", params(64, 2, 1));
+        assert_eq!(passwords.verify("hunter2", &hash), Verification::Outdated);
+        let rehashed = passwords.hash("hunter2").unwrap();
+        assert!(rehashed.starts_with("$argon2id$v=19$m=64,t=2,p=1,keyid=Mg$"));
+        assert_eq!(
+            passwords.verify("hunter2", &rehashed),
+            Verification::Current
+        );
+    }
+}
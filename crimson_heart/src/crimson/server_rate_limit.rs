@@ -0,0 +1,484 @@
+use super::server_config::{RateLimitSection, RouteLimit};
+use super::server_errors::ApiError;
+use super::server_types::ServerState;
+
+use deadpool_redis::redis::AsyncCommands;
+
+/*
+ * Sliding window rate limiting & login lockout, shared through Redis.
+ *
+ * - `rate_limit:{path}:{scope}:{key}` sorted set of request timestamps (ms)
+ *   inside the window, `scope` is `ip`, `session` or `email`.
+ * - `login_failures:{email}` consecutive failed logins, forgotten after `lockout_max`.
+ * - `login_lockout:{email}` present while the email is locked out.
+ *
+ * Redis errors fail open, an outage must not lock everyone out.
+ *
+ * Clients are keyed on the peer address. `Forwarded` / `X-Forwarded-For` are
+ * only read when the peer is one of `rate_limit.trusted_proxies`, anybody else
+ * could send a new address with every request.
+ */
+
+// drops timestamps outside the window, admits the request if there is room,
+// otherwise returns how many ms until the oldest one leaves the window
+const SLIDING_WINDOW_SCRIPT: &str = r#"
+local now = tonumber(ARGV[1])
+local window = tonumber(ARGV[2])
+local limit = tonumber(ARGV[3])
+redis.call('ZREMRANGEBYSCORE', KEYS[1], 0, now - window)
+if redis.call('ZCARD', KEYS[1]) < limit then
+    redis.call('ZADD', KEYS[1], now, ARGV[4])
+    redis.call('PEXPIRE', KEYS[1], window)
+    return 0
+end
+local oldest = redis.call('ZRANGE', KEYS[1], 0, 0, 'WITHSCORES')
+return math.max(tonumber(oldest[2]) + window - now, 1)
+"#;
+
+#[derive(Debug)]
+pub enum RateLimitError {
+    Pool(deadpool_redis::PoolError),
+    Redis(deadpool_redis::redis::RedisError),
+}
+
+impl std::fmt::Display for RateLimitError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RateLimitError::Pool(e) => write!(f, "redis pool error ({})", e),
+            RateLimitError::Redis(e) => write!(f, "redis error ({})", e),
+        }
+    }
+}
+
+impl std::error::Error for RateLimitError {}
+
+impl From<deadpool_redis::PoolError> for RateLimitError {
+    fn from(e: deadpool_redis::PoolError) -> Self {
+        RateLimitError::Pool(e)
+    }
+}
+
+impl From<deadpool_redis::redis::RedisError> for RateLimitError {
+    fn from(e: deadpool_redis::redis::RedisError) -> Self {
+        RateLimitError::Redis(e)
+    }
+}
+
+#[derive(Clone)]
+pub struct RateLimiter {
+    redis_pool: deadpool_redis::Pool,
+    config: std::sync::Arc<RateLimitSection>,
+}
+
+fn login_failures_key(email: &str) -> String {
+    format!("login_failures:{}", email.to_lowercase())
+}
+
+fn login_lockout_key(email: &str) -> String {
+    format!("login_lockout:{}", email.to_lowercase())
+}
+
+impl RateLimiter {
+    pub fn new(redis_pool: deadpool_redis::Pool, config: RateLimitSection) -> Self {
+        RateLimiter {
+            redis_pool,
+            config: std::sync::Arc::new(config),
+        }
+    }
+
+    /// address the request is limited & its session recorded under
+    pub fn client_ip(&self, request: &actix_web::HttpRequest) -> String {
+        client_ip(request, &self.config.trusted_proxies)
+    }
+
+    pub fn route(&self, path: &str) -> Option<RouteLimit> {
+        if !self.config.enabled {
+            return None;
+        }
+        self.config.routes.get(path).copied()
+    }
+
+    /**
+     * # Brief
+     * Counts a request against `{path}:{scope}:{key}`.
+     *
+     * # Detail
+     * - Returns the seconds to wait when the window is full, None when admitted.
+     */
+    pub async fn hit(
+        &self,
+        path: &str,
+        scope: &str,
+        key: &str,
+        route: RouteLimit,
+    ) -> Result<Option<u64>, RateLimitError> {
+        let mut redis_connection = self.redis_pool.get().await?;
+        let retry_after_ms: u64 = deadpool_redis::redis::cmd("EVAL")
+            .arg(SLIDING_WINDOW_SCRIPT)
+            .arg(1)
+            .arg(format!("rate_limit:{}:{}:{}", path, scope, key))
+            .arg(chrono::Utc::now().timestamp_millis())
+            .arg(route.window * 1000)
+            .arg(route.limit)
+            .arg(uuid::Uuid::now_v7().to_string())
+            .query_async(&mut redis_connection)
+            .await?;
+
+        if retry_after_ms == 0 {
+            Ok(None)
+        } else {
+            Ok(Some(retry_after_ms.div_ceil(1000)))
+        }
+    }
+
+    /**
+     * # Brief
+     * Email keyed limit for `path`, for handlers that parsed the payload.
+     *
+     * # Detail
+     * - Returns the seconds to wait when limited, errors are logged & admitted.
+     */
+    pub async fn limit_email(&self, path: &str, email: &str) -> Option<u64> {
+        let route = self.route(path)?;
+        match self.hit(path, "email", &email.to_lowercase(), route).await {
+            Ok(retry_after) => retry_after,
+            Err(e) => {
+                tracing::error!(
+                    error = %e,
+                    component = "rate_limit",
+                    function = "hit",
+                    "function failed & returned error"
+                );
+                None
+            }
+        }
+    }
+
+    /**
+     * # Brief
+     * Seconds left on the lockout of `email`, None when it may try to log in.
+     */
+    pub async fn login_lockout(&self, email: &str) -> Option<u64> {
+        if !self.config.enabled {
+            return None;
+        }
+        let remaining: Result<i64, RateLimitError> = async {
+            let mut redis_connection = self.redis_pool.get().await?;
+            Ok(redis_connection.ttl(login_lockout_key(email)).await?)
+        }
+        .await;
+
+        match remaining {
+            Ok(remaining) if remaining > 0 => Some(remaining as u64),
+            Ok(_) => None,
+            Err(e) => {
+                tracing::error!(
+                    error = %e,
+                    component = "rate_limit",
+                    function = "login_lockout",
+                    "function failed & returned error"
+                );
+                None
+            }
+        }
+    }
+
+    /**
+     * # Brief
+     * Records a failed login for `email`, returns the lockout it triggered.
+     *
+     * # Detail
+     * - From `lockout_threshold` failures on, every failure locks the email out.
+     * - The lockout starts at `lockout_base` seconds and doubles up to `lockout_max`.
+     */
+    pub async fn record_login_failure(&self, email: &str) -> Option<u64> {
+        if !self.config.enabled {
+            return None;
+        }
+        let config = &self.config;
+        let lockout: Result<Option<u64>, RateLimitError> = async {
+            let mut redis_connection = self.redis_pool.get().await?;
+            let failures_key = login_failures_key(email);
+            let failures: u32 = redis_connection.incr(&failures_key, 1).await?;
+            let _: () = redis_connection
+                .expire(&failures_key, config.lockout_max as i64)
+                .await?;
+
+            if failures < config.lockout_threshold {
+                return Ok(None);
+            }
+            let doublings = (failures - config.lockout_threshold).min(32);
+            let lockout = config
+                .lockout_base
+                .saturating_mul(1u64 << doublings)
+                .min(config.lockout_max);
+            let _: () = redis_connection
+                .set_ex(login_lockout_key(email), failures, lockout)
+                .await?;
+            Ok(Some(lockout))
+        }
+        .await;
+
+        match lockout {
+            Ok(lockout) => lockout,
+            Err(e) => {
+                tracing::error!(
+                    error = %e,
+                    component = "rate_limit",
+                    function = "record_login_failure",
+                    "function failed & returned error"
+                );
+                None
+            }
+        }
+    }
+
+    /// forgets the failed logins of `email` after a successful one
+    pub async fn clear_login_failures(&self, email: &str) {
+        if !self.config.enabled {
+            return;
+        }
+        let cleared: Result<(), RateLimitError> = async {
+            let mut redis_connection = self.redis_pool.get().await?;
+            let _: () = redis_connection.del(login_failures_key(email)).await?;
+            Ok(())
+        }
+        .await;
+
+        if let Err(e) = cleared {
+            tracing::error!(
+                error = %e,
+                component = "rate_limit",
+                function = "clear_login_failures",
+                "function failed & returned error"
+            );
+        }
+    }
+}
+
+// `for=` nodes of `Forwarded` elements, nearest hop last
+fn forwarded_for(value: &str) -> Vec<&str> {
+    value
+        .split(',')
+        .filter_map(|element| {
+            element.split(';').find_map(|pair| {
+                let (name, node) = pair.split_once('=')?;
+                name.trim()
+                    .eq_ignore_ascii_case("for")
+                    .then(|| node.trim().trim_matches('"'))
+            })
+        })
+        .collect()
+}
+
+// a forwarded node with or without port, IPv6 in brackets
+fn parse_node(node: &str) -> Option<std::net::IpAddr> {
+    node.parse::<std::net::IpAddr>()
+        .ok()
+        .or_else(|| {
+            node.parse::<std::net::SocketAddr>()
+                .ok()
+                .map(|addr| addr.ip())
+        })
+        .or_else(|| {
+            node.strip_prefix('[')
+                .and_then(|rest| rest.strip_suffix(']'))
+                .and_then(|ip| ip.parse().ok())
+        })
+}
+
+/**
+ * # Brief
+ * Client address of a request, reached from `peer` through trusted proxies.
+ *
+ * # Detail
+ * - Forwarding headers are ignored unless `peer` is a trusted proxy.
+ * - `Forwarded` wins over `X-Forwarded-For`, both are walked from the nearest hop
+ *   & the first address that is not a trusted proxy is the client. Whatever the
+ *   client itself put in front is never reached.
+ * - A node that is not an address, e.g. `unknown`, is used as is.
+ */
+fn resolve_client(
+    peer: Option<std::net::IpAddr>,
+    forwarded: Option<&str>,
+    x_forwarded_for: Option<&str>,
+    trusted_proxies: &[std::net::IpAddr],
+) -> String {
+    let Some(peer) = peer else {
+        return String::new();
+    };
+    if !trusted_proxies.contains(&peer) {
+        return peer.to_string();
+    }
+
+    let chain: Vec<&str> = match (forwarded, x_forwarded_for) {
+        (Some(forwarded), _) => forwarded_for(forwarded),
+        (None, Some(x_forwarded_for)) => x_forwarded_for.split(',').map(str::trim).collect(),
+        (None, None) => Vec::new(),
+    };
+    for node in chain.into_iter().rev().filter(|node| !node.is_empty()) {
+        match parse_node(node) {
+            Some(ip) if trusted_proxies.contains(&ip) => continue,
+            Some(ip) => return ip.to_string(),
+            None => return node.to_string(),
+        }
+    }
+    // only proxies on the way
+    peer.to_string()
+}
+
+/// `resolve_client` over the headers of `request`, repeated headers are joined
+pub fn client_ip(request: &actix_web::HttpRequest, trusted_proxies: &[std::net::IpAddr]) -> String {
+    let header = |name: actix_web::http::header::HeaderName| {
+        let values: Vec<&str> = request
+            .headers()
+            .get_all(name)
+            .filter_map(|value| value.to_str().ok())
+            .collect();
+        (!values.is_empty()).then(|| values.join(","))
+    };
+    resolve_client(
+        request.peer_addr().map(|addr| addr.ip()),
+        header(actix_web::http::header::FORWARDED).as_deref(),
+        header(actix_web::http::header::HeaderName::from_static(
+            "x-forwarded-for",
+        ))
+        .as_deref(),
+        trusted_proxies,
+    )
+}
+
+/**
+ * # Brief
+ * Middleware limiting configured routes by client IP & session.
+ *
+ * # Detail
+ * - Routes are matched on the exact request path, see `rate_limit.routes`.
+ * - The session limit only applies when a `session_id` Cookie is sent.
+ * - Throttled requests get 429 with `Retry-After`, the handler never runs.
+ */
+pub async fn rate_limit(
+    request: actix_web::dev::ServiceRequest,
+    next: actix_web::middleware::Next<impl actix_web::body::MessageBody + 'static>,
+) -> Result<actix_web::dev::ServiceResponse<actix_web::body::BoxBody>, actix_web::Error> {
+    let limiter = request
+        .app_data::<actix_web::web::Data<ServerState>>()
+        .map(|server_state| server_state.rate_limiter.clone());
+    let path = request.path().to_string();
+
+    if let Some((limiter, route)) =
+        limiter.and_then(|limiter| limiter.route(&path).map(|route| (limiter, route)))
+    {
+        let ip = limiter.client_ip(request.request());
+        let session_id = request
+            .cookie("session_id")
+            .map(|cookie| cookie.value().to_string());
+
+        let mut keys = vec![("ip", ip)];
+        keys.extend(session_id.map(|session_id| ("session", session_id)));
+
+        for (scope, key) in keys {
+            match limiter.hit(&path, scope, &key, route).await {
+                Ok(None) => {}
+                Ok(Some(retry_after)) => {
+                    tracing::info!(
+                        component = "rate_limit",
+                        path = %path,
+                        scope = scope,
+                        retry_after = retry_after,
+                        "request throttled"
+                    );
+                    return Ok(request.error_response(ApiError::TooManyRequests(retry_after)));
+                }
+                Err(e) => {
+                    tracing::error!(
+                        error = %e,
+                        component = "rate_limit",
+                        function = "hit",
+                        "function failed & returned error"
+                    );
+                }
+            }
+        }
+    }
+
+    next.call(request)
+        .await
+        .map(actix_web::dev::ServiceResponse::map_into_boxed_body)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::resolve_client;
+
+    fn ip(value: &str) -> std::net::IpAddr {
+        value.parse().unwrap()
+    }
+
+    #[test]
+    fn untrusted_peer_ignores_forwarding_headers() {
+        let client = resolve_client(
+            Some(ip("203.0.113.7")),
+            Some("for=198.51.100.1"),
+            Some("198.51.100.2"),
+            &[ip("10.0.0.1")],
+        );
+        assert_eq!(client, "203.0.113.7");
+    }
+
+    #[test]
+    fn no_trusted_proxies_keys_on_peer() {
+        let client = resolve_client(Some(ip("10.0.0.1")), None, Some("198.51.100.2"), &[]);
+        assert_eq!(client, "10.0.0.1");
+    }
+
+    #[test]
+    fn trusted_peer_takes_nearest_untrusted_hop() {
+        // the client prepended a spoofed address, the proxy appended the real one
+        let client = resolve_client(
+            Some(ip("10.0.0.1")),
+            None,
+            Some("1.2.3.4, 198.51.100.2, 10.0.0.2"),
+            &[ip("10.0.0.1"), ip("10.0.0.2")],
+        );
+        assert_eq!(client, "198.51.100.2");
+    }
+
+    #[test]
+    fn forwarded_wins_over_x_forwarded_for() {
+        let client = resolve_client(
+            Some(ip("10.0.0.1")),
+            Some(r#"for=192.0.2.60;proto=http, for="[2001:db8::1]:4711""#),
+            Some("198.51.100.2"),
+            &[ip("10.0.0.1")],
+        );
+        assert_eq!(client, "2001:db8::1");
+    }
+
+    #[test]
+    fn forwarded_node_with_port() {
+        let client = resolve_client(
+            Some(ip("10.0.0.1")),
+            Some("for=192.0.2.60:8080"),
+            None,
+            &[ip("10.0.0.1")],
+        );
+        assert_eq!(client, "192.0.2.60");
+    }
+
+    #[test]
+    fn only_proxies_keys_on_peer() {
+        let client = resolve_client(
+            Some(ip("10.0.0.1")),
+            None,
+            Some("10.0.0.2"),
+            &[ip("10.0.0.1"), ip("10.0.0.2")],
+        );
+        assert_eq!(client, "10.0.0.1");
+    }
+
+    #[test]
+    fn missing_peer_is_empty() {
+        assert_eq!(resolve_client(None, None, Some("1.2.3.4"), &[]), "");
+    }
+}
@@ -0,0 +1,490 @@
+use super::server_api_keys::AuthenticatedCaller;
+use super::server_errors::ApiError;
+use super::server_types::ServerState;
+
+/*
+ * Role based access control.
+ *
+ * Users hold roles (`user_roles`), roles grant permissions (`roles.permissions`).
+ * Every signed in user also has `rbac.default_permissions`. API keys act as
+ * their user, their scopes narrow what the user may do but never widen it.
+ */
+
+pub const PERMISSION_JOBS_SUBMIT: &str = "jobs:submit";
+pub const PERMISSION_WORKERS_MANAGE: &str = "workers:manage";
+pub const PERMISSION_USERS_ADMIN: &str = "users:admin";
+pub const PERMISSIONS: [&str; 3] = [
+    PERMISSION_JOBS_SUBMIT,
+    PERMISSION_WORKERS_MANAGE,
+    PERMISSION_USERS_ADMIN,
+];
+
+/// seeded by the `create_roles` migration, at least one user keeps it
+pub const ROLE_ADMIN: &str = "admin";
+
+/// a permission checked by `Permitted`
+pub trait Permission {
+    const NAME: &'static str;
+}
+
+/// submit & cancel jobs
+pub struct JobsSubmit;
+/// inspect the worker fleet
+pub struct WorkersManage;
+/// grant & revoke roles
+pub struct UsersAdmin;
+
+impl Permission for JobsSubmit {
+    const NAME: &'static str = PERMISSION_JOBS_SUBMIT;
+}
+
+impl Permission for WorkersManage {
+    const NAME: &'static str = PERMISSION_WORKERS_MANAGE;
+}
+
+impl Permission for UsersAdmin {
+    const NAME: &'static str = PERMISSION_USERS_ADMIN;
+}
+
+/// true when one of the user's roles grants `permission`
+pub async fn has_permission(
+    pool: &sqlx::PgPool,
+    user_id: uuid::Uuid,
+    permission: &str,
+) -> Result<bool, sqlx::Error> {
+    sqlx::query_scalar::<_, bool>(
+        r#"
+        SELECT EXISTS (
+            SELECT 1
+            FROM user_roles
+            JOIN roles ON roles.role = user_roles.role
+            WHERE user_roles.user_id = $1 AND $2 = ANY (roles.permissions)
+        )
+        "#,
+    )
+    .bind(user_id)
+    .bind(permission)
+    .fetch_one(pool)
+    .await
+}
+
+/// a caller holding the permission `P`, derefs to the caller
+#[derive(Debug)]
+pub struct Permitted<P: Permission> {
+    caller: AuthenticatedCaller,
+    permission: std::marker::PhantomData<P>,
+}
+
+impl<P: Permission> std::ops::Deref for Permitted<P> {
+    type Target = AuthenticatedCaller;
+
+    fn deref(&self) -> &Self::Target {
+        &self.caller
+    }
+}
+
+impl<P: Permission> Permitted<P> {
+    async fn load(request: actix_web::HttpRequest) -> Result<Self, ApiError> {
+        let caller = <AuthenticatedCaller as actix_web::FromRequest>::extract(&request).await?;
+        let Some(server_state) = request.app_data::<actix_web::web::Data<ServerState>>() else {
+            return Err(ApiError::internal(
+                "rbac",
+                "app_data",
+                "server state missing from app data",
+            ));
+        };
+
+        let permitted = server_state
+            .rbac
+            .default_permissions
+            .iter()
+            .any(|permission| permission == P::NAME)
+            || has_permission(&server_state.central_db_pool, caller.user_id, P::NAME)
+                .await
+                .map_err(|e| ApiError::database(e, "SELECT", "user_roles"))?;
+        if !permitted {
+            tracing::info!(
+                component = "rbac",
+                user_id = %caller.user_id,
+                permission = P::NAME,
+                "caller lacks permission"
+            );
+            return Err(ApiError::forbidden(
+                "missing_permission",
+                format!("Missing Permission {}", P::NAME),
+            ));
+        }
+
+        Ok(Permitted {
+            caller,
+            permission: std::marker::PhantomData,
+        })
+    }
+}
+
+/**
+ * # Brief
+ * Extracts a caller like `AuthenticatedCaller`, then requires the permission `P`.
+ *
+ * # Detail
+ * - 401 without a session or API key, 403 when no role nor default grants `P`.
+ * - API key scopes are still checked by the handler.
+ */
+impl<P: Permission + 'static> actix_web::FromRequest for Permitted<P> {
+    type Error = ApiError;
+    type Future = std::pin::Pin<Box<dyn std::future::Future<Output = Result<Self, Self::Error>>>>;
+
+    fn from_request(
+        request: &actix_web::HttpRequest,
+        _payload: &mut actix_web::dev::Payload,
+    ) -> Self::Future {
+        Box::pin(Permitted::load(request.clone()))
+    }
+}
+
+#[derive(serde::Serialize, Debug, sqlx::FromRow)]
+pub struct Role {
+    pub role: String,
+    pub description: String,
+    pub permissions: Vec<String>,
+    pub created_at: chrono::DateTime<chrono::Utc>,
+}
+
+#[derive(serde::Serialize, Debug, sqlx::FromRow)]
+pub struct UserRole {
+    pub role: String,
+    pub granted_by: Option<uuid::Uuid>,
+    pub granted_at: chrono::DateTime<chrono::Utc>,
+}
+
+pub async fn list_roles(pool: &sqlx::PgPool) -> Result<Vec<Role>, sqlx::Error> {
+    sqlx::query_as::<_, Role>(
+        "SELECT role, description, permissions, created_at FROM roles ORDER BY role",
+    )
+    .fetch_all(pool)
+    .await
+}
+
+pub async fn role_exists(pool: &sqlx::PgPool, role: &str) -> Result<bool, sqlx::Error> {
+    sqlx::query_scalar::<_, bool>("SELECT EXISTS (SELECT 1 FROM roles WHERE role = $1)")
+        .bind(role)
+        .fetch_one(pool)
+        .await
+}
+
+pub async fn user_roles(
+    pool: &sqlx::PgPool,
+    user_id: uuid::Uuid,
+) -> Result<Vec<UserRole>, sqlx::Error> {
+    sqlx::query_as::<_, UserRole>(
+        "SELECT role, granted_by, granted_at FROM user_roles WHERE user_id = $1 ORDER BY role",
+    )
+    .bind(user_id)
+    .fetch_all(pool)
+    .await
+}
+
+/// emails of the users holding `role`
+pub async fn role_holders(pool: &sqlx::PgPool, role: &str) -> Result<Vec<String>, sqlx::Error> {
+    sqlx::query_scalar::<_, String>(
+        r#"
+        SELECT users.email
+        FROM user_roles
+        JOIN users ON users.user_id = user_roles.user_id
+        WHERE user_roles.role = $1
+        ORDER BY users.email
+        "#,
+    )
+    .bind(role)
+    .fetch_all(pool)
+    .await
+}
+
+/**
+ * # Brief
+ * Grants `role` to a user, false when they already held it.
+ *
+ * # Detail
+ * - The role must exist, see `role_exists`.
+ * - `granted_by` is None for grants from the command line.
+ */
+pub async fn grant_role(
+    pool: &sqlx::PgPool,
+    user_id: uuid::Uuid,
+    role: &str,
+    granted_by: Option<uuid::Uuid>,
+) -> Result<bool, sqlx::Error> {
+    let result = sqlx::query(
+        r#"
+        INSERT INTO user_roles (user_id, role, granted_by)
+        VALUES ($1, $2, $3)
+        ON CONFLICT (user_id, role) DO NOTHING
+        "#,
+    )
+    .bind(user_id)
+    .bind(role)
+    .bind(granted_by)
+    .execute(pool)
+    .await?;
+    Ok(result.rows_affected() == 1)
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum RevokeOutcome {
+    Revoked,
+    NotGranted,
+    /// `admin` is never revoked from its last holder
+    LastAdmin,
+}
+
+/**
+ * # Brief
+ * Revokes `role` from a user.
+ *
+ * # Detail
+ * - Refuses to remove the last `admin`, nobody could grant roles anymore.
+ *   The count & the delete are one statement, like `server_orgs::KEEPS_AN_OWNER`.
+ */
+pub async fn revoke_role(
+    pool: &sqlx::PgPool,
+    user_id: uuid::Uuid,
+    role: &str,
+) -> Result<RevokeOutcome, sqlx::Error> {
+    let result = sqlx::query(
+        r#"
+        DELETE FROM user_roles
+        WHERE user_id = $1 AND role = $2
+          AND ($2 <> $3 OR (SELECT count(*) FROM user_roles WHERE role = $3) > 1)
+        "#,
+    )
+    .bind(user_id)
+    .bind(role)
+    .bind(ROLE_ADMIN)
+    .execute(pool)
+    .await?;
+    if result.rows_affected() == 1 {
+        return Ok(RevokeOutcome::Revoked);
+    }
+
+    let held = sqlx::query_scalar::<_, bool>(
+        "SELECT EXISTS (SELECT 1 FROM user_roles WHERE user_id = $1 AND role = $2)",
+    )
+    .bind(user_id)
+    .bind(role)
+    .fetch_one(pool)
+    .await?;
+    Ok(match held {
+        true => RevokeOutcome::LastAdmin,
+        false => RevokeOutcome::NotGranted,
+    })
+}
+
+pub async fn find_user_by_email(
+    pool: &sqlx::PgPool,
+    email: &str,
+) -> Result<Option<uuid::Uuid>, sqlx::Error> {
+    sqlx::query_scalar::<_, uuid::Uuid>("SELECT user_id FROM users WHERE email = $1")
+        .bind(email.trim().to_lowercase())
+        .fetch_optional(pool)
+        .await
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum BootstrapOutcome {
+    Granted,
+    /// somebody already is an admin, nothing was changed
+    AdminExists,
+    UnknownUser,
+    /// registered, but the address was never confirmed, anybody could have claimed it
+    Unverified,
+}
+
+/**
+ * # Brief
+ * Makes the user with `email` an admin, only while there is no admin at all.
+ *
+ * # Detail
+ * - Safe to run on every start, once an admin exists it does nothing.
+ * - Only a verified email is trusted, registering the address is not enough.
+ */
+pub async fn bootstrap_admin(
+    pool: &sqlx::PgPool,
+    email: &str,
+) -> Result<BootstrapOutcome, sqlx::Error> {
+    let Some((user_id, verified)) = sqlx::query_as::<_, (uuid::Uuid, bool)>(
+        "SELECT user_id, email_verified_at IS NOT NULL FROM users WHERE email = $1",
+    )
+    .bind(email.trim().to_lowercase())
+    .fetch_optional(pool)
+    .await?
+    else {
+        return Ok(BootstrapOutcome::UnknownUser);
+    };
+    if !verified {
+        return Ok(BootstrapOutcome::Unverified);
+    }
+    let result = sqlx::query(
+        r#"
+        INSERT INTO user_roles (user_id, role)
+        SELECT $1, $2
+        WHERE NOT EXISTS (SELECT 1 FROM user_roles WHERE role = $2)
+        "#,
+    )
+    .bind(user_id)
+    .bind(ROLE_ADMIN)
+    .execute(pool)
+    .await?;
+    Ok(match result.rows_affected() {
+        1 => BootstrapOutcome::Granted,
+        _ => BootstrapOutcome::AdminExists,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crimson::server_testing::{self, TestServer};
+
+    const CONFIG: &str = r#"
+        [rbac]
+        default_permissions = ["jobs:submit"]
+    "#;
+
+    async fn jobs(_caller: Permitted<JobsSubmit>) -> actix_web::HttpResponse {
+        actix_web::HttpResponse::Ok().finish()
+    }
+
+    async fn workers(_caller: Permitted<WorkersManage>) -> actix_web::HttpResponse {
+        actix_web::HttpResponse::Ok().finish()
+    }
+
+    async fn call(server: &TestServer, uri: &str, session_id: Option<&str>) -> u16 {
+        let app = actix_web::test::init_service(
+            actix_web::App::new()
+                .app_data(server.state.clone())
+                .route("/jobs", actix_web::web::get().to(jobs))
+                .route("/workers", actix_web::web::get().to(workers)),
+        )
+        .await;
+        let mut request = actix_web::test::TestRequest::get().uri(uri);
+        if let Some(session_id) = session_id {
+            request = request.cookie(actix_web::cookie::Cookie::new("session_id", session_id));
+        }
+        actix_web::test::call_service(&app, request.to_request())
+            .await
+            .status()
+            .as_u16()
+    }
+
+    #[actix_web::test]
+    async fn permitted_needs_a_caller_then_the_permission() {
+        let server = TestServer::start(CONFIG).await;
+        let email = server_testing::unique_email("rbac");
+        let user_id = server_testing::insert_user(server.db(), &email, None, true).await;
+        let session_id = server.sign_in(user_id).await;
+
+        assert_eq!(call(&server, "/jobs", None).await, 401);
+        assert_eq!(call(&server, "/jobs", Some("not-a-session")).await, 401);
+        // granted to everybody by `rbac.default_permissions`
+        assert_eq!(call(&server, "/jobs", Some(&session_id)).await, 200);
+        assert_eq!(call(&server, "/workers", Some(&session_id)).await, 403);
+
+        assert!(
+            grant_role(server.db(), user_id, "operator", None)
+                .await
+                .unwrap()
+        );
+        assert_eq!(call(&server, "/workers", Some(&session_id)).await, 200);
+        server_testing::delete_user(server.db(), user_id).await;
+    }
+
+    #[actix_web::test]
+    async fn default_permissions_are_only_what_is_configured() {
+        let server = TestServer::start(
+            r#"
+            [rbac]
+            default_permissions = []
+            "#,
+        )
+        .await;
+        let email = server_testing::unique_email("rbac-none");
+        let user_id = server_testing::insert_user(server.db(), &email, None, true).await;
+        let session_id = server.sign_in(user_id).await;
+
+        assert_eq!(call(&server, "/jobs", Some(&session_id)).await, 403);
+        server_testing::delete_user(server.db(), user_id).await;
+    }
+
+    // one test, the admin role is shared by the whole database
+    #[actix_web::test]
+    async fn admin_is_bootstrapped_once_and_never_revoked_from_its_last_holder() {
+        let server = TestServer::start("").await;
+        let pool = server.db();
+        // admins of other runs step aside for the test & come back after
+        let others: Vec<(uuid::Uuid, Option<uuid::Uuid>)> =
+            sqlx::query_as("DELETE FROM user_roles WHERE role = $1 RETURNING user_id, granted_by")
+                .bind(ROLE_ADMIN)
+                .fetch_all(pool)
+                .await
+                .unwrap();
+
+        let unverified = server_testing::unique_email("admin-unverified");
+        let first = server_testing::unique_email("admin-first");
+        let second = server_testing::unique_email("admin-second");
+        let unverified_id = server_testing::insert_user(pool, &unverified, None, false).await;
+        let first_id = server_testing::insert_user(pool, &first, None, true).await;
+        let second_id = server_testing::insert_user(pool, &second, None, true).await;
+
+        assert_eq!(
+            bootstrap_admin(pool, &server_testing::unique_email("admin-unknown"))
+                .await
+                .unwrap(),
+            BootstrapOutcome::UnknownUser
+        );
+        // registering somebody else's address is not enough
+        assert_eq!(
+            bootstrap_admin(pool, &unverified).await.unwrap(),
+            BootstrapOutcome::Unverified
+        );
+        assert!(role_holders(pool, ROLE_ADMIN).await.unwrap().is_empty());
+        assert_eq!(
+            bootstrap_admin(pool, &first.to_uppercase()).await.unwrap(),
+            BootstrapOutcome::Granted
+        );
+        assert_eq!(
+            bootstrap_admin(pool, &second).await.unwrap(),
+            BootstrapOutcome::AdminExists
+        );
+
+        assert_eq!(
+            revoke_role(pool, first_id, ROLE_ADMIN).await.unwrap(),
+            RevokeOutcome::LastAdmin
+        );
+        assert_eq!(
+            revoke_role(pool, second_id, ROLE_ADMIN).await.unwrap(),
+            RevokeOutcome::NotGranted
+        );
+        assert!(
+            grant_role(pool, second_id, ROLE_ADMIN, Some(first_id))
+                .await
+                .unwrap()
+        );
+        assert_eq!(
+            revoke_role(pool, first_id, ROLE_ADMIN).await.unwrap(),
+            RevokeOutcome::Revoked
+        );
+        assert_eq!(
+            revoke_role(pool, second_id, ROLE_ADMIN).await.unwrap(),
+            RevokeOutcome::LastAdmin
+        );
+
+        for user_id in [unverified_id, first_id, second_id] {
+            server_testing::delete_user(pool, user_id).await;
+        }
+        for (user_id, granted_by) in others {
+            grant_role(pool, user_id, ROLE_ADMIN, granted_by)
+                .await
+                .unwrap();
+        }
+    }
+}
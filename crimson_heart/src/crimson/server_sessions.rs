@@ -0,0 +1,688 @@
+use super::server_errors::{ApiError, CODE_LOGIN_REQUIRED};
+use super::server_types::{ServerState, SessionUserState};
+
+use deadpool_redis::redis::AsyncCommands;
+
+/*
+ * Redis layout for sessions.
+ *
+ * - `session_id:{session_id}` hash, `state` & `user_id` of a session, plus
+ *   `created_at` & `last_seen` (unix seconds), `ip` and `user_agent`.
+ *   `webauthn_challenge` holds a pending passkey ceremony as
+ *   `{ceremony}:{expires_at}:{challenge}`. `oidc_login` holds a pending
+ *   OpenID Connect login as `{provider}:{expires_at}:{state}:{nonce}:{code_verifier}`.
+ *   `org_id` is the organization the session switched to, absent for the
+ *   personal workspace.
+ * - `user_sessions:{user_id}` set of session ids a user has signed into,
+ *   backs session listing & revocation. Ids of expired sessions are
+ *   pruned lazily.
+ */
+
+#[inline]
+pub fn session_key(session_id: &str) -> String {
+    format!("session_id:{}", session_id)
+}
+
+#[inline]
+pub fn user_sessions_key(user_id: &str) -> String {
+    format!("user_sessions:{}", user_id)
+}
+
+/**
+ * # Brief
+ * Records `session_id` as signed into by `user_id`.
+ *
+ * # Detail
+ * - The index lives as long as the newest session, so it never outlives all of them.
+ */
+pub async fn track_user_session(
+    redis_connection: &mut deadpool_redis::Connection,
+    user_id: &str,
+    session_id: &str,
+    session_ttl: i64,
+) -> Result<(), deadpool_redis::redis::RedisError> {
+    let index_key = user_sessions_key(user_id);
+    let _: () = redis_connection.sadd(&index_key, session_id).await?;
+    let _: () = redis_connection.expire(&index_key, session_ttl).await?;
+    Ok(())
+}
+
+/**
+ * # Brief
+ * Deletes every session of `user_id`, returns how many were still live.
+ */
+pub async fn revoke_user_sessions(
+    redis_connection: &mut deadpool_redis::Connection,
+    user_id: &str,
+) -> Result<usize, deadpool_redis::redis::RedisError> {
+    let index_key = user_sessions_key(user_id);
+    let session_ids: Vec<String> = redis_connection.smembers(&index_key).await?;
+
+    let keys: Vec<String> = session_ids.iter().map(|id| session_key(id)).collect();
+    let revoked = if keys.is_empty() {
+        0
+    } else {
+        redis_connection.del(&keys).await?
+    };
+
+    let _: () = redis_connection.del(&index_key).await?;
+    Ok(revoked)
+}
+
+/// a live session as shown to its owner, the id itself is never exposed
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct SessionInfo {
+    /// opaque handle, see `session_handle`
+    pub session: String,
+    pub created_at: i64,
+    pub last_seen: i64,
+    pub ip: String,
+    pub user_agent: String,
+    /// the session making the request
+    pub current: bool,
+}
+
+/**
+ * # Brief
+ * Public handle of a session, the id doubles as the cookie value.
+ */
+pub fn session_handle(session_id: &str) -> String {
+    use sha2::Digest;
+    hex::encode(&sha2::Sha256::digest(session_id.as_bytes())[..16])
+}
+
+/**
+ * # Brief
+ * Live sessions of `user_id`, most recently seen first.
+ *
+ * # Detail
+ * - Index entries whose session expired or changed hands are pruned on the way.
+ */
+pub async fn list_user_sessions(
+    redis_connection: &mut deadpool_redis::Connection,
+    user_id: &str,
+    current_session_id: &str,
+) -> Result<Vec<SessionInfo>, deadpool_redis::redis::RedisError> {
+    let index_key = user_sessions_key(user_id);
+    let session_ids: Vec<String> = redis_connection.smembers(&index_key).await?;
+    if session_ids.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let mut pipe = deadpool_redis::redis::pipe();
+    for session_id in &session_ids {
+        pipe.hgetall(session_key(session_id));
+    }
+    let hashes: Vec<std::collections::HashMap<String, String>> =
+        pipe.query_async(redis_connection).await?;
+
+    let mut sessions = Vec::new();
+    let mut stale = Vec::new();
+    for (session_id, fields) in session_ids.iter().zip(hashes) {
+        if fields.get("user_id").map(String::as_str) != Some(user_id) {
+            stale.push(session_id.as_str());
+            continue;
+        }
+        let timestamp = |field: &str| {
+            fields
+                .get(field)
+                .and_then(|value| value.parse().ok())
+                .unwrap_or(0)
+        };
+        sessions.push(SessionInfo {
+            session: session_handle(session_id),
+            created_at: timestamp("created_at"),
+            last_seen: timestamp("last_seen"),
+            ip: fields.get("ip").cloned().unwrap_or_default(),
+            user_agent: fields.get("user_agent").cloned().unwrap_or_default(),
+            current: session_id == current_session_id,
+        });
+    }
+
+    if !stale.is_empty() {
+        let _: () = redis_connection.srem(&index_key, &stale).await?;
+    }
+
+    sessions.sort_by_key(|session| std::cmp::Reverse(session.last_seen));
+    Ok(sessions)
+}
+
+/**
+ * # Brief
+ * Deletes the session of `user_id` behind `handle`, false when there is none.
+ */
+pub async fn revoke_user_session(
+    redis_connection: &mut deadpool_redis::Connection,
+    user_id: &str,
+    handle: &str,
+) -> Result<bool, deadpool_redis::redis::RedisError> {
+    let index_key = user_sessions_key(user_id);
+    let session_ids: Vec<String> = redis_connection.smembers(&index_key).await?;
+    let Some(session_id) = session_ids
+        .into_iter()
+        .find(|session_id| session_handle(session_id) == handle)
+    else {
+        return Ok(false);
+    };
+
+    // the index may be stale, only delete a session the user still owns
+    let owner: Option<String> = redis_connection
+        .hget(session_key(&session_id), "user_id")
+        .await?;
+    let _: () = redis_connection.srem(&index_key, &session_id).await?;
+    if owner.as_deref() != Some(user_id) {
+        return Ok(false);
+    }
+
+    let deleted: usize = redis_connection.del(session_key(&session_id)).await?;
+    Ok(deleted == 1)
+}
+
+/**
+ * # Brief
+ * Deletes every session of `user_id` except `keep_session_id`, returns how many went.
+ */
+pub async fn revoke_other_user_sessions(
+    redis_connection: &mut deadpool_redis::Connection,
+    user_id: &str,
+    keep_session_id: &str,
+) -> Result<usize, deadpool_redis::redis::RedisError> {
+    let index_key = user_sessions_key(user_id);
+    let session_ids: Vec<String> = redis_connection.smembers(&index_key).await?;
+    let others: Vec<&String> = session_ids
+        .iter()
+        .filter(|session_id| *session_id != keep_session_id)
+        .collect();
+    if others.is_empty() {
+        return Ok(0);
+    }
+
+    // only sessions still owned by the user, an expired id is just pruned
+    let mut pipe = deadpool_redis::redis::pipe();
+    for session_id in &others {
+        pipe.hget(session_key(session_id), "user_id");
+    }
+    let owners: Vec<Option<String>> = pipe.query_async(redis_connection).await?;
+    let keys: Vec<String> = others
+        .iter()
+        .zip(owners)
+        .filter(|(_, owner)| owner.as_deref() == Some(user_id))
+        .map(|(session_id, _)| session_key(session_id))
+        .collect();
+
+    let revoked = if keys.is_empty() {
+        0
+    } else {
+        redis_connection.del(&keys).await?
+    };
+    let _: () = redis_connection.srem(&index_key, &others).await?;
+    Ok(revoked)
+}
+
+const PROMOTE_SESSION_SCRIPT: &str = r#"
+if redis.call('HGET', KEYS[1], 'state') == ARGV[1] then
+    redis.call('HSET', KEYS[1], 'state', ARGV[2])
+    return 1
+end
+return 0
+"#;
+
+/**
+ * # Brief
+ * Moves every live session of `user_id` in state `from` to state `to`.
+ *
+ * # Detail
+ * - Sessions in any other state are left alone, as are expired ones.
+ */
+pub async fn promote_user_sessions(
+    redis_connection: &mut deadpool_redis::Connection,
+    user_id: &str,
+    from: SessionUserState,
+    to: SessionUserState,
+) -> Result<usize, deadpool_redis::redis::RedisError> {
+    let session_ids: Vec<String> = redis_connection
+        .smembers(user_sessions_key(user_id))
+        .await?;
+
+    let mut promoted = 0;
+    for session_id in session_ids {
+        // check & set in one step, never recreates an expired session
+        let changed: usize = deadpool_redis::redis::cmd("EVAL")
+            .arg(PROMOTE_SESSION_SCRIPT)
+            .arg(1)
+            .arg(session_key(&session_id))
+            .arg(from.as_u32())
+            .arg(to.as_u32())
+            .query_async(redis_connection)
+            .await?;
+        promoted += changed;
+    }
+    Ok(promoted)
+}
+
+const CHALLENGE_FIELD: &str = "webauthn_challenge";
+const OIDC_LOGIN_FIELD: &str = "oidc_login";
+const ORG_FIELD: &str = "org_id";
+
+// who a session belongs to & pending ceremonies, never carried across a rotation
+const IDENTITY_FIELDS: [&str; 5] = [
+    "state",
+    "user_id",
+    CHALLENGE_FIELD,
+    OIDC_LOGIN_FIELD,
+    ORG_FIELD,
+];
+
+/**
+ * # Brief
+ * Replaces a session with a freshly minted one, returns the new session id.
+ *
+ * # Detail
+ * - Non identity fields of `previous_session_id` are carried over, `fields` are written on top.
+ * - The previous session key is deleted, a planted cookie never becomes authenticated.
+ * - Called on every privilege change: login, registration, logout.
+ */
+pub async fn rotate_session(
+    redis_connection: &mut deadpool_redis::Connection,
+    previous_session_id: Option<&str>,
+    fields: &[(&str, String)],
+    session_ttl: i64,
+) -> Result<String, deadpool_redis::redis::RedisError> {
+    let mut carried: Vec<(String, String)> = match previous_session_id {
+        Some(previous_session_id) => {
+            redis_connection
+                .hgetall(session_key(previous_session_id))
+                .await?
+        }
+        None => Vec::new(),
+    };
+    carried.retain(|(field, _)| {
+        !IDENTITY_FIELDS.contains(&field.as_str()) && !fields.iter().any(|(f, _)| f == field)
+    });
+    carried.extend(fields.iter().map(|(f, v)| (f.to_string(), v.clone())));
+
+    let session_id = uuid::Uuid::now_v7().to_string();
+    let key = session_key(&session_id);
+    let mut pipe = deadpool_redis::redis::pipe();
+    pipe.atomic()
+        .hset_multiple(&key, &carried)
+        .ignore()
+        .expire(&key, session_ttl)
+        .ignore();
+    if let Some(previous_session_id) = previous_session_id {
+        pipe.del(session_key(previous_session_id)).ignore();
+    }
+    let _: () = pipe.query_async(redis_connection).await?;
+
+    Ok(session_id)
+}
+
+// sets a field only on a live session, never recreates an expired one
+const SET_IF_EXISTS_SCRIPT: &str = r#"
+if redis.call('EXISTS', KEYS[1]) == 1 then
+    redis.call('HSET', KEYS[1], ARGV[1], ARGV[2])
+    return 1
+end
+return 0
+"#;
+
+const TAKE_FIELD_SCRIPT: &str = r#"
+local value = redis.call('HGET', KEYS[1], ARGV[1])
+if value then
+    redis.call('HDEL', KEYS[1], ARGV[1])
+end
+return value
+"#;
+
+/**
+ * # Brief
+ * Stores the challenge of a WebAuthn `ceremony` in the session.
+ *
+ * # Detail
+ * - A new challenge replaces any pending one, one ceremony at a time.
+ * - Returns false when the session doesn't exist.
+ */
+pub async fn set_session_challenge(
+    redis_connection: &mut deadpool_redis::Connection,
+    session_id: &str,
+    ceremony: &str,
+    challenge: &str,
+    challenge_ttl: i64,
+) -> Result<bool, deadpool_redis::redis::RedisError> {
+    let expires_at = chrono::Utc::now().timestamp() + challenge_ttl;
+    deadpool_redis::redis::cmd("EVAL")
+        .arg(SET_IF_EXISTS_SCRIPT)
+        .arg(1)
+        .arg(session_key(session_id))
+        .arg(CHALLENGE_FIELD)
+        .arg(format!("{}:{}:{}", ceremony, expires_at, challenge))
+        .query_async(redis_connection)
+        .await
+}
+
+/**
+ * # Brief
+ * Removes & returns the pending challenge of `ceremony`.
+ *
+ * # Detail
+ * - The challenge is gone after this call whatever the outcome, it works once.
+ * - None when there is none, it expired or belongs to another ceremony.
+ */
+pub async fn take_session_challenge(
+    redis_connection: &mut deadpool_redis::Connection,
+    session_id: &str,
+    ceremony: &str,
+) -> Result<Option<String>, deadpool_redis::redis::RedisError> {
+    let pending: Option<String> = deadpool_redis::redis::cmd("EVAL")
+        .arg(TAKE_FIELD_SCRIPT)
+        .arg(1)
+        .arg(session_key(session_id))
+        .arg(CHALLENGE_FIELD)
+        .query_async(redis_connection)
+        .await?;
+
+    let now = chrono::Utc::now().timestamp();
+    Ok(pending.and_then(|pending| {
+        let mut parts = pending.splitn(3, ':');
+        match (parts.next(), parts.next(), parts.next()) {
+            (Some(pending_ceremony), Some(expires_at), Some(challenge))
+                if pending_ceremony == ceremony
+                    && expires_at.parse::<i64>().is_ok_and(|expires_at| expires_at > now) =>
+            {
+                Some(challenge.to_string())
+            }
+            _ => None,
+        }
+    }))
+}
+
+/// an OIDC login waiting for the provider's callback
+#[derive(Debug, Clone)]
+pub struct PendingOidcLogin {
+    pub provider: String,
+    pub state: String,
+    pub nonce: String,
+    pub code_verifier: String,
+}
+
+/**
+ * # Brief
+ * Stores a pending OIDC login in the session.
+ *
+ * # Detail
+ * - A new login replaces any pending one, the older callback then fails.
+ * - Returns false when the session doesn't exist.
+ */
+pub async fn set_session_oidc_login(
+    redis_connection: &mut deadpool_redis::Connection,
+    session_id: &str,
+    pending: &PendingOidcLogin,
+    state_ttl: i64,
+) -> Result<bool, deadpool_redis::redis::RedisError> {
+    let expires_at = chrono::Utc::now().timestamp() + state_ttl;
+    deadpool_redis::redis::cmd("EVAL")
+        .arg(SET_IF_EXISTS_SCRIPT)
+        .arg(1)
+        .arg(session_key(session_id))
+        .arg(OIDC_LOGIN_FIELD)
+        .arg(format!(
+            "{}:{}:{}:{}:{}",
+            pending.provider, expires_at, pending.state, pending.nonce, pending.code_verifier
+        ))
+        .query_async(redis_connection)
+        .await
+}
+
+/**
+ * # Brief
+ * Switches a session to `org_id`, or back to the personal workspace with None.
+ *
+ * # Detail
+ * - Membership is checked by the caller, & again on every compute request.
+ * - Returns false when the session doesn't exist.
+ */
+pub async fn set_session_org(
+    redis_connection: &mut deadpool_redis::Connection,
+    session_id: &str,
+    org_id: Option<uuid::Uuid>,
+) -> Result<bool, deadpool_redis::redis::RedisError> {
+    match org_id {
+        Some(org_id) => {
+            deadpool_redis::redis::cmd("EVAL")
+                .arg(SET_IF_EXISTS_SCRIPT)
+                .arg(1)
+                .arg(session_key(session_id))
+                .arg(ORG_FIELD)
+                .arg(org_id.to_string())
+                .query_async(redis_connection)
+                .await
+        }
+        None => {
+            let key = session_key(session_id);
+            let (exists,): (bool,) = deadpool_redis::redis::pipe()
+                .atomic()
+                .exists(&key)
+                .hdel(&key, ORG_FIELD)
+                .ignore()
+                .query_async(redis_connection)
+                .await?;
+            Ok(exists)
+        }
+    }
+}
+
+/**
+ * # Brief
+ * Removes & returns the pending OIDC login, None when there is none or it expired.
+ *
+ * # Detail
+ * - Works once, like `take_session_challenge`.
+ */
+pub async fn take_session_oidc_login(
+    redis_connection: &mut deadpool_redis::Connection,
+    session_id: &str,
+) -> Result<Option<PendingOidcLogin>, deadpool_redis::redis::RedisError> {
+    let pending: Option<String> = deadpool_redis::redis::cmd("EVAL")
+        .arg(TAKE_FIELD_SCRIPT)
+        .arg(1)
+        .arg(session_key(session_id))
+        .arg(OIDC_LOGIN_FIELD)
+        .query_async(redis_connection)
+        .await?;
+
+    let now = chrono::Utc::now().timestamp();
+    Ok(pending.and_then(|pending| {
+        let mut parts = pending.splitn(5, ':');
+        match (
+            parts.next(),
+            parts.next(),
+            parts.next(),
+            parts.next(),
+            parts.next(),
+        ) {
+            (Some(provider), Some(expires_at), Some(state), Some(nonce), Some(code_verifier))
+                if expires_at.parse::<i64>().is_ok_and(|expires_at| expires_at > now) =>
+            {
+                Some(PendingOidcLogin {
+                    provider: provider.to_string(),
+                    state: state.to_string(),
+                    nonce: nonce.to_string(),
+                    code_verifier: code_verifier.to_string(),
+                })
+            }
+            _ => None,
+        }
+    }))
+}
+
+/// the `session_id` cookie for `session_id`
+pub fn session_cookie(session_id: String, session_ttl: i64) -> actix_web::cookie::Cookie<'static> {
+    actix_web::cookie::Cookie::build("session_id", session_id)
+        .path("/")
+        .max_age(actix_web::cookie::time::Duration::seconds(session_ttl))
+        .same_site(actix_web::cookie::SameSite::Lax)
+        .http_only(true)
+        .finish()
+}
+
+/**
+ * # Brief
+ * Client details recorded on a freshly minted session.
+ *
+ * # Detail
+ * - `ip` only honours `Forwarded` / `X-Forwarded-For` from `rate_limit.trusted_proxies`.
+ */
+pub fn client_fields(request: &actix_web::HttpRequest) -> Vec<(&'static str, String)> {
+    let now = chrono::Utc::now().timestamp().to_string();
+    let ip = match request.app_data::<actix_web::web::Data<ServerState>>() {
+        Some(server_state) => server_state.rate_limiter.client_ip(request),
+        None => super::server_rate_limit::client_ip(request, &[]),
+    };
+    let user_agent = request
+        .headers()
+        .get(actix_web::http::header::USER_AGENT)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or_default()
+        .to_string();
+
+    vec![
+        ("created_at", now.clone()),
+        ("last_seen", now),
+        ("ip", ip),
+        ("user_agent", user_agent),
+    ]
+}
+
+// reads a session & bumps `last_seen` in one step, never recreates an expired session
+const TOUCH_SESSION_SCRIPT: &str = r#"
+local fields = redis.call('HGETALL', KEYS[1])
+if #fields > 0 then
+    redis.call('HSET', KEYS[1], 'last_seen', ARGV[1])
+end
+return fields
+"#;
+
+/// a signed in caller, loaded from the `session_id` cookie
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct AuthenticatedUser {
+    pub user_id: uuid::Uuid,
+    #[serde(skip)]
+    pub session_id: String,
+    pub created_at: i64,
+    pub last_seen: i64,
+    pub ip: String,
+    pub user_agent: String,
+    /// organization the session works in, None for the personal workspace
+    pub org_id: Option<uuid::Uuid>,
+}
+
+impl AuthenticatedUser {
+    pub async fn load(request: actix_web::HttpRequest) -> Result<Self, ApiError> {
+        let Some(server_state) = request.app_data::<actix_web::web::Data<ServerState>>() else {
+            return Err(ApiError::internal(
+                "session",
+                "app_data",
+                "server state missing from app data",
+            ));
+        };
+
+        let session_id = match request.cookie("session_id") {
+            Some(cookie) => cookie.value().to_string(),
+            None => {
+                tracing::info!(component = "session", "request without session cookie");
+                return Err(ApiError::unauthorized(
+                    CODE_LOGIN_REQUIRED,
+                    "Login Required",
+                ));
+            }
+        };
+
+        let mut redis_connection = server_state.redis_pool.get().await?;
+
+        let now = chrono::Utc::now().timestamp();
+        let fields: std::collections::HashMap<String, String> = deadpool_redis::redis::cmd("EVAL")
+            .arg(TOUCH_SESSION_SCRIPT)
+            .arg(1)
+            .arg(session_key(&session_id))
+            .arg(now)
+            .query_async(&mut redis_connection)
+            .await
+            .map_err(|e| ApiError::redis(e, "touch_session"))?;
+
+        let state = fields
+            .get("state")
+            .and_then(|state| state.parse().ok())
+            .and_then(SessionUserState::from_u32);
+        let user_id = fields
+            .get("user_id")
+            .and_then(|user_id| uuid::Uuid::parse_str(user_id).ok());
+
+        match (state, user_id) {
+            (Some(SessionUserState::Registered), Some(user_id)) => {
+                let timestamp = |field: &str| {
+                    fields
+                        .get(field)
+                        .and_then(|value| value.parse().ok())
+                        .unwrap_or(now)
+                };
+                Ok(AuthenticatedUser {
+                    user_id,
+                    created_at: timestamp("created_at"),
+                    last_seen: now,
+                    ip: fields.get("ip").cloned().unwrap_or_default(),
+                    user_agent: fields.get("user_agent").cloned().unwrap_or_default(),
+                    org_id: fields
+                        .get(ORG_FIELD)
+                        .and_then(|org_id| uuid::Uuid::parse_str(org_id).ok()),
+                    session_id,
+                })
+            }
+            (Some(SessionUserState::PendingVerification), Some(_)) => {
+                tracing::info!(component = "session", "request before email verification");
+                Err(ApiError::forbidden(
+                    "email_verification_required",
+                    "Email Verification Required",
+                ))
+            }
+            (Some(SessionUserState::TwoFactorPending), Some(_)) => {
+                tracing::info!(component = "session", "request before second factor");
+                Err(ApiError::unauthorized(
+                    "two_factor_required",
+                    "Two-Factor Code Required",
+                ))
+            }
+            _ => {
+                tracing::info!(
+                    component = "session",
+                    "request from anonymous or expired session"
+                );
+                Err(ApiError::unauthorized(
+                    CODE_LOGIN_REQUIRED,
+                    "Login Required",
+                ))
+            }
+        }
+    }
+}
+
+/**
+ * # Brief
+ * Extracts the signed in caller, or rejects the request.
+ *
+ * # Detail
+ * - 401 without a cookie, for anonymous & expired sessions.
+ * - 403 while the email address isn't verified.
+ * - 401 while a 2FA login waits for its code.
+ * - Bumps the session's `last_seen` on every use.
+ */
+impl actix_web::FromRequest for AuthenticatedUser {
+    type Error = ApiError;
+    type Future = std::pin::Pin<Box<dyn std::future::Future<Output = Result<Self, Self::Error>>>>;
+
+    fn from_request(
+        request: &actix_web::HttpRequest,
+        _payload: &mut actix_web::dev::Payload,
+    ) -> Self::Future {
+        Box::pin(AuthenticatedUser::load(request.clone()))
+    }
+}
@@ -0,0 +1,811 @@
+use super::black_channel_client::{BlackChannelClient, ClientOptions};
+use super::compute_registry::WorkerRegistry;
+use super::compute_scheduler::{self, JobScheduler};
+use super::server_breached::BreachedPasswords;
+use super::server_config::{ServerArgs, ServerConfig};
+use super::server_hashing::HashingPool;
+use super::server_mailer::{MailError, MailMessage, Mailer};
+use super::server_oauth::TokenSigner;
+use super::server_oidc::OidcClient;
+use super::server_passwords::Passwords;
+use super::server_rate_limit::RateLimiter;
+use super::server_sessions;
+use super::server_types::{ServerState, SessionUserState};
+use argon2::password_hash::{PasswordHasher, SaltString};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt};
+
+/*
+ * Fixtures shared by the handler tests.
+ *
+ * Handlers run against the central database of `DATABASE_URL`, the one sqlx
+ * checks the queries against at build time, migrated with `migrate up`.
+ * Redis is replaced by `MockRedis`, an in-process server keeping keys in
+ * memory & answering the subset of commands the server sends. Of the Lua
+ * scripts it only runs the sliding window of the rate limiter, the session
+ * promotion & the session touch, natively.
+ *
+ * Users are created with unique emails, tests run side by side on one database.
+ */
+
+// ---------------------------------------------------------------------------
+// mock redis
+// ---------------------------------------------------------------------------
+
+#[derive(Debug, Clone)]
+enum Value {
+    String(Vec<u8>),
+    Hash(std::collections::HashMap<Vec<u8>, Vec<u8>>),
+    Set(std::collections::BTreeSet<Vec<u8>>),
+    // (score, member), kept sorted by score
+    SortedSet(Vec<(f64, Vec<u8>)>),
+}
+
+#[derive(Debug)]
+struct Entry {
+    value: Value,
+    expires_at: Option<std::time::Instant>,
+}
+
+type Store = std::sync::Arc<std::sync::Mutex<std::collections::HashMap<Vec<u8>, Entry>>>;
+
+enum Reply {
+    Status(&'static str),
+    Error(String),
+    Integer(i64),
+    Bulk(Option<Vec<u8>>),
+    Array(Vec<Reply>),
+}
+
+impl Reply {
+    fn wrong_type() -> Reply {
+        Reply::Error("WRONGTYPE Operation against a key holding the wrong kind of value".into())
+    }
+
+    fn encode(&self, out: &mut Vec<u8>) {
+        match self {
+            Reply::Status(status) => out.extend_from_slice(format!("+{}\r\n", status).as_bytes()),
+            Reply::Error(error) => out.extend_from_slice(format!("-{}\r\n", error).as_bytes()),
+            Reply::Integer(value) => out.extend_from_slice(format!(":{}\r\n", value).as_bytes()),
+            Reply::Bulk(None) => out.extend_from_slice(b"$-1\r\n"),
+            Reply::Bulk(Some(value)) => {
+                out.extend_from_slice(format!("${}\r\n", value.len()).as_bytes());
+                out.extend_from_slice(value);
+                out.extend_from_slice(b"\r\n");
+            }
+            Reply::Array(items) => {
+                out.extend_from_slice(format!("*{}\r\n", items.len()).as_bytes());
+                for item in items {
+                    item.encode(out);
+                }
+            }
+        }
+    }
+}
+
+fn text(arg: &[u8]) -> String {
+    String::from_utf8_lossy(arg).to_string()
+}
+
+fn number<T: std::str::FromStr>(arg: &[u8]) -> Option<T> {
+    std::str::from_utf8(arg).ok()?.parse().ok()
+}
+
+// `*` & `?` wildcards, as `KEYS` takes them
+fn glob_match(pattern: &[u8], key: &[u8]) -> bool {
+    match (pattern.first(), key.first()) {
+        (None, None) => true,
+        (Some(b'*'), _) => {
+            glob_match(&pattern[1..], key) || (!key.is_empty() && glob_match(pattern, &key[1..]))
+        }
+        (Some(b'?'), Some(_)) => glob_match(&pattern[1..], &key[1..]),
+        (Some(p), Some(k)) if p == k => glob_match(&pattern[1..], &key[1..]),
+        _ => false,
+    }
+}
+
+/**
+ * # Brief
+ * In-process stand-in for Redis, listening on a local port.
+ *
+ * # Detail
+ * - Speaks RESP2, MULTI/EXEC runs the queued commands in one step.
+ * - Unsupported commands & scripts are answered with an error, never ignored.
+ */
+pub struct MockRedis {
+    pub url: String,
+}
+
+impl MockRedis {
+    pub async fn start() -> MockRedis {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("redis://{}", listener.local_addr().unwrap());
+        let store = Store::default();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                tokio::spawn(serve_connection(stream, store.clone()));
+            }
+        });
+        MockRedis { url }
+    }
+}
+
+async fn read_command(
+    reader: &mut tokio::io::BufReader<tokio::net::tcp::OwnedReadHalf>,
+) -> Option<Vec<Vec<u8>>> {
+    let mut line = String::new();
+    if reader.read_line(&mut line).await.ok()? == 0 {
+        return None;
+    }
+    let count: usize = line.trim_end().strip_prefix('*')?.parse().ok()?;
+    let mut args = Vec::with_capacity(count);
+    for _ in 0..count {
+        line.clear();
+        reader.read_line(&mut line).await.ok()?;
+        let len: usize = line.trim_end().strip_prefix('$')?.parse().ok()?;
+        let mut arg = vec![0; len + 2];
+        reader.read_exact(&mut arg).await.ok()?;
+        arg.truncate(len);
+        args.push(arg);
+    }
+    Some(args)
+}
+
+async fn serve_connection(stream: tokio::net::TcpStream, store: Store) {
+    let (reader, mut writer) = stream.into_split();
+    let mut reader = tokio::io::BufReader::new(reader);
+    let mut queued: Option<Vec<Vec<Vec<u8>>>> = None;
+
+    while let Some(args) = read_command(&mut reader).await {
+        let name = args.first().map(|name| text(name).to_uppercase());
+        let reply = match (name.as_deref(), &mut queued) {
+            (Some("MULTI"), None) => {
+                queued = Some(Vec::new());
+                Reply::Status("OK")
+            }
+            (Some("EXEC"), Some(_)) => {
+                let commands = queued.take().unwrap_or_default();
+                Reply::Array(commands.iter().map(|args| execute(&store, args)).collect())
+            }
+            (Some("DISCARD"), Some(_)) => {
+                queued = None;
+                Reply::Status("OK")
+            }
+            (_, Some(commands)) => {
+                commands.push(args);
+                Reply::Status("QUEUED")
+            }
+            (_, None) => execute(&store, &args),
+        };
+        let mut out = Vec::new();
+        reply.encode(&mut out);
+        if writer.write_all(&out).await.is_err() {
+            return;
+        }
+    }
+}
+
+// drops `key` once expired, as Redis does lazily
+fn live<'a>(
+    entries: &'a mut std::collections::HashMap<Vec<u8>, Entry>,
+    key: &[u8],
+) -> Option<&'a mut Entry> {
+    let expired = entries
+        .get(key)
+        .and_then(|entry| entry.expires_at)
+        .is_some_and(|expires_at| expires_at <= std::time::Instant::now());
+    if expired {
+        entries.remove(key);
+    }
+    entries.get_mut(key)
+}
+
+fn expire_in(
+    entries: &mut std::collections::HashMap<Vec<u8>, Entry>,
+    key: &[u8],
+    ms: i64,
+) -> Reply {
+    match live(entries, key) {
+        Some(_) if ms <= 0 => {
+            entries.remove(key);
+            Reply::Integer(1)
+        }
+        Some(entry) => {
+            entry.expires_at =
+                Some(std::time::Instant::now() + std::time::Duration::from_millis(ms as u64));
+            Reply::Integer(1)
+        }
+        None => Reply::Integer(0),
+    }
+}
+
+fn set_string(
+    entries: &mut std::collections::HashMap<Vec<u8>, Entry>,
+    key: &[u8],
+    value: &[u8],
+    ttl_ms: Option<i64>,
+) {
+    entries.insert(
+        key.to_vec(),
+        Entry {
+            value: Value::String(value.to_vec()),
+            expires_at: ttl_ms
+                .map(|ms| std::time::Instant::now() + std::time::Duration::from_millis(ms as u64)),
+        },
+    );
+}
+
+fn execute(store: &Store, args: &[Vec<u8>]) -> Reply {
+    let mut entries = store.lock().unwrap_or_else(|e| e.into_inner());
+    let Some(name) = args.first() else {
+        return Reply::Error("ERR empty command".into());
+    };
+    let name = text(name).to_uppercase();
+    let args = &args[1..];
+
+    match (name.as_str(), args) {
+        ("PING", _) => Reply::Status("PONG"),
+        ("CLIENT" | "SELECT" | "UNWATCH", _) => Reply::Status("OK"),
+
+        ("GET", [key]) => match live(&mut entries, key) {
+            Some(Entry {
+                value: Value::String(value),
+                ..
+            }) => Reply::Bulk(Some(value.clone())),
+            Some(_) => Reply::wrong_type(),
+            None => Reply::Bulk(None),
+        },
+        ("GETDEL", [key]) => match live(&mut entries, key).map(|entry| entry.value.clone()) {
+            Some(Value::String(value)) => {
+                entries.remove(key.as_slice());
+                Reply::Bulk(Some(value))
+            }
+            Some(_) => Reply::wrong_type(),
+            None => Reply::Bulk(None),
+        },
+        ("SET", [key, value, options @ ..]) => {
+            let mut ttl_ms = None;
+            let mut only_new = false;
+            let mut options = options.iter();
+            while let Some(option) = options.next() {
+                match text(option).to_uppercase().as_str() {
+                    "EX" => {
+                        ttl_ms = options
+                            .next()
+                            .and_then(|s| number::<i64>(s))
+                            .map(|s| s * 1000)
+                    }
+                    "PX" => ttl_ms = options.next().and_then(|ms| number(ms)),
+                    "NX" => only_new = true,
+                    other => return Reply::Error(format!("ERR unsupported SET option {}", other)),
+                }
+            }
+            if only_new && live(&mut entries, key).is_some() {
+                return Reply::Bulk(None);
+            }
+            set_string(&mut entries, key, value, ttl_ms);
+            Reply::Status("OK")
+        }
+        ("SETEX", [key, seconds, value]) => match number::<i64>(seconds) {
+            Some(seconds) => {
+                set_string(&mut entries, key, value, Some(seconds * 1000));
+                Reply::Status("OK")
+            }
+            None => Reply::Error("ERR value is not an integer or out of range".into()),
+        },
+        ("INCR" | "INCRBY", [key, by @ ..]) if by.len() <= 1 => {
+            let Some(by) = by.first().map_or(Some(1), |by| number::<i64>(by)) else {
+                return Reply::Error("ERR value is not an integer or out of range".into());
+            };
+            let current = match live(&mut entries, key) {
+                Some(Entry {
+                    value: Value::String(value),
+                    ..
+                }) => match number::<i64>(value) {
+                    Some(current) => current,
+                    None => return Reply::Error("ERR value is not an integer".into()),
+                },
+                Some(_) => return Reply::wrong_type(),
+                None => 0,
+            };
+            let next = current + by;
+            match live(&mut entries, key) {
+                Some(entry) => entry.value = Value::String(next.to_string().into_bytes()),
+                None => set_string(&mut entries, key, next.to_string().as_bytes(), None),
+            }
+            Reply::Integer(next)
+        }
+        ("DEL", keys) if !keys.is_empty() => Reply::Integer(
+            keys.iter()
+                .filter(|key| {
+                    live(&mut entries, key).is_some() && entries.remove(key.as_slice()).is_some()
+                })
+                .count() as i64,
+        ),
+        ("EXISTS", keys) if !keys.is_empty() => Reply::Integer(
+            keys.iter()
+                .filter(|key| live(&mut entries, key).is_some())
+                .count() as i64,
+        ),
+        ("EXPIRE", [key, seconds]) => match number::<i64>(seconds) {
+            Some(seconds) => expire_in(&mut entries, key, seconds * 1000),
+            None => Reply::Error("ERR value is not an integer or out of range".into()),
+        },
+        ("PEXPIRE", [key, ms]) => match number::<i64>(ms) {
+            Some(ms) => expire_in(&mut entries, key, ms),
+            None => Reply::Error("ERR value is not an integer or out of range".into()),
+        },
+        ("TTL", [key]) => match live(&mut entries, key) {
+            Some(Entry {
+                expires_at: Some(expires_at),
+                ..
+            }) => Reply::Integer(
+                expires_at
+                    .saturating_duration_since(std::time::Instant::now())
+                    .as_millis()
+                    .div_ceil(1000) as i64,
+            ),
+            Some(_) => Reply::Integer(-1),
+            None => Reply::Integer(-2),
+        },
+        ("KEYS", [pattern]) => {
+            let keys: Vec<Vec<u8>> = entries.keys().cloned().collect();
+            Reply::Array(
+                keys.into_iter()
+                    .filter(|key| glob_match(pattern, key) && live(&mut entries, key).is_some())
+                    .map(|key| Reply::Bulk(Some(key)))
+                    .collect(),
+            )
+        }
+
+        ("HSET" | "HMSET", [key, pairs @ ..]) if !pairs.is_empty() && pairs.len() % 2 == 0 => {
+            if live(&mut entries, key).is_none() {
+                entries.insert(
+                    key.clone(),
+                    Entry {
+                        value: Value::Hash(Default::default()),
+                        expires_at: None,
+                    },
+                );
+            }
+            let Some(Entry {
+                value: Value::Hash(hash),
+                ..
+            }) = entries.get_mut(key.as_slice())
+            else {
+                return Reply::wrong_type();
+            };
+            let added = pairs
+                .chunks(2)
+                .filter(|pair| hash.insert(pair[0].clone(), pair[1].clone()).is_none())
+                .count();
+            match name.as_str() {
+                "HMSET" => Reply::Status("OK"),
+                _ => Reply::Integer(added as i64),
+            }
+        }
+        ("HGET", [key, field]) => match live(&mut entries, key) {
+            Some(Entry {
+                value: Value::Hash(hash),
+                ..
+            }) => Reply::Bulk(hash.get(field).cloned()),
+            Some(_) => Reply::wrong_type(),
+            None => Reply::Bulk(None),
+        },
+        ("HGETALL", [key]) => match live(&mut entries, key) {
+            Some(Entry {
+                value: Value::Hash(hash),
+                ..
+            }) => Reply::Array(
+                hash.iter()
+                    .flat_map(|(field, value)| {
+                        [
+                            Reply::Bulk(Some(field.clone())),
+                            Reply::Bulk(Some(value.clone())),
+                        ]
+                    })
+                    .collect(),
+            ),
+            Some(_) => Reply::wrong_type(),
+            None => Reply::Array(Vec::new()),
+        },
+        ("HDEL", [key, fields @ ..]) if !fields.is_empty() => match live(&mut entries, key) {
+            Some(Entry {
+                value: Value::Hash(hash),
+                ..
+            }) => Reply::Integer(
+                fields
+                    .iter()
+                    .filter(|field| hash.remove(field.as_slice()).is_some())
+                    .count() as i64,
+            ),
+            Some(_) => Reply::wrong_type(),
+            None => Reply::Integer(0),
+        },
+
+        ("SADD", [key, members @ ..]) if !members.is_empty() => {
+            if live(&mut entries, key).is_none() {
+                entries.insert(
+                    key.clone(),
+                    Entry {
+                        value: Value::Set(Default::default()),
+                        expires_at: None,
+                    },
+                );
+            }
+            let Some(Entry {
+                value: Value::Set(set),
+                ..
+            }) = entries.get_mut(key.as_slice())
+            else {
+                return Reply::wrong_type();
+            };
+            Reply::Integer(members.iter().filter(|m| set.insert(m.to_vec())).count() as i64)
+        }
+        ("SREM", [key, members @ ..]) if !members.is_empty() => match live(&mut entries, key) {
+            Some(Entry {
+                value: Value::Set(set),
+                ..
+            }) => {
+                Reply::Integer(members.iter().filter(|m| set.remove(m.as_slice())).count() as i64)
+            }
+            Some(_) => Reply::wrong_type(),
+            None => Reply::Integer(0),
+        },
+        ("SMEMBERS", [key]) => match live(&mut entries, key) {
+            Some(Entry {
+                value: Value::Set(set),
+                ..
+            }) => Reply::Array(set.iter().map(|m| Reply::Bulk(Some(m.clone()))).collect()),
+            Some(_) => Reply::wrong_type(),
+            None => Reply::Array(Vec::new()),
+        },
+
+        ("EVAL", [script, _, keys @ ..]) if text(script).contains("ZREMRANGEBYSCORE") => {
+            sliding_window(&mut entries, keys)
+        }
+        ("EVAL", [script, _, key, from, to])
+            if text(script).contains("HSET', KEYS[1], 'state'") =>
+        {
+            promote_session(&mut entries, key, from, to)
+        }
+        ("EVAL", [script, _, key, now]) if text(script).contains("HSET', KEYS[1], 'last_seen'") => {
+            touch_session(&mut entries, key, now)
+        }
+        ("EVAL", _) => Reply::Error("ERR script not supported by the mock redis".into()),
+
+        _ => {
+            // the server only logs redis errors, make gaps in the mock visible
+            eprintln!(
+                "[crimson]: mock redis does not support {} ({} args)",
+                name,
+                args.len()
+            );
+            Reply::Error(format!(
+                "ERR unsupported command {} for the mock redis",
+                name
+            ))
+        }
+    }
+}
+
+// PROMOTE_SESSION_SCRIPT, sets `state` to `to` where it is `from`
+fn promote_session(
+    entries: &mut std::collections::HashMap<Vec<u8>, Entry>,
+    key: &[u8],
+    from: &[u8],
+    to: &[u8],
+) -> Reply {
+    match live(entries, key) {
+        Some(Entry {
+            value: Value::Hash(hash),
+            ..
+        }) if hash.get(b"state".as_slice()).map(Vec::as_slice) == Some(from) => {
+            hash.insert(b"state".to_vec(), to.to_vec());
+            Reply::Integer(1)
+        }
+        Some(Entry {
+            value: Value::Hash(_),
+            ..
+        })
+        | None => Reply::Integer(0),
+        Some(_) => Reply::wrong_type(),
+    }
+}
+
+// TOUCH_SESSION_SCRIPT, the session fields as they were, `last_seen` set to `now`
+fn touch_session(
+    entries: &mut std::collections::HashMap<Vec<u8>, Entry>,
+    key: &[u8],
+    now: &[u8],
+) -> Reply {
+    match live(entries, key) {
+        Some(Entry {
+            value: Value::Hash(hash),
+            ..
+        }) => {
+            let fields = hash
+                .iter()
+                .flat_map(|(field, value)| {
+                    [
+                        Reply::Bulk(Some(field.clone())),
+                        Reply::Bulk(Some(value.clone())),
+                    ]
+                })
+                .collect();
+            hash.insert(b"last_seen".to_vec(), now.to_vec());
+            Reply::Array(fields)
+        }
+        Some(_) => Reply::wrong_type(),
+        None => Reply::Array(Vec::new()),
+    }
+}
+
+// `SLIDING_WINDOW_SCRIPT` of the rate limiter, KEYS[1] & ARGV now, window, limit, member
+fn sliding_window(
+    entries: &mut std::collections::HashMap<Vec<u8>, Entry>,
+    args: &[Vec<u8>],
+) -> Reply {
+    let [key, now, window, limit, member] = args else {
+        return Reply::Error("ERR wrong number of arguments for the sliding window".into());
+    };
+    let (Some(now), Some(window), Some(limit)) = (
+        number::<f64>(now),
+        number::<f64>(window),
+        number::<usize>(limit),
+    ) else {
+        return Reply::Error("ERR value is not a number".into());
+    };
+
+    if live(entries, key).is_none() {
+        entries.insert(
+            key.clone(),
+            Entry {
+                value: Value::SortedSet(Vec::new()),
+                expires_at: None,
+            },
+        );
+    }
+    let Some(entry) = entries.get_mut(key.as_slice()) else {
+        unreachable!()
+    };
+    let Value::SortedSet(hits) = &mut entry.value else {
+        return Reply::wrong_type();
+    };
+    hits.retain(|(score, _)| *score > now - window);
+    if hits.len() < limit {
+        hits.push((now, member.clone()));
+        hits.sort_by(|a, b| a.0.total_cmp(&b.0));
+        entry.expires_at =
+            Some(std::time::Instant::now() + std::time::Duration::from_millis(window as u64));
+        return Reply::Integer(0);
+    }
+    Reply::Integer((hits[0].0 + window - now).max(1.0) as i64)
+}
+
+// ---------------------------------------------------------------------------
+// server state
+// ---------------------------------------------------------------------------
+
+// required settings, tests add what they exercise
+const BASE_CONFIG: &str = r#"
+[database]
+pool_size = 4
+auto_migrate = false
+
+[telemetry]
+loki_url = "http://127.0.0.1:3100"
+trace_level = "info"
+
+[security]
+hash_salt = "test pepper"
+"#;
+
+// tables of `layer` are merged key by key into `base`, other values replace
+fn merge(base: &mut toml::Table, layer: toml::Table) {
+    for (key, value) in layer {
+        match (base.get_mut(&key), value) {
+            (Some(toml::Value::Table(base)), toml::Value::Table(layer)) => merge(base, layer),
+            (_, value) => {
+                base.insert(key, value);
+            }
+        }
+    }
+}
+
+/// keeps every message instead of sending it
+#[derive(Default)]
+pub struct Outbox {
+    pub messages: std::sync::Mutex<Vec<MailMessage>>,
+}
+
+impl Mailer for Outbox {
+    fn send(&self, message: &MailMessage) -> Result<(), MailError> {
+        let mut messages = self.messages.lock().unwrap_or_else(|e| e.into_inner());
+        messages.push(message.clone());
+        Ok(())
+    }
+}
+
+impl Outbox {
+    /// messages sent to `email` so far
+    pub fn sent_to(&self, email: &str) -> Vec<MailMessage> {
+        let messages = self.messages.lock().unwrap_or_else(|e| e.into_inner());
+        messages.iter().filter(|m| m.to == email).cloned().collect()
+    }
+}
+
+/// state the handlers see, mail goes to an `Outbox`
+pub struct TestServer {
+    pub state: actix_web::web::Data<ServerState>,
+    pub outbox: std::sync::Arc<Outbox>,
+}
+
+impl TestServer {
+    /**
+     * # Brief
+     * Builds the server state the way `serve` does, from `config` TOML.
+     *
+     * # Detail
+     * - `config` is laid over `BASE_CONFIG`, the database & redis urls are filled in.
+     * - Background loops (reaper, scheduler) are not started.
+     */
+    pub async fn start(config: &str) -> TestServer {
+        let redis = MockRedis::start().await;
+        let database_url = std::env::var("DATABASE_URL").expect("DATABASE_URL must be set");
+
+        let mut merged: toml::Table = toml::from_str(BASE_CONFIG).unwrap();
+        merge(&mut merged, toml::from_str(config).unwrap());
+        let path = std::env::temp_dir().join(format!("crimson-test-{}.toml", uuid::Uuid::now_v7()));
+        std::fs::write(&path, toml::to_string(&merged).unwrap()).unwrap();
+        let server_config = ServerConfig::load(&ServerArgs {
+            config: Some(path.clone()),
+            database_url: Some(database_url),
+            redis_url: Some(redis.url.clone()),
+            ..Default::default()
+        });
+        let _ = std::fs::remove_file(&path);
+        let server_config = server_config.unwrap();
+
+        let central_db_pool = sqlx::postgres::PgPoolOptions::new()
+            .max_connections(4)
+            .connect(&server_config.database.url)
+            .await
+            .unwrap();
+        let redis_pool = deadpool_redis::Config::from_url(&redis.url)
+            .create_pool(Some(deadpool_redis::Runtime::Tokio1))
+            .unwrap();
+
+        let worker_registry =
+            WorkerRegistry::new(redis_pool.clone(), server_config.compute.heartbeat_timeout);
+        let (black_channel_client, _) = BlackChannelClient::new(ClientOptions::default());
+        let job_scheduler = JobScheduler::new(
+            central_db_pool.clone(),
+            worker_registry.clone(),
+            black_channel_client,
+            compute_scheduler::placement_policy(&server_config.scheduler.policy).unwrap(),
+            server_config.scheduler.batch_size,
+        );
+
+        let outbox = std::sync::Arc::new(Outbox::default());
+        let state = ServerState {
+            central_db_pool,
+            redis_pool: redis_pool.clone(),
+            worker_registry,
+            job_scheduler,
+            worker_token: server_config.compute.worker_token.clone(),
+            // a registry per pool, every test builds its own
+            hashing: HashingPool::new(
+                Passwords::new(&server_config.security, &server_config.password),
+                server_config.password.hashing_workers,
+                server_config.password.hashing_queue,
+                &prometheus::Registry::new(),
+            )
+            .unwrap(),
+            redis_expire_time: server_config.server.session_ttl,
+            mailer: outbox.clone(),
+            public_url: server_config.mail.public_url.clone(),
+            password_reset_ttl: server_config.security.password_reset_ttl,
+            email_verification_ttl: server_config.security.email_verification_ttl,
+            rate_limiter: RateLimiter::new(redis_pool, server_config.rate_limit.clone()),
+            totp_key: server_config.security.totp_key,
+            totp_issuer: server_config.security.totp_issuer.clone(),
+            webauthn: server_config.webauthn.clone(),
+            oidc: OidcClient::new(server_config.oidc.clone()).unwrap(),
+            oauth_signer: server_config
+                .oauth
+                .signing_key
+                .as_ref()
+                .and_then(TokenSigner::new),
+            oauth: server_config.oauth.clone(),
+            rbac: server_config.rbac.clone(),
+            orgs: server_config.orgs.clone(),
+            password: server_config.password.clone(),
+            breached_passwords: server_config
+                .password
+                .breached_list
+                .as_deref()
+                .map(|path| std::sync::Arc::new(BreachedPasswords::load(path).unwrap())),
+        };
+
+        TestServer {
+            state: actix_web::web::Data::new(state),
+            outbox,
+        }
+    }
+
+    pub fn db(&self) -> &sqlx::PgPool {
+        &self.state.central_db_pool
+    }
+
+    /// id of a fresh signed in session of `user_id`, as a login without 2FA leaves it
+    pub async fn sign_in(&self, user_id: uuid::Uuid) -> String {
+        let mut redis_connection = self.state.redis_pool.get().await.unwrap();
+        server_sessions::rotate_session(
+            &mut redis_connection,
+            None,
+            &[
+                ("state", SessionUserState::Registered.as_u32().to_string()),
+                ("user_id", user_id.to_string()),
+            ],
+            self.state.redis_expire_time,
+        )
+        .await
+        .unwrap()
+    }
+}
+
+/// fresh address, unique across tests & runs
+pub fn unique_email(prefix: &str) -> String {
+    format!("{}-{}@example.com", prefix, uuid::Uuid::now_v7().simple())
+}
+
+/**
+ * # Brief
+ * Inserts a user, with a password hash when `password` is given.
+ *
+ * # Detail
+ * - Cheap Argon2id costs, a login re-hashes them with the configured ones.
+ * - Removed again by `delete_user`, or with the test database.
+ */
+pub async fn insert_user(
+    pool: &sqlx::PgPool,
+    email: &str,
+    password: Option<&str>,
+    verified: bool,
+) -> uuid::Uuid {
+    let user_id = uuid::Uuid::now_v7();
+    let hash = password.map(|password| {
+        let salt = SaltString::generate(&mut argon2::password_hash::rand_core::OsRng);
+        argon2::Argon2::new(
+            argon2::Algorithm::Argon2id,
+            argon2::Version::V0x13,
+            argon2::Params::new(64, 1, 1, None).unwrap(),
+        )
+        .hash_password(password.as_bytes(), &salt)
+        .unwrap()
+        .to_string()
+    });
+    sqlx::query(
+        r#"
+        INSERT INTO users (user_id, username, password, email, created_at, email_verified_at)
+        VALUES ($1, $2, $3, $4, NOW(), CASE WHEN $5 THEN now() END)
+        "#,
+    )
+    .bind(user_id)
+    .bind(format!("user_{}", user_id.simple()))
+    .bind(hash)
+    .bind(email)
+    .bind(verified)
+    .execute(pool)
+    .await
+    .unwrap();
+    user_id
+}
+
+pub async fn delete_user(pool: &sqlx::PgPool, user_id: uuid::Uuid) {
+    sqlx::query("DELETE FROM users WHERE user_id = $1")
+        .bind(user_id)
+        .execute(pool)
+        .await
+        .unwrap();
+}
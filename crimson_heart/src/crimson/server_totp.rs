@@ -0,0 +1,322 @@
+use aes_gcm::aead::{Aead, KeyInit, Payload};
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+use hmac::Mac;
+
+/*
+ * RFC 6238 TOTP, HMAC-SHA1 over 30 second steps, 6 digits.
+ *
+ * Secrets are sealed with AES-256-GCM before they reach the database, the
+ * user id is bound as associated data so a sealed secret can't be moved to
+ * another account. Recovery codes are random, only their SHA-256 is stored.
+ */
+
+const SECRET_BYTES: usize = 20;
+const NONCE_BYTES: usize = 12;
+const STEP_SECONDS: i64 = 30;
+const DIGITS: u32 = 6;
+// steps accepted either side of now, covers clock drift
+const SKEW_STEPS: i64 = 1;
+const RECOVERY_CODES: usize = 10;
+const RECOVERY_CODE_BYTES: usize = 10;
+
+#[derive(Debug)]
+pub enum TotpError {
+    Seal,
+    Open,
+    Encoding(hex::FromHexError),
+}
+
+impl std::fmt::Display for TotpError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TotpError::Seal => write!(f, "failed to seal totp secret"),
+            TotpError::Open => write!(f, "failed to open totp secret (wrong key or tampered)"),
+            TotpError::Encoding(e) => write!(f, "sealed totp secret is not hex ({})", e),
+        }
+    }
+}
+
+impl std::error::Error for TotpError {}
+
+impl From<hex::FromHexError> for TotpError {
+    fn from(e: hex::FromHexError) -> Self {
+        TotpError::Encoding(e)
+    }
+}
+
+pub fn new_secret() -> Vec<u8> {
+    let mut secret = vec![0u8; SECRET_BYTES];
+    OsRng.fill_bytes(&mut secret);
+    secret
+}
+
+/// base32 without padding, what authenticator apps expect
+pub fn encode_secret(secret: &[u8]) -> String {
+    data_encoding::BASE32_NOPAD.encode(secret)
+}
+
+/**
+ * # Brief
+ * `otpauth://totp/...` URI, usually rendered as a QR code for the app.
+ */
+pub fn otpauth_uri(issuer: &str, account: &str, secret: &[u8]) -> String {
+    let mut uri = url::Url::parse("otpauth://totp/").expect("static uri is valid");
+    uri.set_path(&format!("{}:{}", issuer, account));
+    uri.query_pairs_mut()
+        .append_pair("secret", &encode_secret(secret))
+        .append_pair("issuer", issuer)
+        .append_pair("algorithm", "SHA1")
+        .append_pair("digits", &DIGITS.to_string())
+        .append_pair("period", &STEP_SECONDS.to_string());
+    uri.to_string()
+}
+
+// RFC 4226 HOTP with dynamic truncation
+fn code_at(secret: &[u8], step: i64) -> u32 {
+    let mut mac = <hmac::Hmac<sha1::Sha1> as Mac>::new_from_slice(secret)
+        .expect("hmac accepts keys of any length");
+    mac.update(&step.to_be_bytes());
+    let digest = mac.finalize().into_bytes();
+    let offset = (digest[digest.len() - 1] & 0x0f) as usize;
+    let binary = u32::from_be_bytes([
+        digest[offset] & 0x7f,
+        digest[offset + 1],
+        digest[offset + 2],
+        digest[offset + 3],
+    ]);
+    binary % 10u32.pow(DIGITS)
+}
+
+/// the code an authenticator app shows at `now`
+#[cfg(test)]
+pub fn code(secret: &[u8], now: i64) -> String {
+    format!(
+        "{:0width$}",
+        code_at(secret, now.div_euclid(STEP_SECONDS)),
+        width = DIGITS as usize
+    )
+}
+
+/**
+ * # Brief
+ * Checks `code` against the steps around `now`.
+ *
+ * # Detail
+ * - Returns the matching step, which the caller stores as `totp_last_step`.
+ * - Steps at or before `last_step` are refused, a code works only once.
+ */
+pub fn verify(secret: &[u8], code: &str, now: i64, last_step: Option<i64>) -> Option<i64> {
+    let code = code.trim();
+    if code.len() != DIGITS as usize || !code.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    let code: u32 = code.parse().ok()?;
+    let current = now.div_euclid(STEP_SECONDS);
+
+    (current - SKEW_STEPS..=current + SKEW_STEPS)
+        .filter(|step| last_step.is_none_or(|last_step| *step > last_step))
+        .find(|step| code_at(secret, *step) == code)
+}
+
+fn cipher(key: &[u8; 32]) -> aes_gcm::Aes256Gcm {
+    aes_gcm::Aes256Gcm::new(key.into())
+}
+
+/// hex of nonce || ciphertext, bound to `user_id`
+pub fn seal(key: &[u8; 32], user_id: &uuid::Uuid, secret: &[u8]) -> Result<String, TotpError> {
+    let mut nonce = [0u8; NONCE_BYTES];
+    OsRng.fill_bytes(&mut nonce);
+    let ciphertext = cipher(key)
+        .encrypt(
+            aes_gcm::Nonce::from_slice(&nonce),
+            Payload {
+                msg: secret,
+                aad: user_id.as_bytes(),
+            },
+        )
+        .map_err(|_| TotpError::Seal)?;
+
+    let mut sealed = nonce.to_vec();
+    sealed.extend(ciphertext);
+    Ok(hex::encode(sealed))
+}
+
+pub fn open(key: &[u8; 32], user_id: &uuid::Uuid, sealed: &str) -> Result<Vec<u8>, TotpError> {
+    let sealed = hex::decode(sealed)?;
+    if sealed.len() <= NONCE_BYTES {
+        return Err(TotpError::Open);
+    }
+    let (nonce, ciphertext) = sealed.split_at(NONCE_BYTES);
+    cipher(key)
+        .decrypt(
+            aes_gcm::Nonce::from_slice(nonce),
+            Payload {
+                msg: ciphertext,
+                aad: user_id.as_bytes(),
+            },
+        )
+        .map_err(|_| TotpError::Open)
+}
+
+/// `xxxx-xxxx-xxxx-xxxx`, shown to the user once
+pub fn new_recovery_codes() -> Vec<String> {
+    (0..RECOVERY_CODES)
+        .map(|_| {
+            let mut code = [0u8; RECOVERY_CODE_BYTES];
+            OsRng.fill_bytes(&mut code);
+            let encoded = data_encoding::BASE32_NOPAD.encode(&code).to_lowercase();
+            encoded
+                .as_bytes()
+                .chunks(4)
+                .map(|chunk| String::from_utf8_lossy(chunk).into_owned())
+                .collect::<Vec<_>>()
+                .join("-")
+        })
+        .collect()
+}
+
+/// dashes & case are ignored, users retype these by hand
+pub fn hash_recovery_code(code: &str) -> String {
+    use sha2::Digest;
+    let normalized: String = code
+        .chars()
+        .filter(|c| c.is_ascii_alphanumeric())
+        .map(|c| c.to_ascii_lowercase())
+        .collect();
+    hex::encode(sha2::Sha256::digest(normalized.as_bytes()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // RFC 6238 appendix B, SHA1 seed
+    const RFC_SECRET: &[u8] = b"12345678901234567890";
+    const KEY: [u8; 32] = [0x42; 32];
+
+    #[test]
+    fn matches_rfc_6238_vectors() {
+        // the appendix lists 8 digits, we keep the last 6
+        for (time, expected) in [
+            (59, 94287082),
+            (1111111109, 7081804),
+            (1111111111, 14050471),
+            (1234567890, 89005924),
+            (2000000000, 69279037),
+            (20000000000, 65353130),
+        ] {
+            assert_eq!(
+                code_at(RFC_SECRET, time / STEP_SECONDS),
+                expected % 1_000_000,
+                "T = {}",
+                time
+            );
+        }
+    }
+
+    #[test]
+    fn accepts_one_step_of_skew() {
+        let now = 1_700_000_000;
+        let current = now / STEP_SECONDS;
+        for offset in [-1, 0, 1] {
+            let code = code(RFC_SECRET, now + offset * STEP_SECONDS);
+            assert_eq!(verify(RFC_SECRET, &code, now, None), Some(current + offset));
+        }
+        for offset in [-2, 2] {
+            let code = code(RFC_SECRET, now + offset * STEP_SECONDS);
+            assert_eq!(verify(RFC_SECRET, &code, now, None), None);
+        }
+    }
+
+    #[test]
+    fn refuses_malformed_codes() {
+        let now = 1_700_000_000;
+        let code = code(RFC_SECRET, now);
+        assert_eq!(
+            verify(RFC_SECRET, &format!(" {} ", code), now, None),
+            Some(now / STEP_SECONDS)
+        );
+        for malformed in ["", "12345", "1234567", "12 456", "+12345", "abcdef"] {
+            assert_eq!(verify(RFC_SECRET, malformed, now, None), None);
+        }
+        assert_eq!(verify(b"another secret", &code, now, None), None);
+    }
+
+    #[test]
+    fn refuses_steps_up_to_the_last_one() {
+        let now = 1_700_000_000;
+        let current = now / STEP_SECONDS;
+        let code = code(RFC_SECRET, now);
+        assert_eq!(
+            verify(RFC_SECRET, &code, now, Some(current - 1)),
+            Some(current)
+        );
+        // replayed, or an older code after a newer one was used
+        assert_eq!(verify(RFC_SECRET, &code, now, Some(current)), None);
+        assert_eq!(verify(RFC_SECRET, &code, now, Some(current + 1)), None);
+    }
+
+    #[test]
+    fn sealed_secrets_open_for_their_user_only() {
+        let user_id = uuid::Uuid::now_v7();
+        let secret = new_secret();
+        let sealed = seal(&KEY, &user_id, &secret).unwrap();
+        assert_eq!(open(&KEY, &user_id, &sealed).unwrap(), secret);
+        // a fresh nonce every time
+        assert_ne!(seal(&KEY, &user_id, &secret).unwrap(), sealed);
+
+        assert!(matches!(
+            open(&KEY, &uuid::Uuid::now_v7(), &sealed),
+            Err(TotpError::Open)
+        ));
+        assert!(matches!(
+            open(&[0x43; 32], &user_id, &sealed),
+            Err(TotpError::Open)
+        ));
+        let mut tampered = hex::decode(&sealed).unwrap();
+        *tampered.last_mut().unwrap() ^= 1;
+        assert!(matches!(
+            open(&KEY, &user_id, &hex::encode(tampered)),
+            Err(TotpError::Open)
+        ));
+        assert!(matches!(
+            open(&KEY, &user_id, &sealed[..NONCE_BYTES * 2]),
+            Err(TotpError::Open)
+        ));
+        assert!(matches!(
+            open(&KEY, &user_id, "not hex"),
+            Err(TotpError::Encoding(_))
+        ));
+    }
+
+    #[test]
+    fn recovery_codes_are_distinct_and_grouped() {
+        let codes = new_recovery_codes();
+        assert_eq!(codes.len(), RECOVERY_CODES);
+        let distinct: std::collections::HashSet<&String> = codes.iter().collect();
+        assert_eq!(distinct.len(), RECOVERY_CODES);
+        for code in &codes {
+            let groups: Vec<&str> = code.split('-').collect();
+            assert_eq!(groups.len(), 4, "{}", code);
+            assert!(groups.iter().all(|group| group.len() == 4));
+            assert!(
+                code.chars()
+                    .all(|c| c == '-' || c.is_ascii_lowercase() || c.is_ascii_digit())
+            );
+        }
+    }
+
+    #[test]
+    fn recovery_codes_ignore_dashes_and_case() {
+        let hash = hash_recovery_code("abcd-efgh-ijkl-mnop");
+        for retyped in [
+            "abcdefghijklmnop",
+            "ABCD-EFGH-IJKL-MNOP",
+            " abcd efgh ijkl mnop ",
+            "abcd--efgh-ijkl-mnop",
+        ] {
+            assert_eq!(hash_recovery_code(retyped), hash, "{}", retyped);
+        }
+        assert_ne!(hash_recovery_code("abcd-efgh-ijkl-mnoq"), hash);
+    }
+}
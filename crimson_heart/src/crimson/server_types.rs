@@ -1,11 +1,44 @@
+use super::compute_registry::WorkerRegistry;
+use super::compute_scheduler::JobScheduler;
+use super::server_breached::BreachedPasswords;
+use super::server_config::{
+    OauthSection, OrgsSection, PasswordSection, RbacSection, WebauthnSection,
+};
+use super::server_hashing::HashingPool;
+use super::server_mailer::Mailer;
+use super::server_oauth::TokenSigner;
+use super::server_oidc::OidcClient;
+use super::server_rate_limit::RateLimiter;
 use sqlx::{Pool, Postgres};
 
 pub struct ServerState {
     pub central_db_pool: Pool<Postgres>,
     pub redis_pool: deadpool_redis::Pool,
-    pub local_compute_ids: Vec<String>,
-    pub crimson_hash_salt: String,
+    pub worker_registry: WorkerRegistry,
+    pub job_scheduler: JobScheduler,
+    pub worker_token: Option<String>,
+    /// hashes & verifies passwords with the pepper of `security.hash_salt`, off the workers
+    pub hashing: HashingPool,
     pub redis_expire_time: i64,
+    pub mailer: std::sync::Arc<dyn Mailer>,
+    /// base of links sent in emails
+    pub public_url: url::Url,
+    pub password_reset_ttl: i64,
+    pub email_verification_ttl: i64,
+    pub rate_limiter: RateLimiter,
+    /// seals TOTP secrets, 2FA enrollment is disabled without it
+    pub totp_key: Option<[u8; 32]>,
+    pub totp_issuer: String,
+    pub webauthn: WebauthnSection,
+    pub oidc: OidcClient,
+    /// signs tokens of the OpenID provider, disabled without it
+    pub oauth_signer: Option<TokenSigner>,
+    pub oauth: OauthSection,
+    pub rbac: RbacSection,
+    pub orgs: OrgsSection,
+    pub password: PasswordSection,
+    /// new passwords found in `password.breached_list` are refused, no check without it
+    pub breached_passwords: Option<std::sync::Arc<BreachedPasswords>>,
 }
 
 #[repr(u32)]
@@ -13,6 +46,10 @@ pub struct ServerState {
 pub enum SessionUserState {
     Anonymous  = 1,
     Registered = 2,
+    /// signed in, email address not confirmed yet
+    PendingVerification = 3,
+    /// password checked, TOTP code still outstanding
+    TwoFactorPending = 4,
 }
 
 impl SessionUserState {
@@ -26,6 +63,8 @@ impl SessionUserState {
         match n {
             1 => Some(SessionUserState::Anonymous),
             2 => Some(SessionUserState::Registered),
+            3 => Some(SessionUserState::PendingVerification),
+            4 => Some(SessionUserState::TwoFactorPending),
             _ => None,
         }
     }
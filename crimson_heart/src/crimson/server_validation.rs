@@ -0,0 +1,383 @@
+use super::server_breached::BreachedPasswords;
+use super::server_config::PasswordSection;
+use super::server_errors::{ApiError, FieldError};
+use std::borrow::Cow;
+
+/*
+ * Validation of request payloads.
+ *
+ * Payloads implement `Validate`, which runs one rule per field & returns the
+ * normalized values. Every rejected field is collected, the caller gets them
+ * all at once as `422 invalid_fields`.
+ *
+ * Rules return the value to store, e.g. the case-folded email or the parsed
+ * date, handlers never touch the raw strings again.
+ */
+
+const MAX_EMAIL_LENGTH: usize = 254;
+const MAX_EMAIL_LOCAL_LENGTH: usize = 64;
+const MAX_DOMAIN_LABEL_LENGTH: usize = 63;
+const MIN_USERNAME_LENGTH: usize = 3;
+const MAX_USERNAME_LENGTH: usize = 32;
+const BIRTH_DATE_FORMAT: &str = "%Y-%m-%d";
+// nobody alive was born earlier
+const MIN_BIRTH_YEAR: i32 = 1900;
+// longer passwords only make hashing slower, bounds `password.max_length`
+pub const MAX_PASSWORD_LENGTH: usize = 1024;
+
+pub type RuleResult<T> = Result<T, Cow<'static, str>>;
+
+/**
+ * # Brief
+ * A request payload checked field by field.
+ *
+ * # Detail
+ * - `Valid` holds the normalized values handlers work with.
+ * - Fails with `ApiError::InvalidFields`, one entry per rejected field.
+ */
+pub trait Validate {
+    type Valid;
+
+    fn validate(&self, policy: &PasswordSection) -> Result<Self::Valid, ApiError>;
+}
+
+/// rejected fields of one payload
+#[derive(Debug, Default)]
+pub struct Fields {
+    errors: Vec<FieldError>,
+}
+
+impl Fields {
+    /// keeps the value of a passing rule, records the message of a failing one
+    pub fn check<T>(&mut self, field: &'static str, result: RuleResult<T>) -> Option<T> {
+        match result {
+            Ok(value) => Some(value),
+            Err(message) => {
+                self.errors.push(FieldError::new(field, message));
+                None
+            }
+        }
+    }
+
+    // values are None only after recording an error
+    pub fn finish<T>(self, valid: Option<T>) -> Result<T, ApiError> {
+        match valid {
+            Some(valid) if self.errors.is_empty() => Ok(valid),
+            _ => Err(ApiError::InvalidFields(self.errors)),
+        }
+    }
+}
+
+/**
+ * # Brief
+ * Checks an email address & case-folds it.
+ *
+ * # Detail
+ * - Surrounding whitespace is trimmed, the whole address is lowercased.
+ * - Syntax is the practical subset: `local@domain.tld`, no quoting nor IP literals.
+ */
+pub fn email(value: &str) -> RuleResult<String> {
+    let email = value.trim().to_lowercase();
+    if email.is_empty() {
+        return Err("is required".into());
+    }
+    if email.len() > MAX_EMAIL_LENGTH {
+        return Err(format!("must be at most {} characters", MAX_EMAIL_LENGTH).into());
+    }
+    let Some((local, domain)) = email.split_once('@') else {
+        return Err("is not a valid email address".into());
+    };
+
+    let local_ok = !local.is_empty()
+        && local.len() <= MAX_EMAIL_LOCAL_LENGTH
+        && !local.starts_with('.')
+        && !local.ends_with('.')
+        && !local.contains("..")
+        && local
+            .chars()
+            .all(|c| c.is_alphanumeric() || "!#$%&'*+-/=?^_`{|}~.".contains(c));
+    let labels: Vec<&str> = domain.split('.').collect();
+    let domain_ok = labels.len() >= 2
+        && labels.iter().all(|label| {
+            !label.is_empty()
+                && label.len() <= MAX_DOMAIN_LABEL_LENGTH
+                && !label.starts_with('-')
+                && !label.ends_with('-')
+                && label.chars().all(|c| c.is_alphanumeric() || c == '-')
+        });
+    if !local_ok || !domain_ok {
+        return Err("is not a valid email address".into());
+    }
+    Ok(email)
+}
+
+/// letters, digits, `_`, `-` & `.`, starting with a letter or digit
+pub fn username(value: &str) -> RuleResult<String> {
+    let username = value.trim();
+    let length = username.chars().count();
+    if !(MIN_USERNAME_LENGTH..=MAX_USERNAME_LENGTH).contains(&length) {
+        return Err(format!(
+            "must be {} to {} characters",
+            MIN_USERNAME_LENGTH, MAX_USERNAME_LENGTH
+        )
+        .into());
+    }
+    if !username.starts_with(|c: char| c.is_ascii_alphanumeric()) {
+        return Err("must start with a letter or a digit".into());
+    }
+    if !username
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '.'))
+    {
+        return Err("may only contain letters, digits, `_`, `-` and `.`".into());
+    }
+    Ok(username.to_string())
+}
+
+/// `YYYY-MM-DD`, not in the future nor before `MIN_BIRTH_YEAR`
+pub fn birth_date(value: &str) -> RuleResult<chrono::NaiveDate> {
+    let Ok(date) = chrono::NaiveDate::parse_from_str(value.trim(), BIRTH_DATE_FORMAT) else {
+        return Err("must be a date like 1990-01-31".into());
+    };
+    if date > chrono::Utc::now().date_naive() {
+        return Err("is in the future".into());
+    }
+    if chrono::Datelike::year(&date) < MIN_BIRTH_YEAR {
+        return Err(format!("must be in {} or later", MIN_BIRTH_YEAR).into());
+    }
+    Ok(date)
+}
+
+/**
+ * # Brief
+ * Checks a new password against `[password]`.
+ *
+ * # Detail
+ * - Lengths count characters, not bytes.
+ * - The password is kept as is, whitespace included.
+ */
+pub fn password(value: &str, policy: &PasswordSection) -> RuleResult<String> {
+    let length = value.chars().count();
+    if length < policy.min_length {
+        return Err(format!("must be at least {} characters", policy.min_length).into());
+    }
+    if length > policy.max_length {
+        return Err(format!("must be at most {} characters", policy.max_length).into());
+    }
+
+    let mut missing = Vec::new();
+    if policy.require_lowercase && !value.chars().any(char::is_lowercase) {
+        missing.push("a lowercase letter");
+    }
+    if policy.require_uppercase && !value.chars().any(char::is_uppercase) {
+        missing.push("an uppercase letter");
+    }
+    if policy.require_digit && !value.chars().any(|c| c.is_ascii_digit()) {
+        missing.push("a digit");
+    }
+    if policy.require_symbol && !value.chars().any(|c| !c.is_alphanumeric()) {
+        missing.push("a symbol");
+    }
+    if !missing.is_empty() {
+        return Err(format!("must contain {}", missing.join(", ")).into());
+    }
+    Ok(value.to_string())
+}
+
+/// a new password that is not in the breached corpus, checked once the other rules pass
+pub fn not_breached(value: &str, breached: Option<&BreachedPasswords>) -> RuleResult<()> {
+    match breached {
+        Some(breached) if breached.contains(value) => {
+            Err("is a known breached or common password, choose another one".into())
+        }
+        _ => Ok(()),
+    }
+}
+
+/// an existing password, set under any earlier policy, only bounded so hashing stays cheap
+pub fn current_password(value: &str) -> RuleResult<String> {
+    if value.is_empty() {
+        return Err("is required".into());
+    }
+    if value.chars().count() > MAX_PASSWORD_LENGTH {
+        return Err(format!("must be at most {} characters", MAX_PASSWORD_LENGTH).into());
+    }
+    Ok(value.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy() -> PasswordSection {
+        PasswordSection {
+            min_length: 8,
+            max_length: 16,
+            require_lowercase: true,
+            require_uppercase: true,
+            require_digit: true,
+            require_symbol: true,
+            breached_list: None,
+            argon2: argon2::Params::default(),
+            hashing_workers: 1,
+            hashing_queue: 1,
+        }
+    }
+
+    #[test]
+    fn email_is_trimmed_and_case_folded() {
+        assert_eq!(
+            email("  Alice.Smith@Example.COM ").unwrap(),
+            "alice.smith@example.com"
+        );
+        assert_eq!(
+            email("a+tag@sub.example.io").unwrap(),
+            "a+tag@sub.example.io"
+        );
+    }
+
+    #[test]
+    fn email_rejects_bad_syntax() {
+        for value in [
+            "",
+            "   ",
+            "alice",
+            "alice@",
+            "@example.com",
+            "alice@example",
+            "alice@@example.com",
+            ".alice@example.com",
+            "alice.@example.com",
+            "al..ice@example.com",
+            "alice@-example.com",
+            "alice@example-.com",
+            "alice@exa_mple.com",
+            "alice@example..com",
+            "ali ce@example.com",
+        ] {
+            assert!(email(value).is_err(), "accepted `{}`", value);
+        }
+    }
+
+    #[test]
+    fn email_bounds_lengths() {
+        let local = "a".repeat(MAX_EMAIL_LOCAL_LENGTH);
+        assert!(email(&format!("{}@example.com", local)).is_ok());
+        assert!(email(&format!("{}a@example.com", local)).is_err());
+
+        let label = "a".repeat(MAX_DOMAIN_LABEL_LENGTH);
+        assert!(email(&format!("alice@{}.com", label)).is_ok());
+        assert!(email(&format!("alice@{}a.com", label)).is_err());
+
+        let domain = vec!["a".repeat(60); 5].join(".");
+        assert!(email(&format!("alice@{}.com", domain)).is_err());
+    }
+
+    #[test]
+    fn username_rules() {
+        assert_eq!(username(" alice_01 ").unwrap(), "alice_01");
+        assert!(username("a.b-c").is_ok());
+        assert!(username(&"a".repeat(MAX_USERNAME_LENGTH)).is_ok());
+
+        assert!(username("ab").is_err());
+        assert!(username(&"a".repeat(MAX_USERNAME_LENGTH + 1)).is_err());
+        assert!(username("_alice").is_err());
+        assert!(username(".alice").is_err());
+        assert!(username("ali ce").is_err());
+        assert!(username("alice!").is_err());
+        assert!(username("élodie").is_err());
+    }
+
+    #[test]
+    fn birth_date_bounds() {
+        assert_eq!(
+            birth_date(" 1990-01-31 ").unwrap(),
+            chrono::NaiveDate::from_ymd_opt(1990, 1, 31).unwrap()
+        );
+        assert!(birth_date(&format!("{}-01-01", MIN_BIRTH_YEAR)).is_ok());
+        assert!(birth_date(&format!("{}-12-31", MIN_BIRTH_YEAR - 1)).is_err());
+
+        let today = chrono::Utc::now().date_naive();
+        assert!(birth_date(&today.format(BIRTH_DATE_FORMAT).to_string()).is_ok());
+        let tomorrow = today.succ_opt().unwrap();
+        assert_eq!(
+            birth_date(&tomorrow.format(BIRTH_DATE_FORMAT).to_string()),
+            Err("is in the future".into())
+        );
+
+        for value in ["", "31/01/1990", "1990-02-30", "1990-1-1x", "yesterday"] {
+            assert!(birth_date(value).is_err(), "accepted `{}`", value);
+        }
+    }
+
+    #[test]
+    fn password_lengths_count_characters() {
+        let policy = PasswordSection {
+            require_lowercase: false,
+            require_uppercase: false,
+            require_digit: false,
+            require_symbol: false,
+            ..policy()
+        };
+        assert!(password("1234567", &policy).is_err());
+        assert!(password("12345678", &policy).is_ok());
+        assert!(password(&"x".repeat(16), &policy).is_ok());
+        assert!(password(&"x".repeat(17), &policy).is_err());
+        // 8 characters, 16 bytes
+        assert!(password("éééééééé", &policy).is_ok());
+        // whitespace is kept
+        assert_eq!(password(" 1234567 ", &policy).unwrap(), " 1234567 ");
+    }
+
+    #[test]
+    fn password_requirements() {
+        let policy = policy();
+        assert!(password("Abcdef1!", &policy).is_ok());
+        assert_eq!(
+            password("abcdefgh", &policy),
+            Err("must contain an uppercase letter, a digit, a symbol".into())
+        );
+        assert_eq!(
+            password("ABCDEF1!", &policy),
+            Err("must contain a lowercase letter".into())
+        );
+        assert_eq!(
+            password("Abcdefg!", &policy),
+            Err("must contain a digit".into())
+        );
+        assert_eq!(
+            password("Abcdefg1", &policy),
+            Err("must contain a symbol".into())
+        );
+    }
+
+    #[test]
+    fn current_password_is_only_bounded() {
+        assert!(current_password("").is_err());
+        assert!(current_password("x").is_ok());
+        assert!(current_password(&"x".repeat(MAX_PASSWORD_LENGTH)).is_ok());
+        assert!(current_password(&"x".repeat(MAX_PASSWORD_LENGTH + 1)).is_err());
+    }
+
+    #[test]
+    fn not_breached_without_a_list() {
+        assert!(not_breached("password", None).is_ok());
+    }
+
+    #[test]
+    fn fields_collects_every_error() {
+        let mut fields = Fields::default();
+        let valid_email = fields.check("email", email("alice"));
+        let valid_username = fields.check("username", username("alice"));
+        let valid_birth_date = fields.check("birth_date", birth_date("tomorrow"));
+        let valid = valid_email.zip(valid_username).zip(valid_birth_date);
+        match fields.finish(valid) {
+            Err(ApiError::InvalidFields(errors)) => assert_eq!(errors.len(), 2),
+            other => panic!("expected invalid fields, got {:?}", other.map(|_| ())),
+        }
+
+        let mut fields = Fields::default();
+        let valid_username = fields.check("username", username("alice"));
+        assert_eq!(fields.finish(valid_username).unwrap(), "alice");
+    }
+}
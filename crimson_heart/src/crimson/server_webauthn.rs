@@ -0,0 +1,628 @@
+use super::server_config::WebauthnSection;
+use super::server_jws::base64url_encode;
+
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+use p256::ecdsa::signature::Verifier;
+use sha2::Digest;
+
+/*
+ * WebAuthn relying party checks, ES256 (P-256) credentials only.
+ *
+ * Registration asks for `none` attestation, the attestation statement is not
+ * verified, only the authenticator data it carries. Binary fields travel as
+ * base64url without padding, as browsers encode them.
+ */
+
+/// COSE algorithm id of ECDSA P-256 with SHA-256
+pub const ES256: i64 = -7;
+const CHALLENGE_BYTES: usize = 32;
+const RP_ID_HASH_BYTES: usize = 32;
+// rp id hash, flags, sign count
+const AUTHENTICATOR_DATA_MIN_BYTES: usize = RP_ID_HASH_BYTES + 1 + 4;
+const AAGUID_BYTES: usize = 16;
+const FLAG_USER_PRESENT: u8 = 0x01;
+const FLAG_USER_VERIFIED: u8 = 0x04;
+const FLAG_ATTESTED_CREDENTIAL: u8 = 0x40;
+
+// COSE_Key labels & values, RFC 9052 / 9053
+const COSE_KTY: i64 = 1;
+const COSE_ALG: i64 = 3;
+const COSE_EC2_CRV: i64 = -1;
+const COSE_EC2_X: i64 = -2;
+const COSE_EC2_Y: i64 = -3;
+const COSE_KTY_EC2: i64 = 2;
+const COSE_CRV_P256: i64 = 1;
+
+#[derive(Debug)]
+pub enum WebauthnError {
+    /// the response doesn't verify, the reason is for logs only
+    Invalid(&'static str),
+}
+
+impl std::fmt::Display for WebauthnError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            WebauthnError::Invalid(reason) => write!(f, "invalid webauthn response ({})", reason),
+        }
+    }
+}
+
+impl std::error::Error for WebauthnError {}
+
+fn invalid<T>(reason: &'static str) -> Result<T, WebauthnError> {
+    Err(WebauthnError::Invalid(reason))
+}
+
+/// browsers may pad, the padding is dropped before decoding
+pub fn base64url_decode(encoded: &str) -> Result<Vec<u8>, WebauthnError> {
+    super::server_jws::base64url_decode(encoded.trim_end_matches('='))
+        .map_err(WebauthnError::Invalid)
+}
+
+pub fn new_challenge() -> String {
+    let mut challenge = [0u8; CHALLENGE_BYTES];
+    OsRng.fill_bytes(&mut challenge);
+    base64url_encode(&challenge)
+}
+
+#[derive(serde::Deserialize)]
+struct ClientData {
+    #[serde(rename = "type")]
+    ceremony: String,
+    challenge: String,
+    origin: String,
+    #[serde(rename = "crossOrigin", default)]
+    cross_origin: bool,
+}
+
+/**
+ * # Brief
+ * Checks `clientDataJSON` was made for this ceremony, challenge & origin.
+ */
+fn verify_client_data(
+    webauthn: &WebauthnSection,
+    client_data_json: &[u8],
+    ceremony: &str,
+    challenge: &str,
+) -> Result<(), WebauthnError> {
+    let client_data: ClientData =
+        serde_json::from_slice(client_data_json).or(invalid("clientDataJSON is malformed"))?;
+    if client_data.ceremony != ceremony {
+        return invalid("clientDataJSON type mismatch");
+    }
+    if client_data.challenge.trim_end_matches('=') != challenge {
+        return invalid("challenge mismatch");
+    }
+    if client_data.origin != webauthn.origin || client_data.cross_origin {
+        return invalid("origin mismatch");
+    }
+    Ok(())
+}
+
+/// a credential created during registration
+#[derive(Debug)]
+pub struct AttestedCredential {
+    pub credential_id: Vec<u8>,
+    /// SEC1 uncompressed point
+    pub public_key: Vec<u8>,
+}
+
+#[derive(Debug)]
+pub struct AuthenticatorData {
+    pub flags: u8,
+    pub sign_count: u32,
+    pub credential: Option<AttestedCredential>,
+}
+
+impl AuthenticatorData {
+    /// biometrics or a PIN were checked, not just a touch
+    pub fn user_verified(&self) -> bool {
+        self.flags & FLAG_USER_VERIFIED != 0
+    }
+}
+
+fn parse_authenticator_data(
+    webauthn: &WebauthnSection,
+    bytes: &[u8],
+) -> Result<AuthenticatorData, WebauthnError> {
+    if bytes.len() < AUTHENTICATOR_DATA_MIN_BYTES {
+        return invalid("authenticator data too short");
+    }
+    let (rp_id_hash, rest) = bytes.split_at(RP_ID_HASH_BYTES);
+    if rp_id_hash != sha2::Sha256::digest(webauthn.rp_id.as_bytes()).as_slice() {
+        return invalid("rp id hash mismatch");
+    }
+    let flags = rest[0];
+    if flags & FLAG_USER_PRESENT == 0 {
+        return invalid("user not present");
+    }
+    let sign_count = u32::from_be_bytes([rest[1], rest[2], rest[3], rest[4]]);
+
+    let credential = if flags & FLAG_ATTESTED_CREDENTIAL != 0 {
+        let rest = &rest[5..];
+        if rest.len() < AAGUID_BYTES + 2 {
+            return invalid("attested credential data too short");
+        }
+        let id_length = u16::from_be_bytes([rest[AAGUID_BYTES], rest[AAGUID_BYTES + 1]]) as usize;
+        let rest = &rest[AAGUID_BYTES + 2..];
+        if rest.len() < id_length {
+            return invalid("credential id truncated");
+        }
+        let (credential_id, rest) = rest.split_at(id_length);
+        // extensions may follow the key, reading one value ignores them
+        let cose_key: ciborium::Value =
+            ciborium::from_reader(rest).or(invalid("credential public key is not cbor"))?;
+        Some(AttestedCredential {
+            credential_id: credential_id.to_vec(),
+            public_key: es256_public_key(&cose_key)?,
+        })
+    } else {
+        None
+    };
+
+    Ok(AuthenticatorData {
+        flags,
+        sign_count,
+        credential,
+    })
+}
+
+fn cose_field(
+    cose_key: &[(ciborium::Value, ciborium::Value)],
+    label: i64,
+) -> Option<&ciborium::Value> {
+    cose_key
+        .iter()
+        .find(|(key, _)| key.as_integer() == Some(label.into()))
+        .map(|(_, value)| value)
+}
+
+// COSE_Key of an ES256 credential to a SEC1 uncompressed point
+fn es256_public_key(cose_key: &ciborium::Value) -> Result<Vec<u8>, WebauthnError> {
+    let Some(cose_key) = cose_key.as_map() else {
+        return invalid("credential public key is not a map");
+    };
+    let integer = |label| cose_field(cose_key, label).and_then(ciborium::Value::as_integer);
+    let bytes = |label| cose_field(cose_key, label).and_then(ciborium::Value::as_bytes);
+
+    if integer(COSE_KTY) != Some(COSE_KTY_EC2.into())
+        || integer(COSE_ALG) != Some(ES256.into())
+        || integer(COSE_EC2_CRV) != Some(COSE_CRV_P256.into())
+    {
+        return invalid("unsupported credential algorithm, only ES256");
+    }
+    let (Some(x), Some(y)) = (bytes(COSE_EC2_X), bytes(COSE_EC2_Y)) else {
+        return invalid("credential public key misses coordinates");
+    };
+
+    let mut point = vec![0x04];
+    point.extend_from_slice(x);
+    point.extend_from_slice(y);
+    p256::ecdsa::VerifyingKey::from_sec1_bytes(&point)
+        .or(invalid("credential public key is not on P-256"))?;
+    Ok(point)
+}
+
+/**
+ * # Brief
+ * Verifies a registration response, returns the new credential.
+ *
+ * # Detail
+ * - `challenge` is the one stored in the session for this ceremony.
+ * - Only the authenticator data of the attestation object is checked.
+ */
+pub fn verify_registration(
+    webauthn: &WebauthnSection,
+    challenge: &str,
+    client_data_json: &[u8],
+    attestation_object: &[u8],
+) -> Result<AttestedCredential, WebauthnError> {
+    verify_client_data(webauthn, client_data_json, "webauthn.create", challenge)?;
+
+    let attestation: ciborium::Value =
+        ciborium::from_reader(attestation_object).or(invalid("attestation object is not cbor"))?;
+    let Some(authenticator_data) = attestation
+        .as_map()
+        .and_then(|fields| {
+            fields
+                .iter()
+                .find(|(key, _)| key.as_text() == Some("authData"))
+        })
+        .and_then(|(_, value)| value.as_bytes())
+    else {
+        return invalid("attestation object misses authData");
+    };
+
+    match parse_authenticator_data(webauthn, authenticator_data)?.credential {
+        Some(credential) => Ok(credential),
+        None => invalid("attestation object misses the credential"),
+    }
+}
+
+/**
+ * # Brief
+ * True when an assertion's counter does not move past the stored one.
+ *
+ * # Detail
+ * - Authenticators without a counter always report 0, two zeros pass.
+ * - Otherwise the counter must strictly grow, a repeat hints at a cloned authenticator.
+ */
+pub fn sign_count_regressed(stored: i64, presented: i64) -> bool {
+    (presented != 0 || stored != 0) && presented <= stored
+}
+
+/**
+ * # Brief
+ * Verifies a login assertion against a stored credential.
+ *
+ * # Detail
+ * - `public_key` is the SEC1 point stored at registration.
+ * - The signature covers the authenticator data & the SHA-256 of `clientDataJSON`.
+ * - Sign counter checks are left to the caller, it owns the stored value.
+ */
+pub fn verify_assertion(
+    webauthn: &WebauthnSection,
+    challenge: &str,
+    public_key: &[u8],
+    client_data_json: &[u8],
+    authenticator_data: &[u8],
+    signature: &[u8],
+) -> Result<AuthenticatorData, WebauthnError> {
+    verify_client_data(webauthn, client_data_json, "webauthn.get", challenge)?;
+    let parsed = parse_authenticator_data(webauthn, authenticator_data)?;
+
+    let verifying_key = p256::ecdsa::VerifyingKey::from_sec1_bytes(public_key)
+        .or(invalid("stored public key is malformed"))?;
+    let signature =
+        p256::ecdsa::Signature::from_der(signature).or(invalid("signature is not DER"))?;
+    let mut signed = authenticator_data.to_vec();
+    signed.extend_from_slice(&sha2::Sha256::digest(client_data_json));
+    verifying_key
+        .verify(&signed, &signature)
+        .or(invalid("signature mismatch"))?;
+
+    Ok(parsed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use p256::ecdsa::signature::Signer;
+
+    const CHALLENGE: &str = "c2VydmVyIGNoYWxsZW5nZQ";
+    const CREDENTIAL_ID: &[u8] = b"software-credential";
+
+    fn webauthn() -> WebauthnSection {
+        WebauthnSection {
+            rp_id: "localhost".to_string(),
+            rp_name: "crimson".to_string(),
+            origin: "http://localhost:8080".to_string(),
+            challenge_ttl: 300,
+        }
+    }
+
+    // software authenticator, a fixed P-256 key
+    fn signing_key() -> p256::ecdsa::SigningKey {
+        p256::ecdsa::SigningKey::from_slice(&[0x11; 32]).unwrap()
+    }
+
+    fn client_data(ceremony: &str, challenge: &str, origin: &str) -> Vec<u8> {
+        serde_json::json!({
+            "type": ceremony,
+            "challenge": challenge,
+            "origin": origin,
+            "crossOrigin": false,
+        })
+        .to_string()
+        .into_bytes()
+    }
+
+    fn cose_key(algorithm: i64, key: &p256::ecdsa::SigningKey) -> Vec<u8> {
+        let point = key.verifying_key().to_encoded_point(false);
+        let integer = |n: i64| ciborium::Value::Integer(n.into());
+        let cose_key = ciborium::Value::Map(vec![
+            (integer(COSE_KTY), integer(COSE_KTY_EC2)),
+            (integer(COSE_ALG), integer(algorithm)),
+            (integer(COSE_EC2_CRV), integer(COSE_CRV_P256)),
+            (
+                integer(COSE_EC2_X),
+                ciborium::Value::Bytes(point.x().unwrap().to_vec()),
+            ),
+            (
+                integer(COSE_EC2_Y),
+                ciborium::Value::Bytes(point.y().unwrap().to_vec()),
+            ),
+        ]);
+        let mut encoded = Vec::new();
+        ciborium::into_writer(&cose_key, &mut encoded).unwrap();
+        encoded
+    }
+
+    fn authenticator_data(rp_id: &str, flags: u8, sign_count: u32, credential: &[u8]) -> Vec<u8> {
+        let mut data = sha2::Sha256::digest(rp_id.as_bytes()).to_vec();
+        data.push(flags);
+        data.extend_from_slice(&sign_count.to_be_bytes());
+        if !credential.is_empty() {
+            data.extend_from_slice(&[0; AAGUID_BYTES]);
+            data.extend_from_slice(&(CREDENTIAL_ID.len() as u16).to_be_bytes());
+            data.extend_from_slice(CREDENTIAL_ID);
+            data.extend_from_slice(credential);
+        }
+        data
+    }
+
+    fn attestation_object(authenticator_data: Vec<u8>) -> Vec<u8> {
+        let attestation = ciborium::Value::Map(vec![
+            (
+                ciborium::Value::Text("fmt".to_string()),
+                ciborium::Value::Text("none".to_string()),
+            ),
+            (
+                ciborium::Value::Text("attStmt".to_string()),
+                ciborium::Value::Map(Vec::new()),
+            ),
+            (
+                ciborium::Value::Text("authData".to_string()),
+                ciborium::Value::Bytes(authenticator_data),
+            ),
+        ]);
+        let mut encoded = Vec::new();
+        ciborium::into_writer(&attestation, &mut encoded).unwrap();
+        encoded
+    }
+
+    fn register(
+        client_data_json: &[u8],
+        authenticator_data: Vec<u8>,
+    ) -> Result<AttestedCredential, WebauthnError> {
+        verify_registration(
+            &webauthn(),
+            CHALLENGE,
+            client_data_json,
+            &attestation_object(authenticator_data),
+        )
+    }
+
+    fn registration_data(flags: u8, algorithm: i64) -> Vec<u8> {
+        authenticator_data(
+            "localhost",
+            flags | FLAG_ATTESTED_CREDENTIAL,
+            0,
+            &cose_key(algorithm, &signing_key()),
+        )
+    }
+
+    fn create() -> Vec<u8> {
+        client_data("webauthn.create", CHALLENGE, "http://localhost:8080")
+    }
+
+    fn get() -> Vec<u8> {
+        client_data("webauthn.get", CHALLENGE, "http://localhost:8080")
+    }
+
+    // signs like an authenticator, over authData & the hash of clientDataJSON
+    fn sign(
+        key: &p256::ecdsa::SigningKey,
+        authenticator_data: &[u8],
+        client_data_json: &[u8],
+    ) -> Vec<u8> {
+        let mut signed = authenticator_data.to_vec();
+        signed.extend_from_slice(&sha2::Sha256::digest(client_data_json));
+        let signature: p256::ecdsa::Signature = key.sign(&signed);
+        signature.to_der().as_bytes().to_vec()
+    }
+
+    fn public_key() -> Vec<u8> {
+        signing_key()
+            .verifying_key()
+            .to_encoded_point(false)
+            .as_bytes()
+            .to_vec()
+    }
+
+    fn assert_invalid<T: std::fmt::Debug>(result: Result<T, WebauthnError>, reason: &str) {
+        match result {
+            Err(WebauthnError::Invalid(actual)) => assert_eq!(actual, reason),
+            Ok(value) => panic!("expected `{}`, got {:?}", reason, value),
+        }
+    }
+
+    #[test]
+    fn registration_returns_the_credential() {
+        let credential = register(&create(), registration_data(FLAG_USER_PRESENT, ES256)).unwrap();
+        assert_eq!(credential.credential_id, CREDENTIAL_ID);
+        assert_eq!(credential.public_key, public_key());
+    }
+
+    #[test]
+    fn registration_rejects_wrong_challenge() {
+        let client_data_json = client_data("webauthn.create", "b3RoZXI", "http://localhost:8080");
+        assert_invalid(
+            register(
+                &client_data_json,
+                registration_data(FLAG_USER_PRESENT, ES256),
+            ),
+            "challenge mismatch",
+        );
+    }
+
+    #[test]
+    fn registration_rejects_wrong_origin() {
+        let client_data_json = client_data("webauthn.create", CHALLENGE, "https://evil.example");
+        assert_invalid(
+            register(
+                &client_data_json,
+                registration_data(FLAG_USER_PRESENT, ES256),
+            ),
+            "origin mismatch",
+        );
+    }
+
+    #[test]
+    fn registration_rejects_wrong_type() {
+        assert_invalid(
+            register(&get(), registration_data(FLAG_USER_PRESENT, ES256)),
+            "clientDataJSON type mismatch",
+        );
+    }
+
+    #[test]
+    fn registration_rejects_wrong_rp_id_hash() {
+        let data = authenticator_data(
+            "evil.example",
+            FLAG_USER_PRESENT | FLAG_ATTESTED_CREDENTIAL,
+            0,
+            &cose_key(ES256, &signing_key()),
+        );
+        assert_invalid(register(&create(), data), "rp id hash mismatch");
+    }
+
+    #[test]
+    fn registration_rejects_missing_user_presence() {
+        assert_invalid(
+            register(&create(), registration_data(0, ES256)),
+            "user not present",
+        );
+    }
+
+    #[test]
+    fn registration_rejects_non_es256_keys() {
+        // -257 is RS256
+        assert_invalid(
+            register(&create(), registration_data(FLAG_USER_PRESENT, -257)),
+            "unsupported credential algorithm, only ES256",
+        );
+    }
+
+    #[test]
+    fn assertion_verifies() {
+        let data = authenticator_data("localhost", FLAG_USER_PRESENT | FLAG_USER_VERIFIED, 7, &[]);
+        let signature = sign(&signing_key(), &data, &get());
+        let parsed = verify_assertion(
+            &webauthn(),
+            CHALLENGE,
+            &public_key(),
+            &get(),
+            &data,
+            &signature,
+        )
+        .unwrap();
+        assert_eq!(parsed.sign_count, 7);
+        assert!(parsed.user_verified());
+    }
+
+    #[test]
+    fn assertion_rejects_wrong_challenge_origin_and_type() {
+        let data = authenticator_data("localhost", FLAG_USER_PRESENT, 1, &[]);
+        for (client_data_json, reason) in [
+            (
+                client_data("webauthn.get", "b3RoZXI", "http://localhost:8080"),
+                "challenge mismatch",
+            ),
+            (
+                client_data("webauthn.get", CHALLENGE, "https://evil.example"),
+                "origin mismatch",
+            ),
+            (create(), "clientDataJSON type mismatch"),
+        ] {
+            let signature = sign(&signing_key(), &data, &client_data_json);
+            assert_invalid(
+                verify_assertion(
+                    &webauthn(),
+                    CHALLENGE,
+                    &public_key(),
+                    &client_data_json,
+                    &data,
+                    &signature,
+                ),
+                reason,
+            );
+        }
+    }
+
+    #[test]
+    fn assertion_rejects_wrong_rp_id_hash_and_missing_user_presence() {
+        for (data, reason) in [
+            (
+                authenticator_data("evil.example", FLAG_USER_PRESENT, 1, &[]),
+                "rp id hash mismatch",
+            ),
+            (
+                authenticator_data("localhost", 0, 1, &[]),
+                "user not present",
+            ),
+        ] {
+            let signature = sign(&signing_key(), &data, &get());
+            assert_invalid(
+                verify_assertion(
+                    &webauthn(),
+                    CHALLENGE,
+                    &public_key(),
+                    &get(),
+                    &data,
+                    &signature,
+                ),
+                reason,
+            );
+        }
+    }
+
+    #[test]
+    fn assertion_rejects_bad_signatures() {
+        let data = authenticator_data("localhost", FLAG_USER_PRESENT, 1, &[]);
+
+        // signed by another key
+        let other_key = p256::ecdsa::SigningKey::from_slice(&[0x22; 32]).unwrap();
+        let signature = sign(&other_key, &data, &get());
+        assert_invalid(
+            verify_assertion(
+                &webauthn(),
+                CHALLENGE,
+                &public_key(),
+                &get(),
+                &data,
+                &signature,
+            ),
+            "signature mismatch",
+        );
+
+        // authenticator data changed after signing
+        let signature = sign(&signing_key(), &data, &get());
+        let tampered = authenticator_data("localhost", FLAG_USER_PRESENT, 2, &[]);
+        assert_invalid(
+            verify_assertion(
+                &webauthn(),
+                CHALLENGE,
+                &public_key(),
+                &get(),
+                &tampered,
+                &signature,
+            ),
+            "signature mismatch",
+        );
+
+        assert_invalid(
+            verify_assertion(
+                &webauthn(),
+                CHALLENGE,
+                &public_key(),
+                &get(),
+                &data,
+                b"not der",
+            ),
+            "signature is not DER",
+        );
+    }
+
+    #[test]
+    fn sign_count_must_grow() {
+        // counterless authenticators
+        assert!(!sign_count_regressed(0, 0));
+        assert!(!sign_count_regressed(0, 1));
+        assert!(!sign_count_regressed(5, 6));
+        // a replayed or cloned authenticator
+        assert!(sign_count_regressed(5, 5));
+        assert!(sign_count_regressed(5, 4));
+        assert!(sign_count_regressed(5, 0));
+    }
+}
@@ -1,5 +1,59 @@
-use crate::crimson::api_auth_defs::{http_get_user_register, http_post_user_login, http_post_user_logout};
-use tracing_loki::Layer;
+use crate::crimson::api_admin_defs::{
+    http_delete_admin_user_role, http_get_admin_roles, http_get_admin_user_roles,
+    http_post_admin_user_role,
+};
+use crate::crimson::api_auth_defs::{
+    http_delete_user_session, http_delete_user_sessions, http_get_user_register,
+    http_get_user_session, http_get_user_sessions, http_get_user_verify, http_post_user_login,
+    http_post_user_logout, http_post_user_password_forgot, http_post_user_password_reset,
+    http_post_user_verify_resend,
+};
+use crate::crimson::api_compute_defs::{
+    http_delete_compute_job, http_get_compute_job, http_get_compute_jobs,
+    http_get_compute_worker, http_get_compute_workers, http_post_compute_job,
+    http_post_compute_worker, http_post_compute_worker_heartbeat,
+};
+use crate::crimson::api_keys_defs::{http_delete_api_key, http_get_api_keys, http_post_api_key};
+use crate::crimson::api_oauth_defs::{
+    http_get_oauth_authorize, http_get_oauth_jwks, http_get_oauth_userinfo,
+    http_get_openid_configuration, http_post_oauth_token,
+};
+use crate::crimson::api_oidc_defs::{
+    http_get_oidc_callback, http_get_oidc_login, http_get_oidc_providers,
+};
+use crate::crimson::api_orgs_defs::{
+    http_delete_org_invitation, http_delete_org_member, http_get_org_invitation_accept,
+    http_get_org_invitations, http_get_org_members, http_get_orgs, http_patch_org_member,
+    http_post_org, http_post_org_invitation, http_post_session_org,
+};
+use crate::crimson::api_totp_defs::{
+    http_post_totp_confirm, http_post_totp_disable, http_post_totp_enroll,
+    http_post_user_login_totp,
+};
+use crate::crimson::api_webauthn_defs::{
+    http_delete_webauthn_credential, http_get_webauthn_credentials,
+    http_post_webauthn_login_begin, http_post_webauthn_login_finish,
+    http_post_webauthn_register_begin, http_post_webauthn_register_finish,
+};
+use crate::crimson::compute_registry::{self, WorkerRegistry};
+use crate::crimson::compute_scheduler::{self, JobScheduler};
+use crate::crimson::black_channel_client::{BlackChannelClient, ClientOptions};
+use crate::crimson::black_channel_mock::{self, MockNodeOptions};
+use crate::crimson::black_channel_protocol::{DispatchJob, Frame, JobOutcome};
+use crate::crimson::server_breached::BreachedPasswords;
+use crate::crimson::server_config::{
+    BlackChannelAction, DatabaseSection, MigrateAction, OauthClientAction, PasswordSection,
+    RoleAction, ServerArgs, ServerCommand, ServerConfig,
+};
+use crate::crimson::server_errors;
+use crate::crimson::server_hashing::HashingPool;
+use crate::crimson::server_mailer;
+use crate::crimson::server_migrations;
+use crate::crimson::server_oauth::{self, TokenSigner};
+use crate::crimson::server_oidc::OidcClient;
+use crate::crimson::server_passwords::{self, Passwords};
+use crate::crimson::server_rate_limit::{self, RateLimiter};
+use crate::crimson::server_roles::{self, BootstrapOutcome, RevokeOutcome};
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 mod crimson;
 
@@ -13,88 +67,483 @@ async fn main() -> std::io::Result<()> {
         }
     }
 
-    // key names
-    let central_db_instance_key = "CENTRAL_DATABASE_INSTANCE";
-    let max_threads_key = "CRIMSON_MAX_THREADS";
-    let redis_cluster_key = "REDIS_CLUSTER_INSTANCE";
-    let loki_url_key = "LOKI_URL";
-    let crimson_hash_salt_key = "CRIMSON_HASH_SALT";
-    let trace_level_key = "TRACE_LEVEL";
+    let server_args = <ServerArgs as clap::Parser>::parse();
+    match server_args.command {
+        Some(ServerCommand::Migrate { ref action }) => migrate(&server_args, action).await,
+        Some(ServerCommand::BlackChannel { ref action }) => black_channel(action).await,
+        Some(ServerCommand::OauthClient { ref action }) => oauth_client(&server_args, action).await,
+        Some(ServerCommand::Role { ref action }) => role(&server_args, action).await,
+        Some(ServerCommand::BenchHash {
+            ref memory,
+            ref iterations,
+            ref parallelism,
+            samples,
+        }) => bench_hash(&server_args, memory, iterations, parallelism, samples),
+        Some(ServerCommand::Serve) | None => serve(&server_args).await,
+    }
+}
+
+/**
+ * # Brief
+ * `black-channel` subcommand, mock node & connectivity probe.
+ */
+async fn black_channel(action: &BlackChannelAction) -> std::io::Result<()> {
+    tracing_subscriber::registry()
+        .with(tracing_subscriber::EnvFilter::new("info"))
+        .with(tracing_subscriber::fmt::layer())
+        .init();
+
+    match action {
+        BlackChannelAction::MockNode {
+            listen,
+            capacity,
+            job_seconds,
+        } => {
+            let listener = tokio::net::TcpListener::bind(listen).await?;
+            eprintln!("[crimson]: mock black_channel node listening on {}", listen);
+            black_channel_mock::run_mock_node(
+                listener,
+                MockNodeOptions {
+                    node_id: format!("mock-{}", listen),
+                    capacity: *capacity,
+                    job_duration: std::time::Duration::from_secs(*job_seconds),
+                },
+            )
+            .await
+        }
+        BlackChannelAction::Probe { address } => {
+            let (client, mut events) = BlackChannelClient::new(ClientOptions::default());
+            let completed = DispatchJob {
+                job_id: uuid::Uuid::now_v7(),
+                required_slots: 1,
+                command: "true".to_string(),
+            };
+            let cancelled = DispatchJob {
+                job_id: uuid::Uuid::now_v7(),
+                required_slots: 1,
+                command: "true".to_string(),
+            };
+
+            let sent = async {
+                client.dispatch(address, completed.clone()).await?;
+                client.dispatch(address, cancelled.clone()).await?;
+                client.cancel(address, cancelled.job_id).await
+            };
+            if let Err(e) = sent.await {
+                eprintln!("[crimson]: probe failed | ({})", e);
+                std::process::exit(1);
+            }
+
+            // wait for both jobs to finish, one way or another
+            let mut finished = 0;
+            let deadline = tokio::time::sleep(std::time::Duration::from_secs(30));
+            tokio::pin!(deadline);
+            while finished < 2 {
+                tokio::select! {
+                    event = events.recv() => {
+                        let Some(event) = event else { break };
+                        println!("{} ({}): {:?}", event.node_id, event.address, event.frame);
+                        if let Frame::Result(result) = event.frame
+                            && result.outcome != JobOutcome::Started
+                        {
+                            finished += 1;
+                        }
+                    }
+                    _ = &mut deadline => {
+                        eprintln!("[crimson]: probe timed out waiting for results");
+                        std::process::exit(1);
+                    }
+                }
+            }
+            Ok(())
+        }
+    }
+}
 
-    // load keys
-    let central_db_instance = match std::env::var(central_db_instance_key) {
-        Ok(var) => var,
+/**
+ * # Brief
+ * `migrate` subcommand, only needs the `[database]` configuration.
+ */
+async fn migrate(server_args: &ServerArgs, action: &MigrateAction) -> std::io::Result<()> {
+    let database_config = match DatabaseSection::load(server_args) {
+        Ok(config) => config,
         Err(e) => {
-            panic!(
-                "[crimson]: missing environment variable {} | ({})",
-                central_db_instance_key, e
-            );
+            eprint!("{}", e);
+            std::process::exit(1);
         }
     };
 
-    let max_threads: u32 = match std::env::var(max_threads_key) {
-        Ok(var) => match var.parse() {
-            Ok(var_u32) => var_u32,
-            Err(e) => {
-                panic!(
-                    "[crimson]: environment variable {} is not an integer | ({})",
-                    max_threads_key, e
-                )
-            }
-        },
+    let central_db_connection_pool = match sqlx::postgres::PgPoolOptions::new()
+        .max_connections(1)
+        .connect(&database_config.url)
+        .await
+    {
+        Ok(connection_pool) => connection_pool,
         Err(e) => {
-            panic!(
-                "[crimson]: missing environment variable {} | ({})",
-                max_threads_key, e
-            );
+            eprintln!("[crimson]: central db connection failed | ({})", e);
+            std::process::exit(1);
         }
     };
 
-    let redis_cluster_instance = match std::env::var(redis_cluster_key) {
-        Ok(var) => var,
+    let result = match action {
+        MigrateAction::Status => {
+            server_migrations::status(&central_db_connection_pool)
+                .await
+                .map(|rows| {
+                    for row in rows {
+                        println!(
+                            "{:>6}  {:<40}  {}",
+                            row.version,
+                            row.name,
+                            row.applied_at.as_deref().unwrap_or("pending")
+                        );
+                    }
+                })
+        }
+        MigrateAction::Up => server_migrations::up(&central_db_connection_pool)
+            .await
+            .map(|applied| eprintln!("[crimson]: applied migrations {:?}", applied)),
+        MigrateAction::Down { to } => server_migrations::down(&central_db_connection_pool, *to)
+            .await
+            .map(|reverted| eprintln!("[crimson]: reverted migrations {:?}", reverted)),
+    };
+
+    if let Err(e) = result {
+        eprintln!("[crimson]: migrate failed | ({})", e);
+        std::process::exit(1);
+    }
+    if let MigrateAction::Up = action {
+        report_email_conflicts(&central_db_connection_pool).await;
+    }
+    Ok(())
+}
+
+// users sharing an email up to case can't log in until an operator steps in
+async fn report_email_conflicts(pool: &sqlx::PgPool) {
+    match server_migrations::email_conflicts(pool).await {
+        Ok(None | Some(0)) => {}
+        Ok(Some(count)) => eprintln!(
+            "[crimson]: {} users share their email with another account up to case & can't log in, resolve user_email_conflicts",
+            count
+        ),
+        Err(e) => eprintln!("[crimson]: checking user_email_conflicts failed | ({})", e),
+    }
+}
+
+/**
+ * # Brief
+ * `oauth-client` subcommand, manages clients of the OpenID provider.
+ */
+async fn oauth_client(server_args: &ServerArgs, action: &OauthClientAction) -> std::io::Result<()> {
+    let database_config = match DatabaseSection::load(server_args) {
+        Ok(config) => config,
         Err(e) => {
-            panic!(
-                "[crimson]: missing environment variable {} | ({})",
-                redis_cluster_key, e
-            );
+            eprint!("{}", e);
+            std::process::exit(1);
         }
     };
 
-    let loki_url = match std::env::var(loki_url_key) {
-        Ok(var) => var,
+    let central_db_connection_pool = match sqlx::postgres::PgPoolOptions::new()
+        .max_connections(1)
+        .connect(&database_config.url)
+        .await
+    {
+        Ok(connection_pool) => connection_pool,
         Err(e) => {
-            panic!(
-                "[crimson]: missing environment variable {} | ({})",
-                loki_url_key, e
-            );
+            eprintln!("[crimson]: central db connection failed | ({})", e);
+            std::process::exit(1);
+        }
+    };
+
+    let result = match action {
+        OauthClientAction::Add {
+            name,
+            redirect_uris,
+            scopes,
+            public,
+        } => {
+            if let Some(redirect_uri) = redirect_uris
+                .iter()
+                .find(|redirect_uri| url::Url::parse(redirect_uri).is_err())
+            {
+                eprintln!("[crimson]: redirect uri is not a url | ({})", redirect_uri);
+                std::process::exit(1);
+            }
+            let scopes = match scopes.is_empty() {
+                true => server_oauth::SCOPES.map(str::to_string).to_vec(),
+                false => server_oauth::parse_scope(&scopes.join(" ")),
+            };
+            if let Some(scope) = scopes
+                .iter()
+                .find(|scope| !server_oauth::SCOPES.contains(&scope.as_str()))
+            {
+                eprintln!(
+                    "[crimson]: unknown scope | ({}, expected one of {:?})",
+                    scope,
+                    server_oauth::SCOPES
+                );
+                std::process::exit(1);
+            }
+            server_oauth::create_client(
+                &central_db_connection_pool,
+                name,
+                redirect_uris,
+                &scopes,
+                *public,
+            )
+            .await
+            .map(|(client, client_secret)| {
+                println!("client_id:     {}", client.client_id);
+                match client_secret {
+                    Some(client_secret) => println!("client_secret: {}", client_secret),
+                    None => println!("client_secret: none (public client, PKCE required)"),
+                }
+            })
+        }
+        OauthClientAction::List => server_oauth::list_clients(&central_db_connection_pool)
+            .await
+            .map(|clients| {
+                for client in clients {
+                    let kind = match client.is_public() {
+                        true => "public",
+                        false => "confidential",
+                    };
+                    println!(
+                        "{}  {:<24}  {:<12}  {}  [{}]",
+                        client.client_id,
+                        client.name,
+                        kind,
+                        client.redirect_uris.join(","),
+                        client.scopes.join(" ")
+                    );
+                }
+            }),
+        OauthClientAction::Remove { client_id } => {
+            server_oauth::delete_client(&central_db_connection_pool, client_id)
+                .await
+                .map(|deleted| match deleted {
+                    true => eprintln!("[crimson]: removed oauth client {}", client_id),
+                    false => eprintln!("[crimson]: no oauth client {}", client_id),
+                })
         }
     };
 
-    let crimson_hash_salt = match std::env::var(crimson_hash_salt_key) {
-        Ok(var) => var,
+    if let Err(e) = result {
+        eprintln!("[crimson]: oauth-client failed | ({})", e);
+        std::process::exit(1);
+    }
+    Ok(())
+}
+
+/**
+ * # Brief
+ * `role` subcommand, grants & revokes roles, e.g. the first `admin`.
+ */
+async fn role(server_args: &ServerArgs, action: &RoleAction) -> std::io::Result<()> {
+    let database_config = match DatabaseSection::load(server_args) {
+        Ok(config) => config,
         Err(e) => {
-            panic!(
-                "[crimson]: missing environment variable {} | ({})",
-                crimson_hash_salt_key, e
-            );
+            eprint!("{}", e);
+            std::process::exit(1);
         }
     };
 
-    let trace_level = match std::env::var(trace_level_key) {
-        Ok(var) => var,
+    let central_db_connection_pool = match sqlx::postgres::PgPoolOptions::new()
+        .max_connections(1)
+        .connect(&database_config.url)
+        .await
+    {
+        Ok(connection_pool) => connection_pool,
         Err(e) => {
-            panic!(
-                "[crimson]: missing environment variable {} | ({})",
-                trace_level_key, e
+            eprintln!("[crimson]: central db connection failed | ({})", e);
+            std::process::exit(1);
+        }
+    };
+
+    let pool = &central_db_connection_pool;
+    let result = match action {
+        RoleAction::List => role_list(pool).await,
+        RoleAction::Grant { email, role } => role_grant(pool, email, role).await,
+        RoleAction::Revoke { email, role } => role_revoke(pool, email, role).await,
+    };
+
+    if let Err(e) = result {
+        eprintln!("[crimson]: role failed | ({})", e);
+        std::process::exit(1);
+    }
+    Ok(())
+}
+
+async fn role_list(pool: &sqlx::PgPool) -> Result<(), sqlx::Error> {
+    for role in server_roles::list_roles(pool).await? {
+        println!("{:<12}  [{}]", role.role, role.permissions.join(" "));
+        for email in server_roles::role_holders(pool, &role.role).await? {
+            println!("{:<12}  {}", "", email);
+        }
+    }
+    Ok(())
+}
+
+async fn role_user_id(pool: &sqlx::PgPool, email: &str) -> Result<uuid::Uuid, sqlx::Error> {
+    match server_roles::find_user_by_email(pool, email).await? {
+        Some(user_id) => Ok(user_id),
+        None => {
+            eprintln!("[crimson]: no user with email {}", email);
+            std::process::exit(1);
+        }
+    }
+}
+
+async fn role_grant(pool: &sqlx::PgPool, email: &str, role: &str) -> Result<(), sqlx::Error> {
+    if !server_roles::role_exists(pool, role).await? {
+        eprintln!("[crimson]: unknown role {}", role);
+        std::process::exit(1);
+    }
+    let user_id = role_user_id(pool, email).await?;
+    match server_roles::grant_role(pool, user_id, role, None).await? {
+        true => eprintln!("[crimson]: granted {} to {}", role, email),
+        false => eprintln!("[crimson]: {} already holds {}", email, role),
+    }
+    Ok(())
+}
+
+async fn role_revoke(pool: &sqlx::PgPool, email: &str, role: &str) -> Result<(), sqlx::Error> {
+    let user_id = role_user_id(pool, email).await?;
+    match server_roles::revoke_role(pool, user_id, role).await? {
+        RevokeOutcome::Revoked => eprintln!("[crimson]: revoked {} from {}", role, email),
+        RevokeOutcome::NotGranted => eprintln!("[crimson]: {} doesn't hold {}", email, role),
+        RevokeOutcome::LastAdmin => {
+            eprintln!(
+                "[crimson]: {} is the last admin, grant another first",
+                email
             );
+            std::process::exit(1);
+        }
+    }
+    Ok(())
+}
+
+// OWASP recommendations for Argon2id, equally strong, trading memory for passes
+const BENCH_HASH_CANDIDATES: [(u32, u32, u32); 5] = [
+    (47104, 1, 1),
+    (19456, 2, 1),
+    (12288, 3, 1),
+    (9216, 4, 1),
+    (7168, 5, 1),
+];
+
+/**
+ * # Brief
+ * `bench-hash` subcommand, times password hashing with candidate Argon2 costs.
+ *
+ * # Detail
+ * - Without flags the configured costs & the OWASP recommendations are timed.
+ * - Flags take comma separated candidates, every combination is timed,
+ *   missing ones fall back to the configured cost.
+ */
+fn bench_hash(
+    server_args: &ServerArgs,
+    memory: &[u32],
+    iterations: &[u32],
+    parallelism: &[u32],
+    samples: u32,
+) -> std::io::Result<()> {
+    let password_config = match PasswordSection::load(server_args) {
+        Ok(config) => config,
+        Err(e) => {
+            eprint!("{}", e);
+            std::process::exit(1);
+        }
+    };
+    let configured = (
+        password_config.argon2.m_cost(),
+        password_config.argon2.t_cost(),
+        password_config.argon2.p_cost(),
+    );
+
+    let candidates: Vec<(u32, u32, u32)> =
+        if memory.is_empty() && iterations.is_empty() && parallelism.is_empty() {
+            std::iter::once(configured)
+                .chain(
+                    BENCH_HASH_CANDIDATES
+                        .into_iter()
+                        .filter(|candidate| *candidate != configured),
+                )
+                .collect()
+        } else {
+            let or_configured = |values: &[u32], configured: u32| match values.is_empty() {
+                true => vec![configured],
+                false => values.to_vec(),
+            };
+            let mut candidates = Vec::new();
+            for m in or_configured(memory, configured.0) {
+                for t in or_configured(iterations, configured.1) {
+                    for p in or_configured(parallelism, configured.2) {
+                        candidates.push((m, t, p));
+                    }
+                }
+            }
+            candidates
+        };
+
+    println!(
+        "{:>10}  {:>10}  {:>11}  {:>9}  {:>9}  {:>9}",
+        "memory_kib", "iterations", "parallelism", "min_ms", "median_ms", "max_ms"
+    );
+    for (m, t, p) in candidates {
+        let params = match argon2::Params::new(m, t, p, None) {
+            Ok(params) => params,
+            Err(e) => {
+                eprintln!("[crimson]: skipping m={} t={} p={} | ({})", m, t, p, e);
+                continue;
+            }
+        };
+        let mut timings = match server_passwords::bench(&params, samples) {
+            Ok(timings) => timings,
+            Err(e) => {
+                eprintln!("[crimson]: bench-hash failed | ({})", e);
+                std::process::exit(1);
+            }
+        };
+        timings.sort();
+        let ms = |duration: std::time::Duration| duration.as_secs_f64() * 1000.0;
+        let marker = match (m, t, p) == configured {
+            true => "  (configured)",
+            false => "",
+        };
+        println!(
+            "{:>10}  {:>10}  {:>11}  {:>9.1}  {:>9.1}  {:>9.1}{}",
+            m,
+            t,
+            p,
+            ms(timings[0]),
+            ms(timings[timings.len() / 2]),
+            ms(timings[timings.len() - 1]),
+            marker
+        );
+    }
+    Ok(())
+}
+
+/**
+ * # Brief
+ * Runs the HTTP server.
+ */
+async fn serve(server_args: &ServerArgs) -> std::io::Result<()> {
+    // resolve configuration: file <- environment <- command line
+    let server_config = match ServerConfig::load(server_args) {
+        Ok(config) => config,
+        Err(e) => {
+            eprint!("{}", e);
+            std::process::exit(1);
         }
     };
 
     // sqlx pool allocation
     let central_db_connection_pool: sqlx::Pool<sqlx::Postgres> =
         match sqlx::postgres::PgPoolOptions::new()
-            .max_connections(max_threads)
-            .connect(&central_db_instance)
+            .max_connections(server_config.database.pool_size)
+            .connect(&server_config.database.url)
             .await
         {
             Ok(connection_pool) => {
@@ -109,8 +558,41 @@ async fn main() -> std::io::Result<()> {
             }
         };
 
+    // schema check, applies pending migrations unless disabled
+    let migration_result = if server_config.database.auto_migrate {
+        server_migrations::up(&central_db_connection_pool)
+            .await
+            .map(|applied| eprintln!("[crimson]: applied migrations {:?}", applied))
+    } else {
+        server_migrations::verify(&central_db_connection_pool).await
+    };
+    if let Err(e) = migration_result {
+        panic!("[crimson]: central db schema check failed | ({})", e);
+    }
+    report_email_conflicts(&central_db_connection_pool).await;
+
+    // first admin of a fresh deployment, a no-op once anybody is admin
+    if let Some(email) = &server_config.rbac.bootstrap_admin {
+        match server_roles::bootstrap_admin(&central_db_connection_pool, email).await {
+            Ok(BootstrapOutcome::Granted) => eprintln!("[crimson]: bootstrapped admin {}", email),
+            Ok(BootstrapOutcome::AdminExists) => {}
+            Ok(BootstrapOutcome::UnknownUser) => eprintln!(
+                "[crimson]: rbac.bootstrap_admin {} is not registered yet, no admin bootstrapped",
+                email
+            ),
+            Ok(BootstrapOutcome::Unverified) => eprintln!(
+                "[crimson]: rbac.bootstrap_admin {} has not verified their email, no admin bootstrapped",
+                email
+            ),
+            Err(e) => panic!("[crimson]: admin bootstrap failed | ({})", e),
+        }
+    }
+
     // redis pool allocation
-    let deadpool_redis_config = deadpool_redis::Config::from_url(&redis_cluster_instance);
+    let mut deadpool_redis_config = deadpool_redis::Config::from_url(&server_config.redis.url);
+    deadpool_redis_config.pool = Some(deadpool_redis::PoolConfig::new(
+        server_config.redis.pool_size,
+    ));
 
     let deadpool_redis_pool =
         match deadpool_redis_config.create_pool(Some(deadpool_redis::Runtime::Tokio1)) {
@@ -127,12 +609,14 @@ async fn main() -> std::io::Result<()> {
     let (loki_layer, task) = tracing_loki::builder()
         .label("service", "crimson_heart")
         .unwrap()
-        .build_url(url::Url::parse(&loki_url).unwrap())
+        .build_url(server_config.telemetry.loki_url.clone())
         .unwrap();
 
     tokio::spawn(task);
     tracing_subscriber::registry()
-        .with(tracing_subscriber::EnvFilter::new(trace_level))
+        .with(tracing_subscriber::EnvFilter::new(
+            &server_config.telemetry.trace_level,
+        ))
         .with(tracing_subscriber::fmt::layer())
         .with(loki_layer)
         .init();
@@ -150,25 +634,265 @@ async fn main() -> std::io::Result<()> {
         }
     };
 
+    // compute worker registry, shared through redis
+    let worker_registry = WorkerRegistry::new(
+        deadpool_redis_pool.clone(),
+        server_config.compute.heartbeat_timeout,
+    );
+    actix_web::rt::spawn(compute_registry::run_reaper(
+        worker_registry.clone(),
+        std::time::Duration::from_secs(server_config.compute.reaper_interval),
+    ));
+
+    // job scheduler, places queued jobs on workers over black_channel
+    let placement_policy =
+        match compute_scheduler::placement_policy(&server_config.scheduler.policy) {
+            Some(policy) => policy,
+            None => panic!(
+                "[crimson]: unknown placement policy | ({})",
+                server_config.scheduler.policy
+            ),
+        };
+    let (black_channel_client, node_events) = BlackChannelClient::new(ClientOptions::default());
+    let job_scheduler = JobScheduler::new(
+        central_db_connection_pool.clone(),
+        worker_registry.clone(),
+        black_channel_client,
+        placement_policy,
+        server_config.scheduler.batch_size,
+    );
+    actix_web::rt::spawn(compute_scheduler::run_event_loop(
+        job_scheduler.clone(),
+        node_events,
+    ));
+    actix_web::rt::spawn(compute_scheduler::run_scheduler(
+        job_scheduler.clone(),
+        std::time::Duration::from_millis(server_config.scheduler.interval_ms),
+    ));
+    eprintln!(
+        "[crimson]: job scheduler started ({})",
+        server_config.scheduler.policy
+    );
+
+    // outgoing mail, password resets & verification
+    let mailer = match server_mailer::mailer(
+        &server_config.mail.transport,
+        &server_config.mail.from_address,
+        server_config.mail.file_path.as_deref(),
+    ) {
+        Some(mailer) => {
+            eprintln!(
+                "[crimson]: mailer created ({})",
+                server_config.mail.transport
+            );
+            mailer
+        }
+        None => panic!(
+            "[crimson]: unknown mail transport | ({})",
+            server_config.mail.transport
+        ),
+    };
+
+    // throttling of the auth endpoints, shared by every worker through redis
+    let rate_limiter = RateLimiter::new(
+        deadpool_redis_pool.clone(),
+        server_config.rate_limit.clone(),
+    );
+    eprintln!(
+        "[crimson]: rate limiter created (enabled: {}, routes: {})",
+        server_config.rate_limit.enabled,
+        server_config.rate_limit.routes.len()
+    );
+    if server_config.security.totp_key.is_none() {
+        eprintln!("[crimson]: security.totp_key is not set, 2FA enrollment disabled");
+    }
+
+    // external identity providers, metadata is fetched on first use
+    let oidc = match OidcClient::new(server_config.oidc.clone()) {
+        Ok(oidc) => {
+            eprintln!(
+                "[crimson]: oidc client created (providers: {})",
+                server_config.oidc.providers.len()
+            );
+            oidc
+        }
+        Err(e) => panic!("[crimson]: failed to create oidc client | ({})", e),
+    };
+
+    // our own OpenID provider, for internal services
+    let oauth_signer = server_config
+        .oauth
+        .signing_key
+        .as_ref()
+        .and_then(TokenSigner::new);
+    if oauth_signer.is_none() {
+        eprintln!("[crimson]: oauth.signing_key is not set, OpenID provider disabled");
+    }
+
+    // password hashing, peppered with security.hash_salt, on its own threads
+    let hashing = match HashingPool::new(
+        Passwords::new(&server_config.security, &server_config.password),
+        server_config.password.hashing_workers,
+        server_config.password.hashing_queue,
+        &prometheus_instance.registry,
+    ) {
+        Ok(hashing) => hashing,
+        Err(e) => {
+            panic!("[crimson]: password hashing pool creation failed | ({})", e);
+        }
+    };
+    eprintln!(
+        "[crimson]: password hashing ready (pepper {}, {} previous, m={} t={} p={}, {} workers, queue {})",
+        hashing.passwords().current_id(),
+        server_config.security.previous_hash_salts.len(),
+        server_config.password.argon2.m_cost(),
+        server_config.password.argon2.t_cost(),
+        server_config.password.argon2.p_cost(),
+        server_config.password.hashing_workers,
+        server_config.password.hashing_queue
+    );
+
+    // corpus of breached passwords, new passwords found in it are refused
+    let breached_passwords = match &server_config.password.breached_list {
+        Some(path) => match BreachedPasswords::load(path) {
+            Ok(breached) => {
+                eprintln!(
+                    "[crimson]: breached password list loaded ({} entries, {} KiB)",
+                    breached.entries(),
+                    breached.size() / 1024
+                );
+                Some(std::sync::Arc::new(breached))
+            }
+            Err(e) => panic!(
+                "[crimson]: failed to load breached password list {} | ({})",
+                path.display(),
+                e
+            ),
+        },
+        None => {
+            eprintln!("[crimson]: password.breached_list is not set, breached passwords accepted");
+            None
+        }
+    };
+
     // spin up the server
+    let session_ttl = server_config.server.session_ttl;
+    let worker_token = server_config.compute.worker_token.clone();
+    let public_url = server_config.mail.public_url.clone();
+    let password_reset_ttl = server_config.security.password_reset_ttl;
+    let email_verification_ttl = server_config.security.email_verification_ttl;
+    let totp_key = server_config.security.totp_key;
+    let totp_issuer = server_config.security.totp_issuer.clone();
+    let webauthn = server_config.webauthn.clone();
+    let oauth = server_config.oauth.clone();
+    let rbac = server_config.rbac.clone();
+    let orgs = server_config.orgs.clone();
+    let password = server_config.password.clone();
     actix_web::HttpServer::new(move || {
         actix_web::App::new()
+            .wrap(actix_web::middleware::from_fn(
+                server_rate_limit::rate_limit,
+            ))
+            .wrap(actix_web::middleware::from_fn(
+                server_errors::request_scope,
+            ))
             .wrap(tracing_actix_web::TracingLogger::default())
             .wrap(prometheus_instance.clone())
+            .app_data(
+                actix_web::web::JsonConfig::default()
+                    .error_handler(server_errors::extractor_error),
+            )
+            .app_data(
+                actix_web::web::QueryConfig::default()
+                    .error_handler(server_errors::extractor_error),
+            )
+            .app_data(
+                actix_web::web::PathConfig::default()
+                    .error_handler(server_errors::path_error),
+            )
             .app_data(actix_web::web::Data::new(
                 crimson::server_types::ServerState {
                     central_db_pool: central_db_connection_pool.clone(),
                     redis_pool: deadpool_redis_pool.clone(),
-                    crimson_hash_salt: crimson_hash_salt.clone(),
-                    redis_expire_time: 86400,
-                    local_compute_ids: Vec::new(),
+                    hashing: hashing.clone(),
+                    redis_expire_time: session_ttl,
+                    worker_registry: worker_registry.clone(),
+                    job_scheduler: job_scheduler.clone(),
+                    mailer: mailer.clone(),
+                    public_url: public_url.clone(),
+                    password_reset_ttl,
+                    email_verification_ttl,
+                    rate_limiter: rate_limiter.clone(),
+                    totp_key,
+                    totp_issuer: totp_issuer.clone(),
+                    webauthn: webauthn.clone(),
+                    oidc: oidc.clone(),
+                    oauth_signer: oauth_signer.clone(),
+                    oauth: oauth.clone(),
+                    rbac: rbac.clone(),
+                    orgs: orgs.clone(),
+                    password: password.clone(),
+                    breached_passwords: breached_passwords.clone(),
+                    worker_token: worker_token.clone(),
                 },
             ))
             .service(http_get_user_register)
             .service(http_post_user_login)
+            .service(http_post_user_login_totp)
             .service(http_post_user_logout)
+            .service(http_post_user_password_forgot)
+            .service(http_post_user_password_reset)
+            .service(http_get_user_verify)
+            .service(http_post_user_verify_resend)
+            .service(http_get_user_session)
+            .service(http_get_user_sessions)
+            .service(http_delete_user_session)
+            .service(http_delete_user_sessions)
+            .service(http_post_totp_enroll)
+            .service(http_post_totp_confirm)
+            .service(http_post_totp_disable)
+            .service(http_post_webauthn_register_begin)
+            .service(http_post_webauthn_register_finish)
+            .service(http_post_webauthn_login_begin)
+            .service(http_post_webauthn_login_finish)
+            .service(http_get_webauthn_credentials)
+            .service(http_delete_webauthn_credential)
+            .service(http_post_api_key)
+            .service(http_get_api_keys)
+            .service(http_delete_api_key)
+            .service(http_get_oidc_providers)
+            .service(http_get_oidc_login)
+            .service(http_get_oidc_callback)
+            .service(http_get_openid_configuration)
+            .service(http_get_oauth_jwks)
+            .service(http_get_oauth_authorize)
+            .service(http_post_oauth_token)
+            .service(http_get_oauth_userinfo)
+            .service(http_get_admin_roles)
+            .service(http_get_admin_user_roles)
+            .service(http_post_admin_user_role)
+            .service(http_delete_admin_user_role)
+            .service(http_post_session_org)
+            .service(http_post_org)
+            .service(http_get_orgs)
+            .service(http_get_org_invitation_accept)
+            .service(http_get_org_members)
+            .service(http_patch_org_member)
+            .service(http_delete_org_member)
+            .service(http_post_org_invitation)
+            .service(http_get_org_invitations)
+            .service(http_delete_org_invitation)
+            .service(http_post_compute_job)
+            .service(http_get_compute_job)
+            .service(http_get_compute_jobs)
+            .service(http_delete_compute_job)
+            .service(http_post_compute_worker)
+            .service(http_post_compute_worker_heartbeat)
+            .service(http_get_compute_workers)
+            .service(http_get_compute_worker)
     })
-    .bind(("127.0.0.1", 8080))?
+    .workers(server_config.server.workers)
+    .bind(server_config.server.bind_address)?
     .run()
     .await
 }